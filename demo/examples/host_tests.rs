@@ -0,0 +1,100 @@
+//! Run the `#[cfg(test)]` modules of the demo on the host.
+//!
+//! All the modules below are the same files which the `src/bin/*` demos include by
+//! the `#[path]` attribute, this example only gives them a `std` test harness, which
+//! works the same way as the `test_on_host` example. The tests only use a part of
+//! most modules, the rest is allowed to be dead code here.
+//!
+//! Running the tests (pick one board feature, as `rcc_clock_settings` needs it):
+//!
+//! cargo test --example host_tests --target x86_64-unknown-linux-gnu --features use-stm32f407g-disc1
+//! cargo test --example host_tests --target x86_64-unknown-linux-gnu --features use-weact-black-pill
+
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+#[allow(dead_code)]
+#[path = "../src/board.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "../src/button.rs"]
+mod button;
+#[path = "../src/clock_frequency.rs"]
+mod clock_frequency;
+#[allow(dead_code)]
+#[path = "../src/clock_utils.rs"]
+mod clock_utils;
+#[allow(dead_code)]
+#[path = "../src/cycle_counter.rs"]
+mod cycle_counter;
+#[allow(dead_code)]
+#[path = "../src/register_utils/data_watchpoint_trace_register.rs"]
+mod data_watchpoint_trace_register;
+#[allow(dead_code)]
+#[path = "../src/delay.rs"]
+mod delay;
+#[allow(dead_code)]
+#[path = "../src/eeprom_emulation.rs"]
+mod eeprom_emulation;
+#[allow(dead_code)]
+#[path = "../src/exti.rs"]
+mod exti;
+#[allow(dead_code)]
+#[path = "../src/register_utils/exti_register.rs"]
+mod exti_register;
+#[allow(dead_code)]
+#[path = "../src/register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[allow(dead_code)]
+#[path = "../src/register_utils/flash_control_register.rs"]
+mod flash_control_register;
+#[allow(dead_code)]
+#[path = "../src/register_utils/flash_option_control_register.rs"]
+mod flash_option_control_register;
+#[allow(dead_code)]
+#[path = "../src/flash_utils.rs"]
+mod flash_utils;
+#[allow(dead_code)]
+#[path = "../src/gpio_alternate_function.rs"]
+mod gpio_alternate_function;
+#[allow(dead_code)]
+#[path = "../src/register_utils/gpio_register.rs"]
+mod gpio_register;
+#[allow(dead_code)]
+#[path = "../src/monotonic.rs"]
+mod monotonic;
+#[allow(dead_code)]
+#[path = "../src/register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[allow(dead_code)]
+#[path = "../src/register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../src/rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../src/register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[path = "../src/register_utils/register_access.rs"]
+mod register_access;
+#[cfg(test)]
+#[path = "../src/register_utils/simulated_flash.rs"]
+mod simulated_flash;
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../src/register_utils/simulated_registers.rs"]
+mod simulated_registers;
+#[allow(dead_code)]
+#[path = "../src/software_timer.rs"]
+mod software_timer;
+#[allow(dead_code)]
+#[path = "../src/register_utils/system_tick_timer_register.rs"]
+mod system_tick_timer_register;
+
+#[cfg(not(test))]
+use panic_semihosting as _;
+
+#[cfg(not(test))]
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    loop {}
+}
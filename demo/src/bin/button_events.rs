@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+
+// We have to use the attribute to locate the module file!
+//
+// Every bin compiles the shared modules, it only uses a part of them.
+#[allow(dead_code)]
+#[path = "../board.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "../button.rs"]
+mod button;
+#[path = "../clock_frequency.rs"]
+mod clock_frequency;
+#[allow(dead_code)]
+#[path = "../clock_utils.rs"]
+mod clock_utils;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[allow(dead_code)]
+#[path = "../gpio_alternate_function.rs"]
+mod gpio_alternate_function;
+#[allow(dead_code)]
+#[path = "../register_utils/gpio_register.rs"]
+mod gpio_register;
+#[allow(dead_code)]
+#[path = "../monotonic.rs"]
+mod monotonic;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+#[allow(dead_code)]
+#[path = "../software_timer.rs"]
+mod software_timer;
+#[allow(dead_code)]
+#[path = "../register_utils/system_tick_timer_register.rs"]
+mod system_tick_timer_register;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m_rt::entry;
+use panic_semihosting as _;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+use crate::board::{Board, SelectedBoard, UserLeds};
+use crate::button::{Button, ButtonConfig, ButtonEvent};
+use crate::clock_utils::{ClockConfig, ClockSource, RccClocks};
+use crate::flash_access_control_register::FlashAccessControlRegister;
+use crate::monotonic::{Monotonic, MILLISECONDS};
+use crate::register_access::Mmio;
+use crate::software_timer::{SharedSoftwareTimers, TimerAction};
+
+// Advanced by the SysTick exception handler every millisecond
+static TIMERS: SharedSoftwareTimers<1> = SharedSoftwareTimers::new();
+
+// Set every 5ms by the sampling timer, cleared by the thread mode
+static SAMPLE_BUTTON: AtomicBool = AtomicBool::new(false);
+
+/// Click toggles one LED, double click toggles all of them, long press turns them off
+#[entry]
+fn main() -> ! {
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!("STM32F4 button events demo is running >>>>>");
+
+    let rcc_clock =
+        RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HseThroughPll));
+
+    FlashAccessControlRegister::enable_prefetch(&Mmio);
+    FlashAccessControlRegister::enable_instruction_cache(&Mmio);
+    FlashAccessControlRegister::enable_data_cache(&Mmio);
+
+    // The SysTick exception handler is in the `monotonic` module
+    let _monotonic = Monotonic::start(Mmio, rcc_clock.get_cpu_clock_frequency_in_hertz()).unwrap();
+
+    #[cfg(feature = "enable-debug")]
+    SelectedBoard::print_info();
+
+    let board = SelectedBoard::take(Mmio).unwrap();
+    let mut leds = board.leds;
+    let key = board.user_button;
+    let mut button = Button::<8>::new(ButtonConfig::new(SelectedBoard::BUTTON_ACTIVE_LEVEL));
+
+    crate::monotonic::set_tick_handler(|now| TIMERS.advance(now));
+    TIMERS
+        .start_periodic(5, TimerAction::Flag(&SAMPLE_BUTTON))
+        .unwrap();
+
+    loop {
+        if !SAMPLE_BUTTON.swap(false, Ordering::AcqRel) {
+            // Sleep until the next SysTick exception
+            cortex_m::asm::wfi();
+            continue;
+        }
+
+        button.poll(MILLISECONDS.get(), &key);
+
+        while let Some(event) = button.next_event() {
+            match event {
+                ButtonEvent::Click => leds.toggle(0),
+                ButtonEvent::DoubleClick => leds.toggle_all(),
+                ButtonEvent::LongPress(_) => leds.set_all(false),
+                _ => {}
+            }
+
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("button event: {:?}", event);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+
+// We have to use the attribute to locate the module file!
+//
+// Every bin compiles the shared modules, it only uses a part of them.
+#[path = "../clock_frequency.rs"]
+mod clock_frequency;
+#[allow(dead_code)]
+#[path = "../clock_utils.rs"]
+mod clock_utils;
+#[allow(dead_code)]
+#[path = "../eeprom_emulation.rs"]
+mod eeprom_emulation;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_control_register.rs"]
+mod flash_control_register;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_option_control_register.rs"]
+mod flash_option_control_register;
+#[allow(dead_code)]
+#[path = "../flash_utils.rs"]
+mod flash_utils;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+
+use cortex_m_rt::entry;
+use panic_semihosting as _;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+use crate::eeprom_emulation::{EmulatedEeprom, BOARD_EEPROM_SECTORS};
+use crate::flash_utils::FlashDriver;
+use crate::register_access::Mmio;
+
+// The keys of the values which survive the reset
+const BOOT_COUNT_KEY: u16 = 0x0001;
+
+///
+#[entry]
+fn main() -> ! {
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!("STM32F4 EEPROM emulation boot counter demo is running >>>>>");
+
+    // The program code takes the first sectors only (`memory.x`), the last 2 sectors
+    // are the EEPROM pages
+    let mut eeprom = EmulatedEeprom::new(FlashDriver::new(Mmio), BOARD_EEPROM_SECTORS)
+        .expect("Emulated EEPROM init failed");
+
+    let boot_count = eeprom.read(BOOT_COUNT_KEY).unwrap_or(0) + 1;
+    eeprom
+        .write(BOOT_COUNT_KEY, boot_count)
+        .expect("Emulated EEPROM write failed");
+
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!(
+        "Boot count: {}, {} records left in sector {} before the page transfer",
+        boot_count,
+        eeprom.get_free_records(),
+        eeprom.get_valid_sector().number
+    );
+
+    loop {}
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+
+// We have to use the attribute to locate the module file!
+//
+// Every bin compiles the shared modules, it only uses a part of them.
+#[allow(dead_code)]
+#[path = "../board.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "../exti.rs"]
+mod exti;
+#[allow(dead_code)]
+#[path = "../register_utils/exti_register.rs"]
+mod exti_register;
+#[allow(dead_code)]
+#[path = "../gpio_alternate_function.rs"]
+mod gpio_alternate_function;
+#[allow(dead_code)]
+#[path = "../register_utils/gpio_register.rs"]
+mod gpio_register;
+#[allow(dead_code)]
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m_rt::entry;
+use panic_semihosting as _;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+use crate::board::{Board, SelectedBoard, UserLeds};
+#[cfg(feature = "enable-debug")]
+use crate::exti_register::ExternalInterrupt;
+use crate::exti_register::ExtiEdge;
+use crate::gpio_register::ActiveLevel;
+use crate::register_access::Mmio;
+
+// The Discovery user button (B1) is high when pressed, the Black Pill KEY button is
+// low when pressed
+const BUTTON_PRESSED_EDGE: ExtiEdge = match SelectedBoard::BUTTON_ACTIVE_LEVEL {
+    ActiveLevel::High => ExtiEdge::Rising,
+    ActiveLevel::Low => ExtiEdge::Falling,
+};
+
+// Moved into the static after setup, then only the EXTI0 handler uses them
+static LEDS: Mutex<RefCell<Option<<SelectedBoard as Board>::Leds<Mmio>>>> =
+    Mutex::new(RefCell::new(None));
+
+static BUTTON_PRESSES: AtomicU32 = AtomicU32::new(0);
+
+/// Runs in the EXTI0 interrupt handler. The button isn't debounced, one press may
+/// toggle the LEDs more than once.
+fn on_button_pressed(_line: u8) {
+    BUTTON_PRESSES.fetch_add(1, Ordering::Relaxed);
+
+    interrupt::free(|cs| {
+        if let Some(leds) = LEDS.borrow(cs).borrow_mut().as_mut() {
+            leds.toggle_all();
+        }
+    });
+}
+
+///
+#[entry]
+fn main() -> ! {
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!("STM32F4 EXTI button LED demo is running >>>>>");
+
+    #[cfg(feature = "enable-debug")]
+    SelectedBoard::print_info();
+
+    let board = SelectedBoard::take(Mmio).unwrap();
+    let leds = board.leds;
+    interrupt::free(|cs| LEDS.borrow(cs).replace(Some(leds)));
+
+    let button = board.user_button;
+    exti::listen(&Mmio, &button, BUTTON_PRESSED_EDGE, on_button_pressed)
+        .expect("EXTI line 0 is in use");
+
+    #[cfg(feature = "enable-debug")]
+    ExternalInterrupt::print_config(&Mmio);
+
+    let mut last_button_presses = 0;
+    loop {
+        // Sleep until the next interrupt
+        cortex_m::asm::wfi();
+
+        let button_presses = BUTTON_PRESSES.load(Ordering::Relaxed);
+        if button_presses != last_button_presses {
+            last_button_presses = button_presses;
+
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("button presses: {}", button_presses);
+        }
+    }
+}
@@ -2,6 +2,34 @@
 #![no_std]
 #![no_main]
 
+// We have to use the attribute to locate the module file!
+#[path = "../board.rs"]
+mod board;
+#[path = "../clock_frequency.rs"]
+mod clock_frequency;
+#[path = "../clock_utils.rs"]
+mod clock_utils;
+#[path = "../delay.rs"]
+mod delay;
+#[path = "../register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[path = "../gpio_alternate_function.rs"]
+mod gpio_alternate_function;
+#[path = "../register_utils/gpio_register.rs"]
+mod gpio_register;
+#[path = "../register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[path = "../register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[path = "../register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+#[path = "../register_utils/system_tick_timer_register.rs"]
+mod system_tick_timer_register;
+
 use cortex_m_rt::entry;
 
 #[cfg(feature = "enable-debug")]
@@ -19,21 +47,40 @@ use stm32f4xx_hal as hal;
 // Import from `stm32f4xx_hal`
 #[cfg(feature = "enable-hal")]
 use hal::{
-    delay::Delay,
-    gpio::{
-        gpiod::{Parts, PD12, PD13, PD14, PD15},
-        Output, PushPull,
-    },
+    gpio::{Output, PushPull},
     rcc::Rcc, // Constrained RCC peripheral
 };
 
+use crate::board::{Board, SelectedBoard};
+use crate::clock_utils::RccClocks;
+use crate::delay::Delay;
+use crate::gpio_register::ActiveLevel;
+use crate::register_access::Mmio;
+
+// `downgrade()` erases the pin number from the type, then all the LEDs of the port fit
+// in one array
+#[cfg(all(feature = "enable-hal", feature = "use-stm32f407g-disc1"))]
+type HalLed = hal::gpio::gpiod::PD<Output<PushPull>>;
+
+#[cfg(all(feature = "enable-hal", feature = "use-weact-black-pill"))]
+type HalLed = hal::gpio::gpioc::PC<Output<PushPull>>;
+
+/// The Black Pill LED is on when the pin is low
+#[cfg(feature = "enable-hal")]
+fn set_led(led: &mut HalLed, on: bool) {
+    if on == (SelectedBoard::LED_ACTIVE_LEVEL == ActiveLevel::High) {
+        led.set_high().unwrap();
+    } else {
+        led.set_low().unwrap();
+    }
+}
+
 #[entry]
 fn main() -> ! {
     #[cfg(feature = "enable-debug")]
     let _ = hprintln!("STM32F4 GPIO Led demo is running >>>>>");
 
     let stm32407_peripherals = stm32::Peripherals::take().unwrap();
-    let cortex_m_peripherals = cortex_m::peripheral::Peripherals::take().unwrap();
 
     // Set up the LEDs. Below LED info copied from STM32F4Discovery user manual:
     //
@@ -48,40 +95,48 @@ fn main() -> ! {
     // `GPIOD.splt()` return a `Parts` struct instance which include all pins with the default
     // <MODE<type state>> which is `Input<Floating>`:
     // https://docs.rs/stm32f4xx-hal/0.8.3/stm32f4xx_hal/gpio/gpiod/struct.Parts.html
-    let gpiod: Parts = stm32407_peripherals.GPIOD.split();
-
-    // Take all those LED pins and convert into `Output` mode with `PushPull` type state
-    let mut green_led: PD12<Output<PushPull>> = gpiod.pd12.into_push_pull_output();
-    let mut orange_led: PD13<Output<PushPull>> = gpiod.pd13.into_push_pull_output();
-    let mut red_led: PD14<Output<PushPull>> = gpiod.pd14.into_push_pull_output();
-    let mut blue_led: PD15<Output<PushPull>> = gpiod.pd15.into_push_pull_output();
+    //
+    // Take all those LED pins and convert into `Output` mode with `PushPull` type state.
+    #[cfg(feature = "use-stm32f407g-disc1")]
+    let mut leds: [HalLed; 4] = {
+        let gpiod = stm32407_peripherals.GPIOD.split();
+        [
+            gpiod.pd12.into_push_pull_output().downgrade(),
+            gpiod.pd13.into_push_pull_output().downgrade(),
+            gpiod.pd14.into_push_pull_output().downgrade(),
+            gpiod.pd15.into_push_pull_output().downgrade(),
+        ]
+    };
+
+    // The Black Pill only has the blue LED on PC13
+    #[cfg(feature = "use-weact-black-pill")]
+    let mut leds: [HalLed; 1] = {
+        let gpioc = stm32407_peripherals.GPIOC.split();
+        [gpioc.pc13.into_push_pull_output().downgrade()]
+    };
 
     // Set up the system clock. We want to run at 16Mhz for this one.
     let constrained_rcc_peripheral: Rcc = stm32407_peripherals.RCC.constrain();
     let clocks = constrained_rcc_peripheral.cfgr.sysclk(16.mhz()).freeze();
 
-    // Create a delay abstraction based on SysTick
-    let mut delay = Delay::new(cortex_m_peripherals.SYST, clocks);
-
-    // I don't know how the `sysclk` works and how to set the correct `Mhz`, but for now,
-    // the `excepted_delay_time_in_ms` needs to cut half for getting the correct delay time.
-    let expected_delay_time_in_ms = 1000u32;
-    let delay_time_in_ms = (expected_delay_time_in_ms / 2) as u32;
+    // Create a delay abstraction based on SysTick. It counts on the HCLK which is read back
+    // from the RCC registers, and it implements the same `embedded-hal` `DelayMs` trait
+    // with the HAL one, no need to cut the delay time in half anymore.
+    let mut delay = Delay::new(Mmio, &RccClocks::from_hardware(&Mmio).unwrap());
+    let delay_time_in_ms = 1000u32;
 
     loop {
         // On for 1s
-        green_led.set_high().unwrap();
-        orange_led.set_high().unwrap();
-        red_led.set_high().unwrap();
-        blue_led.set_high().unwrap();
+        for led in leds.iter_mut() {
+            set_led(led, true);
+        }
 
         delay.delay_ms(delay_time_in_ms);
 
         // off for 1s
-        green_led.set_low().unwrap();
-        orange_led.set_low().unwrap();
-        red_led.set_low().unwrap();
-        blue_led.set_low().unwrap();
+        for led in leds.iter_mut() {
+            set_led(led, false);
+        }
 
         delay.delay_ms(delay_time_in_ms);
     }
@@ -1,7 +1,47 @@
 #![no_std]
 #![no_main]
 
-use cortex_m::asm::delay;
+// We have to use the attribute to locate the module file!
+//
+// Every bin compiles the shared modules, it only uses a part of them.
+#[allow(dead_code)]
+#[path = "../board.rs"]
+mod board;
+#[path = "../clock_frequency.rs"]
+mod clock_frequency;
+#[allow(dead_code)]
+#[path = "../clock_utils.rs"]
+mod clock_utils;
+#[allow(dead_code)]
+#[path = "../delay.rs"]
+mod delay;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[allow(dead_code)]
+#[path = "../gpio_alternate_function.rs"]
+mod gpio_alternate_function;
+#[allow(dead_code)]
+#[path = "../register_utils/gpio_register.rs"]
+mod gpio_register;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+#[allow(dead_code)]
+#[path = "../register_utils/system_tick_timer_register.rs"]
+mod system_tick_timer_register;
+
 use cortex_m_rt::entry;
 
 #[cfg(feature = "enable-debug")]
@@ -9,12 +49,10 @@ use cortex_m_semihosting::hprintln;
 
 use panic_semihosting as _;
 
-// As we don't use `PAC` and `HAL` in this example, and we didn't touch the `Clock` and
-// `Interrupt` yet. That's why we use a dumb version `delay` at this moment. It's not
-// accuracy, that's fine, as that's not the point we focus on at this moment.
-fn dumb_delay(millisecond: u32) {
-    delay(100_000 * millisecond);
-}
+use crate::board::{Board, SelectedBoard, UserLeds};
+use crate::clock_utils::RccClocks;
+use crate::delay::Delay;
+use crate::register_access::Mmio;
 
 #[entry]
 fn main() -> ! {
@@ -24,78 +62,34 @@ fn main() -> ! {
     // Below is the very important step:
     //
     // When you first turn on the `MCU`, everything turns off for power saving. We need to enable
-    // the `GPIOD` port. Info in `reference manual` (page 265, RCC register map).
+    // the LED port clock in `RCC_AHB1ENR` (page 242), `take()` only sets the bits of the board
+    // ports, the other ports and peripherals keep their bits.
     //
-    // RCC (Reset and Clock Control)
-    const RCC_REGISTER: u32 = 0x4002_3800;
-    const RCC_AHB1ENR_REGISTER: u32 = RCC_REGISTER + 0x30; // page 242, 243
-    const RCC_AHB1LPENR_REGISTER: u32 = RCC_REGISTER + 0x50; // Low power (sleep) mode, page 250, 252,
-    unsafe {
-        // Enable `GPIOD` by setting the `bit3` to `1` for both registers.
-        *(RCC_AHB1ENR_REGISTER as *mut u32) = 1 << 3;
-        *(RCC_AHB1LPENR_REGISTER as *mut u32) = 1 << 3;
-    }
-
-    // `GPIOD` register mapping address, in `reference manual` (page 65, `STM32F4xx register boundary addresses`).
-    const GPIOD_REGISTER: u32 = 0x4002_0c00;
-
-    // GPIO port mode register (GPIOx_MODER) address, `reference manual` (page 281).
-    const GPIOD_MODER: u32 = GPIOD_REGISTER + 0x00;
-    let gpiod_moder_mut_ptr: *mut u32 = GPIOD_MODER as *mut u32; // Mutable raw pointer
-    let gpiod_moder_ptr: *const u32 = GPIOD_MODER as *const u32; // Immutable raw pointer
-    unsafe {
-        // Set `GPIOD` pin12 ~ pin15 to OUTPUT mode
-
-        // Keep the prev value and add (`|`) new setting:
-        // bit 25, 24 set to `01`
-        // bit 27, 26 set to `01`
-        // bit 29, 28 set to `01`
-        // bit 31, 30 set to `01`
-        //
-        // As the "GPIOD_BSRR" does nothing when set bit to `0`, so actually, we even don't
-        // need the `|=` for keeping the prev value. But we keep that just doing in the normal
-        // way.
-        //
-        *gpiod_moder_mut_ptr |= (1 << 24) | (1 << 26) | (1 << 28) | (1 << 30);
+    // Then it sets the LED pins to OUTPUT mode (push-pull), `MODER` 2 bits per pin (page 281),
+    // the other pins are untouched. The LEDs start as off, PD12 ~ PD15 on the Discovery, PC13 on
+    // the Black Pill.
+    let board = SelectedBoard::take(Mmio).unwrap();
+    let mut leds = board.leds;
 
-        // Let's print the "GPIOD_MODER" register bit value (32bit, 4 bytes), and it should be:
-        // 0b01010101000000000000000000000000
-        //
-        // From right to left is bit0 ~ bit31, only bit24, bit26, bit 28, bit30 set to `1`.
-        #[cfg(feature = "enable-debug")]
-        let _ = hprintln!("GPIOD_MODER: {:#034b}", *gpiod_moder_ptr);
-    }
-
-    // GPIO port output type register (GPIOx_OTYPER) address, `reference manual` (page 281).
-    // As the output type `push-pull` is `0`, then we don't need to set `GPIOD_OTYPER` explicitly.
-    // const GPIOD_OTYPER: u32 = GPIOD_REGISTER + 0x04;
-
-    // GPIO port bit set/reset register (GPIOx_BSRR) address, `reference manual` (page 284).
-    const GPIOD_BSRR: u32 = GPIOD_REGISTER + 0x18;
-    let gpiod_bsrr_mut_ptr = GPIOD_BSRR as *mut u32;
-
-    // Setup GPIOD.P12 ~ P15 to output mode with `
+    // Every LED pin is `0b01` in the "GPIOx_MODER" register
+    #[cfg(feature = "enable-debug")]
+    board.led_port.print_config();
 
-    unsafe {
-        // Set bit (pin) 12 ~ 15 to `1` to turn on 4 LEDs.
-        //
-        // As the "GPIOD_BSRR" does nothing when setting bit to `0`, so actually, we even don't
-        // need the `|=` for keeping the previous value.
-        *gpiod_bsrr_mut_ptr = (1 << 12) | (1 << 13) | (1 << 14) | (1 << 15);
-    }
+    // Turn on all LEDs, every LED is one `GPIOx_BSRR` write (page 284), no read-modify-write.
+    // The Black Pill LED is on when the pin is low, `UserLeds` takes care of it.
+    leds.set_all(true);
 
     #[cfg(feature = "enable-debug")]
     let _ = hprintln!("\nDelay 1s......\n");
 
-    dumb_delay(10000);
+    // We didn't touch the clock in this example, the SysTick delay counts on the clock
+    // which is running after reset (HSI 16MHz)
+    let rcc_clocks = RccClocks::from_hardware(&Mmio).unwrap();
+    Delay::new(Mmio, &rcc_clocks).delay_ms(1000);
 
-    unsafe {
-        // Set bit (pint) 12 + 16, 13 + 16 to `1` to turn off 2 LEDs.
-        //
-        // As the "GPIOD_BSRR" does nothing when setting bit to `0`, so actually, we even don't
-        // need the `|=` for keeping the previous value.
-        *gpiod_bsrr_mut_ptr = (1 << (12 + 16)) | (1 << (13 + 16));
-    }
+    // Turn off 2 LEDs, the index wraps around on the Black Pill which has only one
+    leds.set(0, false);
+    leds.set(1, false);
 
     loop {}
 }
@@ -8,6 +8,8 @@ mod clock_frequency;
 mod clock_utils;
 #[path = "../register_utils/flash_access_control_register.rs"]
 mod flash_access_control_register;
+#[path = "../monotonic.rs"]
+mod monotonic;
 #[path = "../register_utils/rcc_clock_config_register.rs"]
 mod rcc_clock_config_register;
 #[path = "../register_utils/rcc_clock_control_register.rs"]
@@ -16,55 +18,84 @@ mod rcc_clock_control_register;
 mod rcc_clock_settings;
 #[path = "../register_utils/rcc_pll_config_register.rs"]
 mod rcc_pll_config_register;
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+#[path = "../software_timer.rs"]
+mod software_timer;
 #[path = "../register_utils/system_tick_timer_register.rs"]
 mod system_tick_timer_register;
 
-use cortex_m_rt::{entry, exception};
+use cortex_m_rt::entry;
 use panic_semihosting as _;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-use crate::clock_utils::{ClockSource, RccClocks};
+use crate::clock_utils::{ClockConfig, ClockSource, RccClocks};
+use crate::flash_access_control_register::FlashAccessControlRegister;
+use crate::monotonic::{Monotonic, MILLISECONDS};
+use crate::register_access::Mmio;
+use crate::software_timer::{SharedSoftwareTimers, TimerAction};
+use core::sync::atomic::{AtomicBool, Ordering};
 use system_tick_timer_register::SystemTickTimer;
 
+// Advanced by the SysTick exception handler every millisecond
+static TIMERS: SharedSoftwareTimers<4> = SharedSoftwareTimers::new();
+
+// Set by the 1 second timer, cleared by the thread mode
+static PRINT_SECONDS_PASSED: AtomicBool = AtomicBool::new(false);
+
+// Set by the 5 seconds timer, cleared by the thread mode
+static REPORT_MISSED_DEADLINES: AtomicBool = AtomicBool::new(false);
+
+/// Runs in the thread mode, semihosting is too slow for the SysTick exception handler
+fn print_seconds_passed() {
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!("seconds_passed: {}", MILLISECONDS.get() / 1000);
+}
+
 #[entry]
 fn main() -> ! {
     #[cfg(feature = "enable-debug")]
     let _ = hprintln!("STM32F4 system tick interrput demo is running >>>>>");
 
-    let rcc_clock = RccClocks::setup_system_clock(ClockSource::HseThroughPll);
-    SystemTickTimer::enable(rcc_clock.get_cpu_clock_frequency_in_hertz(), true);
+    let rcc_clock =
+        RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HseThroughPll));
+
+    // ART accelerator: prefetch, instruction cache and data cache
+    FlashAccessControlRegister::enable_prefetch(&Mmio);
+    FlashAccessControlRegister::enable_instruction_cache(&Mmio);
+    FlashAccessControlRegister::enable_data_cache(&Mmio);
+
+    // The SysTick exception handler is in the `monotonic` module
+    let monotonic = Monotonic::start(Mmio, rcc_clock.get_cpu_clock_frequency_in_hertz()).unwrap();
 
     #[cfg(feature = "enable-debug")]
     {
-        // RccClocks::print_system_clock_info();
-        SystemTickTimer::print_config();
+        // RccClocks::print_system_clock_info(&Mmio);
+        SystemTickTimer::print_config(&Mmio);
     }
 
-    loop {}
-}
+    crate::monotonic::set_tick_handler(|now| TIMERS.advance(now));
+    TIMERS
+        .start_periodic(1000, TimerAction::Flag(&PRINT_SECONDS_PASSED))
+        .unwrap();
+    TIMERS
+        .start_periodic(5000, TimerAction::Flag(&REPORT_MISSED_DEADLINES))
+        .unwrap();
 
-#[exception]
-fn SysTick() {
-    // One feature of the `cortex_m_rt` is that:
-    //
-    // "Initializing static variables before the program entry point."
-    //
-    // So the `static mut u32` variable here should be defined before the `entry` and
-    // make a `let SAME_NAME_VAR: &mut u32;` here for making the compiler happy to
-    // pass the borrow checker.
-    //
-    // That's why we can use `*SAME_NAME_VAR` to set the static variable below;
-    static mut current_past_milliseconds_count: u32 = 0u32;
-
-    *current_past_milliseconds_count += 1;
+    loop {
+        if PRINT_SECONDS_PASSED.swap(false, Ordering::AcqRel) {
+            print_seconds_passed();
+        }
 
-    #[cfg(feature = "enable-debug")]
-    {
-        let seconds_passed = (*current_past_milliseconds_count / 1000) as u32;
-        if *current_past_milliseconds_count % 1000 == 0 {
-            hprintln!("seconds_passed: {}", seconds_passed);
+        if REPORT_MISSED_DEADLINES.swap(false, Ordering::AcqRel) {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!(
+                "uptime: {}ms, missed deadlines: {}",
+                monotonic.now().as_millis(),
+                TIMERS.get_total_missed_deadlines()
+            );
         }
     }
 }
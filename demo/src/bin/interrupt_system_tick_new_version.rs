@@ -8,6 +8,8 @@ mod clock_frequency;
 mod clock_utils;
 #[path = "../register_utils/flash_access_control_register.rs"]
 mod flash_access_control_register;
+#[path = "../monotonic.rs"]
+mod monotonic;
 #[path = "../register_utils/rcc_clock_config_register.rs"]
 mod rcc_clock_config_register;
 #[path = "../register_utils/rcc_clock_control_register.rs"]
@@ -16,6 +18,10 @@ mod rcc_clock_control_register;
 mod rcc_clock_settings;
 #[path = "../register_utils/rcc_pll_config_register.rs"]
 mod rcc_pll_config_register;
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+#[path = "../software_timer.rs"]
+mod software_timer;
 #[path = "../register_utils/system_tick_timer_register.rs"]
 mod system_tick_timer_register;
 
@@ -25,44 +31,60 @@ use panic_semihosting as _;
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-use crate::clock_utils::{ClockSource, RccClocks};
+use crate::clock_utils::{ClockConfig, ClockSource, RccClocks};
+use crate::flash_access_control_register::FlashAccessControlRegister;
+use crate::monotonic::Monotonic;
+use crate::register_access::Mmio;
+use crate::software_timer::{SharedSoftwareTimers, TimerAction};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 use system_tick_timer_register::SystemTickTimer;
 
+// Advanced by the SysTick exception handler every millisecond
+static TIMERS: SharedSoftwareTimers<2> = SharedSoftwareTimers::new();
+
+// Set by the 1 second timer, cleared by the thread mode
+static ONE_SECOND_PASSED: AtomicBool = AtomicBool::new(false);
+
 #[entry]
 fn main() -> ! {
     #[cfg(feature = "enable-debug")]
     let _ = hprintln!("STM32F4 system tick interrput demo is running >>>>>");
 
-    let rcc_clock = RccClocks::setup_system_clock(ClockSource::HseThroughPll);
-    SystemTickTimer::enable(rcc_clock.get_cpu_clock_frequency_in_hertz(), true);
+    let rcc_clock =
+        RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HseThroughPll));
+
+    // ART accelerator: prefetch, instruction cache and data cache
+    FlashAccessControlRegister::enable_prefetch(&Mmio);
+    FlashAccessControlRegister::enable_instruction_cache(&Mmio);
+    FlashAccessControlRegister::enable_data_cache(&Mmio);
+
+    // The SysTick exception handler is in the `monotonic` module
+    let monotonic = Monotonic::start(Mmio, rcc_clock.get_cpu_clock_frequency_in_hertz()).unwrap();
 
     #[cfg(feature = "enable-debug")]
     {
-        // RccClocks::print_system_clock_info();
-        SystemTickTimer::print_config();
+        // RccClocks::print_system_clock_info(&Mmio);
+        SystemTickTimer::print_config(&Mmio);
     }
 
-    loop {}
-}
+    crate::monotonic::set_tick_handler(|now| TIMERS.advance(now));
+    let one_second = TIMERS
+        .start_periodic(1000, TimerAction::Flag(&ONE_SECOND_PASSED))
+        .unwrap();
 
-#[export_name = "SysTick"]
-pub unsafe extern "C" fn system_tick_exception_handler_wrapper() {
-    system_tick_exception_handler({
-        static mut current_past_milliseconds_count: u32 = 0u32;
-        &mut current_past_milliseconds_count
-    })
-}
+    // Sub-millisecond resolution, the uptime is read between 2 SysTick exceptions
+    loop {
+        if ONE_SECOND_PASSED.swap(false, Ordering::AcqRel) {
+            let now = monotonic.now();
 
-fn system_tick_exception_handler(
-    #[allow(non_snake_case)] current_past_milliseconds_count: &mut u32,
-) {
-    *current_past_milliseconds_count += 1;
-    //
-    #[cfg(feature = "enable-debug")]
-    {
-        let seconds_passed = (*current_past_milliseconds_count / 1000) as u32;
-        if *current_past_milliseconds_count % 1000 == 0 {
-            hprintln!("New version, seconds_passed: {}", seconds_passed);
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!(
+                "New version, seconds_passed: {}, uptime: {:?}, missed deadlines: {}",
+                now.as_secs(),
+                Duration::from_micros(now.as_micros()),
+                TIMERS.get_missed_deadlines(one_second).unwrap_or(0)
+            );
         }
     }
 }
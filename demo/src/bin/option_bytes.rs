@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+
+// We have to use the attribute to locate the module file!
+//
+// Every bin compiles the shared modules, it only uses a part of them.
+#[path = "../clock_frequency.rs"]
+mod clock_frequency;
+#[allow(dead_code)]
+#[path = "../clock_utils.rs"]
+mod clock_utils;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_access_control_register.rs"]
+mod flash_access_control_register;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_control_register.rs"]
+mod flash_control_register;
+#[allow(dead_code)]
+#[path = "../register_utils/flash_option_control_register.rs"]
+mod flash_option_control_register;
+#[allow(dead_code)]
+#[path = "../flash_utils.rs"]
+mod flash_utils;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_config_register.rs"]
+mod rcc_clock_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_clock_control_register.rs"]
+mod rcc_clock_control_register;
+#[path = "../rcc_clock_settings.rs"]
+mod rcc_clock_settings;
+#[allow(dead_code)]
+#[path = "../register_utils/rcc_pll_config_register.rs"]
+mod rcc_pll_config_register;
+#[allow(dead_code)]
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
+
+use cortex_m_rt::entry;
+use panic_semihosting as _;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+#[cfg(feature = "enable-production")]
+use crate::flash_access_control_register::SupplyVoltage;
+#[cfg(feature = "enable-production")]
+use crate::flash_option_control_register::BorLevel;
+#[cfg(feature = "enable-debug")]
+use crate::flash_option_control_register::FlashOptionControlRegister;
+#[cfg(feature = "enable-production")]
+use crate::flash_utils::FlashDriver;
+#[cfg(any(feature = "enable-production", feature = "enable-debug"))]
+use crate::register_access::Mmio;
+
+// The bootloader lives in the first 32KB (sector 0 and 1), it should never be
+// overwritten by the firmware update.
+#[cfg(feature = "enable-production")]
+const BOOTLOADER_SECTORS: [u8; 2] = [0, 1];
+
+///
+#[entry]
+fn main() -> ! {
+    #[cfg(feature = "enable-debug")]
+    let _ = hprintln!("STM32F4 option bytes demo is running >>>>>");
+
+    // Only the production build touches the option bytes. Remove the write protection
+    // by the debugger before flashing the bootloader again!
+    #[cfg(feature = "enable-production")]
+    {
+        let flash = FlashDriver::new(Mmio);
+        let mut options = flash
+            .read_option_bytes()
+            .set_bor_level(BorLevel::for_supply_voltage(SupplyVoltage::From2V7To3V6));
+
+        for sector_number in BOOTLOADER_SECTORS.iter() {
+            options = options.set_sector_write_protected(*sector_number, true);
+        }
+
+        flash
+            .program_option_bytes(options)
+            .expect("Program option bytes failed");
+    }
+
+    #[cfg(feature = "enable-debug")]
+    FlashOptionControlRegister::print_config(&Mmio);
+
+    loop {}
+}
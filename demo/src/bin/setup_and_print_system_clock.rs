@@ -7,6 +7,10 @@
 mod clock_frequency;
 #[path = "../clock_utils.rs"]
 mod clock_utils;
+#[path = "../cycle_counter.rs"]
+mod cycle_counter;
+#[path = "../register_utils/data_watchpoint_trace_register.rs"]
+mod data_watchpoint_trace_register;
 #[path = "../register_utils/flash_access_control_register.rs"]
 mod flash_access_control_register;
 #[path = "../register_utils/rcc_clock_config_register.rs"]
@@ -17,6 +21,8 @@ mod rcc_clock_control_register;
 mod rcc_clock_settings;
 #[path = "../register_utils/rcc_pll_config_register.rs"]
 mod rcc_pll_config_register;
+#[path = "../register_utils/register_access.rs"]
+mod register_access;
 
 use cortex_m_rt::entry;
 use panic_semihosting as _;
@@ -24,7 +30,10 @@ use panic_semihosting as _;
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-use crate::clock_utils::{ClockSource, RccClocks};
+use crate::clock_utils::{ClockConfig, ClockSource, RccClocks};
+use crate::cycle_counter::{CycleCounter, Profiler};
+use crate::flash_access_control_register::FlashAccessControlRegister;
+use crate::register_access::Mmio;
 
 ///
 #[entry]
@@ -32,13 +41,39 @@ fn main() -> ! {
     #[cfg(feature = "enable-debug")]
     let _ = hprintln!("STM32F4 setup and print system clock demo is running >>>>>");
 
-    // RccClocks::setup_system_clock(ClockSource::Hsi);
-    // RccClocks::setup_system_clock(ClockSource::HsiThroughPll);
-    RccClocks::setup_system_clock(ClockSource::HseThroughPll);
+    // The chip runs on HSI after reset
+    let mut cycle_counter = CycleCounter::start(Mmio, &RccClocks::from_hardware(&Mmio).unwrap());
+    let mut profiler = Profiler::<4>::new();
+
+    // RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::Hsi));
+    // RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HsiThroughPll));
+    let rcc_clocks = cycle_counter.measure_probe(&mut profiler, "setup_system_clock", || {
+        RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HseThroughPll))
+    });
+
+    // Part of `setup_system_clock` runs on HSI and the rest on the new HCLK, so its
+    // microseconds below are only a rough number
+    cycle_counter.update_clocks(&rcc_clocks);
+
+    // ART accelerator: prefetch, instruction cache and data cache
+    FlashAccessControlRegister::enable_prefetch(&Mmio);
+    FlashAccessControlRegister::enable_instruction_cache(&Mmio);
+    FlashAccessControlRegister::enable_data_cache(&Mmio);
+
+    for _ in 0..10 {
+        cycle_counter.measure_probe(&mut profiler, "from_hardware", || {
+            RccClocks::from_hardware(&Mmio)
+        });
+    }
 
     #[cfg(feature = "enable-debug")]
     {
-        RccClocks::print_system_clock_info();
+        RccClocks::print_system_clock_info(&Mmio);
+
+        // What the chip is actually running
+        let _ = hprintln!("\nFrom hardware: {:#?}", RccClocks::from_hardware(&Mmio));
+
+        profiler.print_statistics(&cycle_counter);
     }
 
     loop {}
@@ -0,0 +1,405 @@
+// ------ Board support ---------------------------------------
+//
+// Everything a demo needs to know about the board it runs on, pick the board by
+// the `use-stm32f407g-disc1` or `use-weact-black-pill` feature, then use
+// `SelectedBoard` instead of the hard-coded pins:
+//
+// let board = SelectedBoard::take(Mmio).unwrap();
+// let mut leds = board.leds;
+// leds.set_all(board.user_button.is_high() == (SelectedBoard::BUTTON_ACTIVE_LEVEL == ActiveLevel::High));
+//
+// The clock numbers come from `rcc_clock_settings::clock_source_selecting`, which
+// the clock setup uses as well.
+//
+
+use crate::gpio_alternate_function::signal;
+use crate::gpio_register::{ActiveLevel, Alternate, GpioPort, GpioPull, Input, Output, Pin};
+use crate::rcc_clock_settings::clock_source_selecting;
+use crate::register_access::{Mmio, RegisterAccess};
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+#[cfg(feature = "use-stm32f407g-disc1")]
+pub type SelectedBoard = stm32f407g_disc1::Stm32f407gDisc1;
+
+#[cfg(feature = "use-weact-black-pill")]
+pub type SelectedBoard = weact_black_pill::WeActBlackPill;
+
+/// A user LED, `on()` drives the pin to the LED's active level
+pub struct Led<const PORT: char, const N: u8, R: RegisterAccess = Mmio> {
+    pin: Pin<PORT, N, Output, R>,
+    active_level: ActiveLevel,
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> Led<PORT, N, R> {
+    /// Starts as off
+    pub fn new(pin: Pin<PORT, N, Output, R>, active_level: ActiveLevel) -> Self {
+        let mut led = Led { pin, active_level };
+        led.off();
+        led
+    }
+
+    ///
+    pub fn on(&mut self) {
+        self.set(true);
+    }
+
+    ///
+    pub fn off(&mut self) {
+        self.set(false);
+    }
+
+    ///
+    pub fn set(&mut self, on: bool) {
+        if on == (self.active_level == ActiveLevel::High) {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+
+    ///
+    pub fn toggle(&mut self) {
+        self.pin.toggle();
+    }
+
+    /// From `ODR`
+    pub fn is_on(&self) -> bool {
+        self.pin.is_set_high() == (self.active_level == ActiveLevel::High)
+    }
+
+    ///
+    pub fn get_active_level(&self) -> ActiveLevel {
+        self.active_level
+    }
+
+    /// E.g. to `set_speed()`
+    pub fn get_pin(&self) -> &Pin<PORT, N, Output, R> {
+        &self.pin
+    }
+}
+
+/// All the user LEDs of a board by index. The index wraps around the LED count, so
+/// a demo written for the 4 Discovery LEDs still blinks the only Black Pill LED.
+pub trait UserLeds {
+    ///
+    fn get_count(&self) -> usize;
+
+    ///
+    fn set(&mut self, index: usize, on: bool);
+
+    ///
+    fn is_on(&self, index: usize) -> bool;
+
+    ///
+    fn toggle(&mut self, index: usize) {
+        let on = self.is_on(index);
+        self.set(index, !on);
+    }
+
+    ///
+    fn set_all(&mut self, on: bool) {
+        for index in 0..self.get_count() {
+            self.set(index, on);
+        }
+    }
+
+    ///
+    fn toggle_all(&mut self) {
+        for index in 0..self.get_count() {
+            self.toggle(index);
+        }
+    }
+}
+
+/// The board pins from `Board::take()`, move them out one by one
+pub struct BoardParts<P, L, B, U> {
+    /// The port of all the user LEDs, e.g. to `set_and_reset_pins()` them at once
+    pub led_port: P,
+    pub leds: L,
+    pub user_button: B,
+    pub debug_uart_pins: U,
+}
+
+/// What differs between the boards
+pub trait Board {
+    const NAME: &'static str;
+
+    /// The onboard crystal
+    const HSE_FREQUENCY: u32 = clock_source_selecting::HSE_FREQUENCY;
+    const SYS_CLOCK_MAX_SPEED: u32 = clock_source_selecting::SYS_CLOCK_MAX_SPEED;
+    const APB1_PERIPHERAL_MAX_SPEED: u32 = clock_source_selecting::APB1_PERIPHERAL_MAX_SPEED;
+    const APB2_PERIPHERAL_MAX_SPEED: u32 = clock_source_selecting::APB2_PERIPHERAL_MAX_SPEED;
+
+    /// All the user LEDs are on this port, `LED_PIN_MASK` has one bit per pin
+    const LED_PORT: char;
+    const LED_PIN_MASK: u16;
+    const LED_ACTIVE_LEVEL: ActiveLevel;
+
+    const BUTTON_ACTIVE_LEVEL: ActiveLevel;
+    const BUTTON_PULL: GpioPull;
+
+    type LedPort<R: RegisterAccess + Clone>;
+    type Leds<R: RegisterAccess + Clone>: UserLeds;
+    type UserButton<R: RegisterAccess + Clone>;
+    /// `(TX, RX)` in the USART alternate function
+    type DebugUartPins<R: RegisterAccess + Clone>;
+
+    /// Take the GPIO ports of the board and configure the pins: the LEDs are all off, the
+    /// button has the pull which it needs. `None` if one of the ports is taken already.
+    fn take<R: RegisterAccess + Clone>(
+        registers: R,
+    ) -> Option<
+        BoardParts<Self::LedPort<R>, Self::Leds<R>, Self::UserButton<R>, Self::DebugUartPins<R>>,
+    >;
+
+    #[cfg(feature = "enable-debug")]
+    fn print_info() {
+        let _ = hprintln!(
+            "\n[ Board ]: {}\nHSE: {}Hz\nSYSCLK max: {}Hz\nAPB1 max: {}Hz\nAPB2 max: {}Hz\nLEDs: port {}, pins {:#018b}, active {:?}\nButton active: {:?}",
+            Self::NAME,
+            Self::HSE_FREQUENCY,
+            Self::SYS_CLOCK_MAX_SPEED,
+            Self::APB1_PERIPHERAL_MAX_SPEED,
+            Self::APB2_PERIPHERAL_MAX_SPEED,
+            Self::LED_PORT,
+            Self::LED_PIN_MASK,
+            Self::LED_ACTIVE_LEVEL,
+            Self::BUTTON_ACTIVE_LEVEL
+        );
+    }
+}
+
+/// STM32F407G-DISC1, UM1472
+#[cfg(feature = "use-stm32f407g-disc1")]
+pub mod stm32f407g_disc1 {
+    use super::*;
+
+    pub struct Stm32f407gDisc1;
+
+    /// LD4 green, LD3 orange, LD5 red and LD6 blue on PD12 ~ PD15, index 0 ~ 3
+    pub struct Leds<R: RegisterAccess = Mmio> {
+        pub green: Led<'D', 12, R>,
+        pub orange: Led<'D', 13, R>,
+        pub red: Led<'D', 14, R>,
+        pub blue: Led<'D', 15, R>,
+    }
+
+    ///
+    impl<R: RegisterAccess> UserLeds for Leds<R> {
+        fn get_count(&self) -> usize {
+            4
+        }
+
+        fn set(&mut self, index: usize, on: bool) {
+            match index % 4 {
+                0 => self.green.set(on),
+                1 => self.orange.set(on),
+                2 => self.red.set(on),
+                _ => self.blue.set(on),
+            }
+        }
+
+        fn is_on(&self, index: usize) -> bool {
+            match index % 4 {
+                0 => self.green.is_on(),
+                1 => self.orange.is_on(),
+                2 => self.red.is_on(),
+                _ => self.blue.is_on(),
+            }
+        }
+    }
+
+    ///
+    impl Board for Stm32f407gDisc1 {
+        const NAME: &'static str = "STM32F407G-DISC1";
+
+        const LED_PORT: char = 'D';
+        const LED_PIN_MASK: u16 = 1 << 12 | 1 << 13 | 1 << 14 | 1 << 15;
+        const LED_ACTIVE_LEVEL: ActiveLevel = ActiveLevel::High;
+
+        // B1 connects PA0 to VDD, with the external pull-down
+        const BUTTON_ACTIVE_LEVEL: ActiveLevel = ActiveLevel::High;
+        const BUTTON_PULL: GpioPull = GpioPull::None;
+
+        type LedPort<R: RegisterAccess + Clone> = GpioPort<'D', R>;
+        type Leds<R: RegisterAccess + Clone> = Leds<R>;
+        type UserButton<R: RegisterAccess + Clone> = Pin<'A', 0, Input, R>;
+        type DebugUartPins<R: RegisterAccess + Clone> =
+            (Pin<'A', 2, Alternate<7>, R>, Pin<'A', 3, Alternate<7>, R>);
+
+        /// The debug UART is USART2, the ST-LINK on this board has no virtual COM port,
+        /// wire a USB-UART adapter to PA2/PA3
+        fn take<R: RegisterAccess + Clone>(
+            registers: R,
+        ) -> Option<
+            BoardParts<
+                Self::LedPort<R>,
+                Self::Leds<R>,
+                Self::UserButton<R>,
+                Self::DebugUartPins<R>,
+            >,
+        > {
+            let gpioa = GpioPort::<'A', R>::take(registers.clone())?;
+            let gpiod = GpioPort::<'D', R>::take(registers)?;
+
+            Some(BoardParts {
+                leds: Leds {
+                    green: Led::new(gpiod.p12.into_push_pull_output(), Self::LED_ACTIVE_LEVEL),
+                    orange: Led::new(gpiod.p13.into_push_pull_output(), Self::LED_ACTIVE_LEVEL),
+                    red: Led::new(gpiod.p14.into_push_pull_output(), Self::LED_ACTIVE_LEVEL),
+                    blue: Led::new(gpiod.p15.into_push_pull_output(), Self::LED_ACTIVE_LEVEL),
+                },
+                led_port: gpiod.port,
+                user_button: gpioa.p0.into_input(Self::BUTTON_PULL),
+                debug_uart_pins: (
+                    gpioa.p2.into_signal::<signal::Usart2Tx>(),
+                    gpioa.p3.into_signal::<signal::Usart2Rx>(),
+                ),
+            })
+        }
+    }
+}
+
+/// WeAct Black Pill V3.0 (STM32F411CEU6)
+#[cfg(feature = "use-weact-black-pill")]
+pub mod weact_black_pill {
+    use super::*;
+
+    pub struct WeActBlackPill;
+
+    /// The blue LED on PC13, it's on when the pin is low
+    pub struct Leds<R: RegisterAccess = Mmio> {
+        pub blue: Led<'C', 13, R>,
+    }
+
+    ///
+    impl<R: RegisterAccess> UserLeds for Leds<R> {
+        fn get_count(&self) -> usize {
+            1
+        }
+
+        fn set(&mut self, _index: usize, on: bool) {
+            self.blue.set(on);
+        }
+
+        fn is_on(&self, _index: usize) -> bool {
+            self.blue.is_on()
+        }
+    }
+
+    ///
+    impl Board for WeActBlackPill {
+        const NAME: &'static str = "WeAct Black Pill";
+
+        const LED_PORT: char = 'C';
+        const LED_PIN_MASK: u16 = 1 << 13;
+        const LED_ACTIVE_LEVEL: ActiveLevel = ActiveLevel::Low;
+
+        // KEY connects PA0 to GND, no external pull-up
+        const BUTTON_ACTIVE_LEVEL: ActiveLevel = ActiveLevel::Low;
+        const BUTTON_PULL: GpioPull = GpioPull::Up;
+
+        type LedPort<R: RegisterAccess + Clone> = GpioPort<'C', R>;
+        type Leds<R: RegisterAccess + Clone> = Leds<R>;
+        type UserButton<R: RegisterAccess + Clone> = Pin<'A', 0, Input, R>;
+        type DebugUartPins<R: RegisterAccess + Clone> =
+            (Pin<'A', 9, Alternate<7>, R>, Pin<'A', 10, Alternate<7>, R>);
+
+        /// The debug UART is USART1 on PA9/PA10
+        fn take<R: RegisterAccess + Clone>(
+            registers: R,
+        ) -> Option<
+            BoardParts<
+                Self::LedPort<R>,
+                Self::Leds<R>,
+                Self::UserButton<R>,
+                Self::DebugUartPins<R>,
+            >,
+        > {
+            let gpioa = GpioPort::<'A', R>::take(registers.clone())?;
+            let gpioc = GpioPort::<'C', R>::take(registers)?;
+
+            Some(BoardParts {
+                leds: Leds {
+                    blue: Led::new(gpioc.p13.into_push_pull_output(), Self::LED_ACTIVE_LEVEL),
+                },
+                led_port: gpioc.port,
+                user_button: gpioa.p0.into_input(Self::BUTTON_PULL),
+                debug_uart_pins: (
+                    gpioa.p9.into_signal::<signal::Usart1Tx>(),
+                    gpioa.p10.into_signal::<signal::Usart1Rx>(),
+                ),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpio_register::{
+        GpioMode, GPIOA, GPIO_BSRR_OFFSET, GPIO_ODR_OFFSET, GPIO_PUPDR_OFFSET,
+    };
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn active_low_led_is_on_when_the_pin_is_low() {
+        let registers = RecordingRegisters::new();
+        let gpioc = GpioPort::<'C', _>::take(&registers).unwrap();
+        let bsrr = GpioPort::<'C', &RecordingRegisters>::BASE_ADDRESS + GPIO_BSRR_OFFSET;
+
+        // Off at the start is high
+        let mut led = Led::new(gpioc.p13.into_push_pull_output(), ActiveLevel::Low);
+        assert_eq!(registers.get_writes().last(), Some(&(bsrr, 1 << 13)));
+
+        led.on();
+        assert_eq!(registers.get_writes().last(), Some(&(bsrr, 1 << 29)));
+        assert!(led.is_on());
+
+        registers.write(bsrr - GPIO_BSRR_OFFSET + GPIO_ODR_OFFSET, 1 << 13);
+        assert!(!led.is_on());
+        led.toggle();
+        assert_eq!(registers.get_writes().last(), Some(&(bsrr, 1 << 29)));
+    }
+
+    #[test]
+    fn selected_board_pins() {
+        let registers = RecordingRegisters::new();
+        let led_port = GpioPort::<{ SelectedBoard::LED_PORT }, &RecordingRegisters>::BASE_ADDRESS;
+        let first_led = SelectedBoard::LED_PIN_MASK.trailing_zeros();
+
+        let mut board = SelectedBoard::take(&registers).unwrap();
+        assert!(SelectedBoard::take(&registers).is_none());
+        assert!(board.led_port.is_enabled());
+
+        let leds = &mut board.leds;
+        assert_eq!(
+            leds.get_count(),
+            SelectedBoard::LED_PIN_MASK.count_ones() as usize
+        );
+
+        // The index wraps around
+        leds.set(leds.get_count(), true);
+        let on_bit = match SelectedBoard::LED_ACTIVE_LEVEL {
+            ActiveLevel::High => 1 << first_led,
+            ActiveLevel::Low => 1 << (first_led + 16),
+        };
+        assert_eq!(
+            registers.get_writes().last(),
+            Some(&(led_port + GPIO_BSRR_OFFSET, on_bit))
+        );
+
+        assert_eq!(board.user_button.get_mode(), GpioMode::Input);
+        assert_eq!(
+            registers.get_value(GPIOA + GPIO_PUPDR_OFFSET) & 0b11,
+            SelectedBoard::BUTTON_PULL.to_register_bits()
+        );
+
+        let (tx, rx) = board.debug_uart_pins;
+        assert_eq!(tx.get_mode(), GpioMode::Alternate);
+        assert_eq!(rx.get_mode(), GpioMode::Alternate);
+    }
+}
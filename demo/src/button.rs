@@ -0,0 +1,446 @@
+use crate::gpio_register::{ActiveLevel, Input, Pin};
+use crate::register_access::RegisterAccess;
+use core::time::Duration;
+
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    /// The debounced level becomes active
+    Pressed,
+    /// The debounced level becomes inactive
+    Released,
+    /// A short press without a second one in the double click window, it comes
+    /// after the window has passed
+    Click,
+    /// The second short press in the double click window, instead of 2 `Click`
+    DoubleClick,
+    /// Released after holding at least the long press duration, with how long it's
+    /// held, no `Click` for it
+    LongPress(Duration),
+}
+
+/// The timing of `Button`, all in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ButtonConfig {
+    active_level: ActiveLevel,
+    debounce_window: u32,
+    double_click_window: u32,
+    long_press_duration: u32,
+}
+
+///
+impl ButtonConfig {
+    /// 20ms debounce window, 300ms double click window and 1s long press
+    pub fn new(active_level: ActiveLevel) -> Self {
+        ButtonConfig {
+            active_level,
+            debounce_window: 20,
+            double_click_window: 300,
+            long_press_duration: 1000,
+        }
+    }
+
+    /// The level has to stay for this long before it's accepted
+    pub fn debounce_window(mut self, milliseconds: u32) -> Self {
+        self.debounce_window = milliseconds;
+        self
+    }
+
+    /// From a release to the next press, `0` turns off `DoubleClick` and a `Click`
+    /// comes right after `Released`
+    pub fn double_click_window(mut self, milliseconds: u32) -> Self {
+        self.double_click_window = milliseconds;
+        self
+    }
+
+    ///
+    pub fn long_press_duration(mut self, milliseconds: u32) -> Self {
+        self.long_press_duration = milliseconds;
+        self
+    }
+
+    ///
+    pub fn get_active_level(&self) -> ActiveLevel {
+        self.active_level
+    }
+}
+
+/// A fixed-capacity FIFO, no heap. When it's full, the new events are dropped (and
+/// counted).
+pub struct EventQueue<const CAPACITY: usize> {
+    events: [Option<ButtonEvent>; CAPACITY],
+    head: usize,
+    len: usize,
+    dropped_events: u32,
+}
+
+///
+impl<const CAPACITY: usize> EventQueue<CAPACITY> {
+    ///
+    pub const fn new() -> Self {
+        EventQueue {
+            events: [None; CAPACITY],
+            head: 0,
+            len: 0,
+            dropped_events: 0,
+        }
+    }
+
+    ///
+    pub fn push(&mut self, event: ButtonEvent) {
+        if self.len == CAPACITY {
+            self.dropped_events = self.dropped_events.saturating_add(1);
+            return;
+        }
+
+        self.events[(self.head + self.len) % CAPACITY] = Some(event);
+        self.len += 1;
+    }
+
+    /// The oldest event
+    pub fn pop(&mut self) -> Option<ButtonEvent> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % CAPACITY;
+        self.len -= 1;
+        event
+    }
+
+    ///
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    ///
+    pub fn get_dropped_events(&self) -> u32 {
+        self.dropped_events
+    }
+}
+
+/// A debounced button which turns the pin level samples into `ButtonEvent`s.
+///
+/// The timing logic only sees `(now, is_high)` samples, so it doesn't care where they
+/// come from: `poll()` reads an input pin, `update()` takes any sample. Sample it
+/// every few milliseconds, e.g. from the thread loop or a `SoftwareTimers` callback.
+///
+/// ```
+/// let key = gpioa.p0.into_input(GpioPull::Up);
+/// let mut button = Button::<8>::new(ButtonConfig::new(ActiveLevel::Low));
+///
+/// loop {
+///     button.poll(MILLISECONDS.get(), &key);
+///     while let Some(event) = button.next_event() { ... }
+/// }
+/// ```
+pub struct Button<const CAPACITY: usize> {
+    config: ButtonConfig,
+    /// The last sample and since when it's been the same
+    raw_pressed: bool,
+    raw_since: u64,
+    /// The debounced state and since when
+    pressed: bool,
+    pressed_since: u64,
+    /// The release time of a click which may become a double click
+    pending_click_since: Option<u64>,
+    events: EventQueue<CAPACITY>,
+}
+
+///
+impl<const CAPACITY: usize> Button<CAPACITY> {
+    /// Starts as released
+    pub fn new(config: ButtonConfig) -> Self {
+        Button {
+            config,
+            raw_pressed: false,
+            raw_since: 0,
+            pressed: false,
+            pressed_since: 0,
+            pending_click_since: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Read `pin` as the sample at `now` (milliseconds)
+    pub fn poll<const PORT: char, const N: u8, R: RegisterAccess>(
+        &mut self,
+        now: u64,
+        pin: &Pin<PORT, N, Input, R>,
+    ) {
+        self.update(now, pin.is_high());
+    }
+
+    /// Feed one sample: the pin level at `now` (milliseconds). The samples have to be
+    /// in time order.
+    pub fn update(&mut self, now: u64, is_high: bool) {
+        let raw_pressed = is_high == (self.config.active_level == ActiveLevel::High);
+
+        if raw_pressed != self.raw_pressed {
+            self.raw_pressed = raw_pressed;
+            self.raw_since = now;
+        }
+
+        // Stable for the whole debounce window, the change happened when the level
+        // started to stay
+        if self.raw_pressed != self.pressed
+            && now - self.raw_since >= self.config.debounce_window as u64
+        {
+            if self.raw_pressed {
+                self.on_pressed(self.raw_since);
+            } else {
+                self.on_released(self.raw_since);
+            }
+        }
+
+        self.flush_pending_click(now);
+    }
+
+    /// The oldest event
+    pub fn next_event(&mut self) -> Option<ButtonEvent> {
+        self.events.pop()
+    }
+
+    /// The debounced state
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// How long it's been pressed at `now`, `None` if it's released
+    pub fn get_pressed_duration(&self, now: u64) -> Option<Duration> {
+        if self.pressed {
+            Some(Duration::from_millis(now - self.pressed_since))
+        } else {
+            None
+        }
+    }
+
+    ///
+    pub fn get_config(&self) -> &ButtonConfig {
+        &self.config
+    }
+
+    ///
+    pub fn get_dropped_events(&self) -> u32 {
+        self.events.get_dropped_events()
+    }
+
+    fn on_pressed(&mut self, at: u64) {
+        // A click whose window has passed before this press
+        self.flush_pending_click(at);
+
+        self.pressed = true;
+        self.pressed_since = at;
+        self.events.push(ButtonEvent::Pressed);
+    }
+
+    fn on_released(&mut self, at: u64) {
+        let pressed_duration = at - self.pressed_since;
+
+        self.pressed = false;
+        self.events.push(ButtonEvent::Released);
+
+        if pressed_duration >= self.config.long_press_duration as u64 {
+            // A short press right before the long press is still a click
+            if self.pending_click_since.take().is_some() {
+                self.events.push(ButtonEvent::Click);
+            }
+            self.events
+                .push(ButtonEvent::LongPress(Duration::from_millis(
+                    pressed_duration,
+                )));
+        } else if self.pending_click_since.take().is_some() {
+            // `flush_pending_click()` before the press made sure it's in the window
+            self.events.push(ButtonEvent::DoubleClick);
+        } else if self.config.double_click_window == 0 {
+            self.events.push(ButtonEvent::Click);
+        } else {
+            self.pending_click_since = Some(at);
+        }
+    }
+
+    /// Emit the waiting `Click` when no second press came in the double click window.
+    /// A second press which started in the window but is still being debounced keeps
+    /// it waiting.
+    fn flush_pending_click(&mut self, now: u64) {
+        if let Some(released_at) = self.pending_click_since {
+            let window = self.config.double_click_window as u64;
+            let pressed_in_window = self.raw_pressed && self.raw_since - released_at <= window;
+
+            if !self.pressed && !pressed_in_window && now - released_at > window {
+                self.pending_click_since = None;
+                self.events.push(ButtonEvent::Click);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Sample every millisecond from `from` until `to` (excluded) at `is_high`
+    fn hold<const CAPACITY: usize>(
+        button: &mut Button<CAPACITY>,
+        from: u64,
+        to: u64,
+        is_high: bool,
+    ) {
+        for now in from..to {
+            button.update(now, is_high);
+        }
+    }
+
+    fn drain<const CAPACITY: usize>(button: &mut Button<CAPACITY>) -> Vec<ButtonEvent> {
+        core::iter::from_fn(|| button.next_event()).collect()
+    }
+
+    #[test]
+    fn bounces_are_filtered_out() {
+        let mut button = Button::<8>::new(ButtonConfig::new(ActiveLevel::High).debounce_window(10));
+
+        // Bounces shorter than the window, then stays high from 102
+        for (now, is_high) in [(100, true), (101, false), (102, true), (103, true)] {
+            button.update(now, is_high);
+        }
+        hold(&mut button, 104, 112, true);
+        assert!(!button.is_pressed());
+
+        button.update(113, true);
+        assert!(button.is_pressed());
+        assert_eq!(
+            button.get_pressed_duration(120),
+            Some(Duration::from_millis(18))
+        );
+        assert_eq!(drain(&mut button), vec![ButtonEvent::Pressed]);
+
+        // A 5ms glitch while it's held doesn't release it
+        hold(&mut button, 120, 125, false);
+        hold(&mut button, 125, 200, true);
+        assert!(button.is_pressed());
+        assert!(drain(&mut button).is_empty());
+    }
+
+    #[test]
+    fn click_double_click_and_long_press() {
+        // Active low: pressed is `false`
+        let config = ButtonConfig::new(ActiveLevel::Low)
+            .debounce_window(10)
+            .double_click_window(200)
+            .long_press_duration(800);
+        let mut button = Button::<16>::new(config);
+        hold(&mut button, 0, 100, true);
+
+        // A click is reported after the double click window
+        hold(&mut button, 100, 200, false);
+        hold(&mut button, 200, 400, true);
+        assert_eq!(
+            drain(&mut button),
+            vec![ButtonEvent::Pressed, ButtonEvent::Released]
+        );
+        hold(&mut button, 400, 500, true);
+        assert_eq!(drain(&mut button), vec![ButtonEvent::Click]);
+
+        // 2 short presses 100ms apart
+        hold(&mut button, 500, 580, false);
+        hold(&mut button, 580, 680, true);
+        hold(&mut button, 680, 760, false);
+        hold(&mut button, 760, 1200, true);
+        assert_eq!(
+            drain(&mut button),
+            vec![
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::DoubleClick
+            ]
+        );
+
+        // Held for 1.5s
+        hold(&mut button, 1200, 2700, false);
+        hold(&mut button, 2700, 3200, true);
+        assert_eq!(
+            drain(&mut button),
+            vec![
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::LongPress(Duration::from_millis(1500))
+            ]
+        );
+
+        // A press after the window: the first click is reported before it
+        hold(&mut button, 3200, 3250, false);
+        hold(&mut button, 3250, 3300, true);
+        button.update(3600, false);
+        hold(&mut button, 3601, 3620, false);
+        assert_eq!(
+            drain(&mut button),
+            vec![
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::Click,
+                ButtonEvent::Pressed
+            ]
+        );
+
+        // Released at 3700, the second press starts at 3895 (in the window) but it's
+        // only debounced at 3905 (after the window), it's still a double click
+        hold(&mut button, 3620, 3700, false);
+        hold(&mut button, 3700, 3895, true);
+        hold(&mut button, 3895, 3960, false);
+        hold(&mut button, 3960, 4300, true);
+        assert_eq!(
+            drain(&mut button),
+            vec![
+                ButtonEvent::Released,
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::DoubleClick
+            ]
+        );
+
+        // A click, then a long press in the window
+        hold(&mut button, 4300, 4350, false);
+        hold(&mut button, 4350, 4400, true);
+        hold(&mut button, 4400, 5400, false);
+        hold(&mut button, 5400, 5600, true);
+        assert_eq!(
+            drain(&mut button),
+            vec![
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::Pressed,
+                ButtonEvent::Released,
+                ButtonEvent::Click,
+                ButtonEvent::LongPress(Duration::from_millis(1000))
+            ]
+        );
+    }
+
+    #[test]
+    fn full_queue_drops_new_events() {
+        let mut button = Button::<2>::new(
+            ButtonConfig::new(ActiveLevel::High)
+                .debounce_window(0)
+                .double_click_window(0),
+        );
+
+        button.update(0, true);
+        button.update(10, false);
+        assert_eq!(button.get_dropped_events(), 1);
+        assert_eq!(
+            drain(&mut button),
+            vec![ButtonEvent::Pressed, ButtonEvent::Released]
+        );
+
+        button.update(20, true);
+        assert_eq!(drain(&mut button), vec![ButtonEvent::Pressed]);
+    }
+}
@@ -1,9 +1,16 @@
-use crate::clock_frequency::MegaHertz;
-use crate::flash_access_control_register::FlashAccessControlRegister;
-use crate::rcc_clock_config_register::{RccClockConfigurationRegister, RccSystemClockSwtich};
-use crate::rcc_clock_control_register::RccClockControlRegister;
+use crate::clock_frequency::{Hertz, MegaHertz};
+use crate::flash_access_control_register::{
+    FlashAccessControlRegister, FlashReadLatency, SupplyVoltage,
+};
+use crate::rcc_clock_config_register::{
+    RccAhbPrescaler, RccApbPrescaler, RccClockConfigurationRegister, RccSystemClockSwtich,
+    RccSystemClockSwtichStatus,
+};
+use crate::rcc_clock_control_register::{RccClockControlRegister, RccCrValue};
 use crate::rcc_clock_settings::clock_source_selecting;
-use crate::rcc_pll_config_register::RccPllConfigurationRegister;
+use crate::rcc_pll_config_register::{RccPllConfigurationError, RccPllConfigurationRegister};
+use crate::register_access::RegisterAccess;
+use core::convert::TryFrom;
 
 #[cfg(feature = "enable-debug")]
 use core::fmt::Write;
@@ -18,10 +25,368 @@ use heapless::{consts::*, String};
 #[derive(Debug, Clone, PartialEq)]
 pub enum ClockSource {
     Hsi,
+    Hse,
     HsiThroughPll,
     HseThroughPll,
 }
 
+///
+impl ClockSource {
+    /// Whether HSE has to be enabled
+    pub fn use_hse(&self) -> bool {
+        *self == ClockSource::Hse || *self == ClockSource::HseThroughPll
+    }
+
+    /// Whether PLL has to be enabled
+    pub fn use_pll(&self) -> bool {
+        *self == ClockSource::HsiThroughPll || *self == ClockSource::HseThroughPll
+    }
+}
+
+/// Why a `ClockConfig` can't be applied, all the frequencies are in Hertz
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClockConfigError {
+    WrongAhbPrescaler(u32),
+    WrongApbPrescaler(u32),
+    WrongFlashLatency(u32),
+    WrongPllConfiguration(RccPllConfigurationError),
+    VcoInputOutOfRange(u32),
+    VcoOutputOutOfRange(u32),
+    Pll48ClockTooHigh(u32),
+    SystemClockMismatch { expected: u32, actual: u32 },
+    SystemClockTooHigh(u32),
+    Apb1ClockTooHigh(u32),
+    Apb2ClockTooHigh(u32),
+    FlashLatencyTooLow { required: u32, actual: u32 },
+    CpuClockTooHighForSupplyVoltage(u32),
+    WrongClockSwitchStatus(u32),
+    HseStartupTimeout,
+    PllLockTimeout,
+    PllStopTimeout,
+    ClockSwitchTimeout,
+}
+
+/// How many times to poll the ready flag before giving up on HSE, PLL and the clock
+/// switch, it's long enough for a healthy crystal which starts within a few milliseconds.
+pub const DEFAULT_MAX_WAIT_CYCLES: u32 = 100_000;
+
+/// From `RccPllConfigurationError` to `ClockConfigError`
+impl From<RccPllConfigurationError> for ClockConfigError {
+    fn from(error: RccPllConfigurationError) -> Self {
+        ClockConfigError::WrongPllConfiguration(error)
+    }
+}
+
+/// Runtime clock tree settings which passed to `RccClocks::setup_system_clock`.
+///
+/// `ClockConfig::new()` starts from the board defaults in `clock_source_selecting`,
+/// then every setting can be overridden at runtime, for example:
+///
+/// ```
+/// let config = ClockConfig::new(ClockSource::HseThroughPll)
+///     .hse_frequency(25_000_000)
+///     .system_clock(100_000_000)
+///     .apb1_prescaler(2)
+///     .apb2_prescaler(1)
+///     .supply_voltage(SupplyVoltage::From2V7To3V6);
+/// ```
+///
+/// If `system_clock()` is called without `pll_mnpq()`, the PLL factors will be
+/// searched by `find_pll_factors()`. If `flash_latency()` is not called, the minimum
+/// flash latency for `HCLK` and the supply voltage will be used.
+#[derive(Debug, Clone)]
+pub struct ClockConfig {
+    clock_source: ClockSource,
+    hsi_frequency: u32,
+    hse_frequency: u32,
+    system_clock: Option<u32>,
+    ahb_prescaler: u32,
+    apb1_prescaler: u32,
+    apb2_prescaler: u32,
+    pll_m: u32,
+    pll_n: u32,
+    pll_p: u32,
+    pll_q: u32,
+    pll_factors_are_explicit: bool,
+    flash_latency: Option<u32>,
+    supply_voltage: SupplyVoltage,
+    max_wait_cycles: u32,
+}
+
+///
+impl ClockConfig {
+    /// Create with the board default settings for the given clock source
+    pub fn new(clock_source: ClockSource) -> Self {
+        let use_hse = clock_source.use_hse();
+
+        ClockConfig {
+            clock_source,
+            hsi_frequency: clock_source_selecting::HSI_FREQUENCY,
+            hse_frequency: clock_source_selecting::HSE_FREQUENCY,
+            system_clock: None,
+            ahb_prescaler: if use_hse {
+                clock_source_selecting::AHB_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::AHB_PRESCALER_FOR_HSI
+            },
+            apb1_prescaler: if use_hse {
+                clock_source_selecting::APB1_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::APB1_PRESCALER_FOR_HSI
+            },
+            apb2_prescaler: if use_hse {
+                clock_source_selecting::APB2_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::APB2_PRESCALER_FOR_HSI
+            },
+            pll_m: if use_hse {
+                clock_source_selecting::PLL_M_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::PLL_M_PRESCALER_FOR_HSI
+            },
+            pll_n: if use_hse {
+                clock_source_selecting::PLL_N_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::PLL_N_PRESCALER_FOR_HSI
+            },
+            pll_p: if use_hse {
+                clock_source_selecting::PLL_P_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::PLL_P_PRESCALER_FOR_HSI
+            },
+            pll_q: if use_hse {
+                clock_source_selecting::PLL_Q_PRESCALER_FOR_HSE
+            } else {
+                clock_source_selecting::PLL_Q_PRESCALER_FOR_HSI
+            },
+            pll_factors_are_explicit: false,
+            flash_latency: None,
+            supply_voltage: SupplyVoltage::From2V7To3V6,
+            max_wait_cycles: DEFAULT_MAX_WAIT_CYCLES,
+        }
+    }
+
+    /// Onboard HSE oscillator frequency (unit in Hertz)
+    pub fn hse_frequency(mut self, hertz: u32) -> Self {
+        self.hse_frequency = hertz;
+        self.update_pll_factors();
+        self
+    }
+
+    /// The expected `SYSCLK` frequency (unit in Hertz)
+    pub fn system_clock(mut self, hertz: u32) -> Self {
+        self.system_clock = Some(hertz);
+        self.update_pll_factors();
+        self
+    }
+
+    /// AHB prescaler: 1, 2, 4, 8, 16, 64, 128, 256 or 512
+    pub fn ahb_prescaler(mut self, prescaler: u32) -> Self {
+        self.ahb_prescaler = prescaler;
+        self
+    }
+
+    /// APB1 (low speed) prescaler: 1, 2, 4, 8 or 16
+    pub fn apb1_prescaler(mut self, prescaler: u32) -> Self {
+        self.apb1_prescaler = prescaler;
+        self
+    }
+
+    /// APB2 (high speed) prescaler: 1, 2, 4, 8 or 16
+    pub fn apb2_prescaler(mut self, prescaler: u32) -> Self {
+        self.apb2_prescaler = prescaler;
+        self
+    }
+
+    /// PLL factors, they win over the `system_clock()` setting
+    pub fn pll_mnpq(mut self, pll_m: u32, pll_n: u32, pll_p: u32, pll_q: u32) -> Self {
+        self.pll_m = pll_m;
+        self.pll_n = pll_n;
+        self.pll_p = pll_p;
+        self.pll_q = pll_q;
+        self.pll_factors_are_explicit = true;
+        self
+    }
+
+    /// Flash read latency (wait states), it wins over the one derived from `HCLK` and
+    /// the supply voltage
+    pub fn flash_latency(mut self, wait_state: u32) -> Self {
+        self.flash_latency = Some(wait_state);
+        self
+    }
+
+    /// The MCU supply voltage range, the boards run at 3.3V by default
+    pub fn supply_voltage(mut self, supply_voltage: SupplyVoltage) -> Self {
+        self.supply_voltage = supply_voltage;
+        self
+    }
+
+    /// How many times to poll HSE, PLL and the clock switch ready flag before giving up
+    pub fn max_wait_cycles(mut self, cycles: u32) -> Self {
+        self.max_wait_cycles = cycles;
+        self
+    }
+
+    ///
+    pub fn get_clock_source(&self) -> &ClockSource {
+        &self.clock_source
+    }
+
+    /// The same settings but use another clock source, the PLL factors will be searched
+    /// again to keep the same `SYSCLK` as close as possible.
+    pub fn fallback_to(&self, clock_source: ClockSource) -> ClockConfig {
+        let mut config = ClockConfig::new(clock_source.clone());
+        config.max_wait_cycles = self.max_wait_cycles;
+        config.supply_voltage = self.supply_voltage;
+
+        if !clock_source.use_pll() {
+            return config;
+        }
+
+        config.ahb_prescaler = self.ahb_prescaler;
+        config.apb1_prescaler = self.apb1_prescaler;
+        config.apb2_prescaler = self.apb2_prescaler;
+        config.flash_latency = self.flash_latency;
+        config.system_clock(self.get_system_clock_frequency())
+    }
+
+    /// The `HCLK` frequency which this config produces (unit in Hertz)
+    pub fn get_cpu_clock_frequency(&self) -> u32 {
+        self.get_system_clock_frequency() / self.ahb_prescaler
+    }
+
+    /// The explicit flash latency, or the minimum one for `HCLK` and the supply voltage
+    pub fn get_flash_latency(&self) -> Result<FlashReadLatency, ClockConfigError> {
+        match self.flash_latency {
+            Some(wait_state) => FlashReadLatency::try_from(wait_state),
+            None => {
+                let cpu_clock = self.get_cpu_clock_frequency();
+                FlashReadLatency::minimum_for_cpu_clock(cpu_clock, self.supply_voltage)
+                    .ok_or(ClockConfigError::CpuClockTooHighForSupplyVoltage(cpu_clock))
+            }
+        }
+    }
+
+    /// The PLL input frequency: HSI or HSE
+    pub fn get_pll_input_frequency(&self) -> u32 {
+        if self.clock_source.use_hse() {
+            self.hse_frequency
+        } else {
+            self.hsi_frequency
+        }
+    }
+
+    /// The `SYSCLK` frequency which this config produces (unit in Hertz)
+    pub fn get_system_clock_frequency(&self) -> u32 {
+        match self.clock_source {
+            ClockSource::Hsi => self.hsi_frequency,
+            ClockSource::Hse => self.hse_frequency,
+            _ => {
+                (self.get_pll_input_frequency() as u64 * self.pll_n as u64
+                    / (self.pll_m as u64 * self.pll_p as u64)) as u32
+            }
+        }
+    }
+
+    /// Check the whole clock tree without touching any register
+    pub fn validate(&self) -> Result<(), ClockConfigError> {
+        RccAhbPrescaler::try_from(self.ahb_prescaler)?;
+        RccApbPrescaler::try_from(self.apb1_prescaler)?;
+        RccApbPrescaler::try_from(self.apb2_prescaler)?;
+
+        if self.clock_source.use_pll() {
+            RccPllConfigurationRegister::check_pll_mnpq(
+                self.pll_m, self.pll_n, self.pll_p, self.pll_q,
+            )?;
+
+            let vco_input = self.get_pll_input_frequency() / self.pll_m;
+            if vco_input < PLL_VCO_INPUT_MIN_FREQUENCY || vco_input > PLL_VCO_INPUT_MAX_FREQUENCY {
+                return Err(ClockConfigError::VcoInputOutOfRange(vco_input));
+            }
+
+            let vco_output = (self.get_pll_input_frequency() as u64 * self.pll_n as u64
+                / self.pll_m as u64) as u32;
+            if vco_output < PLL_VCO_OUTPUT_MIN_FREQUENCY
+                || vco_output > PLL_VCO_OUTPUT_MAX_FREQUENCY
+            {
+                return Err(ClockConfigError::VcoOutputOutOfRange(vco_output));
+            }
+
+            let pll48_clock = vco_output / self.pll_q;
+            if pll48_clock > PLL48_CLOCK_FREQUENCY {
+                return Err(ClockConfigError::Pll48ClockTooHigh(pll48_clock));
+            }
+        }
+
+        let system_clock = self.get_system_clock_frequency();
+        if let Some(expected) = self.system_clock {
+            if expected != system_clock {
+                return Err(ClockConfigError::SystemClockMismatch {
+                    expected,
+                    actual: system_clock,
+                });
+            }
+        }
+
+        if system_clock > clock_source_selecting::SYS_CLOCK_MAX_SPEED {
+            return Err(ClockConfigError::SystemClockTooHigh(system_clock));
+        }
+
+        let cpu_clock = system_clock / self.ahb_prescaler;
+
+        let apb1_peripheral_clock = cpu_clock / self.apb1_prescaler;
+        if apb1_peripheral_clock > clock_source_selecting::APB1_PERIPHERAL_MAX_SPEED {
+            return Err(ClockConfigError::Apb1ClockTooHigh(apb1_peripheral_clock));
+        }
+
+        let apb2_peripheral_clock = cpu_clock / self.apb2_prescaler;
+        if apb2_peripheral_clock > clock_source_selecting::APB2_PERIPHERAL_MAX_SPEED {
+            return Err(ClockConfigError::Apb2ClockTooHigh(apb2_peripheral_clock));
+        }
+
+        let flash_latency = self.get_flash_latency()?;
+        let required = FlashReadLatency::minimum_for_cpu_clock(cpu_clock, self.supply_voltage)
+            .ok_or(ClockConfigError::CpuClockTooHighForSupplyVoltage(cpu_clock))?;
+        if flash_latency < required {
+            return Err(ClockConfigError::FlashLatencyTooLow {
+                required: required.to_register_bits(),
+                actual: flash_latency.to_register_bits(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// APB timer clock is the same as the APB peripheral clock when the APB prescaler
+    /// is 1, otherwise, it's twice the APB peripheral clock.
+    fn timer_factor(apb_prescaler: u32) -> u32 {
+        if apb_prescaler == 1 {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Pick the PLL factors for the expected `SYSCLK`
+    fn update_pll_factors(&mut self) {
+        if self.pll_factors_are_explicit || !self.clock_source.use_pll() {
+            return;
+        }
+
+        let system_clock = match self.system_clock {
+            Some(value) => value,
+            None => return,
+        };
+
+        if let Some(factors) = find_pll_factors(self.get_pll_input_frequency(), system_clock) {
+            self.pll_m = factors.pll_m;
+            self.pll_n = factors.pll_n;
+            self.pll_p = factors.pll_p;
+            self.pll_q = factors.pll_q;
+        }
+    }
+}
+
 ///
 pub struct RccClocks {
     // HSI fixed frequency
@@ -30,10 +395,10 @@ pub struct RccClocks {
     hse: Option<MegaHertz>,
     // The clock source we picked
     clock_source: ClockSource,
-    // SYSCLK calculated frequency
-    system_clock: Option<MegaHertz>,
-    // HCLK calculated frequency
-    hardware_cpu_clock: Option<MegaHertz>,
+    // SYSCLK calculated frequency, in Hertz as it's not always whole MHz
+    system_clock: Option<Hertz>,
+    // HCLK calculated frequency, in Hertz as e.g. `AHB / 64` is below 1MHz
+    hardware_cpu_clock: Option<Hertz>,
     // AHB bus prescaler
     ahb_prescaler: Option<u32>,
     // PLL factors
@@ -51,6 +416,135 @@ pub struct RccClocks {
     apb2_timer_clock: Option<MegaHertz>,
 }
 
+// ------ PLL factors searching ---------------------------------
+pub const PLL_M_MIN: u32 = 2;
+pub const PLL_M_MAX: u32 = 63;
+pub const PLL_N_MIN: u32 = 50;
+pub const PLL_N_MAX: u32 = 432;
+pub const PLL_P_VALUES: [u32; 4] = [2, 4, 6, 8];
+pub const PLL_Q_MIN: u32 = 2;
+pub const PLL_Q_MAX: u32 = 15;
+pub const PLL_VCO_INPUT_MIN_FREQUENCY: u32 = 1_000_000;
+pub const PLL_VCO_INPUT_MAX_FREQUENCY: u32 = 2_000_000;
+pub const PLL_VCO_OUTPUT_MIN_FREQUENCY: u32 = 100_000_000;
+pub const PLL_VCO_OUTPUT_MAX_FREQUENCY: u32 = 432_000_000;
+pub const PLL48_CLOCK_FREQUENCY: u32 = 48_000_000;
+
+/// The PLL factors found by `find_pll_factors()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PllFactors {
+    pub pll_m: u32,
+    pub pll_n: u32,
+    pub pll_p: u32,
+    pub pll_q: u32,
+    /// The achieved `SYSCLK` frequency (unit in Hertz)
+    pub system_clock: u32,
+    /// The distance between the achieved and the requested `SYSCLK` (unit in Hertz)
+    pub system_clock_error: u32,
+    /// The achieved `PLL48CLK` frequency for USB OTG FS, SDIO and RNG (unit in Hertz)
+    pub pll48_clock: u32,
+    /// Whether `pll48_clock` is exactly 48MHz (no rounding at all)
+    pub pll48_clock_is_exact: bool,
+}
+
+/// Search the legal PLL factors range for the `SYSCLK` closest to `target_system_clock`:
+///
+/// PLL_VCO = (input_frequency / PLL_M) * PLL_N
+/// SYSCLK = PLL_VCO / PLL_P
+/// PLL48CLK = PLL_VCO / PLL_Q
+///
+/// `input_frequency` is the HSI or HSE frequency (unit in Hertz). The `VCO` input has to
+/// stay in 1MHz ~ 2MHz and the `VCO` output has to stay in 100MHz ~ 432MHz.
+///
+/// When more than one combination gives the same `SYSCLK`, the one with an exact 48MHz
+/// `PLL48CLK` wins, then the one with the higher `VCO` input (less PLL jitter).
+///
+/// Return `None` when no legal combination exists.
+pub fn find_pll_factors(input_frequency: u32, target_system_clock: u32) -> Option<PllFactors> {
+    let input = input_frequency as u64;
+    let target = target_system_clock as u64;
+    let mut best: Option<PllFactors> = None;
+
+    for pll_m in PLL_M_MIN..=PLL_M_MAX {
+        let m = pll_m as u64;
+        if input < PLL_VCO_INPUT_MIN_FREQUENCY as u64 * m
+            || input > PLL_VCO_INPUT_MAX_FREQUENCY as u64 * m
+        {
+            continue;
+        }
+
+        for &pll_p in PLL_P_VALUES.iter() {
+            let p = pll_p as u64;
+
+            // The `PLL_N` closest to the target, plus its neighbours for the rounding
+            let closest_n = (target * m * p + input / 2) / input;
+            for n in closest_n.saturating_sub(1)..=closest_n + 1 {
+                if n < PLL_N_MIN as u64 || n > PLL_N_MAX as u64 {
+                    continue;
+                }
+
+                let vco = input * n / m;
+                if vco < PLL_VCO_OUTPUT_MIN_FREQUENCY as u64
+                    || vco > PLL_VCO_OUTPUT_MAX_FREQUENCY as u64
+                {
+                    continue;
+                }
+
+                let system_clock = input * n / (m * p);
+                let system_clock_error = if system_clock > target {
+                    system_clock - target
+                } else {
+                    target - system_clock
+                };
+
+                // `PLL48CLK` must not go above 48MHz
+                let pll48 = PLL48_CLOCK_FREQUENCY as u64;
+                let q = ((vco + pll48 - 1) / pll48)
+                    .max(PLL_Q_MIN as u64)
+                    .min(PLL_Q_MAX as u64);
+                let pll48_clock = input * n / (m * q);
+                let pll48_clock_is_exact = input * n == pll48 * m * q;
+
+                let candidate = PllFactors {
+                    pll_m,
+                    pll_n: n as u32,
+                    pll_p,
+                    pll_q: q as u32,
+                    system_clock: system_clock as u32,
+                    system_clock_error: system_clock_error as u32,
+                    pll48_clock: pll48_clock as u32,
+                    pll48_clock_is_exact,
+                };
+
+                best = match best {
+                    Some(current) if !candidate.is_better_than(&current) => Some(current),
+                    _ => Some(candidate),
+                };
+            }
+        }
+    }
+
+    best
+}
+
+///
+impl PllFactors {
+    /// Lower `SYSCLK` error first, then exact `PLL48CLK`, then closer `PLL48CLK`.
+    /// `PLL_M` is searched from small to big, so the first one (higher `VCO` input)
+    /// is kept when everything else is the same.
+    fn is_better_than(&self, other: &PllFactors) -> bool {
+        if self.system_clock_error != other.system_clock_error {
+            return self.system_clock_error < other.system_clock_error;
+        }
+
+        if self.pll48_clock_is_exact != other.pll48_clock_is_exact {
+            return self.pll48_clock_is_exact;
+        }
+
+        self.pll48_clock > other.pll48_clock
+    }
+}
+
 /// As `heapless::String<N>` allocates fixed memory on the stack,
 /// and the maximum speed is up to "XXXMhz", that's why take 6bytes.
 #[cfg(feature = "enable-debug")]
@@ -64,6 +558,19 @@ fn debug_frequency(speed: &Option<MegaHertz>) -> String<U6> {
     }
 }
 
+/// Up to "XXXXXXXXXHz"
+#[cfg(feature = "enable-debug")]
+fn debug_frequency_in_hertz(speed: &Option<Hertz>) -> String<U11> {
+    match speed {
+        Some(speed) => {
+            let mut temp_str: String<U11> = String::new();
+            let _ = write!(temp_str, "{}Hz", speed.0);
+            temp_str
+        }
+        None => String::<U11>::from("None"),
+    }
+}
+
 ///
 #[cfg(feature = "enable-debug")]
 impl core::fmt::Debug for RccClocks {
@@ -72,10 +579,13 @@ impl core::fmt::Debug for RccClocks {
             .field("hsi", &debug_frequency(&self.hsi))
             .field("hse", &debug_frequency(&self.hse))
             .field("clock_source", &self.clock_source)
-            .field("system_clock", &debug_frequency(&self.system_clock))
+            .field(
+                "system_clock",
+                &debug_frequency_in_hertz(&self.system_clock),
+            )
             .field(
                 "hardware_cpu_clock",
-                &debug_frequency(&self.hardware_cpu_clock),
+                &debug_frequency_in_hertz(&self.hardware_cpu_clock),
             )
             .field("pll_m", &self.pll_m)
             .field("pll_n", &self.pll_n)
@@ -99,122 +609,21 @@ impl core::fmt::Debug for RccClocks {
 ///
 impl RccClocks {
     #[cfg(feature = "enable-debug")]
-    pub fn print_system_clock_info() {
-        RccClockControlRegister::print_config();
-        RccClockConfigurationRegister::print_config();
-        RccPllConfigurationRegister::print_config();
-        FlashAccessControlRegister::print_config();
+    pub fn print_system_clock_info(registers: &impl RegisterAccess) {
+        RccClockControlRegister::print_config(registers);
+        RccClockConfigurationRegister::print_config(registers);
+        RccPllConfigurationRegister::print_config(registers);
+        FlashAccessControlRegister::print_config(registers);
     }
 
     /// Reset all rcc registers
-    fn init_rcc_clock() {
-        RccClockControlRegister::reset();
+    fn init_rcc_clock(registers: &impl RegisterAccess) {
+        RccClockControlRegister::reset(registers);
     }
 
     /// Create `RccClocks` instance
-    fn create_rcc_clocks(clock_source: &ClockSource) -> RccClocks {
-        let rcc_clock = match clock_source {
-            ClockSource::Hsi => {
-                let system_clock_speed = clock_source_selecting::HSI_FREQUENCY;
-                let cpu_clock_speed =
-                    system_clock_speed / clock_source_selecting::AHB_PRESCALER_FOR_HSI;
-                let apb1_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB1_PRESCALER_FOR_HSI;
-                let apb2_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB2_PRESCALER_FOR_HSI;
-                let apb1_timer_clock_speed =
-                    apb1_peripheral_clock_speed * clock_source_selecting::APB1_TIMER_FACTOR;
-                let apb2_timer_clock_speed =
-                    apb2_peripheral_clock_speed * clock_source_selecting::APB2_TIMER_FACTOR;
-
-                RccClocks {
-                    hsi: Some(clock_source_selecting::HSI_FREQUENCY.into()),
-                    hse: Some(clock_source_selecting::HSE_FREQUENCY.into()),
-                    clock_source: (*clock_source).clone(),
-                    system_clock: Some(system_clock_speed.into()),
-                    hardware_cpu_clock: Some(cpu_clock_speed.into()),
-                    ahb_prescaler: None,
-                    pll_m: None,
-                    pll_n: None,
-                    pll_p: None,
-                    pll_q: None,
-                    apb1_peripheral_clock: Some(apb1_peripheral_clock_speed.into()),
-                    apb1_timer_clock: Some(apb1_timer_clock_speed.into()),
-                    apb2_peripheral_clock: Some(apb2_peripheral_clock_speed.into()),
-                    apb2_timer_clock: Some(apb2_timer_clock_speed.into()),
-                }
-            }
-            ClockSource::HsiThroughPll => {
-                let system_clock_speed = clock_source_selecting::HSI_FREQUENCY
-                    / clock_source_selecting::PLL_M_PRESCALER_FOR_HSI
-                    * clock_source_selecting::PLL_N_PRESCALER_FOR_HSI
-                    / clock_source_selecting::PLL_P_PRESCALER_FOR_HSI;
-
-                let cpu_clock_speed =
-                    system_clock_speed / clock_source_selecting::AHB_PRESCALER_FOR_HSI;
-
-                let apb1_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB1_PRESCALER_FOR_HSI;
-                let apb2_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB2_PRESCALER_FOR_HSI;
-                let apb1_timer_clock_speed =
-                    apb1_peripheral_clock_speed * clock_source_selecting::APB1_TIMER_FACTOR;
-                let apb2_timer_clock_speed =
-                    apb2_peripheral_clock_speed * clock_source_selecting::APB2_TIMER_FACTOR;
-
-                RccClocks {
-                    hsi: Some(clock_source_selecting::HSI_FREQUENCY.into()),
-                    hse: Some(clock_source_selecting::HSE_FREQUENCY.into()),
-                    clock_source: (*clock_source).clone(),
-                    system_clock: Some(system_clock_speed.into()),
-                    hardware_cpu_clock: Some(cpu_clock_speed.into()),
-                    ahb_prescaler: Some(clock_source_selecting::AHB_PRESCALER_FOR_HSI),
-                    pll_m: Some(clock_source_selecting::PLL_M_PRESCALER_FOR_HSI),
-                    pll_n: Some(clock_source_selecting::PLL_N_PRESCALER_FOR_HSI),
-                    pll_p: Some(clock_source_selecting::PLL_P_PRESCALER_FOR_HSI),
-                    pll_q: Some(clock_source_selecting::PLL_Q_PRESCALER_FOR_HSI),
-                    apb1_peripheral_clock: Some(apb1_peripheral_clock_speed.into()),
-                    apb1_timer_clock: Some(apb1_timer_clock_speed.into()),
-                    apb2_peripheral_clock: Some(apb2_peripheral_clock_speed.into()),
-                    apb2_timer_clock: Some(apb2_timer_clock_speed.into()),
-                }
-            }
-            ClockSource::HseThroughPll => {
-                let system_clock_speed = clock_source_selecting::HSE_FREQUENCY
-                    / clock_source_selecting::PLL_M_PRESCALER_FOR_HSE
-                    * clock_source_selecting::PLL_N_PRESCALER_FOR_HSE
-                    / clock_source_selecting::PLL_P_PRESCALER_FOR_HSE;
-
-                let cpu_clock_speed =
-                    system_clock_speed / clock_source_selecting::AHB_PRESCALER_FOR_HSE;
-
-                let apb1_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB1_PRESCALER_FOR_HSE;
-                let apb2_peripheral_clock_speed =
-                    cpu_clock_speed / clock_source_selecting::APB2_PRESCALER_FOR_HSE;
-                let apb1_timer_clock_speed =
-                    apb1_peripheral_clock_speed * clock_source_selecting::APB1_TIMER_FACTOR;
-                let apb2_timer_clock_speed =
-                    apb2_peripheral_clock_speed * clock_source_selecting::APB2_TIMER_FACTOR;
-
-                RccClocks {
-                    hsi: Some(clock_source_selecting::HSI_FREQUENCY.into()),
-                    hse: Some(clock_source_selecting::HSE_FREQUENCY.into()),
-                    clock_source: (*clock_source).clone(),
-                    system_clock: Some(system_clock_speed.into()),
-                    hardware_cpu_clock: Some(cpu_clock_speed.into()),
-                    ahb_prescaler: Some(clock_source_selecting::AHB_PRESCALER_FOR_HSE),
-                    pll_m: Some(clock_source_selecting::PLL_M_PRESCALER_FOR_HSE),
-                    pll_n: Some(clock_source_selecting::PLL_N_PRESCALER_FOR_HSE),
-                    pll_p: Some(clock_source_selecting::PLL_P_PRESCALER_FOR_HSE),
-                    pll_q: Some(clock_source_selecting::PLL_Q_PRESCALER_FOR_HSE),
-                    apb1_peripheral_clock: Some(apb1_peripheral_clock_speed.into()),
-                    apb1_timer_clock: Some(apb1_timer_clock_speed.into()),
-                    apb2_peripheral_clock: Some(apb2_peripheral_clock_speed.into()),
-                    apb2_timer_clock: Some(apb2_timer_clock_speed.into()),
-                }
-            }
-        };
+    fn create_rcc_clocks(config: &ClockConfig) -> RccClocks {
+        let rcc_clock = Self::calculate_rcc_clocks(config);
 
         #[cfg(feature = "enable-debug")]
         let _ = hprintln!("\n{:#?}", &rcc_clock);
@@ -222,60 +631,796 @@ impl RccClocks {
         rcc_clock
     }
 
-    /// Setup system clock
-    pub fn setup_system_clock(clock_source: ClockSource) -> RccClocks {
-        Self::init_rcc_clock();
+    /// Calculate all the bus clocks from `SYSCLK` and the bus prescalers
+    fn calculate_rcc_clocks(config: &ClockConfig) -> RccClocks {
+        let system_clock_speed = config.get_system_clock_frequency();
+        let cpu_clock_speed = system_clock_speed / config.ahb_prescaler;
+        let apb1_peripheral_clock_speed = cpu_clock_speed / config.apb1_prescaler;
+        let apb2_peripheral_clock_speed = cpu_clock_speed / config.apb2_prescaler;
+        let apb1_timer_clock_speed =
+            apb1_peripheral_clock_speed * ClockConfig::timer_factor(config.apb1_prescaler);
+        let apb2_timer_clock_speed =
+            apb2_peripheral_clock_speed * ClockConfig::timer_factor(config.apb2_prescaler);
+        let use_pll = config.clock_source.use_pll();
+
+        RccClocks {
+            hsi: Some(config.hsi_frequency.into()),
+            hse: Some(config.hse_frequency.into()),
+            clock_source: config.clock_source.clone(),
+            system_clock: Some(system_clock_speed.into()),
+            hardware_cpu_clock: Some(cpu_clock_speed.into()),
+            ahb_prescaler: Some(config.ahb_prescaler),
+            pll_m: if use_pll { Some(config.pll_m) } else { None },
+            pll_n: if use_pll { Some(config.pll_n) } else { None },
+            pll_p: if use_pll { Some(config.pll_p) } else { None },
+            pll_q: if use_pll { Some(config.pll_q) } else { None },
+            apb1_peripheral_clock: Some(apb1_peripheral_clock_speed.into()),
+            apb1_timer_clock: Some(apb1_timer_clock_speed.into()),
+            apb2_peripheral_clock: Some(apb2_peripheral_clock_speed.into()),
+            apb2_timer_clock: Some(apb2_timer_clock_speed.into()),
+        }
+    }
+
+    /// Read back the clock tree which the chip is running right now, e.g. after a
+    /// bootloader already configured the clocks, or to verify `setup_system_clock`.
+    ///
+    /// The HSE frequency can't be read from any register, the board default
+    /// `clock_source_selecting::HSE_FREQUENCY` is used.
+    pub fn from_hardware(registers: &impl RegisterAccess) -> Result<RccClocks, ClockConfigError> {
+        let config = match RccClockConfigurationRegister::get_clock_switch_status(registers) {
+            RccSystemClockSwtichStatus::HsiUsedAsSytemClock => ClockConfig::new(ClockSource::Hsi),
+            RccSystemClockSwtichStatus::HseUsedAsSytemClock => ClockConfig::new(ClockSource::Hse),
+            RccSystemClockSwtichStatus::PllUsedAsSytemClock => {
+                let clock_source = if RccPllConfigurationRegister::is_hse_as_pll_src(registers) {
+                    ClockSource::HseThroughPll
+                } else {
+                    ClockSource::HsiThroughPll
+                };
+
+                ClockConfig::new(clock_source).pll_mnpq(
+                    RccPllConfigurationRegister::get_pll_m_value(registers)?,
+                    RccPllConfigurationRegister::get_pll_n_value(registers)?,
+                    RccPllConfigurationRegister::get_pll_p_value(registers)?,
+                    RccPllConfigurationRegister::get_pll_q_value(registers)?,
+                )
+            }
+            RccSystemClockSwtichStatus::NotApplicable => {
+                return Err(ClockConfigError::WrongClockSwitchStatus(0b11))
+            }
+        }
+        .ahb_prescaler(RccClockConfigurationRegister::get_ahb_prescaler(registers).into())
+        .apb1_prescaler(RccClockConfigurationRegister::get_apb1_prescaler(registers).into())
+        .apb2_prescaler(RccClockConfigurationRegister::get_apb2_prescaler(registers).into());
 
-        let rcc_clock = Self::create_rcc_clocks(&clock_source);
+        Ok(Self::calculate_rcc_clocks(&config))
+    }
+
+    /// Setup system clock with the given `ClockConfig`
+    ///
+    /// When HSE or PLL doesn't become ready in time, it falls back to `HsiThroughPll`
+    /// with the same `SYSCLK`, then to `Hsi`. Call `get_clock_source()` on the result
+    /// to find out which clock source is actually used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `ClockConfig` is invalid, use `try_setup_system_clock` to handle
+    /// the error instead.
+    pub fn setup_system_clock(registers: &impl RegisterAccess, config: ClockConfig) -> RccClocks {
+        let mut config = config;
 
-        // For this default option, we do nothing
-        if clock_source == ClockSource::Hsi {
-            return rcc_clock;
+        loop {
+            match Self::try_setup_system_clock(registers, config.clone()) {
+                Ok(rcc_clock) => return rcc_clock,
+                Err(ClockConfigError::HseStartupTimeout)
+                | Err(ClockConfigError::PllLockTimeout)
+                | Err(ClockConfigError::PllStopTimeout)
+                | Err(ClockConfigError::ClockSwitchTimeout)
+                    if config.clock_source != ClockSource::Hsi =>
+                {
+                    let fallback_clock_source = match config.clock_source {
+                        ClockSource::HseThroughPll => ClockSource::HsiThroughPll,
+                        _ => ClockSource::Hsi,
+                    };
+
+                    #[cfg(feature = "enable-debug")]
+                    let _ = hprintln!(
+                        "{:?} is not ready, fall back to {:?}",
+                        config.clock_source,
+                        fallback_clock_source
+                    );
+
+                    config = config.fallback_to(fallback_clock_source);
+                }
+                Err(error) => panic!("Invalid clock configuration: {:?}", error),
+            }
         }
+    }
 
-        match clock_source {
-            ClockSource::HsiThroughPll => {
-                // 2. Set the AHB prescaler, APB1 prescaler, APB2 prescaler
-                RccClockConfigurationRegister::set_bus_prescaler(false);
-                // 3. Setup flash
-                FlashAccessControlRegister::set_flash_latency(
-                    clock_source_selecting::FLASH_LATENCY,
-                );
-                // 4. Set PLL factors MNPQ
-                RccPllConfigurationRegister::set_pll_mnpq(false);
-                // 5. Enable PLL and wait for it stable
-                RccClockControlRegister::enable_pll_and_wait_for_it_stable();
+    /// Validate the whole clock tree before touching any register, then setup
+    /// system clock with the given `ClockConfig`
+    pub fn try_setup_system_clock(
+        registers: &impl RegisterAccess,
+        config: ClockConfig,
+    ) -> Result<RccClocks, ClockConfigError> {
+        config.validate()?;
+
+        let ahb_prescaler = RccAhbPrescaler::try_from(config.ahb_prescaler)?;
+        let apb1_prescaler = RccApbPrescaler::try_from(config.apb1_prescaler)?;
+        let apb2_prescaler = RccApbPrescaler::try_from(config.apb2_prescaler)?;
+        let flash_latency = config.get_flash_latency()?;
+        let current_flash_latency = FlashAccessControlRegister::get_flash_latency(registers);
+
+        // 0. Run on HSI while reconfiguring, HSE and PLL can't be turned off (or the
+        //    PLL factors changed) as long as they are used as system clock
+        if RccClockConfigurationRegister::get_clock_switch_status(registers)
+            != RccSystemClockSwtichStatus::HsiUsedAsSytemClock
+        {
+            RccCrValue::modify(registers, |r| r.set_hsi_on(true));
+            RccClockConfigurationRegister::try_switch_clock_source(
+                registers,
+                RccSystemClockSwtich::HsiSelectedAsSytemClock,
+                config.max_wait_cycles,
+            )?;
+        }
+
+        Self::init_rcc_clock(registers);
+
+        let rcc_clock = Self::create_rcc_clocks(&config);
+        let use_hse = config.clock_source.use_hse();
+
+        // 1. Enable HSE and wait for it stable
+        if use_hse {
+            RccClockControlRegister::try_enable_hse_as_clock_source(
+                registers,
+                config.max_wait_cycles,
+            )?;
+        }
+
+        // 2. Set the AHB prescaler, APB1 prescaler, APB2 prescaler
+        RccClockConfigurationRegister::set_bus_prescaler(
+            registers,
+            ahb_prescaler,
+            apb1_prescaler,
+            apb2_prescaler,
+        );
+
+        // 3. Raise the flash latency before increasing the frequency
+        if flash_latency >= current_flash_latency {
+            FlashAccessControlRegister::set_flash_latency(registers, flash_latency);
+        }
+
+        match config.clock_source {
+            // For the HSI option, we don't need PLL at all
+            ClockSource::Hsi => {
+                RccClockConfigurationRegister::try_switch_clock_source(
+                    registers,
+                    RccSystemClockSwtich::HsiSelectedAsSytemClock,
+                    config.max_wait_cycles,
+                )?;
             }
-            ClockSource::HseThroughPll => {
-                // 1. Enable HSE and wait for it stable
-                RccClockControlRegister::enable_hse_as_clock_source_and_wait_for_it_stable();
-                // 2. Set the AHB prescaler, APB1 prescaler, APB2 prescaler
-                RccClockConfigurationRegister::set_bus_prescaler(true);
-                // 3. Setup flash
-                FlashAccessControlRegister::set_flash_latency(
-                    clock_source_selecting::FLASH_LATENCY,
+
+            // For the HSE option, switch to HSE directly
+            ClockSource::Hse => {
+                RccClockConfigurationRegister::try_switch_clock_source(
+                    registers,
+                    RccSystemClockSwtich::HseSelectedAsSytemClock,
+                    config.max_wait_cycles,
+                )?;
+            }
+
+            ClockSource::HsiThroughPll | ClockSource::HseThroughPll => {
+                // 4. Stop PLL, then set PLL factors MNPQ
+                let (pll_m, pll_n, pll_p, pll_q) = RccPllConfigurationRegister::check_pll_mnpq(
+                    config.pll_m,
+                    config.pll_n,
+                    config.pll_p,
+                    config.pll_q,
+                )?;
+                RccClockControlRegister::try_disable_pll(registers, config.max_wait_cycles)?;
+                RccPllConfigurationRegister::set_pll_mnpq(
+                    registers, pll_m, pll_n, pll_p, pll_q, use_hse,
                 );
-                // 4. Set PLL factors MNPQ
-                RccPllConfigurationRegister::set_pll_mnpq(true);
                 // 5. Enable PLL and wait for it stable
-                RccClockControlRegister::enable_pll_and_wait_for_it_stable();
+                RccClockControlRegister::try_enable_pll(registers, config.max_wait_cycles)?;
+
+                // 6. Switch clock source
+                RccClockConfigurationRegister::try_switch_clock_source(
+                    registers,
+                    RccSystemClockSwtich::PllSelectedAsSytemClock,
+                    config.max_wait_cycles,
+                )?;
             }
-            _ => {}
         }
 
-        // 6. Switch clock source
-        RccClockConfigurationRegister::switch_clock_source_and_wait_for_stable(
-            RccSystemClockSwtich::PllSelectedAsSytemClock,
-        );
+        // 7. Lower the flash latency after `SWS` reports the slower clock
+        if flash_latency < current_flash_latency {
+            FlashAccessControlRegister::set_flash_latency(registers, flash_latency);
+        }
 
-        rcc_clock
+        Ok(rcc_clock)
+    }
+
+    /// The clock source which is actually used
+    pub fn get_clock_source(&self) -> &ClockSource {
+        &self.clock_source
+    }
+
+    ///
+    pub fn get_system_clock_frequency_in_hertz(&self) -> u32 {
+        match self.system_clock {
+            Some(value) => value.0,
+            None => 0,
+        }
     }
 
     ///
     pub fn get_cpu_clock_frequency_in_hertz(&self) -> u32 {
         match self.hardware_cpu_clock {
-            Some(value) => value.0 * 1_000_000,
+            Some(value) => value.0,
             None => 0,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flash_access_control_register::FLASH_ACR;
+    use crate::rcc_clock_config_register::RccCfgrValue;
+    use crate::rcc_clock_control_register::RccCrValue;
+    use crate::rcc_pll_config_register::RCC_PLLCFGR;
+    use crate::register_access::RecordingRegisters;
+    use crate::simulated_registers::{FlashLatencyViolation, SimulatedRegisters};
+
+    const RCC_CR: u32 = RccCrValue::ADDRESS;
+    const RCC_CGFCR: u32 = RccCfgrValue::ADDRESS;
+
+    const RCC_CR_HSE_IS_ON: u32 = RccCrValue::HSEON.mask();
+    const RCC_CR_MAIN_PLL_IS_ON: u32 = RccCrValue::PLLON.mask();
+
+    /// `HPRE`, `PPRE1` and `PPRE2` in `RCC_CFGR`
+    const BUS_PRESCALER_MASK: u32 =
+        RccCfgrValue::HPRE.mask() | RccCfgrValue::PPRE1.mask() | RccCfgrValue::PPRE2.mask();
+
+    fn assert_hse_on_keeps_hsi(rcc_cr_value: u32) {
+        assert_eq!(rcc_cr_value & 0x83, 0x83);
+        assert_eq!(rcc_cr_value & RCC_CR_HSE_IS_ON, RCC_CR_HSE_IS_ON);
+    }
+
+    fn written_addresses(writes: &[(u32, u32)]) -> Vec<u32> {
+        writes.iter().map(|(address, _)| *address).collect()
+    }
+
+    /// The value written to `CFGR` by `set_bus_prescaler` for the given config
+    fn bus_prescaler_bits(config: &ClockConfig) -> u32 {
+        let ahb: u32 = RccAhbPrescaler::try_from(config.ahb_prescaler)
+            .unwrap()
+            .to_register_bits();
+        let apb1 = RccApbPrescaler::try_from(config.apb1_prescaler)
+            .unwrap()
+            .to_register_bits();
+        let apb2 = RccApbPrescaler::try_from(config.apb2_prescaler)
+            .unwrap()
+            .to_register_bits();
+
+        (ahb << 4) | (apb1 << 10) | (apb2 << 13)
+    }
+
+    fn assert_legal(factors: &PllFactors, input_frequency: u32) {
+        assert!(factors.pll_m >= PLL_M_MIN && factors.pll_m <= PLL_M_MAX);
+        assert!(factors.pll_n >= PLL_N_MIN && factors.pll_n <= PLL_N_MAX);
+        assert!(PLL_P_VALUES.contains(&factors.pll_p));
+        assert!(factors.pll_q >= PLL_Q_MIN && factors.pll_q <= PLL_Q_MAX);
+
+        let vco_input = input_frequency / factors.pll_m;
+        assert!(vco_input >= PLL_VCO_INPUT_MIN_FREQUENCY);
+        assert!(vco_input <= PLL_VCO_INPUT_MAX_FREQUENCY);
+
+        let vco = input_frequency as u64 * factors.pll_n as u64 / factors.pll_m as u64;
+        assert!(vco >= PLL_VCO_OUTPUT_MIN_FREQUENCY as u64);
+        assert!(vco <= PLL_VCO_OUTPUT_MAX_FREQUENCY as u64);
+        assert!(factors.pll48_clock <= PLL48_CLOCK_FREQUENCY);
+    }
+
+    #[test]
+    fn discovery_hse_to_168mhz_with_exact_usb_clock() {
+        let factors = find_pll_factors(8_000_000, 168_000_000).unwrap();
+        assert_legal(&factors, 8_000_000);
+        assert_eq!(factors.system_clock, 168_000_000);
+        assert_eq!(factors.system_clock_error, 0);
+        assert_eq!(factors.pll48_clock, 48_000_000);
+        assert!(factors.pll48_clock_is_exact);
+    }
+
+    #[test]
+    fn hsi_to_168mhz_with_exact_usb_clock() {
+        let factors = find_pll_factors(16_000_000, 168_000_000).unwrap();
+        assert_legal(&factors, 16_000_000);
+        assert_eq!(factors.system_clock_error, 0);
+        assert!(factors.pll48_clock_is_exact);
+    }
+
+    #[test]
+    fn black_pill_hse_to_96mhz_with_exact_usb_clock() {
+        let factors = find_pll_factors(25_000_000, 96_000_000).unwrap();
+        assert_legal(&factors, 25_000_000);
+        assert_eq!(factors.system_clock, 96_000_000);
+        assert!(factors.pll48_clock_is_exact);
+    }
+
+    #[test]
+    fn black_pill_hse_to_100mhz_without_exact_usb_clock() {
+        // 100MHz can't share the VCO with an exact 48MHz, `SYSCLK` still wins
+        let factors = find_pll_factors(25_000_000, 100_000_000).unwrap();
+        assert_legal(&factors, 25_000_000);
+        assert_eq!(factors.system_clock, 100_000_000);
+        assert!(!factors.pll48_clock_is_exact);
+    }
+
+    #[test]
+    fn unreachable_frequency_reports_the_error() {
+        // 433MHz VCO is out of range, the closest is 432MHz / 2
+        let factors = find_pll_factors(16_000_000, 217_000_000).unwrap();
+        assert_legal(&factors, 16_000_000);
+        assert_eq!(factors.system_clock, 216_000_000);
+        assert_eq!(factors.system_clock_error, 1_000_000);
+    }
+
+    #[test]
+    fn input_frequency_too_low_for_vco_input() {
+        assert_eq!(find_pll_factors(1_500_000, 84_000_000), None);
+    }
+
+    #[test]
+    fn board_default_configs_are_valid() {
+        assert_eq!(ClockConfig::new(ClockSource::Hsi).validate(), Ok(()));
+        assert_eq!(ClockConfig::new(ClockSource::Hse).validate(), Ok(()));
+        assert_eq!(
+            ClockConfig::new(ClockSource::HsiThroughPll).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            ClockConfig::new(ClockSource::HseThroughPll).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = ClockConfig::new(ClockSource::HsiThroughPll);
+
+        assert_eq!(
+            config.clone().ahb_prescaler(3).validate(),
+            Err(ClockConfigError::WrongAhbPrescaler(3))
+        );
+        assert_eq!(
+            config.clone().pll_mnpq(1, 336, 2, 7).validate(),
+            Err(ClockConfigError::WrongPllConfiguration(
+                RccPllConfigurationError::WrongPllMConfiguration(1)
+            ))
+        );
+        assert_eq!(
+            config.clone().pll_mnpq(16, 60, 2, 2).validate(),
+            Err(ClockConfigError::VcoOutputOutOfRange(60_000_000))
+        );
+        assert_eq!(
+            config.clone().apb1_prescaler(1).validate(),
+            Err(ClockConfigError::Apb1ClockTooHigh(
+                clock_source_selecting::SYS_CLOCK_MAX_SPEED
+            ))
+        );
+        assert_eq!(
+            config.clone().flash_latency(0).validate(),
+            Err(ClockConfigError::FlashLatencyTooLow {
+                required: config.get_flash_latency().unwrap().to_register_bits(),
+                actual: 0
+            })
+        );
+        assert_eq!(
+            config.clone().pll_mnpq(16, 432, 2, 9).validate(),
+            Err(ClockConfigError::SystemClockTooHigh(216_000_000))
+        );
+    }
+
+    #[test]
+    fn flash_latency_follows_hclk_and_supply_voltage() {
+        let config = ClockConfig::new(ClockSource::HsiThroughPll);
+        let cpu_clock = config.get_cpu_clock_frequency();
+
+        assert_eq!(
+            ClockConfig::new(ClockSource::Hsi).get_flash_latency(),
+            Ok(FlashReadLatency::ZeroWaitState1CpuCycle)
+        );
+        assert_eq!(
+            config.get_flash_latency().ok(),
+            FlashReadLatency::minimum_for_cpu_clock(cpu_clock, SupplyVoltage::From2V7To3V6)
+        );
+
+        // Lower voltage needs more wait states for the same `HCLK`
+        let low_voltage_config = config.clone().supply_voltage(SupplyVoltage::From2V1To2V4);
+        assert!(
+            low_voltage_config.get_flash_latency().unwrap() > config.get_flash_latency().unwrap()
+        );
+        assert_eq!(low_voltage_config.validate(), Ok(()));
+
+        // Half `HCLK` needs less wait states
+        let slow_config = config.clone().ahb_prescaler(2);
+        assert!(slow_config.get_flash_latency().unwrap() < config.get_flash_latency().unwrap());
+    }
+
+    #[test]
+    fn fallback_config_keeps_the_system_clock() {
+        let config = ClockConfig::new(ClockSource::HseThroughPll).max_wait_cycles(10);
+
+        let fallback = config.fallback_to(ClockSource::HsiThroughPll);
+        assert_eq!(fallback.get_clock_source(), &ClockSource::HsiThroughPll);
+        assert_eq!(
+            fallback.get_system_clock_frequency(),
+            config.get_system_clock_frequency()
+        );
+        assert_eq!(fallback.validate(), Ok(()));
+
+        let fallback = config.fallback_to(ClockSource::Hsi);
+        assert_eq!(fallback.get_system_clock_frequency(), 16_000_000);
+        assert_eq!(fallback.validate(), Ok(()));
+    }
+
+    #[test]
+    fn clock_config_picks_pll_factors_for_system_clock() {
+        let config = ClockConfig::new(ClockSource::HseThroughPll)
+            .hse_frequency(8_000_000)
+            .system_clock(84_000_000);
+        assert_eq!(config.get_system_clock_frequency(), 84_000_000);
+    }
+
+    #[test]
+    fn setup_hsi_writes_registers_in_order() {
+        let registers = SimulatedRegisters::new();
+        let config = ClockConfig::new(ClockSource::Hsi);
+
+        let rcc_clock = RccClocks::try_setup_system_clock(&registers, config.clone()).unwrap();
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::Hsi);
+
+        let writes = registers.get_writes();
+        assert_eq!(
+            written_addresses(&writes),
+            vec![RCC_CR, RCC_CGFCR, FLASH_ACR, RCC_CGFCR]
+        );
+        assert_eq!(writes[0].1, 0x0000_0083);
+        assert_eq!(writes[1].1, bus_prescaler_bits(&config));
+        assert_eq!(writes[2].1 & 0b111, 0);
+        assert_eq!(writes[3].1 & 0b11, 0b00);
+    }
+
+    #[test]
+    fn setup_hse_writes_registers_in_order() {
+        let registers = SimulatedRegisters::new();
+        let config = ClockConfig::new(ClockSource::Hse);
+
+        let rcc_clock = RccClocks::try_setup_system_clock(&registers, config.clone()).unwrap();
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::Hse);
+
+        let writes = registers.get_writes();
+        assert_eq!(
+            written_addresses(&writes),
+            vec![RCC_CR, RCC_CR, RCC_CGFCR, FLASH_ACR, RCC_CGFCR]
+        );
+        assert_eq!(writes[0].1, 0x0000_0083);
+        assert_hse_on_keeps_hsi(writes[1].1);
+        assert_eq!(
+            writes[2].1 & BUS_PRESCALER_MASK,
+            bus_prescaler_bits(&config)
+        );
+
+        // The clock switch keeps the prescalers
+        assert_eq!(writes[4].1 & 0b11, 0b01);
+        assert_eq!(
+            writes[4].1 & BUS_PRESCALER_MASK,
+            bus_prescaler_bits(&config)
+        );
+    }
+
+    #[test]
+    fn setup_lowers_flash_latency_after_switching_to_a_slower_clock() {
+        let registers = SimulatedRegisters::new();
+        FlashAccessControlRegister::set_flash_latency(
+            &registers,
+            FlashReadLatency::ThreeWaitState4CpuCycles,
+        );
+        let config = ClockConfig::new(ClockSource::Hse);
+
+        RccClocks::try_setup_system_clock(&registers, config).unwrap();
+        assert_eq!(
+            written_addresses(&registers.get_writes()),
+            vec![FLASH_ACR, RCC_CR, RCC_CR, RCC_CGFCR, RCC_CGFCR, FLASH_ACR]
+        );
+        assert_eq!(
+            FlashAccessControlRegister::get_flash_latency(&registers),
+            FlashReadLatency::ZeroWaitState1CpuCycle
+        );
+        assert!(registers.get_flash_latency_violations().is_empty());
+    }
+
+    #[test]
+    fn setup_hsi_through_pll_writes_registers_in_order() {
+        let registers = SimulatedRegisters::new();
+        let config = ClockConfig::new(ClockSource::HsiThroughPll);
+
+        RccClocks::try_setup_system_clock(&registers, config.clone()).unwrap();
+
+        let writes = registers.get_writes();
+        assert_eq!(
+            written_addresses(&writes),
+            vec![RCC_CR, RCC_CGFCR, FLASH_ACR, RCC_PLLCFGR, RCC_CR, RCC_CGFCR]
+        );
+        assert_eq!(
+            writes[1].1 & BUS_PRESCALER_MASK,
+            bus_prescaler_bits(&config)
+        );
+        assert_eq!(
+            writes[2].1 & 0b111,
+            config.get_flash_latency().unwrap().to_register_bits()
+        );
+        assert_eq!(writes[4].1 & 0x83, 0x83);
+        assert_eq!(writes[4].1 & RCC_CR_MAIN_PLL_IS_ON, RCC_CR_MAIN_PLL_IS_ON);
+        assert_eq!(writes[5].1 & 0b11, 0b10);
+        assert_eq!(
+            writes[5].1 & BUS_PRESCALER_MASK,
+            bus_prescaler_bits(&config)
+        );
+
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_m_value(&registers),
+            Ok(config.pll_m)
+        );
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_n_value(&registers),
+            Ok(config.pll_n)
+        );
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_p_value(&registers),
+            Ok(config.pll_p)
+        );
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_q_value(&registers),
+            Ok(config.pll_q)
+        );
+        assert!(!RccPllConfigurationRegister::is_hse_as_pll_src(&registers));
+    }
+
+    #[test]
+    fn setup_hse_through_pll_writes_registers_in_order() {
+        let registers = SimulatedRegisters::new();
+        let config = ClockConfig::new(ClockSource::HseThroughPll);
+
+        RccClocks::try_setup_system_clock(&registers, config.clone()).unwrap();
+
+        let writes = registers.get_writes();
+        assert_eq!(
+            written_addresses(&writes),
+            vec![
+                RCC_CR,
+                RCC_CR,
+                RCC_CGFCR,
+                FLASH_ACR,
+                RCC_PLLCFGR,
+                RCC_CR,
+                RCC_CGFCR
+            ]
+        );
+        assert_eq!(writes[0].1, 0x0000_0083);
+        assert_hse_on_keeps_hsi(writes[1].1);
+        assert_eq!(
+            writes[2].1 & BUS_PRESCALER_MASK,
+            bus_prescaler_bits(&config)
+        );
+
+        // Turning on the PLL must not turn off the HSE
+        assert_hse_on_keeps_hsi(writes[5].1);
+        assert_eq!(writes[5].1 & RCC_CR_MAIN_PLL_IS_ON, RCC_CR_MAIN_PLL_IS_ON);
+        assert_eq!(writes[6].1 & 0b11, 0b10);
+        assert!(RccPllConfigurationRegister::is_hse_as_pll_src(&registers));
+    }
+
+    #[test]
+    fn pll_config_keeps_the_reserved_bits() {
+        let registers = SimulatedRegisters::new();
+        registers.write(RCC_PLLCFGR, 0x2400_3010);
+
+        RccClocks::try_setup_system_clock(&registers, ClockConfig::new(ClockSource::HseThroughPll))
+            .unwrap();
+        assert_eq!(registers.read(RCC_PLLCFGR) & 0xF000_0000, 0x2000_0000);
+    }
+
+    #[test]
+    fn hse_timeout_stops_before_touching_other_registers() {
+        let registers = RecordingRegisters::new();
+        let config = ClockConfig::new(ClockSource::HseThroughPll).max_wait_cycles(10);
+
+        assert_eq!(
+            RccClocks::try_setup_system_clock(&registers, config).map(|_| ()),
+            Err(ClockConfigError::HseStartupTimeout)
+        );
+        assert_eq!(
+            written_addresses(&registers.get_writes()),
+            vec![RCC_CR, RCC_CR]
+        );
+    }
+
+    #[test]
+    fn invalid_config_never_touches_registers() {
+        let registers = RecordingRegisters::new();
+        let config = ClockConfig::new(ClockSource::HsiThroughPll).ahb_prescaler(3);
+
+        assert!(RccClocks::try_setup_system_clock(&registers, config).is_err());
+        assert!(registers.get_operations().is_empty());
+    }
+
+    #[test]
+    fn setup_falls_back_to_hsi_through_pll_without_hse() {
+        let registers = SimulatedRegisters::new().hse_never_ready();
+        let config = ClockConfig::new(ClockSource::HseThroughPll).max_wait_cycles(1000);
+
+        let rcc_clock = RccClocks::setup_system_clock(&registers, config.clone());
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::HsiThroughPll);
+        assert_eq!(
+            rcc_clock.get_system_clock_frequency_in_hertz(),
+            config.get_system_clock_frequency()
+        );
+        assert_eq!(
+            registers.get_system_clock_frequency(),
+            config.get_system_clock_frequency()
+        );
+        assert!(!RccPllConfigurationRegister::is_hse_as_pll_src(&registers));
+    }
+
+    #[test]
+    fn simulated_setup_for_each_clock_source() {
+        for clock_source in [
+            ClockSource::Hsi,
+            ClockSource::Hse,
+            ClockSource::HsiThroughPll,
+            ClockSource::HseThroughPll,
+        ]
+        .iter()
+        {
+            let registers = SimulatedRegisters::new();
+            let config = ClockConfig::new(clock_source.clone());
+
+            let system_clock = config.get_system_clock_frequency();
+
+            let rcc_clock = RccClocks::try_setup_system_clock(&registers, config).unwrap();
+            assert_eq!(rcc_clock.get_clock_source(), clock_source);
+            assert_eq!(registers.get_system_clock_frequency(), system_clock);
+            assert!(registers.get_flash_latency_violations().is_empty());
+            assert_eq!(
+                RccClocks::from_hardware(&registers)
+                    .unwrap()
+                    .get_clock_source(),
+                clock_source
+            );
+        }
+    }
+
+    #[test]
+    fn simulated_reconfigure_from_a_running_pll() {
+        for (config, system_clock) in [
+            (ClockConfig::new(ClockSource::Hsi), 16_000_000),
+            (
+                ClockConfig::new(ClockSource::HsiThroughPll).system_clock(48_000_000),
+                48_000_000,
+            ),
+        ]
+        .iter()
+        {
+            // A slow PLL stop, the new factors are ignored if they come too early
+            let registers = SimulatedRegisters::new().pll_stop_cycles(1000);
+            let running_config = ClockConfig::new(ClockSource::HseThroughPll);
+            RccClocks::try_setup_system_clock(&registers, running_config.clone()).unwrap();
+            assert_eq!(
+                registers.get_system_clock_frequency(),
+                running_config.get_system_clock_frequency()
+            );
+
+            RccClocks::try_setup_system_clock(&registers, config.clone()).unwrap();
+            assert_eq!(registers.get_system_clock_frequency(), *system_clock);
+            assert_eq!(
+                RccClocks::from_hardware(&registers)
+                    .unwrap()
+                    .get_clock_source(),
+                config.get_clock_source()
+            );
+
+            // Lowered only after running on the slower clock
+            assert_eq!(
+                FlashAccessControlRegister::get_flash_latency(&registers),
+                config.get_flash_latency().unwrap()
+            );
+            assert_eq!(registers.get_writes().last().unwrap().0, FLASH_ACR);
+            assert!(registers.get_flash_latency_violations().is_empty());
+        }
+    }
+
+    #[test]
+    fn simulated_flash_latency_is_raised_before_hclk() {
+        for supply_voltage in [SupplyVoltage::From2V1To2V4, SupplyVoltage::From2V7To3V6].iter() {
+            let registers = SimulatedRegisters::new().supply_voltage(*supply_voltage);
+            let config = ClockConfig::new(ClockSource::HseThroughPll)
+                .system_clock(clock_source_selecting::SYS_CLOCK_MAX_SPEED)
+                .supply_voltage(*supply_voltage);
+
+            RccClocks::try_setup_system_clock(&registers, config).unwrap();
+            assert!(registers.get_flash_latency_violations().is_empty());
+
+            // Lowering it while running on the fast clock is caught
+            FlashAccessControlRegister::set_flash_latency(
+                &registers,
+                FlashReadLatency::ZeroWaitState1CpuCycle,
+            );
+            assert_eq!(
+                registers.get_flash_latency_violations(),
+                vec![FlashLatencyViolation {
+                    cpu_clock: clock_source_selecting::SYS_CLOCK_MAX_SPEED,
+                    flash_latency: FlashReadLatency::ZeroWaitState1CpuCycle,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn simulated_slow_hse_reports_the_timeout() {
+        let registers = SimulatedRegisters::new().hse_startup_cycles(1000);
+        let config = ClockConfig::new(ClockSource::Hse).max_wait_cycles(100);
+
+        assert_eq!(
+            RccClocks::try_setup_system_clock(&registers, config).map(|_| ()),
+            Err(ClockConfigError::HseStartupTimeout)
+        );
+        assert_eq!(
+            registers.get_system_clock_frequency(),
+            clock_source_selecting::HSI_FREQUENCY
+        );
+    }
+
+    #[test]
+    fn simulated_dead_hse_falls_back_to_hsi_through_pll() {
+        let registers = SimulatedRegisters::new().hse_never_ready();
+        let config = ClockConfig::new(ClockSource::HseThroughPll).max_wait_cycles(1000);
+
+        let rcc_clock = RccClocks::setup_system_clock(&registers, config);
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::HsiThroughPll);
+        assert_eq!(
+            RccClocks::from_hardware(&registers)
+                .unwrap()
+                .get_clock_source(),
+            &ClockSource::HsiThroughPll
+        );
+    }
+
+    #[test]
+    fn simulated_dead_hse_falls_back_to_hsi() {
+        let registers = SimulatedRegisters::new().hse_never_ready();
+        let config = ClockConfig::new(ClockSource::Hse).max_wait_cycles(1000);
+
+        let rcc_clock = RccClocks::setup_system_clock(&registers, config);
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::Hsi);
+        assert_eq!(
+            registers.get_system_clock_frequency(),
+            clock_source_selecting::HSI_FREQUENCY
+        );
+    }
+
+    #[test]
+    fn simulated_pll_never_locks_falls_back_to_hsi() {
+        let registers = SimulatedRegisters::new().pll_never_locks();
+        let config = ClockConfig::new(ClockSource::HsiThroughPll).max_wait_cycles(1000);
+
+        assert_eq!(
+            RccClocks::try_setup_system_clock(&registers, config.clone()).map(|_| ()),
+            Err(ClockConfigError::PllLockTimeout)
+        );
+
+        let rcc_clock = RccClocks::setup_system_clock(&registers, config);
+        assert_eq!(rcc_clock.get_clock_source(), &ClockSource::Hsi);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::clock_utils::RccClocks;
+use crate::data_watchpoint_trace_register::DataWatchpointTrace;
+use crate::register_access::RegisterAccess;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+/// Counts the processor clock cycles by the DWT cycle counter (`DWT_CYCCNT`), it
+/// doesn't need any interrupt and works inside the interrupt handlers.
+///
+/// The 32 bits counter wraps after about 25s at 168Mhz, only measure the code which
+/// takes less than that.
+///
+/// ```
+/// // Before `setup_system_clock()`, the chip runs on HSI
+/// let mut cycle_counter = CycleCounter::start(Mmio, &RccClocks::from_hardware(&Mmio).unwrap());
+/// let (rcc_clocks, cycles) = cycle_counter.measure(|| RccClocks::setup_system_clock(&Mmio, config));
+///
+/// // The cycles after this are converted on the new HCLK
+/// cycle_counter.update_clocks(&rcc_clocks);
+/// ```
+pub struct CycleCounter<R: RegisterAccess> {
+    registers: R,
+    cpu_clock_frequency_in_hertz: u32,
+}
+
+///
+impl<R: RegisterAccess> CycleCounter<R> {
+    /// Enable the trace (`DEMCR` `TRCENA`), then start the cycle counter from `0`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the HCLK frequency in `rcc_clocks` is 0
+    pub fn start(registers: R, rcc_clocks: &RccClocks) -> Self {
+        DataWatchpointTrace::enable_trace(&registers);
+        DataWatchpointTrace::start_cycle_counter(&registers);
+
+        CycleCounter {
+            registers,
+            cpu_clock_frequency_in_hertz: Self::get_nonzero_cpu_clock(rcc_clocks),
+        }
+    }
+
+    /// Call it after the system clock is changed, the cycles are converted to the
+    /// microseconds on HCLK
+    ///
+    /// # Panics
+    ///
+    /// Panics if the HCLK frequency in `rcc_clocks` is 0
+    pub fn update_clocks(&mut self, rcc_clocks: &RccClocks) {
+        self.cpu_clock_frequency_in_hertz = Self::get_nonzero_cpu_clock(rcc_clocks);
+    }
+
+    /// `cycles_to_microseconds()` divides by it
+    fn get_nonzero_cpu_clock(rcc_clocks: &RccClocks) -> u32 {
+        let cpu_clock_frequency_in_hertz = rcc_clocks.get_cpu_clock_frequency_in_hertz();
+        assert!(cpu_clock_frequency_in_hertz > 0, "HCLK can't be 0Hz");
+
+        cpu_clock_frequency_in_hertz
+    }
+
+    ///
+    pub fn get_cpu_clock_frequency_in_hertz(&self) -> u32 {
+        self.cpu_clock_frequency_in_hertz
+    }
+
+    /// The current `DWT_CYCCNT` value
+    pub fn get_cycles(&self) -> u32 {
+        DataWatchpointTrace::get_cycles(&self.registers)
+    }
+
+    /// The cycles since `start_cycles`, correct across one counter wrap
+    pub fn get_elapsed_cycles(&self, start_cycles: u32) -> u32 {
+        self.get_cycles().wrapping_sub(start_cycles)
+    }
+
+    ///
+    pub fn cycles_to_microseconds(&self, cycles: u64) -> u64 {
+        cycles * 1_000_000 / self.cpu_clock_frequency_in_hertz as u64
+    }
+
+    /// Run `f` and return its result with the cycles it takes
+    pub fn measure<T, F: FnOnce() -> T>(&self, f: F) -> (T, u32) {
+        let start_cycles = self.get_cycles();
+        let result = f();
+
+        (result, self.get_elapsed_cycles(start_cycles))
+    }
+
+    /// Run `f` and record the cycles it takes into the probe `name` of `profiler`
+    pub fn measure_probe<T, F: FnOnce() -> T, const CAPACITY: usize>(
+        &self,
+        profiler: &mut Profiler<CAPACITY>,
+        name: &'static str,
+        f: F,
+    ) -> T {
+        let (result, cycles) = self.measure(f);
+        profiler.record(name, cycles);
+
+        result
+    }
+}
+
+/// The min/max/average cycles of one measured code section
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+    name: &'static str,
+    count: u32,
+    min_cycles: u32,
+    max_cycles: u32,
+    total_cycles: u64,
+}
+
+///
+impl Probe {
+    ///
+    pub const fn new(name: &'static str) -> Self {
+        Probe {
+            name,
+            count: 0,
+            min_cycles: u32::MAX,
+            max_cycles: 0,
+            total_cycles: 0,
+        }
+    }
+
+    ///
+    pub fn record(&mut self, cycles: u32) {
+        self.count = self.count.saturating_add(1);
+        self.min_cycles = self.min_cycles.min(cycles);
+        self.max_cycles = self.max_cycles.max(cycles);
+        self.total_cycles = self.total_cycles.saturating_add(cycles as u64);
+    }
+
+    ///
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    /// How many times it's measured
+    pub fn get_count(&self) -> u32 {
+        self.count
+    }
+
+    /// `None` before the first measurement
+    pub fn get_min_cycles(&self) -> Option<u32> {
+        Some(self.min_cycles).filter(|_| self.count > 0)
+    }
+
+    /// `None` before the first measurement
+    pub fn get_max_cycles(&self) -> Option<u32> {
+        Some(self.max_cycles).filter(|_| self.count > 0)
+    }
+
+    /// `None` before the first measurement
+    pub fn get_average_cycles(&self) -> Option<u32> {
+        self.total_cycles
+            .checked_div(self.count as u64)
+            .map(|average| average as u32)
+    }
+}
+
+/// Up to `CAPACITY` named probes, no heap. A probe is added by the first measurement
+/// with its name, the measurements of a new name are dropped (and counted) when all
+/// the probes are in use.
+///
+/// Put it in a `Mutex<RefCell<..>>` to measure the interrupt handlers.
+pub struct Profiler<const CAPACITY: usize> {
+    probes: [Option<Probe>; CAPACITY],
+    dropped_measurements: u32,
+}
+
+///
+impl<const CAPACITY: usize> Profiler<CAPACITY> {
+    ///
+    pub const fn new() -> Self {
+        Profiler {
+            probes: [None; CAPACITY],
+            dropped_measurements: 0,
+        }
+    }
+
+    ///
+    pub fn record(&mut self, name: &'static str, cycles: u32) {
+        let slot = match self.probes.iter().position(|slot| match slot {
+            Some(probe) => probe.name == name,
+            None => true,
+        }) {
+            Some(index) => &mut self.probes[index],
+            None => {
+                self.dropped_measurements = self.dropped_measurements.saturating_add(1);
+                return;
+            }
+        };
+
+        slot.get_or_insert(Probe::new(name)).record(cycles);
+    }
+
+    ///
+    pub fn get_probe(&self, name: &str) -> Option<&Probe> {
+        self.get_probes().find(|probe| probe.name == name)
+    }
+
+    /// In the order of the first measurement
+    pub fn get_probes(&self) -> impl Iterator<Item = &Probe> {
+        self.probes.iter().flatten()
+    }
+
+    /// The measurements which didn't have a free probe
+    pub fn get_dropped_measurements(&self) -> u32 {
+        self.dropped_measurements
+    }
+
+    /// Remove all the probes
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The cycles and the microseconds (on the current HCLK of `cycle_counter`) of
+    /// every probe
+    #[cfg(feature = "enable-debug")]
+    pub fn print_statistics<R: RegisterAccess>(&self, cycle_counter: &CycleCounter<R>) {
+        let _ = hprintln!(
+            "\n[ Cycle counter statistics, HCLK: {}Hz ]",
+            cycle_counter.get_cpu_clock_frequency_in_hertz()
+        );
+
+        for probe in self.get_probes() {
+            let min_cycles = probe.get_min_cycles().unwrap_or(0);
+            let max_cycles = probe.get_max_cycles().unwrap_or(0);
+            let average_cycles = probe.get_average_cycles().unwrap_or(0);
+
+            let _ = hprintln!(
+                "{}: count: {}, min: {} cycles ({}us), max: {} cycles ({}us), avg: {} cycles ({}us)",
+                probe.get_name(),
+                probe.get_count(),
+                min_cycles,
+                cycle_counter.cycles_to_microseconds(min_cycles as u64),
+                max_cycles,
+                cycle_counter.cycles_to_microseconds(max_cycles as u64),
+                average_cycles,
+                cycle_counter.cycles_to_microseconds(average_cycles as u64)
+            );
+        }
+
+        if self.dropped_measurements > 0 {
+            let _ = hprintln!(
+                "dropped measurements (no free probe): {}",
+                self.dropped_measurements
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock_utils::{ClockConfig, ClockSource};
+    use crate::data_watchpoint_trace_register::{DemcrValue, DwtCtrlValue, DEMCR, DWT_CYCCNT};
+    use crate::register_access::RecordingRegisters;
+    use crate::simulated_registers::SimulatedRegisters;
+
+    #[test]
+    fn start_enables_trace_and_measures_across_the_wrap() {
+        let rcc_clocks = RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::HseThroughPll),
+        );
+        let registers = RecordingRegisters::new();
+        // Vector catch bits which the debugger set
+        registers.write(DEMCR, 0x0000_07F1);
+        registers.write(DWT_CYCCNT, 1234);
+
+        let cycle_counter = CycleCounter::start(&registers, &rcc_clocks);
+        assert_eq!(
+            registers.get_value(DEMCR),
+            0x0000_07F1 | DemcrValue::TRCENA.mask()
+        );
+        assert!(DwtCtrlValue::read(&registers).cycle_counter_enabled());
+        assert_eq!(cycle_counter.get_cycles(), 0);
+
+        registers.write(DWT_CYCCNT, u32::MAX - 99);
+        let ((), cycles) = cycle_counter.measure(|| registers.write(DWT_CYCCNT, 400));
+        assert_eq!(cycles, 500);
+
+        let hclk = cycle_counter.get_cpu_clock_frequency_in_hertz() as u64;
+        assert_eq!(cycle_counter.cycles_to_microseconds(hclk / 1000), 1000);
+    }
+
+    #[test]
+    fn converts_on_the_exact_hclk() {
+        let registers = RecordingRegisters::new();
+
+        // HSI / 64 is 250kHz
+        let mut cycle_counter = CycleCounter::start(
+            &registers,
+            &RccClocks::setup_system_clock(
+                &SimulatedRegisters::new(),
+                ClockConfig::new(ClockSource::Hsi).ahb_prescaler(64),
+            ),
+        );
+        assert_eq!(cycle_counter.cycles_to_microseconds(250), 1000);
+
+        // 25MHz HSE / 2 is 12.5MHz
+        cycle_counter.update_clocks(&RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::Hse)
+                .hse_frequency(25_000_000)
+                .ahb_prescaler(2),
+        ));
+        assert_eq!(cycle_counter.cycles_to_microseconds(12_500_000), 1_000_000);
+    }
+
+    #[test]
+    fn probe_statistics_by_name() {
+        let mut profiler = Profiler::<2>::new();
+        assert_eq!(profiler.get_probe("setup_system_clock"), None);
+
+        for cycles in [300, 100, 200] {
+            profiler.record("setup_system_clock", cycles);
+        }
+        profiler.record("SysTick", 42);
+        profiler.record("EXTI0", 7);
+
+        let probe = profiler.get_probe("setup_system_clock").unwrap();
+        assert_eq!(probe.get_count(), 3);
+        assert_eq!(probe.get_min_cycles(), Some(100));
+        assert_eq!(probe.get_max_cycles(), Some(300));
+        assert_eq!(probe.get_average_cycles(), Some(200));
+
+        assert_eq!(
+            profiler
+                .get_probes()
+                .map(|probe| probe.get_name())
+                .collect::<Vec<_>>(),
+            vec!["setup_system_clock", "SysTick"]
+        );
+        assert_eq!(profiler.get_dropped_measurements(), 1);
+
+        assert_eq!(Probe::new("unused").get_average_cycles(), None);
+        profiler.reset();
+        assert_eq!(profiler.get_probes().count(), 0);
+    }
+}
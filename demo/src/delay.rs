@@ -0,0 +1,248 @@
+use crate::clock_utils::RccClocks;
+use crate::register_access::RegisterAccess;
+use crate::system_tick_timer_register::{StkCtrlValue, SystemTickTimer, MAX_RELOAD_VALUE};
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+
+/// `STK_LOAD` only has 24 bits, one countdown takes `MAX_RELOAD_VALUE + 1` ticks at most
+pub const MAX_COUNTDOWN_TICKS: u64 = MAX_RELOAD_VALUE as u64 + 1;
+
+/// `STK_LOAD = 0` never counts to zero, one countdown takes 2 ticks at least
+const MIN_COUNTDOWN_TICKS: u64 = 2;
+
+/// A blocking delay which counts the SysTick ticks on the AHB clock (HCLK), so it's
+/// accurate at any HCLK.
+///
+/// It takes the SysTick timer (no exception), don't use it together with `Monotonic`.
+///
+/// ```
+/// let rcc_clocks = RccClocks::setup_system_clock(&Mmio, ClockConfig::new(ClockSource::HseThroughPll));
+/// let mut delay = Delay::new(Mmio, &rcc_clocks);
+/// delay.delay_ms(1000u32);
+/// ```
+pub struct Delay<R: RegisterAccess> {
+    registers: R,
+    cpu_clock_frequency_in_hertz: u32,
+}
+
+///
+impl<R: RegisterAccess> Delay<R> {
+    /// # Panics
+    ///
+    /// Panics if the HCLK frequency in `rcc_clocks` is 0, no delay would wait at all.
+    pub fn new(registers: R, rcc_clocks: &RccClocks) -> Self {
+        let cpu_clock_frequency_in_hertz = rcc_clocks.get_cpu_clock_frequency_in_hertz();
+        assert!(cpu_clock_frequency_in_hertz > 0, "HCLK can't be 0Hz");
+
+        Delay {
+            registers,
+            cpu_clock_frequency_in_hertz,
+        }
+    }
+
+    ///
+    pub fn get_cpu_clock_frequency_in_hertz(&self) -> u32 {
+        self.cpu_clock_frequency_in_hertz
+    }
+
+    ///
+    pub fn delay_us(&self, microseconds: u32) {
+        self.delay_ticks(
+            microseconds as u64 * self.cpu_clock_frequency_in_hertz as u64 / 1_000_000,
+        );
+    }
+
+    ///
+    pub fn delay_ms(&self, milliseconds: u32) {
+        self.delay_ticks(milliseconds as u64 * self.cpu_clock_frequency_in_hertz as u64 / 1000);
+    }
+
+    /// A long delay is split into the countdowns which fit into the 24 bits
+    /// `STK_LOAD`, without a 1 tick countdown at the end. A 1 tick delay takes 2
+    /// ticks.
+    fn delay_ticks(&self, ticks: u64) {
+        let mut remaining_ticks = ticks;
+
+        while remaining_ticks > 0 {
+            let mut countdown_ticks = remaining_ticks
+                .min(MAX_COUNTDOWN_TICKS)
+                .max(MIN_COUNTDOWN_TICKS);
+            if remaining_ticks == countdown_ticks + 1 {
+                countdown_ticks -= 1;
+            }
+
+            self.countdown(countdown_ticks as u32);
+            remaining_ticks = remaining_ticks.saturating_sub(countdown_ticks);
+        }
+    }
+
+    /// Count down from `ticks - 1` to 0, page 246
+    fn countdown(&self, ticks: u32) {
+        SystemTickTimer::disable(&self.registers);
+        SystemTickTimer::set_reload_value(&self.registers, ticks - 1);
+        SystemTickTimer::reset(&self.registers);
+
+        StkCtrlValue::modify(&self.registers, |r| {
+            r.set_use_cpu_clock(true)
+                .set_exception_enabled(false)
+                .set_enabled(true)
+        });
+
+        while !SystemTickTimer::is_counted_to_zero(&self.registers) {}
+
+        SystemTickTimer::disable(&self.registers);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayUs<u32> for Delay<R> {
+    fn delay_us(&mut self, microseconds: u32) {
+        Delay::delay_us(self, microseconds);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayUs<u16> for Delay<R> {
+    fn delay_us(&mut self, microseconds: u16) {
+        Delay::delay_us(self, microseconds as u32);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayUs<u8> for Delay<R> {
+    fn delay_us(&mut self, microseconds: u8) {
+        Delay::delay_us(self, microseconds as u32);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayMs<u32> for Delay<R> {
+    fn delay_ms(&mut self, milliseconds: u32) {
+        Delay::delay_ms(self, milliseconds);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayMs<u16> for Delay<R> {
+    fn delay_ms(&mut self, milliseconds: u16) {
+        Delay::delay_ms(self, milliseconds as u32);
+    }
+}
+
+///
+impl<R: RegisterAccess> DelayMs<u8> for Delay<R> {
+    fn delay_ms(&mut self, milliseconds: u8) {
+        Delay::delay_ms(self, milliseconds as u32);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock_utils::{ClockConfig, ClockSource};
+    use crate::register_access::RecordingRegisters;
+    use crate::simulated_registers::SimulatedRegisters;
+    use crate::system_tick_timer_register::{STK_CTRL, STK_LOAD};
+
+    fn get_reload_writes(registers: &RecordingRegisters) -> Vec<u32> {
+        registers
+            .get_writes()
+            .iter()
+            .filter(|(address, _)| *address == STK_LOAD)
+            .map(|(_, value)| *value)
+            .collect()
+    }
+
+    fn blink<D: DelayMs<u32>>(delay: &mut D) {
+        delay.delay_ms(500);
+    }
+
+    #[test]
+    fn long_delay_is_split_into_24_bits_countdowns() {
+        let rcc_clocks = RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::HseThroughPll),
+        );
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+
+        let delay = Delay::new(&registers, &rcc_clocks);
+        let hclk = delay.get_cpu_clock_frequency_in_hertz() as u64;
+
+        delay.delay_us(10);
+        assert_eq!(
+            get_reload_writes(&registers),
+            vec![(hclk / 100_000) as u32 - 1]
+        );
+
+        // 1s is more than 24 bits at any HCLK above 16.7MHz
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        let mut delay = Delay::new(&registers, &rcc_clocks);
+        blink(&mut delay);
+        blink(&mut delay);
+
+        let reloads = get_reload_writes(&registers);
+        assert!(reloads.len() > 2);
+        let total_ticks: u64 = reloads.iter().map(|reload| *reload as u64 + 1).sum();
+        assert_eq!(total_ticks, hclk);
+        assert!(reloads
+            .iter()
+            .all(|reload| *reload as u64 + 1 <= MAX_COUNTDOWN_TICKS));
+        assert!(!StkCtrlValue::read(&registers).enabled());
+        assert!(!StkCtrlValue::read(&registers).exception_enabled());
+    }
+
+    #[test]
+    fn never_counts_down_from_zero() {
+        // 1MHz HCLK, 1 tick every microsecond
+        let rcc_clocks = RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::Hsi).ahb_prescaler(16),
+        );
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        let delay = Delay::new(&registers, &rcc_clocks);
+        assert_eq!(delay.get_cpu_clock_frequency_in_hertz(), 1_000_000);
+
+        delay.delay_us(1);
+        assert_eq!(get_reload_writes(&registers), vec![1]);
+
+        // 1 tick more than a full countdown
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        let delay = Delay::new(&registers, &rcc_clocks);
+        delay.delay_us(MAX_COUNTDOWN_TICKS as u32 + 1);
+        assert_eq!(get_reload_writes(&registers), vec![MAX_RELOAD_VALUE - 1, 1]);
+    }
+
+    #[test]
+    fn counts_below_and_between_whole_megahertz() {
+        // HSI / 64 is 250kHz, 1 tick every 4 microseconds
+        let rcc_clocks = RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::Hsi).ahb_prescaler(64),
+        );
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        let delay = Delay::new(&registers, &rcc_clocks);
+        assert_eq!(delay.get_cpu_clock_frequency_in_hertz(), 250_000);
+
+        delay.delay_ms(1);
+        assert_eq!(get_reload_writes(&registers), vec![249]);
+
+        // 25MHz HSE / 2 is 12.5MHz
+        let rcc_clocks = RccClocks::setup_system_clock(
+            &SimulatedRegisters::new(),
+            ClockConfig::new(ClockSource::Hse)
+                .hse_frequency(25_000_000)
+                .ahb_prescaler(2),
+        );
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        let delay = Delay::new(&registers, &rcc_clocks);
+        assert_eq!(delay.get_cpu_clock_frequency_in_hertz(), 12_500_000);
+
+        delay.delay_us(10);
+        assert_eq!(get_reload_writes(&registers), vec![124]);
+    }
+}
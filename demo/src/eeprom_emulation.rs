@@ -0,0 +1,661 @@
+use crate::flash_control_register::FlashParallelism;
+use crate::flash_utils::{FlashDriver, FlashError, FlashSector};
+use crate::register_access::RegisterAccess;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ Emulated EEPROM (ST AN3969 page swap) ---------------
+//
+// Two flash sectors are used as 2 pages, only one page is `VALID` at any time. A
+// page starts with the 32bit page status, then the 8 bytes records are appended one
+// by one:
+//
+// | key (16bit) | CRC16 of key + value (16bit) | value (32bit) |
+//
+// Writing a key never erases anything, the new record is just appended after the
+// last one, reading a key returns the value of its last valid record. When the
+// `VALID` page is full, the latest value of every key is copied to the other page
+// (page transfer), then the old page is erased. That's how the erase cycles are
+// spread over the whole sector instead of hitting the same flash word again and
+// again.
+//
+// Power loss safety:
+//
+// - the value word is programmed before the key/CRC word, a record which is torn
+//   by a power cut doesn't pass the CRC check, and its slot is never reused.
+// - the page status only moves forward `ERASED` -> `RECEIVE_DATA` -> `VALID`, every
+//   step only clears bits, so a torn status write still tells which step it was in.
+//   `EmulatedEeprom::new()` finishes the interrupted page transfer on the next boot.
+
+/// Page status: erased and ready to become the next `VALID` page
+pub const EEPROM_PAGE_ERASED: u32 = 0xFFFF_FFFF;
+/// Page status: page transfer in progress, the records are copied into this page
+pub const EEPROM_PAGE_RECEIVE_DATA: u32 = 0xEEEE_EEEE;
+/// Page status: the page which holds the current records
+pub const EEPROM_PAGE_VALID: u32 = 0x0000_0000;
+
+/// The page status takes the first word of the page
+const PAGE_HEADER_SIZE: u32 = 4;
+/// Key (16bit) + CRC16 (16bit) + value (32bit)
+const RECORD_SIZE: u32 = 8;
+/// The key which an erased record has
+const ERASED_KEY: u16 = 0xFFFF;
+
+/// The last 2 sectors are used by default, they are far away from the program code
+#[cfg(feature = "use-stm32f407g-disc1")]
+pub const BOARD_EEPROM_SECTORS: [u8; 2] = [10, 11];
+
+#[cfg(feature = "use-weact-black-pill")]
+pub const BOARD_EEPROM_SECTORS: [u8; 2] = [6, 7];
+
+/// Why an emulated EEPROM operation failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum EepromError {
+    Flash(FlashError),
+    WrongSector(u8),
+    /// `0xFFFF` is what an erased record reads, it can't be a key
+    InvalidKey(u16),
+    /// `x64` programs 2 words at once, the page status and the records can't be
+    /// programmed word by word
+    UnsupportedParallelism(FlashParallelism),
+    /// Too many keys to fit in one page
+    Full,
+}
+
+/// From `FlashError` to `EepromError`
+impl From<FlashError> for EepromError {
+    fn from(error: FlashError) -> Self {
+        EepromError::Flash(error)
+    }
+}
+
+/// The decoded page status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EepromPageStatus {
+    Erased,
+    ReceiveData,
+    Valid,
+    Invalid,
+}
+
+///
+impl EepromPageStatus {
+    /// A torn status write leaves the bits between 2 status values, both the torn
+    /// `ERASED` -> `RECEIVE_DATA` and the torn `RECEIVE_DATA` -> `VALID` are treated
+    /// as `ReceiveData`, as the page transfer wasn't finished yet.
+    pub fn from_status_word(status: u32) -> Self {
+        match status {
+            EEPROM_PAGE_ERASED => Self::Erased,
+            EEPROM_PAGE_VALID => Self::Valid,
+            _ if status & EEPROM_PAGE_RECEIVE_DATA == EEPROM_PAGE_RECEIVE_DATA => Self::ReceiveData,
+            _ if status & !EEPROM_PAGE_RECEIVE_DATA == 0 => Self::ReceiveData,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+/// A key/value store which keeps `u32` values under `u16` keys in 2 flash sectors.
+///
+/// ```
+/// let flash = FlashDriver::new(Mmio);
+/// let mut eeprom = EmulatedEeprom::new(flash, BOARD_EEPROM_SECTORS)?;
+/// let boot_count = eeprom.read(BOOT_COUNT_KEY).unwrap_or(0);
+/// eeprom.write(BOOT_COUNT_KEY, boot_count + 1)?;
+/// ```
+pub struct EmulatedEeprom<R: RegisterAccess> {
+    flash: FlashDriver<R>,
+    pages: [FlashSector; 2],
+    valid_page: usize,
+    next_record_offset: u32,
+}
+
+///
+impl<R: RegisterAccess> EmulatedEeprom<R> {
+    /// Use the 2 sectors as pages, finish the page transfer which was interrupted by
+    /// a power cut, or format the pages if none of them is valid.
+    pub fn new(flash: FlashDriver<R>, sector_numbers: [u8; 2]) -> Result<Self, EepromError> {
+        if flash.get_parallelism() == FlashParallelism::X64 {
+            return Err(EepromError::UnsupportedParallelism(FlashParallelism::X64));
+        }
+
+        let layout = flash.get_layout();
+        let get_sector = |number| {
+            layout
+                .get_sector(number)
+                .ok_or(EepromError::WrongSector(number))
+        };
+        let pages = [
+            get_sector(sector_numbers[0])?,
+            get_sector(sector_numbers[1])?,
+        ];
+
+        let mut eeprom = EmulatedEeprom {
+            flash,
+            pages,
+            valid_page: 0,
+            next_record_offset: PAGE_HEADER_SIZE,
+        };
+
+        eeprom.flash.unlock()?;
+        let result = eeprom.recover();
+        eeprom.flash.lock();
+
+        #[cfg(feature = "enable-debug")]
+        let _ = hprintln!(
+            "Emulated EEPROM in sector {}, {:?}",
+            eeprom.get_valid_sector().number,
+            result
+        );
+
+        result.map(|_| eeprom)
+    }
+
+    /// The last value which was written to `key`
+    pub fn read(&self, key: u16) -> Option<u32> {
+        self.find(self.valid_page, key, self.next_record_offset)
+    }
+
+    /// Append a new record, nothing will be written if the value doesn't change
+    pub fn write(&mut self, key: u16, value: u32) -> Result<(), EepromError> {
+        if key == ERASED_KEY {
+            return Err(EepromError::InvalidKey(key));
+        }
+
+        if self.read(key) == Some(value) {
+            return Ok(());
+        }
+
+        self.flash.unlock()?;
+        let result = if self.next_record_offset + RECORD_SIZE <= self.pages[self.valid_page].size {
+            self.append(key, value)
+        } else {
+            self.transfer_page(key, value)
+        };
+        self.flash.lock();
+
+        result
+    }
+
+    /// Erase both pages, all the keys are gone
+    pub fn format(&mut self) -> Result<(), EepromError> {
+        self.flash.unlock()?;
+        let result = self.format_pages();
+        self.flash.lock();
+
+        result
+    }
+
+    /// The sector which holds the current records
+    pub fn get_valid_sector(&self) -> FlashSector {
+        self.pages[self.valid_page]
+    }
+
+    /// How many more records fit into the valid page before the next page transfer
+    pub fn get_free_records(&self) -> u32 {
+        (self.pages[self.valid_page].size - self.next_record_offset) / RECORD_SIZE
+    }
+
+    fn recover(&mut self) -> Result<(), EepromError> {
+        let statuses = [self.get_page_status(0), self.get_page_status(1)];
+
+        match statuses {
+            [EepromPageStatus::Valid, EepromPageStatus::Valid] => self.format_pages(),
+            [EepromPageStatus::Valid, _] => self.use_page(0),
+            [_, EepromPageStatus::Valid] => self.use_page(1),
+            [EepromPageStatus::ReceiveData, EepromPageStatus::ReceiveData] => self.format_pages(),
+            // The old page was erased after the records were copied, only the
+            // status is left
+            [EepromPageStatus::ReceiveData, _] => self.mark_valid(0),
+            [_, EepromPageStatus::ReceiveData] => self.mark_valid(1),
+            _ => self.format_pages(),
+        }?;
+
+        // The records were being copied from the valid page
+        let receiving_page = 1 - self.valid_page;
+        if self.get_page_status(receiving_page) == EepromPageStatus::ReceiveData {
+            let old_page = self.valid_page;
+            self.use_page(receiving_page)?;
+            self.copy_records(old_page, None)?;
+            self.erase_page(old_page)?;
+            self.mark_valid(receiving_page)?;
+        }
+
+        Ok(())
+    }
+
+    /// The records in this page must be kept, find the next free record from it
+    fn use_page(&mut self, page: usize) -> Result<(), EepromError> {
+        self.valid_page = page;
+        self.next_record_offset = PAGE_HEADER_SIZE;
+
+        while self.next_record_offset + RECORD_SIZE <= self.pages[page].size {
+            let address = self.pages[page].start_address + self.next_record_offset;
+
+            // A torn record is not erased, its slot can't be programmed again
+            if self.read_word(address) == 0xFFFF_FFFF && self.read_word(address + 4) == 0xFFFF_FFFF
+            {
+                break;
+            }
+
+            self.next_record_offset += RECORD_SIZE;
+        }
+
+        Ok(())
+    }
+
+    fn mark_valid(&mut self, page: usize) -> Result<(), EepromError> {
+        self.program_word(self.pages[page].start_address, EEPROM_PAGE_VALID)?;
+        self.use_page(page)
+    }
+
+    fn format_pages(&mut self) -> Result<(), EepromError> {
+        self.erase_page(0)?;
+        self.erase_page(1)?;
+        self.mark_valid(0)
+    }
+
+    fn append(&mut self, key: u16, value: u32) -> Result<(), EepromError> {
+        let address = self.pages[self.valid_page].start_address + self.next_record_offset;
+
+        // The slot is used even if the power is cut in the middle
+        self.next_record_offset += RECORD_SIZE;
+
+        // The value first, then the key and CRC which make the record valid
+        self.program_word(address + 4, value)?;
+        self.program_word(address, record_header(key, value))?;
+
+        Ok(())
+    }
+
+    /// Write the new value as the first record of the other page, then copy the
+    /// latest value of every other key there, erase the full page at the end
+    fn transfer_page(&mut self, key: u16, value: u32) -> Result<(), EepromError> {
+        let old_page = self.valid_page;
+        let new_page = 1 - old_page;
+
+        #[cfg(feature = "enable-debug")]
+        let _ = hprintln!(
+            "Emulated EEPROM page transfer: sector {} -> {}",
+            self.pages[old_page].number,
+            self.pages[new_page].number
+        );
+
+        if !self.is_page_erased(new_page) {
+            self.erase_page(new_page)?;
+        }
+
+        self.program_word(self.pages[new_page].start_address, EEPROM_PAGE_RECEIVE_DATA)?;
+        self.valid_page = new_page;
+        self.next_record_offset = PAGE_HEADER_SIZE;
+        self.append(key, value)?;
+
+        self.copy_records(old_page, Some(key))?;
+        self.erase_page(old_page)?;
+        self.mark_valid(new_page)
+    }
+
+    /// Copy the latest value of every key in `from_page` which the valid page doesn't
+    /// have yet
+    fn copy_records(&mut self, from_page: usize, skip_key: Option<u16>) -> Result<(), EepromError> {
+        let from_end = self.get_end_offset(from_page);
+        let mut offset = PAGE_HEADER_SIZE;
+
+        while offset + RECORD_SIZE <= from_end {
+            let address = self.pages[from_page].start_address + offset;
+            offset += RECORD_SIZE;
+
+            let key = match self.read_record(address) {
+                Some((key, _)) => key,
+                None => continue,
+            };
+
+            if Some(key) == skip_key
+                || self
+                    .find(self.valid_page, key, self.next_record_offset)
+                    .is_some()
+            {
+                continue;
+            }
+
+            if let Some(value) = self.find(from_page, key, from_end) {
+                if self.next_record_offset + RECORD_SIZE > self.pages[self.valid_page].size {
+                    return Err(EepromError::Full);
+                }
+
+                self.append(key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The value of the last valid record of `key` before `end_offset`
+    fn find(&self, page: usize, key: u16, end_offset: u32) -> Option<u32> {
+        let mut offset = end_offset;
+
+        while offset >= PAGE_HEADER_SIZE + RECORD_SIZE {
+            offset -= RECORD_SIZE;
+
+            match self.read_record(self.pages[page].start_address + offset) {
+                Some((record_key, value)) if record_key == key => return Some(value),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// `None` for an erased or torn record
+    fn read_record(&self, address: u32) -> Option<(u16, u32)> {
+        let header = self.read_word(address);
+        let key = header as u16;
+        let value = self.read_word(address + 4);
+
+        if key != ERASED_KEY && header == record_header(key, value) {
+            Some((key, value))
+        } else {
+            None
+        }
+    }
+
+    /// The offset right after the last record slot, the records don't fill the page
+    /// to the last byte
+    fn get_end_offset(&self, page: usize) -> u32 {
+        let records = (self.pages[page].size - PAGE_HEADER_SIZE) / RECORD_SIZE;
+        PAGE_HEADER_SIZE + records * RECORD_SIZE
+    }
+
+    fn get_page_status(&self, page: usize) -> EepromPageStatus {
+        EepromPageStatus::from_status_word(self.read_word(self.pages[page].start_address))
+    }
+
+    fn is_page_erased(&self, page: usize) -> bool {
+        let sector = self.pages[page];
+
+        (sector.start_address..sector.get_end_address())
+            .step_by(4)
+            .all(|address| self.read_word(address) == 0xFFFF_FFFF)
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), EepromError> {
+        Ok(self.flash.erase_sector(self.pages[page].number)?)
+    }
+
+    fn program_word(&self, address: u32, value: u32) -> Result<(), EepromError> {
+        Ok(self.flash.program(address, &value.to_le_bytes())?)
+    }
+
+    fn read_word(&self, address: u32) -> u32 {
+        let mut bytes = [0; 4];
+        self.flash.read(address, &mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Key in the low half word, CRC16 of key and value in the high half word
+fn record_header(key: u16, value: u32) -> u32 {
+    let key_bytes = key.to_le_bytes();
+    let value_bytes = value.to_le_bytes();
+    let crc = crc16(&[
+        key_bytes[0],
+        key_bytes[1],
+        value_bytes[0],
+        value_bytes[1],
+        value_bytes[2],
+        value_bytes[3],
+    ]);
+
+    (crc as u32) << 16 | key as u32
+}
+
+/// CRC-16/CCITT-FALSE, polynomial `0x1021`, initial value `0xFFFF`
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flash_utils::FlashLayout;
+    use crate::simulated_flash::SimulatedFlash;
+
+    // The 16KB sectors, 2047 records per page
+    const PAGES: [u8; 2] = [1, 2];
+    const SECTOR_1: u32 = 0x0800_4000;
+    const SECTOR_2: u32 = 0x0800_8000;
+
+    fn open(flash: &SimulatedFlash) -> Result<EmulatedEeprom<&SimulatedFlash>, EepromError> {
+        let driver = FlashDriver::new(flash)
+            .layout(FlashLayout::Stm32f407)
+            .max_wait_cycles(10_000);
+        EmulatedEeprom::new(driver, PAGES)
+    }
+
+    fn new_flash() -> SimulatedFlash {
+        SimulatedFlash::new(FlashLayout::Stm32f407)
+            .erase_cycles(10)
+            .program_cycles(1)
+    }
+
+    /// A `VALID` page with the records of `key`, the values are `0`, `1`, `2`...
+    fn fill_page(flash: &SimulatedFlash, page_address: u32, key: u16, records: u32) {
+        flash.set_bytes(page_address, &EEPROM_PAGE_VALID.to_le_bytes());
+
+        for value in 0..records {
+            let address = page_address + PAGE_HEADER_SIZE + value * RECORD_SIZE;
+            flash.set_bytes(address, &record_header(key, value).to_le_bytes());
+            flash.set_bytes(address + 4, &value.to_le_bytes());
+        }
+    }
+
+    fn read_word(flash: &SimulatedFlash, address: u32) -> u32 {
+        let bytes = flash.get_bytes(address, 4);
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn torn_page_status() {
+        assert_eq!(
+            EepromPageStatus::from_status_word(0xFFFF_EEEE),
+            EepromPageStatus::ReceiveData
+        );
+        assert_eq!(
+            EepromPageStatus::from_status_word(0x0000_EEEE),
+            EepromPageStatus::ReceiveData
+        );
+        assert_eq!(
+            EepromPageStatus::from_status_word(0x1234_5678),
+            EepromPageStatus::Invalid
+        );
+    }
+
+    #[test]
+    fn blank_flash_is_formatted_and_keeps_the_values() {
+        let flash = new_flash();
+        flash.set_bytes(SECTOR_2, &[0x12, 0x34]);
+
+        let mut eeprom = open(&flash).unwrap();
+        assert_eq!(eeprom.get_valid_sector().number, 1);
+        assert_eq!(read_word(&flash, SECTOR_1), EEPROM_PAGE_VALID);
+        assert_eq!(read_word(&flash, SECTOR_2), EEPROM_PAGE_ERASED);
+        assert_eq!(eeprom.read(1), None);
+
+        eeprom.write(1, 100).unwrap();
+        eeprom.write(2, 200).unwrap();
+        eeprom.write(1, 101).unwrap();
+        assert_eq!(eeprom.get_free_records(), 2044);
+
+        // Same value, nothing is written
+        eeprom.write(2, 200).unwrap();
+        assert_eq!(eeprom.get_free_records(), 2044);
+        assert_eq!(
+            eeprom.write(0xFFFF, 0),
+            Err(EepromError::InvalidKey(0xFFFF))
+        );
+
+        let flash = flash.reboot();
+        let eeprom = open(&flash).unwrap();
+        assert_eq!(eeprom.read(1), Some(101));
+        assert_eq!(eeprom.read(2), Some(200));
+        assert_eq!(eeprom.read(3), None);
+        assert_eq!(eeprom.get_free_records(), 2044);
+    }
+
+    #[test]
+    fn full_page_is_transferred_to_the_other_page() {
+        let flash = new_flash();
+        fill_page(&flash, SECTOR_1, 1, 2046);
+        let mut eeprom = open(&flash).unwrap();
+        assert_eq!(eeprom.read(1), Some(2045));
+
+        eeprom.write(7, 7).unwrap();
+        assert_eq!(eeprom.get_valid_sector().number, 1);
+        assert_eq!(eeprom.get_free_records(), 0);
+
+        eeprom.write(2, 2).unwrap();
+        assert_eq!(eeprom.get_valid_sector().number, 2);
+        assert_eq!(eeprom.get_free_records(), 2044);
+        assert_eq!(read_word(&flash, SECTOR_1), EEPROM_PAGE_ERASED);
+        assert_eq!(read_word(&flash, SECTOR_2), EEPROM_PAGE_VALID);
+        assert_eq!(eeprom.read(1), Some(2045));
+        assert_eq!(eeprom.read(2), Some(2));
+        assert_eq!(eeprom.read(7), Some(7));
+        assert!(FlashDriver::new(&flash).is_locked());
+    }
+
+    #[test]
+    fn torn_record_is_ignored_and_skipped() {
+        let flash = new_flash();
+        let mut eeprom = open(&flash).unwrap();
+        eeprom.write(1, 1).unwrap();
+
+        // The value of the second record is programmed, but not its key and CRC
+        let flash = flash.reboot().power_cut_after_operations(1);
+        let mut eeprom = open(&flash).unwrap();
+        let _ = eeprom.write(1, 2);
+        assert!(flash.is_powered_off());
+
+        let flash = flash.reboot();
+        let mut eeprom = open(&flash).unwrap();
+        assert_eq!(eeprom.read(1), Some(1));
+        assert_eq!(eeprom.get_free_records(), 2045);
+
+        eeprom.write(1, 3).unwrap();
+        assert_eq!(open(&flash.reboot()).unwrap().read(1), Some(3));
+    }
+
+    #[test]
+    fn power_cut_at_any_point_keeps_old_or_new_values() {
+        // The page is one record away from full, so the next write transfers the page
+        let base = new_flash();
+        fill_page(&base, SECTOR_1, 3, 2044);
+        let mut eeprom = open(&base).unwrap();
+        eeprom.write(1, 10).unwrap();
+        eeprom.write(2, 20).unwrap();
+        assert_eq!(eeprom.get_free_records(), 1);
+        let old_3 = 2043;
+
+        let scenario = |eeprom: &mut EmulatedEeprom<&SimulatedFlash>| -> Result<(), EepromError> {
+            eeprom.write(1, 11)?;
+            eeprom.write(2, 21)?;
+            eeprom.write(4, 40)
+        };
+
+        let mut cut_point = 0;
+        loop {
+            let flash = base.reboot().power_cut_after_operations(cut_point);
+            let mut eeprom = open(&flash).unwrap();
+            let _ = scenario(&mut eeprom);
+            let finished = !flash.is_powered_off();
+
+            let flash = flash.reboot();
+            let mut eeprom = open(&flash).unwrap();
+            let value_1 = eeprom.read(1);
+            let value_2 = eeprom.read(2);
+            let value_4 = eeprom.read(4);
+            assert!(
+                value_1 == Some(10) || value_1 == Some(11),
+                "cut point {}: {:?}",
+                cut_point,
+                value_1
+            );
+            assert!(
+                value_2 == Some(20) || value_2 == Some(21),
+                "cut point {}: {:?}",
+                cut_point,
+                value_2
+            );
+            assert!(
+                value_4 == None || value_4 == Some(40),
+                "cut point {}: {:?}",
+                cut_point,
+                value_4
+            );
+            assert_eq!(eeprom.read(3), Some(old_3), "cut point {}", cut_point);
+
+            // Still writable after the recovery
+            scenario(&mut eeprom).unwrap();
+            let flash = flash.reboot();
+            let eeprom = open(&flash).unwrap();
+            assert_eq!(
+                [
+                    eeprom.read(1),
+                    eeprom.read(2),
+                    eeprom.read(3),
+                    eeprom.read(4)
+                ],
+                [Some(11), Some(21), Some(old_3), Some(40)],
+                "cut point {}",
+                cut_point
+            );
+
+            if finished {
+                break;
+            }
+            cut_point += 1;
+        }
+
+        // Record, RECEIVE_DATA status, new record, 2 copied records, erase, VALID
+        // status, record: 13 program and erase operations
+        assert_eq!(cut_point, 13);
+    }
+
+    #[test]
+    fn wrong_sector_and_x64_parallelism_are_refused() {
+        let flash = new_flash();
+        let driver = FlashDriver::new(&flash).layout(FlashLayout::Stm32f407);
+        assert_eq!(
+            EmulatedEeprom::new(driver, [1, 12]).err(),
+            Some(EepromError::WrongSector(12))
+        );
+
+        let driver = FlashDriver::new(&flash)
+            .layout(FlashLayout::Stm32f407)
+            .parallelism(FlashParallelism::X64);
+        assert_eq!(
+            EmulatedEeprom::new(driver, PAGES).err(),
+            Some(EepromError::UnsupportedParallelism(FlashParallelism::X64))
+        );
+        assert_eq!(flash.get_operations(), 0);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::exti_register::{
+    get_irq_lines, get_irq_number, ExternalInterrupt, ExtiEdge, EXTI_GPIO_LINES,
+};
+use crate::gpio_register::{Input, Pin};
+use crate::register_access::RegisterAccess;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(test))]
+use crate::register_access::Mmio;
+#[cfg(not(test))]
+use cortex_m_rt::exception;
+
+/// The `fn(u8)` of every GPIO line, `0` for none
+static CALLBACKS: [AtomicUsize; EXTI_GPIO_LINES as usize] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; EXTI_GPIO_LINES as usize]
+};
+
+/// Every interrupt without its own handler ends up here, with or without the device
+/// vector table of the PAC (`enable-pac`). The EXTI IRQs are dispatched to the
+/// callbacks, the bins which include this module can't have their own
+/// `DefaultHandler`.
+#[cfg(not(test))]
+#[exception]
+fn DefaultHandler(irqn: i16) {
+    if !dispatch(&Mmio, irqn as u16) {
+        panic!("Unhandled interrupt (IRQn = {})", irqn);
+    }
+}
+
+/// Why a line can't be listened to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtiError {
+    /// The line has a callback for the same pin number of another port already
+    LineInUse { line: u8, port: char },
+}
+
+/// Call `callback` with the line number from the interrupt handler when `pin` sees
+/// `edge`. It routes the line to the pin's port, clears the stale pending bit, then
+/// unmasks the line and enables its IRQ.
+///
+/// ```
+/// let button = GpioPort::<'A'>::take(Mmio).unwrap().p0.into_input(GpioPull::None);
+/// exti::listen(&Mmio, &button, ExtiEdge::Rising, on_button_pressed)?;
+/// ```
+pub fn listen<const PORT: char, const N: u8, R: RegisterAccess>(
+    registers: &impl RegisterAccess,
+    _pin: &Pin<PORT, N, Input, R>,
+    edge: ExtiEdge,
+    callback: fn(u8),
+) -> Result<(), ExtiError> {
+    let line = N;
+
+    if has_callback(line) && ExternalInterrupt::get_route(registers, line) != PORT {
+        return Err(ExtiError::LineInUse {
+            line,
+            port: ExternalInterrupt::get_route(registers, line),
+        });
+    }
+
+    ExternalInterrupt::mask(registers, line);
+    CALLBACKS[line as usize].store(callback as usize, Ordering::Release);
+
+    ExternalInterrupt::enable_syscfg_clock(registers);
+    ExternalInterrupt::route(registers, line, PORT);
+    ExternalInterrupt::set_edge(registers, line, edge);
+    ExternalInterrupt::clear_pending(registers, line);
+
+    ExternalInterrupt::unmask(registers, line);
+    ExternalInterrupt::clear_pending_irq(registers, get_irq_number(line));
+    ExternalInterrupt::enable_irq(registers, get_irq_number(line));
+
+    Ok(())
+}
+
+/// Mask the line and drop its callback. A shared IRQ (line 5 ~ 9, 10 ~ 15) is only
+/// disabled when all its lines are masked.
+pub fn unlisten(registers: &impl RegisterAccess, line: u8) {
+    ExternalInterrupt::mask(registers, line);
+    CALLBACKS[line as usize].store(0, Ordering::Release);
+
+    let irq = get_irq_number(line);
+    let all_lines_masked = get_irq_lines(irq)
+        .map(|mut lines| lines.all(|line| ExternalInterrupt::is_masked(registers, line)))
+        .unwrap_or(true);
+
+    if all_lines_masked {
+        ExternalInterrupt::disable_irq(registers, irq);
+    }
+}
+
+/// Handle `irq`: every pending and unmasked line it serves gets its pending bit
+/// cleared, then its callback called. `false` if it's not an EXTI GPIO IRQ.
+pub fn dispatch(registers: &impl RegisterAccess, irq: u16) -> bool {
+    let lines = match get_irq_lines(irq) {
+        Some(lines) => lines,
+        None => return false,
+    };
+
+    for line in lines {
+        if ExternalInterrupt::is_masked(registers, line)
+            || !ExternalInterrupt::is_pending(registers, line)
+        {
+            continue;
+        }
+
+        // Clear first, so an edge during the callback raises the IRQ again
+        ExternalInterrupt::clear_pending(registers, line);
+        call_callback(line);
+    }
+
+    true
+}
+
+fn has_callback(line: u8) -> bool {
+    CALLBACKS[line as usize].load(Ordering::Acquire) != 0
+}
+
+fn call_callback(line: u8) {
+    let callback = CALLBACKS[line as usize].load(Ordering::Acquire);
+
+    if callback != 0 {
+        // Only `listen()` stores a non-zero value, it's always a `fn(u8)`
+        let callback: fn(u8) = unsafe { core::mem::transmute(callback) };
+        callback(line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exti_register::{
+        EXTI9_5_IRQ, EXTI_IMR, EXTI_PR, NVIC_ICER0, NVIC_ISER0, SYSCFG_EXTICR1,
+    };
+    use crate::gpio_register::{GpioPort, GpioPull};
+    use crate::register_access::RecordingRegisters;
+    use core::sync::atomic::AtomicU32;
+
+    static CALLED_LINES: AtomicU32 = AtomicU32::new(0);
+
+    fn record_line(line: u8) {
+        CALLED_LINES.fetch_or(1 << line, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn dispatch_shared_irq_to_pending_lines() {
+        let registers = RecordingRegisters::new();
+        let gpiob = GpioPort::<'B', _>::take(&registers).unwrap();
+        let pb6 = gpiob.p6.into_input(GpioPull::Up);
+        let pb8 = gpiob.p8.into_input(GpioPull::Up);
+
+        listen(&registers, &pb6, ExtiEdge::Falling, record_line).unwrap();
+        listen(&registers, &pb8, ExtiEdge::Both, record_line).unwrap();
+        assert_eq!(registers.get_value(SYSCFG_EXTICR1 + 4), 1 << 8);
+        assert_eq!(registers.get_value(SYSCFG_EXTICR1 + 8), 1);
+        assert_eq!(registers.get_value(NVIC_ISER0), 1 << EXTI9_5_IRQ);
+
+        // Line 6 and 7 are pending, line 7 is masked
+        registers.set_forced_bits(EXTI_PR, 1 << 6 | 1 << 7);
+        assert!(dispatch(&registers, EXTI9_5_IRQ));
+        assert_eq!(CALLED_LINES.load(Ordering::Relaxed) & 0x3E0, 1 << 6);
+        assert_eq!(registers.get_writes().last(), Some(&(EXTI_PR, 1 << 6)));
+
+        assert!(!dispatch(&registers, 15));
+
+        // Line 8 still uses `EXTI9_5`
+        unlisten(&registers, 6);
+        assert_eq!(registers.get_value(EXTI_IMR), 1 << 8);
+        assert!(!registers
+            .get_writes()
+            .contains(&(NVIC_ICER0, 1 << EXTI9_5_IRQ)));
+        unlisten(&registers, 8);
+        assert_eq!(
+            registers.get_writes().last(),
+            Some(&(NVIC_ICER0, 1 << EXTI9_5_IRQ))
+        );
+    }
+
+    #[test]
+    fn one_port_per_line() {
+        let registers = RecordingRegisters::new();
+        let pa11 = GpioPort::<'A', _>::take(&registers)
+            .unwrap()
+            .p11
+            .into_input(GpioPull::None);
+        let pc11 = GpioPort::<'C', _>::take(&registers)
+            .unwrap()
+            .p11
+            .into_input(GpioPull::None);
+
+        listen(&registers, &pc11, ExtiEdge::Rising, record_line).unwrap();
+        assert_eq!(
+            listen(&registers, &pa11, ExtiEdge::Rising, record_line),
+            Err(ExtiError::LineInUse {
+                line: 11,
+                port: 'C'
+            })
+        );
+
+        // Listening again on the same pin replaces the callback
+        assert_eq!(
+            listen(&registers, &pc11, ExtiEdge::Falling, record_line),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,706 @@
+use crate::flash_access_control_register::FlashAccessControlRegister;
+use crate::flash_control_register::{FlashControlRegister, FlashCrValue, FlashParallelism};
+use crate::flash_option_control_register::{
+    FlashOptcrValue, FlashOptionControlRegister, RdpLevel, MAX_WRITE_PROTECTION_SECTORS,
+};
+use crate::register_access::RegisterAccess;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+/// The main memory (user sectors) start address, page 75
+pub const FLASH_MEMORY_START: u32 = 0x0800_0000;
+
+/// How many times to poll `BSY` before giving up. A 128KB sector erase can take a few
+/// seconds, so the default waits (almost) forever.
+pub const DEFAULT_FLASH_MAX_WAIT_CYCLES: u32 = u32::MAX;
+
+/// `program_option_bytes_with_rdp_level_2()` only accepts this key, it's the `RDP`
+/// level 2 value repeated, so it can't be passed by accident
+pub const RDP_LEVEL_2_CONFIRM_KEY: u32 = 0xCCCC_CCCC;
+
+/// STM32F407VG 1MB: 4 x 16KB, 1 x 64KB and 7 x 128KB, RM0090 page 75
+const STM32F407_SECTOR_SIZES: [u32; 12] = [
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    64 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+];
+
+/// STM32F411CE 512KB: 4 x 16KB, 1 x 64KB and 3 x 128KB, RM0383 page 43
+const STM32F411_SECTOR_SIZES: [u32; 8] = [
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    64 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+];
+
+/// Why a flash operation failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlashError {
+    UnlockFailed,
+    BusyTimeout,
+    /// `WRPERR`
+    WriteProtectionError,
+    /// `PGAERR`
+    ProgrammingAlignmentError,
+    /// `PGPERR`
+    ProgrammingParallelismError,
+    /// `PGSERR`
+    ProgrammingSequenceError,
+    /// `OPERR`
+    OperationError,
+    WrongSector(u8),
+    AddressOutOfRange(u32),
+    UnalignedAddress(u32),
+    WrongDataLength(usize),
+    /// `RDP` level 2 has to go through `program_option_bytes_with_rdp_level_2()`
+    RdpLevel2NotConfirmed,
+    /// `RDP` level 2 is active, the option bytes can't be changed anymore
+    OptionBytesFrozen,
+}
+
+/// The sector layout of the main memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashLayout {
+    Stm32f407,
+    Stm32f411,
+}
+
+#[cfg(feature = "use-stm32f407g-disc1")]
+pub const BOARD_FLASH_LAYOUT: FlashLayout = FlashLayout::Stm32f407;
+
+#[cfg(feature = "use-weact-black-pill")]
+pub const BOARD_FLASH_LAYOUT: FlashLayout = FlashLayout::Stm32f411;
+
+///
+impl FlashLayout {
+    ///
+    pub fn get_sector_sizes(&self) -> &'static [u32] {
+        match self {
+            Self::Stm32f407 => &STM32F407_SECTOR_SIZES,
+            Self::Stm32f411 => &STM32F411_SECTOR_SIZES,
+        }
+    }
+
+    ///
+    pub fn get_total_size(&self) -> u32 {
+        self.get_sector_sizes().iter().sum()
+    }
+
+    ///
+    pub fn get_sector(&self, number: u8) -> Option<FlashSector> {
+        let sizes = self.get_sector_sizes();
+        let size = *sizes.get(number as usize)?;
+        let offset: u32 = sizes[..number as usize].iter().sum();
+
+        Some(FlashSector {
+            number,
+            start_address: FLASH_MEMORY_START + offset,
+            size,
+        })
+    }
+
+    /// The sector which contains `address`
+    pub fn get_sector_by_address(&self, address: u32) -> Option<FlashSector> {
+        (0..self.get_sector_sizes().len() as u8)
+            .filter_map(|number| self.get_sector(number))
+            .find(|sector| sector.contains(address))
+    }
+}
+
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashSector {
+    pub number: u8,
+    pub start_address: u32,
+    pub size: u32,
+}
+
+///
+impl FlashSector {
+    /// The first address after this sector
+    pub fn get_end_address(&self) -> u32 {
+        self.start_address + self.size
+    }
+
+    ///
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start_address && address < self.get_end_address()
+    }
+}
+
+/// Erase and program the main memory through `FLASH_KEYR`, `FLASH_SR` and `FLASH_CR`.
+///
+/// ```
+/// let flash = FlashDriver::new(Mmio).parallelism(FlashParallelism::X32);
+/// flash.unlock()?;
+/// flash.erase_sector(7)?;
+/// flash.program(0x0806_0000, &[0x12, 0x34, 0x56, 0x78])?;
+/// flash.lock();
+/// ```
+pub struct FlashDriver<R: RegisterAccess> {
+    registers: R,
+    layout: FlashLayout,
+    parallelism: FlashParallelism,
+    max_wait_cycles: u32,
+}
+
+///
+impl<R: RegisterAccess> FlashDriver<R> {
+    /// The board flash layout, `x32` parallelism (2.7V ~ 3.6V)
+    pub fn new(registers: R) -> Self {
+        FlashDriver {
+            registers,
+            layout: BOARD_FLASH_LAYOUT,
+            parallelism: FlashParallelism::X32,
+            max_wait_cycles: DEFAULT_FLASH_MAX_WAIT_CYCLES,
+        }
+    }
+
+    ///
+    pub fn layout(mut self, layout: FlashLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// It has to match the supply voltage, see `FlashParallelism::for_supply_voltage()`
+    pub fn parallelism(mut self, parallelism: FlashParallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// How many times to poll `BSY` before giving up
+    pub fn max_wait_cycles(mut self, cycles: u32) -> Self {
+        self.max_wait_cycles = cycles;
+        self
+    }
+
+    ///
+    pub fn get_layout(&self) -> FlashLayout {
+        self.layout
+    }
+
+    ///
+    pub fn get_parallelism(&self) -> FlashParallelism {
+        self.parallelism
+    }
+
+    ///
+    pub fn unlock(&self) -> Result<(), FlashError> {
+        FlashControlRegister::unlock(&self.registers)
+    }
+
+    ///
+    pub fn lock(&self) {
+        FlashControlRegister::lock(&self.registers);
+    }
+
+    ///
+    pub fn is_locked(&self) -> bool {
+        FlashControlRegister::is_locked(&self.registers)
+    }
+
+    /// Set all the bytes in the sector to `0xFF`, `unlock()` first
+    pub fn erase_sector(&self, number: u8) -> Result<(), FlashError> {
+        self.layout
+            .get_sector(number)
+            .ok_or(FlashError::WrongSector(number))?;
+
+        self.prepare()?;
+
+        let parallelism = self.parallelism;
+        FlashCrValue::modify(&self.registers, |r| {
+            r.set_program_size(parallelism)
+                .set_sector_number(number as u32)
+                .set_sector_erase(true)
+        });
+        FlashCrValue::modify(&self.registers, |r| r.set_start(true));
+
+        let result = self.finish();
+
+        FlashCrValue::modify(&self.registers, |r| {
+            r.set_sector_erase(false).set_sector_number(0)
+        });
+
+        // The ART caches may still hold the old content of the sector
+        FlashAccessControlRegister::reset_instruction_cache(&self.registers);
+        FlashAccessControlRegister::reset_data_cache(&self.registers);
+
+        #[cfg(feature = "enable-debug")]
+        let _ = hprintln!("Erase flash sector {}: {:?}", number, result);
+
+        result
+    }
+
+    /// Program `data` from `address`, both the `address` and the `data` length have
+    /// to be a multiple of the parallelism size. The target bytes should be erased,
+    /// programming can only change a bit from `1` to `0`.
+    pub fn program(&self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let size = self.parallelism.get_size_in_bytes();
+
+        if address % size != 0 {
+            return Err(FlashError::UnalignedAddress(address));
+        }
+
+        if data.len() % size as usize != 0 {
+            return Err(FlashError::WrongDataLength(data.len()));
+        }
+
+        let flash_memory_end = FLASH_MEMORY_START + self.layout.get_total_size();
+        if address < FLASH_MEMORY_START
+            || address as u64 + data.len() as u64 > flash_memory_end as u64
+        {
+            return Err(FlashError::AddressOutOfRange(address));
+        }
+
+        self.prepare()?;
+
+        let parallelism = self.parallelism;
+        FlashCrValue::modify(&self.registers, |r| {
+            r.set_program_size(parallelism).set_programming(true)
+        });
+
+        let mut result = Ok(());
+        for (index, chunk) in data.chunks(size as usize).enumerate() {
+            self.write_chunk(address + index as u32 * size, chunk);
+
+            result = self.finish();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        FlashCrValue::modify(&self.registers, |r| r.set_programming(false));
+
+        result
+    }
+
+    /// Read `buffer.len()` bytes from `address`
+    pub fn read(&self, address: u32, buffer: &mut [u8]) {
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            let byte_address = address + index as u32;
+            let word = self.registers.read(byte_address & !0b11);
+            *byte = (word >> ((byte_address & 0b11) * 8)) as u8;
+        }
+    }
+
+    /// The option bytes which are loaded after reset
+    pub fn read_option_bytes(&self) -> FlashOptcrValue {
+        FlashOptcrValue::read(&self.registers)
+    }
+
+    /// Program the BOR level, watchdog, reset and write protection options, it
+    /// doesn't need `unlock()`. Nothing is written if the options don't change.
+    ///
+    /// `RDP` level 2 is refused, see `program_option_bytes_with_rdp_level_2()`.
+    ///
+    /// ```
+    /// let options = flash
+    ///     .read_option_bytes()
+    ///     .set_bor_level(BorLevel::Level3)
+    ///     .set_sector_write_protected(0, true);
+    /// flash.program_option_bytes(options)?;
+    /// ```
+    pub fn program_option_bytes(&self, options: FlashOptcrValue) -> Result<(), FlashError> {
+        if options.read_protection() == RdpLevel::Level2 {
+            return Err(FlashError::RdpLevel2NotConfirmed);
+        }
+
+        self.write_option_bytes(options)
+    }
+
+    /// The same with `program_option_bytes()`, plus `RDP` level 2. The debug
+    /// interface is disabled and the option bytes are frozen FOREVER, the chip
+    /// can't be reprogrammed by the debugger anymore!!!
+    ///
+    /// `confirm_key` has to be `RDP_LEVEL_2_CONFIRM_KEY`.
+    pub fn program_option_bytes_with_rdp_level_2(
+        &self,
+        options: FlashOptcrValue,
+        confirm_key: u32,
+    ) -> Result<(), FlashError> {
+        if confirm_key != RDP_LEVEL_2_CONFIRM_KEY {
+            return Err(FlashError::RdpLevel2NotConfirmed);
+        }
+
+        self.write_option_bytes(options.set_read_protection(RdpLevel::Level2))
+    }
+
+    fn write_option_bytes(&self, options: FlashOptcrValue) -> Result<(), FlashError> {
+        // The `nWRP` bits of the sectors which don't exist
+        let sector_count = self.layout.get_sector_sizes().len() as u8;
+        if let Some(number) = (sector_count..MAX_WRITE_PROTECTION_SECTORS)
+            .find(|&number| options.is_sector_write_protected(number))
+        {
+            return Err(FlashError::WrongSector(number));
+        }
+
+        let current = self.read_option_bytes();
+        if current.read_protection() == RdpLevel::Level2 {
+            return Err(FlashError::OptionBytesFrozen);
+        }
+
+        let options = options.set_option_locked(false).set_option_start(false);
+        if options.bits() == current.set_option_locked(false).bits() {
+            return Ok(());
+        }
+
+        FlashOptionControlRegister::unlock(&self.registers)?;
+        let result = self.prepare().and_then(|_| {
+            options.write(&self.registers);
+            FlashOptcrValue::modify(&self.registers, |r| r.set_option_start(true));
+            self.finish()
+        });
+        FlashOptionControlRegister::lock(&self.registers);
+
+        #[cfg(feature = "enable-debug")]
+        let _ = hprintln!("Program option bytes: {:?}", result);
+
+        result
+    }
+
+    /// Wait for the previous operation and clear its flags
+    fn prepare(&self) -> Result<(), FlashError> {
+        FlashControlRegister::wait_while_busy(&self.registers, self.max_wait_cycles)?;
+        FlashControlRegister::clear_flags(&self.registers);
+
+        Ok(())
+    }
+
+    /// Wait for the current operation and check its error flags
+    fn finish(&self) -> Result<(), FlashError> {
+        FlashControlRegister::wait_while_busy(&self.registers, self.max_wait_cycles)?;
+        FlashControlRegister::check_errors(&self.registers)
+    }
+
+    /// The access size has to match `PSIZE`, `x64` is written as 2 words
+    fn write_chunk(&self, address: u32, chunk: &[u8]) {
+        match self.parallelism {
+            FlashParallelism::X8 => self.registers.write_u8(address, chunk[0]),
+            FlashParallelism::X16 => self
+                .registers
+                .write_u16(address, u16::from_le_bytes([chunk[0], chunk[1]])),
+            FlashParallelism::X32 | FlashParallelism::X64 => {
+                for (index, word) in chunk.chunks(4).enumerate() {
+                    self.registers.write(
+                        address + index as u32 * 4,
+                        u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flash_access_control_register::FLASH_ACR;
+    use crate::flash_control_register::{FlashSrValue, FLASH_KEYR};
+    use crate::flash_option_control_register::BorLevel;
+    use crate::register_access::RecordingRegisters;
+    use crate::simulated_flash::SimulatedFlash;
+
+    const SECTOR_5: u32 = 0x0802_0000;
+
+    fn unlocked_driver(flash: &SimulatedFlash) -> FlashDriver<&SimulatedFlash> {
+        let driver = FlashDriver::new(flash)
+            .layout(FlashLayout::Stm32f407)
+            .max_wait_cycles(10_000);
+        driver.unlock().unwrap();
+        driver
+    }
+
+    #[test]
+    fn sector_layouts() {
+        let layout = FlashLayout::Stm32f407;
+        assert_eq!(layout.get_total_size(), 1024 * 1024);
+        assert_eq!(
+            layout.get_sector(4),
+            Some(FlashSector {
+                number: 4,
+                start_address: 0x0801_0000,
+                size: 64 * 1024
+            })
+        );
+        assert_eq!(layout.get_sector(11).unwrap().start_address, 0x080E_0000);
+        assert_eq!(layout.get_sector(12), None);
+        assert_eq!(
+            layout.get_sector_by_address(0x0800_7FFF).map(|s| s.number),
+            Some(1)
+        );
+
+        let layout = FlashLayout::Stm32f411;
+        assert_eq!(layout.get_total_size(), 512 * 1024);
+        assert_eq!(layout.get_sector(7).unwrap().start_address, 0x0806_0000);
+        assert_eq!(layout.get_sector_by_address(0x0808_0000), None);
+    }
+
+    #[test]
+    fn unlock_and_lock() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        let driver = FlashDriver::new(&flash);
+        assert!(driver.is_locked());
+
+        driver.unlock().unwrap();
+        assert!(!driver.is_locked());
+        driver.lock();
+        assert!(driver.is_locked());
+
+        // A wrong key locks it until reset
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        flash.write(FLASH_KEYR, 0x1234_5678);
+        assert_eq!(
+            FlashDriver::new(&flash).unlock(),
+            Err(FlashError::UnlockFailed)
+        );
+    }
+
+    #[test]
+    fn erase_and_program_with_each_parallelism() {
+        for parallelism in [
+            FlashParallelism::X8,
+            FlashParallelism::X16,
+            FlashParallelism::X32,
+            FlashParallelism::X64,
+        ]
+        .iter()
+        {
+            let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+            flash.set_bytes(SECTOR_5 - 4, &[0; 4]);
+            flash.set_bytes(SECTOR_5, &[0; 16]);
+
+            let driver = unlocked_driver(&flash).parallelism(*parallelism);
+            driver.erase_sector(5).unwrap();
+            assert_eq!(flash.get_bytes(SECTOR_5, 16), vec![0xFF; 16]);
+            // Sector 4 is untouched
+            assert_eq!(flash.get_bytes(SECTOR_5 - 4, 4), vec![0; 4]);
+
+            let data = [1, 2, 3, 4, 5, 6, 7, 8];
+            driver.program(SECTOR_5 + 8, &data).unwrap();
+
+            let mut buffer = [0; 8];
+            driver.read(SECTOR_5 + 8, &mut buffer);
+            assert_eq!(buffer, data);
+            assert!(!FlashCrValue::read(&flash).programming());
+        }
+    }
+
+    #[test]
+    fn erase_flushes_the_caches() {
+        let registers = RecordingRegisters::new();
+        FlashAccessControlRegister::enable_instruction_cache(&registers);
+        FlashAccessControlRegister::enable_data_cache(&registers);
+
+        // The `SR` clear bits written by `prepare()` read back as errors, the caches
+        // are flushed anyway
+        let driver = FlashDriver::new(&registers).layout(FlashLayout::Stm32f407);
+        assert!(driver.erase_sector(5).is_err());
+
+        // Disable, reset and enable again, the instruction cache first
+        let writes: Vec<u32> = registers
+            .get_writes()
+            .iter()
+            .filter(|(address, _)| *address == FLASH_ACR)
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(
+            writes,
+            vec![
+                1 << 9,
+                (1 << 9) | (1 << 10),
+                1 << 10,
+                (1 << 10) | (1 << 11),
+                1 << 10,
+                (1 << 9) | (1 << 10),
+                1 << 9,
+                (1 << 9) | (1 << 12),
+                1 << 9,
+                (1 << 9) | (1 << 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_arguments_never_touch_the_flash() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f411);
+        let driver = unlocked_driver(&flash).layout(FlashLayout::Stm32f411);
+
+        assert_eq!(driver.erase_sector(8), Err(FlashError::WrongSector(8)));
+        assert_eq!(
+            driver.program(SECTOR_5 + 2, &[0; 4]),
+            Err(FlashError::UnalignedAddress(SECTOR_5 + 2))
+        );
+        assert_eq!(
+            driver.program(SECTOR_5, &[0; 3]),
+            Err(FlashError::WrongDataLength(3))
+        );
+        assert_eq!(
+            driver.program(0x0807_FFFC, &[0; 8]),
+            Err(FlashError::AddressOutOfRange(0x0807_FFFC))
+        );
+        assert_eq!(flash.get_cycles(), 4);
+    }
+
+    #[test]
+    fn write_protected_sector_reports_wrperr() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407).write_protect_sector(5);
+        let driver = unlocked_driver(&flash);
+
+        assert_eq!(
+            driver.erase_sector(5),
+            Err(FlashError::WriteProtectionError)
+        );
+        assert_eq!(
+            driver.program(SECTOR_5, &[0; 4]),
+            Err(FlashError::WriteProtectionError)
+        );
+        assert_eq!(flash.get_bytes(SECTOR_5, 4), vec![0xFF; 4]);
+
+        // The error flags are cleared for the next operation
+        assert_eq!(FlashSrValue::read(&flash).bits(), 0);
+        driver.program(SECTOR_5 - 4, &[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn hardware_errors_are_typed() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        let driver = unlocked_driver(&flash);
+
+        // Byte access with `x32` parallelism
+        FlashCrValue::modify(&flash, |r| {
+            r.set_program_size(FlashParallelism::X32)
+                .set_programming(true)
+        });
+        flash.write_u8(SECTOR_5, 0);
+        assert_eq!(
+            FlashControlRegister::check_errors(&flash),
+            Err(FlashError::ProgrammingParallelismError)
+        );
+
+        FlashCrValue::modify(&flash, |r| r.set_program_size(FlashParallelism::X16));
+        flash.write_u16(SECTOR_5 + 1, 0);
+        assert_eq!(
+            FlashControlRegister::check_errors(&flash),
+            Err(FlashError::ProgrammingAlignmentError)
+        );
+
+        FlashCrValue::modify(&flash, |r| r.set_programming(false));
+        flash.write(SECTOR_5, 0);
+        assert_eq!(
+            FlashControlRegister::check_errors(&flash),
+            Err(FlashError::ProgrammingSequenceError)
+        );
+        assert_eq!(FlashControlRegister::check_errors(&flash), Ok(()));
+        assert_eq!(flash.get_bytes(SECTOR_5, 4), vec![0xFF; 4]);
+
+        driver.program(SECTOR_5, &[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn busy_timeout() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407).erase_cycles(1_000_000);
+        let driver = unlocked_driver(&flash).max_wait_cycles(100);
+
+        assert_eq!(driver.erase_sector(5), Err(FlashError::BusyTimeout));
+    }
+
+    #[test]
+    fn option_bytes_set_bor_and_write_protection() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        let driver = FlashDriver::new(&flash)
+            .layout(FlashLayout::Stm32f407)
+            .max_wait_cycles(10_000);
+
+        let options = driver.read_option_bytes();
+        assert_eq!(options.bor_level(), BorLevel::Off);
+        assert_eq!(options.read_protection(), RdpLevel::Level0);
+        assert!(options.software_watchdog());
+        assert!(!options.is_sector_write_protected(0));
+
+        driver
+            .program_option_bytes(
+                options
+                    .set_bor_level(BorLevel::Level3)
+                    .set_sector_write_protected(0, true)
+                    .set_sector_write_protected(1, true),
+            )
+            .unwrap();
+        assert!(FlashOptionControlRegister::is_locked(&flash));
+
+        // Kept after reset, and the sectors are protected
+        let flash = flash.reboot();
+        let driver = unlocked_driver(&flash);
+        let options = driver.read_option_bytes();
+        assert_eq!(options.bor_level(), BorLevel::Level3);
+        assert!(options.is_sector_write_protected(1));
+        assert!(!options.is_sector_write_protected(2));
+        assert_eq!(
+            driver.erase_sector(1),
+            Err(FlashError::WriteProtectionError)
+        );
+
+        // Nothing to program
+        let cycles = flash.get_cycles();
+        driver.program_option_bytes(options).unwrap();
+        assert!(flash.get_cycles() < cycles + 10);
+
+        // F411 has 8 sectors only
+        let driver = driver.layout(FlashLayout::Stm32f411);
+        assert_eq!(
+            driver.program_option_bytes(options.set_sector_write_protected(8, true)),
+            Err(FlashError::WrongSector(8))
+        );
+    }
+
+    #[test]
+    fn rdp_level_2_needs_the_confirm_key() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        let driver = FlashDriver::new(&flash)
+            .layout(FlashLayout::Stm32f407)
+            .max_wait_cycles(10_000);
+        let options = driver.read_option_bytes();
+
+        assert_eq!(
+            driver.program_option_bytes(options.set_read_protection(RdpLevel::Level2)),
+            Err(FlashError::RdpLevel2NotConfirmed)
+        );
+        assert_eq!(
+            driver.program_option_bytes_with_rdp_level_2(options, 0xCCCC_0000),
+            Err(FlashError::RdpLevel2NotConfirmed)
+        );
+        assert_eq!(
+            driver.read_option_bytes().read_protection(),
+            RdpLevel::Level0
+        );
+
+        driver
+            .program_option_bytes_with_rdp_level_2(options, RDP_LEVEL_2_CONFIRM_KEY)
+            .unwrap();
+        let flash = flash.reboot();
+        let driver = FlashDriver::new(&flash).layout(FlashLayout::Stm32f407);
+        assert_eq!(
+            driver.read_option_bytes().read_protection(),
+            RdpLevel::Level2
+        );
+
+        // Frozen
+        assert_eq!(
+            driver.program_option_bytes(options.set_bor_level(BorLevel::Level1)),
+            Err(FlashError::OptionBytesFrozen)
+        );
+    }
+}
@@ -0,0 +1,368 @@
+// ------ GPIO alternate function mapping ---------------------
+//
+// A peripheral (USART, SPI, I2C, TIM) signal only reaches a pin
+// when the pin is in alternate mode with the right AF number in
+// `AFRL`/`AFRH`. The numbers come from the datasheet "Alternate
+// function mapping" table:
+//
+// STM32F407 (DS8626 Table 9), STM32F411 (DS10314 Table 9)
+//
+// The tables below only list the pins which the board's package
+// has: LQFP100 on the STM32F407G-DISC1 (no port F, G, I) and
+// UFQFPN48 on the WeAct Black Pill (no port D, E, no PB11).
+//
+// A valid pairing is checked by the compiler:
+//
+// let tx = gpioa.p2.into_signal::<signal::Usart2Tx>();
+//
+// and at runtime by `get_alternate_function()`.
+//
+
+use crate::gpio_register::{Alternate, Pin};
+use crate::register_access::RegisterAccess;
+
+/// A peripheral signal which is routed to a pin by an alternate function, the
+/// marker types in `signal` are the same signals for the compile-time check
+macro_rules! signals {
+    ($($signal:ident),* $(,)?) => {
+        ///
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Signal {
+            $($signal,)*
+        }
+
+        /// One marker type per `Signal`, e.g. `into_signal::<signal::Usart2Tx>()`
+        pub mod signal {
+            $(
+                pub struct $signal;
+
+                impl super::PeripheralSignal for $signal {
+                    const SIGNAL: super::Signal = super::Signal::$signal;
+                }
+            )*
+        }
+    };
+}
+
+signals! {
+    Usart1Tx, Usart1Rx, Usart2Tx, Usart2Rx, Usart3Tx, Usart3Rx, Usart6Tx, Usart6Rx,
+    Spi1Sck, Spi1Miso, Spi1Mosi, Spi2Sck, Spi2Miso, Spi2Mosi, Spi3Sck, Spi3Miso, Spi3Mosi,
+    I2c1Scl, I2c1Sda, I2c2Scl, I2c2Sda, I2c3Scl, I2c3Sda,
+    Tim1Ch1, Tim1Ch2, Tim1Ch3, Tim1Ch4, Tim2Ch1, Tim2Ch2, Tim2Ch3, Tim2Ch4,
+    Tim3Ch1, Tim3Ch2, Tim3Ch3, Tim3Ch4, Tim4Ch1, Tim4Ch2, Tim4Ch3, Tim4Ch4,
+    Tim5Ch1, Tim5Ch2, Tim5Ch3, Tim5Ch4,
+}
+
+/// Implemented by the marker types in `signal`
+pub trait PeripheralSignal {
+    const SIGNAL: Signal;
+}
+
+/// Implemented only for the pin and signal pairs in the board's table, a pin which
+/// can't carry the signal fails to compile.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't carry `{S}`",
+    note = "check the alternate function table of the board in `gpio_alternate_function.rs`"
+)]
+pub trait AlternateFunction<S: PeripheralSignal> {
+    /// The pin in `Alternate<AF>` mode
+    type Output;
+    const ALTERNATE_FUNCTION: u8;
+
+    ///
+    fn into_alternate_function(self) -> Self::Output;
+}
+
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlternateFunctionError {
+    /// The board's table doesn't have the pairing, the chip doesn't support it or the
+    /// package doesn't have the pin
+    NotAvailable { port: char, pin: u8, signal: Signal },
+}
+
+/// One row of the alternate function table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlternateFunctionMapping {
+    pub port: char,
+    pub pin: u8,
+    pub signal: Signal,
+    pub alternate_function: u8,
+}
+
+/// Generates `ALTERNATE_FUNCTIONS` and an `AlternateFunction` impl for every row
+macro_rules! alternate_functions {
+    ($($port:literal, $pin:literal, $signal:ident => $alternate_function:literal;)*) => {
+        /// The board's alternate function table
+        pub const ALTERNATE_FUNCTIONS: &[AlternateFunctionMapping] = &[
+            $(
+                AlternateFunctionMapping {
+                    port: $port,
+                    pin: $pin,
+                    signal: Signal::$signal,
+                    alternate_function: $alternate_function,
+                },
+            )*
+        ];
+
+        $(
+            impl<MODE, R: RegisterAccess> AlternateFunction<signal::$signal>
+                for Pin<$port, $pin, MODE, R>
+            {
+                type Output = Pin<$port, $pin, Alternate<$alternate_function>, R>;
+                const ALTERNATE_FUNCTION: u8 = $alternate_function;
+
+                fn into_alternate_function(self) -> Self::Output {
+                    self.into_alternate::<$alternate_function>()
+                }
+            }
+        )*
+    };
+}
+
+// STM32F407VG, LQFP100
+#[cfg(feature = "use-stm32f407g-disc1")]
+alternate_functions! {
+    'A', 9, Usart1Tx => 7;
+    'B', 6, Usart1Tx => 7;
+    'A', 10, Usart1Rx => 7;
+    'B', 7, Usart1Rx => 7;
+    'A', 2, Usart2Tx => 7;
+    'D', 5, Usart2Tx => 7;
+    'A', 3, Usart2Rx => 7;
+    'D', 6, Usart2Rx => 7;
+    'B', 10, Usart3Tx => 7;
+    'C', 10, Usart3Tx => 7;
+    'D', 8, Usart3Tx => 7;
+    'B', 11, Usart3Rx => 7;
+    'C', 11, Usart3Rx => 7;
+    'D', 9, Usart3Rx => 7;
+    'C', 6, Usart6Tx => 8;
+    'C', 7, Usart6Rx => 8;
+
+    'A', 5, Spi1Sck => 5;
+    'B', 3, Spi1Sck => 5;
+    'A', 6, Spi1Miso => 5;
+    'B', 4, Spi1Miso => 5;
+    'A', 7, Spi1Mosi => 5;
+    'B', 5, Spi1Mosi => 5;
+    'B', 10, Spi2Sck => 5;
+    'B', 13, Spi2Sck => 5;
+    'B', 14, Spi2Miso => 5;
+    'C', 2, Spi2Miso => 5;
+    'B', 15, Spi2Mosi => 5;
+    'C', 3, Spi2Mosi => 5;
+    'B', 3, Spi3Sck => 6;
+    'C', 10, Spi3Sck => 6;
+    'B', 4, Spi3Miso => 6;
+    'C', 11, Spi3Miso => 6;
+    'B', 5, Spi3Mosi => 6;
+    'C', 12, Spi3Mosi => 6;
+
+    'B', 6, I2c1Scl => 4;
+    'B', 8, I2c1Scl => 4;
+    'B', 7, I2c1Sda => 4;
+    'B', 9, I2c1Sda => 4;
+    'B', 10, I2c2Scl => 4;
+    'B', 11, I2c2Sda => 4;
+    'A', 8, I2c3Scl => 4;
+    'C', 9, I2c3Sda => 4;
+
+    'A', 8, Tim1Ch1 => 1;
+    'E', 9, Tim1Ch1 => 1;
+    'A', 9, Tim1Ch2 => 1;
+    'E', 11, Tim1Ch2 => 1;
+    'A', 10, Tim1Ch3 => 1;
+    'E', 13, Tim1Ch3 => 1;
+    'A', 11, Tim1Ch4 => 1;
+    'E', 14, Tim1Ch4 => 1;
+    'A', 0, Tim2Ch1 => 1;
+    'A', 5, Tim2Ch1 => 1;
+    'A', 15, Tim2Ch1 => 1;
+    'A', 1, Tim2Ch2 => 1;
+    'B', 3, Tim2Ch2 => 1;
+    'A', 2, Tim2Ch3 => 1;
+    'B', 10, Tim2Ch3 => 1;
+    'A', 3, Tim2Ch4 => 1;
+    'B', 11, Tim2Ch4 => 1;
+    'A', 6, Tim3Ch1 => 2;
+    'B', 4, Tim3Ch1 => 2;
+    'C', 6, Tim3Ch1 => 2;
+    'A', 7, Tim3Ch2 => 2;
+    'B', 5, Tim3Ch2 => 2;
+    'C', 7, Tim3Ch2 => 2;
+    'B', 0, Tim3Ch3 => 2;
+    'C', 8, Tim3Ch3 => 2;
+    'B', 1, Tim3Ch4 => 2;
+    'C', 9, Tim3Ch4 => 2;
+    'B', 6, Tim4Ch1 => 2;
+    'D', 12, Tim4Ch1 => 2;
+    'B', 7, Tim4Ch2 => 2;
+    'D', 13, Tim4Ch2 => 2;
+    'B', 8, Tim4Ch3 => 2;
+    'D', 14, Tim4Ch3 => 2;
+    'B', 9, Tim4Ch4 => 2;
+    'D', 15, Tim4Ch4 => 2;
+    'A', 0, Tim5Ch1 => 2;
+    'A', 1, Tim5Ch2 => 2;
+    'A', 2, Tim5Ch3 => 2;
+    'A', 3, Tim5Ch4 => 2;
+}
+
+// STM32F411CE, UFQFPN48
+#[cfg(feature = "use-weact-black-pill")]
+alternate_functions! {
+    'A', 9, Usart1Tx => 7;
+    'A', 15, Usart1Tx => 7;
+    'B', 6, Usart1Tx => 7;
+    'A', 10, Usart1Rx => 7;
+    'B', 3, Usart1Rx => 7;
+    'B', 7, Usart1Rx => 7;
+    'A', 2, Usart2Tx => 7;
+    'A', 3, Usart2Rx => 7;
+    'A', 11, Usart6Tx => 8;
+    'A', 12, Usart6Rx => 8;
+
+    'A', 5, Spi1Sck => 5;
+    'B', 3, Spi1Sck => 5;
+    'A', 6, Spi1Miso => 5;
+    'B', 4, Spi1Miso => 5;
+    'A', 7, Spi1Mosi => 5;
+    'B', 5, Spi1Mosi => 5;
+    'B', 10, Spi2Sck => 5;
+    'B', 13, Spi2Sck => 5;
+    'B', 14, Spi2Miso => 5;
+    'B', 15, Spi2Mosi => 5;
+    'B', 3, Spi3Sck => 6;
+    'B', 4, Spi3Miso => 6;
+    'B', 5, Spi3Mosi => 6;
+
+    'B', 6, I2c1Scl => 4;
+    'B', 8, I2c1Scl => 4;
+    'B', 7, I2c1Sda => 4;
+    'B', 9, I2c1Sda => 4;
+    'B', 10, I2c2Scl => 4;
+    'B', 3, I2c2Sda => 9;
+    'A', 8, I2c3Scl => 4;
+    'B', 4, I2c3Sda => 9;
+    'B', 8, I2c3Sda => 9;
+
+    'A', 8, Tim1Ch1 => 1;
+    'A', 9, Tim1Ch2 => 1;
+    'A', 10, Tim1Ch3 => 1;
+    'A', 11, Tim1Ch4 => 1;
+    'A', 0, Tim2Ch1 => 1;
+    'A', 5, Tim2Ch1 => 1;
+    'A', 15, Tim2Ch1 => 1;
+    'A', 1, Tim2Ch2 => 1;
+    'B', 3, Tim2Ch2 => 1;
+    'A', 2, Tim2Ch3 => 1;
+    'B', 10, Tim2Ch3 => 1;
+    'A', 3, Tim2Ch4 => 1;
+    'A', 6, Tim3Ch1 => 2;
+    'B', 4, Tim3Ch1 => 2;
+    'A', 7, Tim3Ch2 => 2;
+    'B', 5, Tim3Ch2 => 2;
+    'B', 0, Tim3Ch3 => 2;
+    'B', 1, Tim3Ch4 => 2;
+    'B', 6, Tim4Ch1 => 2;
+    'B', 7, Tim4Ch2 => 2;
+    'B', 8, Tim4Ch3 => 2;
+    'B', 9, Tim4Ch4 => 2;
+    'A', 0, Tim5Ch1 => 2;
+    'A', 1, Tim5Ch2 => 2;
+    'A', 2, Tim5Ch3 => 2;
+    'A', 3, Tim5Ch4 => 2;
+}
+
+/// The AF number of `signal` on the pin, for the pins which are only known at runtime
+pub fn get_alternate_function(
+    port: char,
+    pin: u8,
+    signal: Signal,
+) -> Result<u8, AlternateFunctionError> {
+    ALTERNATE_FUNCTIONS
+        .iter()
+        .find(|mapping| mapping.port == port && mapping.pin == pin && mapping.signal == signal)
+        .map(|mapping| mapping.alternate_function)
+        .ok_or(AlternateFunctionError::NotAvailable { port, pin, signal })
+}
+
+///
+impl<const PORT: char, const N: u8, MODE, R: RegisterAccess> Pin<PORT, N, MODE, R> {
+    /// Switch the pin to the alternate function which carries `S`, it only compiles if
+    /// the board's table has the pairing
+    pub fn into_signal<S: PeripheralSignal>(self) -> <Self as AlternateFunction<S>>::Output
+    where
+        Self: AlternateFunction<S>,
+    {
+        AlternateFunction::<S>::into_alternate_function(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpio_register::{GpioMode, GpioPort, GPIOA, GPIO_AFRH_OFFSET, GPIO_AFRL_OFFSET};
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn valid_pairing_configures_the_pin() {
+        let registers = RecordingRegisters::new();
+        let gpioa = GpioPort::<'A', _>::take(&registers).unwrap();
+
+        // USART2 on PA2/PA3 and TIM1 CH4 on PA11 on both boards
+        let tx = gpioa.p2.into_signal::<signal::Usart2Tx>();
+        gpioa.p3.into_signal::<signal::Usart2Rx>();
+        gpioa.p11.into_signal::<signal::Tim1Ch4>();
+
+        assert_eq!(tx.get_mode(), GpioMode::Alternate);
+        assert_eq!(
+            registers.get_value(GPIOA + GPIO_AFRL_OFFSET),
+            7 << 12 | 7 << 8
+        );
+        assert_eq!(registers.get_value(GPIOA + GPIO_AFRH_OFFSET), 1 << 12);
+        assert_eq!(
+            <Pin<'A', 2, Alternate<7>> as AlternateFunction<signal::Usart2Tx>>::ALTERNATE_FUNCTION,
+            7
+        );
+    }
+
+    #[test]
+    fn invalid_pairing_is_an_error() {
+        assert_eq!(get_alternate_function('A', 9, Signal::Usart1Tx), Ok(7));
+        assert_eq!(
+            get_alternate_function('A', 3, Signal::Usart2Tx),
+            Err(AlternateFunctionError::NotAvailable {
+                port: 'A',
+                pin: 3,
+                signal: Signal::Usart2Tx
+            })
+        );
+
+        // The Discovery board has USART3 and port D, the Black Pill has neither
+        #[cfg(feature = "use-stm32f407g-disc1")]
+        assert_eq!(get_alternate_function('D', 8, Signal::Usart3Tx), Ok(7));
+        #[cfg(feature = "use-weact-black-pill")]
+        {
+            assert!(get_alternate_function('D', 8, Signal::Usart3Tx).is_err());
+            assert_eq!(get_alternate_function('B', 3, Signal::I2c2Sda), Ok(9));
+        }
+    }
+
+    #[test]
+    fn one_function_per_pin_and_af_number() {
+        for (index, mapping) in ALTERNATE_FUNCTIONS.iter().enumerate() {
+            assert!(mapping.alternate_function < 16 && mapping.pin < 16);
+
+            assert!(
+                ALTERNATE_FUNCTIONS[index + 1..].iter().all(|other| {
+                    (other.port, other.pin, other.alternate_function)
+                        != (mapping.port, mapping.pin, mapping.alternate_function)
+                }),
+                "{:?}",
+                mapping
+            );
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use crate::register_access::RegisterAccess;
+use crate::system_tick_timer_register::{SystemTickError, SystemTickTimer};
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+#[cfg(not(test))]
+use cortex_m_rt::exception;
+
+/// The milliseconds since `Monotonic::start()`, only the SysTick exception handler
+/// below increases it.
+pub static MILLISECONDS: TickCounter = TickCounter::new();
+
+/// The `fn(u64)` which is called after every tick, `0` for none
+static TICK_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// The SysTick exception belongs to this module, the bins which include it can't
+/// have their own `SysTick` handler, use `set_tick_handler()` instead.
+#[cfg(not(test))]
+#[exception]
+fn SysTick() {
+    MILLISECONDS.increment();
+    call_tick_handler(MILLISECONDS.get());
+}
+
+/// Call `handler` with the milliseconds since `Monotonic::start()` from the SysTick
+/// exception handler on every tick, e.g. to advance `SharedSoftwareTimers`
+pub fn set_tick_handler(handler: fn(u64)) {
+    TICK_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+///
+pub fn clear_tick_handler() {
+    TICK_HANDLER.store(0, Ordering::Release);
+}
+
+fn call_tick_handler(milliseconds: u64) {
+    let handler = TICK_HANDLER.load(Ordering::Acquire);
+
+    if handler != 0 {
+        // Only `set_tick_handler()` stores a non-zero value, it's always a `fn(u64)`
+        let handler: fn(u64) = unsafe { core::mem::transmute(handler) };
+        handler(milliseconds);
+    }
+}
+
+/// A 64bit counter which is increased by the exception handler and read by the
+/// thread mode.
+///
+/// Cortex-M4 doesn't have the 64bit atomic operations, so the counter is split into
+/// two `AtomicU32`. The handler can't be interrupted by the thread mode, so it always
+/// updates both halves in one go, a reader only needs to make sure the high half
+/// doesn't change while it reads the low half.
+pub struct TickCounter {
+    low: AtomicU32,
+    high: AtomicU32,
+}
+
+///
+impl TickCounter {
+    ///
+    pub const fn new() -> Self {
+        TickCounter {
+            low: AtomicU32::new(0),
+            high: AtomicU32::new(0),
+        }
+    }
+
+    /// Only call it from the exception handler, it's the only writer
+    pub fn increment(&self) {
+        let low = self.low.load(Ordering::Relaxed).wrapping_add(1);
+        self.low.store(low, Ordering::Release);
+
+        if low == 0 {
+            let high = self.high.load(Ordering::Relaxed).wrapping_add(1);
+            self.high.store(high, Ordering::Release);
+        }
+    }
+
+    /// Never torn: read again if the exception handler changed the high half in the
+    /// middle
+    pub fn get(&self) -> u64 {
+        loop {
+            let high = self.high.load(Ordering::Acquire);
+            let low = self.low.load(Ordering::Acquire);
+
+            if self.high.load(Ordering::Acquire) == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    /// Only call it before the SysTick exception is enabled
+    pub fn set(&self, ticks: u64) {
+        self.high.store((ticks >> 32) as u32, Ordering::Release);
+        self.low.store(ticks as u32, Ordering::Release);
+    }
+}
+
+/// A point in time since `Monotonic::start()`, in microseconds. It takes 584,942
+/// years to overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    microseconds: u64,
+}
+
+///
+impl Instant {
+    ///
+    pub const fn from_micros(microseconds: u64) -> Self {
+        Instant { microseconds }
+    }
+
+    ///
+    pub const fn from_millis(milliseconds: u64) -> Self {
+        Instant {
+            microseconds: milliseconds * 1000,
+        }
+    }
+
+    ///
+    pub fn as_micros(&self) -> u64 {
+        self.microseconds
+    }
+
+    ///
+    pub fn as_millis(&self) -> u64 {
+        self.microseconds / 1000
+    }
+
+    ///
+    pub fn as_secs(&self) -> u64 {
+        self.microseconds / 1_000_000
+    }
+
+    /// `None` if `earlier` is later than `self`
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.microseconds
+            .checked_sub(earlier.microseconds)
+            .map(Duration::from_micros)
+    }
+
+    /// Zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .unwrap_or(Duration::from_micros(0))
+    }
+
+    /// `None` if `duration` goes before the boot
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.microseconds
+            .checked_sub(duration.as_micros() as u64)
+            .map(Instant::from_micros)
+    }
+}
+
+///
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant::from_micros(self.microseconds + duration.as_micros() as u64)
+    }
+}
+
+///
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+///
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// Stops at the boot (zero)
+    fn sub(self, duration: Duration) -> Instant {
+        Instant::from_micros(
+            self.microseconds
+                .saturating_sub(duration.as_micros() as u64),
+        )
+    }
+}
+
+///
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A monotonic clock which ticks every millisecond by the SysTick exception, and
+/// reads the SysTick countdown value for the microseconds in between.
+///
+/// ```
+/// let monotonic = Monotonic::start(Mmio, rcc_clock.get_cpu_clock_frequency_in_hertz()).unwrap();
+/// let start = monotonic.now();
+/// ...
+/// let duration = monotonic.now() - start;
+/// ```
+pub struct Monotonic<R: RegisterAccess> {
+    registers: R,
+    counter: &'static TickCounter,
+}
+
+///
+impl<R: RegisterAccess> Monotonic<R> {
+    /// Reset the uptime to `0`, then start the SysTick timer with the 1ms exception.
+    /// The timer runs on `cpu_clock_frequency_in_hertz` (AHB), which has to be 2kHz at
+    /// least.
+    pub fn start(registers: R, cpu_clock_frequency_in_hertz: u32) -> Result<Self, SystemTickError> {
+        Self::start_with_counter(registers, cpu_clock_frequency_in_hertz, &MILLISECONDS)
+    }
+
+    /// The same with `start()`, but reads `counter` instead of `MILLISECONDS`, e.g. the
+    /// host tests which don't have the SysTick exception
+    pub fn start_with_counter(
+        registers: R,
+        cpu_clock_frequency_in_hertz: u32,
+        counter: &'static TickCounter,
+    ) -> Result<Self, SystemTickError> {
+        counter.set(0);
+        SystemTickTimer::enable(&registers, cpu_clock_frequency_in_hertz, true)?;
+
+        Ok(Monotonic { registers, counter })
+    }
+
+    /// The uptime with sub-millisecond resolution
+    pub fn now(&self) -> Instant {
+        loop {
+            let milliseconds = self.counter.get();
+            let mut countdown = SystemTickTimer::get_current_countdown_value(&self.registers);
+            let is_pending = SystemTickTimer::is_exception_pending(&self.registers);
+
+            // The exception handler ran in the middle
+            if self.counter.get() != milliseconds {
+                continue;
+            }
+
+            // The counter wrapped but the handler hasn't run yet, the countdown may be
+            // read before or after the wrap, read it again to be sure it's after.
+            let milliseconds = if is_pending {
+                countdown = SystemTickTimer::get_current_countdown_value(&self.registers);
+                milliseconds + 1
+            } else {
+                milliseconds
+            };
+
+            let reload = SystemTickTimer::get_reload_value(&self.registers);
+            let elapsed_ticks = reload.saturating_sub(countdown) as u64;
+            let microseconds = elapsed_ticks * 1000 / (reload as u64 + 1);
+
+            return Instant::from_micros(milliseconds * 1000 + microseconds);
+        }
+    }
+
+    /// How long since `earlier`
+    pub fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+    use crate::system_tick_timer_register::{ScbIcsrValue, StkCtrlValue, SCB_ICSR, STK_VAL};
+
+    #[test]
+    fn tick_counter_carries_into_the_high_half() {
+        let counter = TickCounter::new();
+        counter.set(u32::MAX as u64 - 1);
+
+        counter.increment();
+        assert_eq!(counter.get(), u32::MAX as u64);
+        counter.increment();
+        assert_eq!(counter.get(), 1 << 32);
+    }
+
+    #[test]
+    fn now_adds_the_countdown_progress() {
+        static COUNTER: TickCounter = TickCounter::new();
+        let registers = RecordingRegisters::new();
+
+        assert_eq!(
+            Monotonic::start_with_counter(&registers, 0, &COUNTER).err(),
+            Some(SystemTickError::TickFrequencyTooHigh(1000))
+        );
+        let monotonic = Monotonic::start_with_counter(&registers, 168_000_000, &COUNTER).unwrap();
+        assert!(StkCtrlValue::read(&registers).exception_enabled());
+
+        // Started from the reload value 167_999
+        registers.write(STK_VAL, 167_999);
+        assert_eq!(monotonic.now(), Instant::from_micros(0));
+
+        COUNTER.set(1234);
+        registers.write(STK_VAL, 84_000);
+        assert_eq!(monotonic.now(), Instant::from_micros(1_234_499));
+
+        // Wrapped while the exception is not handled yet
+        registers.write(STK_VAL, 167_000);
+        registers.write(SCB_ICSR, ScbIcsrValue::PENDSTSET.mask());
+        assert_eq!(monotonic.now(), Instant::from_micros(1_235_005));
+    }
+
+    #[test]
+    fn tick_handler_gets_the_milliseconds() {
+        static LAST_TICK: TickCounter = TickCounter::new();
+
+        call_tick_handler(1);
+        set_tick_handler(|milliseconds| LAST_TICK.set(milliseconds));
+        call_tick_handler(42);
+        assert_eq!(LAST_TICK.get(), 42);
+
+        clear_tick_handler();
+        call_tick_handler(43);
+        assert_eq!(LAST_TICK.get(), 42);
+    }
+
+    #[test]
+    fn instant_and_duration_arithmetic() {
+        let start = Instant::from_millis(1500);
+        let later = start + Duration::from_micros(2_250);
+
+        assert_eq!(later.as_micros(), 1_502_250);
+        assert_eq!(later.as_millis(), 1502);
+        assert_eq!(later.as_secs(), 1);
+        assert_eq!(later - start, Duration::from_micros(2_250));
+        assert_eq!(
+            later - Duration::from_millis(2),
+            Instant::from_micros(1_500_250)
+        );
+        assert_eq!(start.checked_duration_since(later), None);
+        assert_eq!(start - later, Duration::from_micros(0));
+        assert_eq!(
+            start.checked_sub(Duration::from_millis(500)),
+            Some(Instant::from_millis(1000))
+        );
+        assert_eq!(start.checked_sub(Duration::from_secs(2)), None);
+        assert_eq!(start - Duration::from_secs(2), Instant::from_micros(0));
+
+        let mut deadline = start;
+        deadline += Duration::from_secs(1);
+        assert!(deadline > later);
+    }
+}
@@ -44,8 +44,13 @@
 //  2. `PLL_P`: We can try start from `2`, then `PLL_M` and `PLL_P`
 //      already fixed, only left the `PLL_N` to choose.
 //
-//  3. If fixed `PLL_M` and `PLL_P` not works, then go to `STM32CubeMX`
-//     UI to try the combination.
+//  3. If fixed `PLL_M` and `PLL_P` not works, then call the
+//     `clock_utils::find_pll_factors()` to search all the legal
+//     combinations, or go to `STM32CubeMX` UI to try the combination.
+//
+// The `clock_source_selecting` module below only provides the board
+// default settings, use `clock_utils::ClockConfig` to override any of
+// them at runtime before calling `RccClocks::setup_system_clock`.
 //
 
 #[cfg(feature = "use-stm32f407g-disc1")]
@@ -53,9 +58,44 @@ pub mod clock_source_selecting {
     pub const SYS_CLOCK_MAX_SPEED: u32 = 168_000_000;
     pub const APB1_PERIPHERAL_MAX_SPEED: u32 = 42_000_000;
     pub const APB2_PERIPHERAL_MAX_SPEED: u32 = 84_000_000;
-    pub const APB1_TIMER_FACTOR: u32 = 2;
-    pub const APB2_TIMER_FACTOR: u32 = 2;
-    pub const FLASH_LATENCY: u32 = 5;
+    // The max `HCLK` for each wait state (index) per supply voltage range, RM0090 page 80
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_1V8_TO_2V1: &[u32] = &[
+        20_000_000,
+        40_000_000,
+        60_000_000,
+        80_000_000,
+        100_000_000,
+        120_000_000,
+        140_000_000,
+        160_000_000,
+    ];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V1_TO_2V4: &[u32] = &[
+        22_000_000,
+        44_000_000,
+        66_000_000,
+        88_000_000,
+        110_000_000,
+        132_000_000,
+        154_000_000,
+        168_000_000,
+    ];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V4_TO_2V7: &[u32] = &[
+        24_000_000,
+        48_000_000,
+        72_000_000,
+        96_000_000,
+        120_000_000,
+        144_000_000,
+        168_000_000,
+    ];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V7_TO_3V6: &[u32] = &[
+        30_000_000,
+        60_000_000,
+        90_000_000,
+        120_000_000,
+        150_000_000,
+        168_000_000,
+    ];
 
     // Use HSI --> PLL as clock source and to max frequency
     pub const HSI_FREQUENCY: u32 = 16_000_000;
@@ -65,7 +105,7 @@ pub mod clock_source_selecting {
     pub const PLL_M_PRESCALER_FOR_HSI: u32 = 16; // 2 ≤PLLM ≤63
     pub const PLL_N_PRESCALER_FOR_HSI: u32 = 336; // 50 ≤PLLN ≤432
     pub const PLL_P_PRESCALER_FOR_HSI: u32 = 2; // PLLP = 2, 4, 6, or 8
-    pub const PLL_Q_PRESCALER_FOR_HSI: u32 = 4; // PLLQ with 2 ≤PLLQ ≤15
+    pub const PLL_Q_PRESCALER_FOR_HSI: u32 = 7; // PLLQ with 2 ≤PLLQ ≤15
 
     // Use HSE --> PLL as clock source and to max frequency
     pub const HSE_FREQUENCY: u32 = 8_000_000;
@@ -75,7 +115,7 @@ pub mod clock_source_selecting {
     pub const PLL_M_PRESCALER_FOR_HSE: u32 = 8; // 2 ≤PLLM ≤63
     pub const PLL_N_PRESCALER_FOR_HSE: u32 = 336; // 50 ≤PLLN ≤432
     pub const PLL_P_PRESCALER_FOR_HSE: u32 = 2; // PLLP = 2, 4, 6, or 8
-    pub const PLL_Q_PRESCALER_FOR_HSE: u32 = 4; // PLLQ with 2 ≤PLLQ ≤15
+    pub const PLL_Q_PRESCALER_FOR_HSE: u32 = 7; // PLLQ with 2 ≤PLLQ ≤15
 }
 
 #[cfg(feature = "use-weact-black-pill")]
@@ -83,9 +123,28 @@ pub mod clock_source_selecting {
     pub const SYS_CLOCK_MAX_SPEED: u32 = 100_000_000;
     pub const APB1_PERIPHERAL_MAX_SPEED: u32 = 50_000_000;
     pub const APB2_PERIPHERAL_MAX_SPEED: u32 = 100_000_000;
-    pub const APB1_TIMER_FACTOR: u32 = 2;
-    pub const APB2_TIMER_FACTOR: u32 = 1;
-    pub const FLASH_LATENCY: u32 = 3;
+    // The max `HCLK` for each wait state (index) per supply voltage range, RM0383 page 45
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_1V8_TO_2V1: &[u32] = &[
+        16_000_000,
+        32_000_000,
+        48_000_000,
+        64_000_000,
+        80_000_000,
+        96_000_000,
+        100_000_000,
+    ];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V1_TO_2V4: &[u32] = &[
+        18_000_000,
+        36_000_000,
+        54_000_000,
+        72_000_000,
+        90_000_000,
+        100_000_000,
+    ];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V4_TO_2V7: &[u32] =
+        &[24_000_000, 48_000_000, 72_000_000, 96_000_000, 100_000_000];
+    pub const FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V7_TO_3V6: &[u32] =
+        &[30_000_000, 64_000_000, 90_000_000, 100_000_000];
 
     // Use HSI --> PLL as clock source and to max frequency
     pub const HSI_FREQUENCY: u32 = 16_000_000;
@@ -95,7 +154,7 @@ pub mod clock_source_selecting {
     pub const PLL_M_PRESCALER_FOR_HSI: u32 = 16; // 2 ≤PLLM ≤63
     pub const PLL_N_PRESCALER_FOR_HSI: u32 = 200; // 50 ≤PLLN ≤432
     pub const PLL_P_PRESCALER_FOR_HSI: u32 = 2; // PLLP = 2, 4, 6, or 8
-    pub const PLL_Q_PRESCALER_FOR_HSI: u32 = 4; // PLLQ with 2 ≤PLLQ ≤15
+    pub const PLL_Q_PRESCALER_FOR_HSI: u32 = 5; // PLLQ with 2 ≤PLLQ ≤15
 
     // Use HSE --> PLL as clock source and to max frequency
     pub const HSE_FREQUENCY: u32 = 25_000_000;
@@ -105,7 +164,7 @@ pub mod clock_source_selecting {
     pub const PLL_M_PRESCALER_FOR_HSE: u32 = 25; // 2 ≤PLLM ≤63
     pub const PLL_N_PRESCALER_FOR_HSE: u32 = 200; // 50 ≤PLLN ≤432
     pub const PLL_P_PRESCALER_FOR_HSE: u32 = 2; // PLLP = 2, 4, 6, or 8
-    pub const PLL_Q_PRESCALER_FOR_HSE: u32 = 4; // PLLQ with 2 ≤PLLQ ≤15
+    pub const PLL_Q_PRESCALER_FOR_HSE: u32 = 5; // PLLQ with 2 ≤PLLQ ≤15
 }
 
 // ------ RCC registers address -------------------------------
@@ -0,0 +1,82 @@
+use crate::register;
+use crate::register_access::RegisterAccess;
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ Debug Exception and Monitor Control Register --------
+pub const DEMCR: u32 = 0xE000_EDFC; // ARMv7-M ARM, C1.6.5
+
+// ------ Data Watchpoint and Trace unit (DWT) ----------------
+pub const DWT_CTRL: u32 = 0xE000_1000; // ARMv7-M ARM, C1.8.7
+pub const DWT_CYCCNT: u32 = 0xE000_1004; // ARMv7-M ARM, C1.8.8
+
+register! {
+    /// Debug exception and monitor control register (DEMCR), only the trace enable bit,
+    /// ARMv7-M ARM C1.6.5
+    pub struct DemcrValue: "DEMCR" @ DEMCR, reset = 0x0000_0000, {
+        /// Global enable for the DWT and ITM units
+        trace_enabled, set_trace_enabled: TRCENA[24, 1] => bool;
+    }
+}
+
+register! {
+    /// DWT control register (DWT_CTRL), ARMv7-M ARM C1.8.7
+    pub struct DwtCtrlValue: "DWT_CTRL" @ DWT_CTRL, reset = 0x4000_0000, {
+        /// Cycle counter enable
+        cycle_counter_enabled, set_cycle_counter_enabled: CYCCNTENA[0, 1] => bool;
+        /// Read-only, the cycle counter is not implemented
+        no_cycle_counter, set_no_cycle_counter: NOCYCCNT[25, 1] => bool;
+        /// Read-only, the number of comparators
+        comparators, set_comparators: NUMCOMP[28, 4] => u32;
+    }
+}
+
+register! {
+    /// DWT cycle count register (DWT_CYCCNT), ARMv7-M ARM C1.8.8
+    pub struct DwtCyccntValue: "DWT_CYCCNT" @ DWT_CYCCNT, reset = 0x0000_0000, {
+        /// Increases on every processor clock cycle, wraps to `0` after `u32::MAX`
+        cycles, set_cycles: CYCCNT[0, 32] => u32;
+    }
+}
+
+pub struct DataWatchpointTrace {}
+
+///
+impl DataWatchpointTrace {
+    /// The DWT registers can't be written before `TRCENA` is set. The other `DEMCR`
+    /// bits belong to the debugger, they stay untouched.
+    pub fn enable_trace(registers: &impl RegisterAccess) {
+        DemcrValue::modify(registers, |r| r.set_trace_enabled(true));
+    }
+
+    /// Clear the cycle counter to `0` and start it
+    pub fn start_cycle_counter(registers: &impl RegisterAccess) {
+        DwtCyccntValue::reset().write(registers);
+        DwtCtrlValue::modify(registers, |r| r.set_cycle_counter_enabled(true));
+    }
+
+    /// The counter value is kept
+    pub fn stop_cycle_counter(registers: &impl RegisterAccess) {
+        DwtCtrlValue::modify(registers, |r| r.set_cycle_counter_enabled(false));
+    }
+
+    ///
+    pub fn is_cycle_counter_supported(registers: &impl RegisterAccess) -> bool {
+        !DwtCtrlValue::read(registers).no_cycle_counter()
+    }
+
+    ///
+    pub fn get_cycles(registers: &impl RegisterAccess) -> u32 {
+        DwtCyccntValue::read(registers).cycles()
+    }
+
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
+        let _ = hprintln!(
+            "\n[ Debug Exception and Monitor Control Register (DEMCR) ]: \n{:#?}\n\n[ DWT Control Register (DWT_CTRL) ]: \n{:#?}",
+            DemcrValue::read(registers),
+            DwtCtrlValue::read(registers)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::gpio_register::get_port_index;
+use crate::register;
+use crate::register_access::{Field, Register, RegisterAccess};
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ RCC APB2 peripheral clock enable register -----------
+pub const RCC_APB2ENR: u32 = 0x4002_3844; // page 248
+
+// ------ System configuration controller (SYSCFG) ------------
+pub const SYSCFG_EXTICR1: u32 = 0x4001_3808; // page 291, `EXTICR2` ~ `EXTICR4` follow
+
+// ------ External interrupt/event controller (EXTI) ----------
+pub const EXTI_IMR: u32 = 0x4001_3C00; // page 384
+pub const EXTI_EMR: u32 = 0x4001_3C04; // page 384
+pub const EXTI_RTSR: u32 = 0x4001_3C08; // page 385
+pub const EXTI_FTSR: u32 = 0x4001_3C0C; // page 385
+pub const EXTI_SWIER: u32 = 0x4001_3C10; // page 386
+pub const EXTI_PR: u32 = 0x4001_3C14; // page 386
+
+// ------ Nested vectored interrupt controller (NVIC) ---------
+pub const NVIC_ISER0: u32 = 0xE000_E100; // PM0214 page 210
+pub const NVIC_ICER0: u32 = 0xE000_E180; // PM0214 page 211
+pub const NVIC_ICPR0: u32 = 0xE000_E280; // PM0214 page 212
+
+/// Line 0 ~ 15 are the GPIO pins, a line is shared by the same pin number of all
+/// the ports (e.g. `PA0`, `PB0`, ...), only one port at a time
+pub const EXTI_GPIO_LINES: u8 = 16;
+
+// ------ EXTI interrupt numbers (IRQn), page 372 -------------
+pub const EXTI0_IRQ: u16 = 6;
+pub const EXTI1_IRQ: u16 = 7;
+pub const EXTI2_IRQ: u16 = 8;
+pub const EXTI3_IRQ: u16 = 9;
+pub const EXTI4_IRQ: u16 = 10;
+pub const EXTI9_5_IRQ: u16 = 23;
+pub const EXTI15_10_IRQ: u16 = 40;
+
+register! {
+    /// RCC APB2 peripheral clock enable register (RCC_APB2ENR), only the SYSCFG bit,
+    /// page 248
+    pub struct RccApb2enrValue: "RCC_APB2ENR" @ RCC_APB2ENR, reset = 0x0000_0000, {
+        /// System configuration controller clock enable, `EXTICR` needs it
+        syscfg_enabled, set_syscfg_enabled: SYSCFGEN[14, 1] => bool;
+    }
+}
+
+/// Which signal edge sets the pending bit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtiEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// The IRQ of a GPIO line, line 5 ~ 9 and line 10 ~ 15 share one IRQ
+pub const fn get_irq_number(line: u8) -> u16 {
+    match line {
+        0 => EXTI0_IRQ,
+        1 => EXTI1_IRQ,
+        2 => EXTI2_IRQ,
+        3 => EXTI3_IRQ,
+        4 => EXTI4_IRQ,
+        5..=9 => EXTI9_5_IRQ,
+        _ => EXTI15_10_IRQ,
+    }
+}
+
+/// The GPIO lines which `irq` serves, `None` if it's not an EXTI GPIO IRQ
+pub fn get_irq_lines(irq: u16) -> Option<core::ops::RangeInclusive<u8>> {
+    match irq {
+        EXTI0_IRQ..=EXTI4_IRQ => {
+            let line = (irq - EXTI0_IRQ) as u8;
+            Some(line..=line)
+        }
+        EXTI9_5_IRQ => Some(5..=9),
+        EXTI15_10_IRQ => Some(10..=15),
+        _ => None,
+    }
+}
+
+pub struct ExternalInterrupt {}
+
+///
+impl ExternalInterrupt {
+    /// `SYSCFG_EXTICR` can't be written before the SYSCFG clock is enabled, the other
+    /// APB2 peripherals keep their bits
+    pub fn enable_syscfg_clock(registers: &impl RegisterAccess) {
+        RccApb2enrValue::modify(registers, |r| r.set_syscfg_enabled(true));
+    }
+
+    /// Connect `line` to the same pin number of `port`, `EXTICR1` ~ `EXTICR4` have 4
+    /// lines each, 4 bits per line, page 291
+    pub fn route(registers: &impl RegisterAccess, line: u8, port: char) {
+        let (address, field) = Self::get_route_field(line);
+        Register::new(registers, address).write_field(field, get_port_index(port));
+    }
+
+    /// The port which `line` is connected to, `'A'` after reset
+    pub fn get_route(registers: &impl RegisterAccess, line: u8) -> char {
+        let (address, field) = Self::get_route_field(line);
+        (b'A' + Register::new(registers, address).read_field(field) as u8) as char
+    }
+
+    ///
+    pub fn set_edge(registers: &impl RegisterAccess, line: u8, edge: ExtiEdge) {
+        let rising = Register::new(registers, EXTI_RTSR);
+        let falling = Register::new(registers, EXTI_FTSR);
+
+        match edge {
+            ExtiEdge::Rising => {
+                rising.set_bits(1 << line);
+                falling.clear_bits(1 << line);
+            }
+            ExtiEdge::Falling => {
+                rising.clear_bits(1 << line);
+                falling.set_bits(1 << line);
+            }
+            ExtiEdge::Both => {
+                rising.set_bits(1 << line);
+                falling.set_bits(1 << line);
+            }
+        }
+    }
+
+    /// The interrupt request of `line` reaches the NVIC
+    pub fn unmask(registers: &impl RegisterAccess, line: u8) {
+        Register::new(registers, EXTI_IMR).set_bits(1 << line);
+    }
+
+    ///
+    pub fn mask(registers: &impl RegisterAccess, line: u8) {
+        Register::new(registers, EXTI_IMR).clear_bits(1 << line);
+    }
+
+    ///
+    pub fn is_masked(registers: &impl RegisterAccess, line: u8) -> bool {
+        !Register::new(registers, EXTI_IMR).is_set(1 << line)
+    }
+
+    ///
+    pub fn is_pending(registers: &impl RegisterAccess, line: u8) -> bool {
+        Register::new(registers, EXTI_PR).is_set(1 << line)
+    }
+
+    /// `PR` is cleared by writing `1`, no read-modify-write, otherwise the other pending
+    /// lines are cleared as well
+    pub fn clear_pending(registers: &impl RegisterAccess, line: u8) {
+        Register::new(registers, EXTI_PR).write(1 << line);
+    }
+
+    /// Set the pending bit of an unmasked line as if the edge happened
+    pub fn trigger_by_software(registers: &impl RegisterAccess, line: u8) {
+        Register::new(registers, EXTI_SWIER).write(1 << line);
+    }
+
+    /// `ISER`/`ICER`/`ICPR` are written with `1` for the IRQ only, `0` does nothing
+    pub fn enable_irq(registers: &impl RegisterAccess, irq: u16) {
+        Register::new(registers, Self::get_nvic_address(NVIC_ISER0, irq)).write(1 << (irq % 32));
+    }
+
+    ///
+    pub fn disable_irq(registers: &impl RegisterAccess, irq: u16) {
+        Register::new(registers, Self::get_nvic_address(NVIC_ICER0, irq)).write(1 << (irq % 32));
+    }
+
+    ///
+    pub fn is_irq_enabled(registers: &impl RegisterAccess, irq: u16) -> bool {
+        Register::new(registers, Self::get_nvic_address(NVIC_ISER0, irq)).is_set(1 << (irq % 32))
+    }
+
+    /// Drop the IRQ which was pending before the line is configured
+    pub fn clear_pending_irq(registers: &impl RegisterAccess, irq: u16) {
+        Register::new(registers, Self::get_nvic_address(NVIC_ICPR0, irq)).write(1 << (irq % 32));
+    }
+
+    fn get_route_field(line: u8) -> (u32, Field) {
+        let line = line % EXTI_GPIO_LINES;
+        (
+            SYSCFG_EXTICR1 + (line / 4) as u32 * 4,
+            Field::new((line % 4) * 4, 4),
+        )
+    }
+
+    fn get_nvic_address(register_0: u32, irq: u16) -> u32 {
+        register_0 + (irq / 32) as u32 * 4
+    }
+
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
+        let _ = hprintln!(
+            "\n[ EXTI ]: \nIMR: {:#034b}\nRTSR: {:#034b}\nFTSR: {:#034b}\nPR: {:#034b}\nSYSCFG_EXTICR1 ~ 4: {:#06x}, {:#06x}, {:#06x}, {:#06x}",
+            Register::new(registers, EXTI_IMR).read(),
+            Register::new(registers, EXTI_RTSR).read(),
+            Register::new(registers, EXTI_FTSR).read(),
+            Register::new(registers, EXTI_PR).read(),
+            Register::new(registers, SYSCFG_EXTICR1).read(),
+            Register::new(registers, SYSCFG_EXTICR1 + 4).read(),
+            Register::new(registers, SYSCFG_EXTICR1 + 8).read(),
+            Register::new(registers, SYSCFG_EXTICR1 + 12).read()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn routing_and_edges_only_change_their_own_line() {
+        let registers = RecordingRegisters::new();
+        registers.write(RCC_APB2ENR, 1 << 4);
+
+        ExternalInterrupt::enable_syscfg_clock(&registers);
+        assert_eq!(registers.get_value(RCC_APB2ENR), 1 << 4 | 1 << 14);
+
+        // PC13 is `EXTICR4` bit 4 ~ 7, PB0 is `EXTICR1` bit 0 ~ 3
+        ExternalInterrupt::route(&registers, 13, 'C');
+        ExternalInterrupt::route(&registers, 0, 'B');
+        assert_eq!(registers.get_value(SYSCFG_EXTICR1 + 12), 2 << 4);
+        assert_eq!(registers.get_value(SYSCFG_EXTICR1), 1);
+        assert_eq!(ExternalInterrupt::get_route(&registers, 13), 'C');
+        assert_eq!(ExternalInterrupt::get_route(&registers, 12), 'A');
+
+        ExternalInterrupt::set_edge(&registers, 0, ExtiEdge::Both);
+        ExternalInterrupt::set_edge(&registers, 13, ExtiEdge::Falling);
+        assert_eq!(registers.get_value(EXTI_RTSR), 1);
+        assert_eq!(registers.get_value(EXTI_FTSR), 1 | 1 << 13);
+        ExternalInterrupt::set_edge(&registers, 0, ExtiEdge::Rising);
+        assert_eq!(registers.get_value(EXTI_FTSR), 1 << 13);
+
+        assert!(ExternalInterrupt::is_masked(&registers, 13));
+        ExternalInterrupt::unmask(&registers, 13);
+        ExternalInterrupt::unmask(&registers, 0);
+        ExternalInterrupt::mask(&registers, 0);
+        assert_eq!(registers.get_value(EXTI_IMR), 1 << 13);
+    }
+
+    #[test]
+    fn pending_bits_and_nvic_are_write_one_only() {
+        let registers = RecordingRegisters::new();
+        registers.set_forced_bits(EXTI_PR, 1 << 3);
+        assert!(ExternalInterrupt::is_pending(&registers, 3));
+
+        ExternalInterrupt::clear_pending(&registers, 3);
+        ExternalInterrupt::enable_irq(&registers, get_irq_number(12));
+        ExternalInterrupt::disable_irq(&registers, get_irq_number(0));
+        assert_eq!(
+            registers.get_writes(),
+            vec![
+                (EXTI_PR, 1 << 3),
+                (NVIC_ISER0 + 4, 1 << 8),
+                (NVIC_ICER0, 1 << 6)
+            ]
+        );
+
+        assert_eq!(get_irq_number(7), EXTI9_5_IRQ);
+        assert_eq!(get_irq_lines(EXTI3_IRQ), Some(3..=3));
+        assert_eq!(get_irq_lines(EXTI15_10_IRQ), Some(10..=15));
+        assert_eq!(get_irq_lines(15), None);
+    }
+}
@@ -1,4 +1,8 @@
-use core::ptr;
+use crate::clock_utils::ClockConfigError;
+use crate::rcc_clock_settings::clock_source_selecting;
+use crate::register_access::RegisterAccess;
+use crate::{impl_field_value, register};
+use core::convert::TryFrom;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
@@ -7,26 +11,29 @@ use cortex_m_semihosting::hprintln;
 pub const FLASH_INTERFACE_REGISTER: u32 = 0x4002_3C00; // page 65
 pub const FLASH_ACR: u32 = FLASH_INTERFACE_REGISTER; // page 98
 
-// bit0 ~ bit2
-pub const FLASH_ACR_LATENCY_START_BIT: u8 = 0;
-pub const FLASH_ACR_LATENCY_BITS: u32 = 0b111;
-
-// bit8
-pub const FLASH_ACR_PREFETCH_ENABLE_START_BIT: u8 = 8;
-pub const FLASH_ACR_PREFETCH_ENABLE_BITS: u32 = 1 << 8;
-
-// bit9
-pub const FLASH_ACR_INSTRUCTION_CACHE_ENABLE_START_BIT: u8 = 9;
-pub const FLASH_ACR_INSTRUCTION_CACHE_ENABLE_BITS: u32 = 1 << 9;
+register! {
+    /// Flash access control register (FLASH_ACR), page 98
+    pub struct FlashAcrValue: "FLASH_ACR" @ FLASH_ACR, reset = 0x0000_0000, {
+        /// Latency, the ratio of the CPU clock period to the Flash memory access time
+        latency, set_latency: LATENCY[0, 3] => FlashReadLatency;
+        /// Prefetch enable
+        prefetch_enabled, set_prefetch_enabled: PRFTEN[8, 1] => bool;
+        /// Instruction cache enable
+        instruction_cache_enabled, set_instruction_cache_enabled: ICEN[9, 1] => bool;
+        /// Data cache enable
+        data_cache_enabled, set_data_cache_enabled: DCEN[10, 1] => bool;
+        /// Instruction cache reset
+        instruction_cache_reset, set_instruction_cache_reset: ICRST[11, 1] => bool;
+        /// Data cache reset
+        data_cache_reset, set_data_cache_reset: DCRST[12, 1] => bool;
+    }
+}
 
-// bit10
-pub const FLASH_ACR_DATA_CACHE_ENABLE_START_BIT: u8 = 10;
-pub const FLASH_ACR_DATA_CACHE_ENABLE_BITS: u32 = 1 << 10;
+impl_field_value!(FlashReadLatency);
 
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum FlashReadLatency {
-    WrongSetting,
     ZeroWaitState1CpuCycle,
     OneWaitState2CpuCycles,
     TwoWaitState3CpuCycles,
@@ -37,28 +44,43 @@ pub enum FlashReadLatency {
     SevenWaitState8CpuCycles,
 }
 
-/// From `u32` to `FlashReadLatency`
-impl From<u32> for FlashReadLatency {
-    fn from(value: u32) -> Self {
+/// From `u32` (wait states) to `FlashReadLatency`
+impl TryFrom<u32> for FlashReadLatency {
+    type Error = ClockConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
-            0 => FlashReadLatency::ZeroWaitState1CpuCycle,
-            1 => FlashReadLatency::OneWaitState2CpuCycles,
-            2 => FlashReadLatency::TwoWaitState3CpuCycles,
-            3 => FlashReadLatency::ThreeWaitState4CpuCycles,
-            4 => FlashReadLatency::FourWaitState5CpuCycles,
-            5 => FlashReadLatency::FiveWaitState6CpuCycles,
-            6 => FlashReadLatency::SixWaitState7CpuCycles,
-            7 => FlashReadLatency::SevenWaitState8CpuCycles,
-            _ => FlashReadLatency::WrongSetting,
+            0 => Ok(FlashReadLatency::ZeroWaitState1CpuCycle),
+            1 => Ok(FlashReadLatency::OneWaitState2CpuCycles),
+            2 => Ok(FlashReadLatency::TwoWaitState3CpuCycles),
+            3 => Ok(FlashReadLatency::ThreeWaitState4CpuCycles),
+            4 => Ok(FlashReadLatency::FourWaitState5CpuCycles),
+            5 => Ok(FlashReadLatency::FiveWaitState6CpuCycles),
+            6 => Ok(FlashReadLatency::SixWaitState7CpuCycles),
+            7 => Ok(FlashReadLatency::SevenWaitState8CpuCycles),
+            _ => Err(ClockConfigError::WrongFlashLatency(value)),
         }
     }
 }
 
 ///
 impl FlashReadLatency {
+    /// `LATENCY` is 3 bits, every value is a valid latency
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b111 {
+            0b000 => Self::ZeroWaitState1CpuCycle,
+            0b001 => Self::OneWaitState2CpuCycles,
+            0b010 => Self::TwoWaitState3CpuCycles,
+            0b011 => Self::ThreeWaitState4CpuCycles,
+            0b100 => Self::FourWaitState5CpuCycles,
+            0b101 => Self::FiveWaitState6CpuCycles,
+            0b110 => Self::SixWaitState7CpuCycles,
+            _ => Self::SevenWaitState8CpuCycles,
+        }
+    }
+
     pub fn to_register_bits(&self) -> u32 {
         match self {
-            Self::WrongSetting => 0b000,
             Self::ZeroWaitState1CpuCycle => 0b000,
             Self::OneWaitState2CpuCycles => 0b001,
             Self::TwoWaitState3CpuCycles => 0b010,
@@ -69,93 +91,267 @@ impl FlashReadLatency {
             Self::SevenWaitState8CpuCycles => 0b111,
         }
     }
+
+    /// The minimum latency for the given `HCLK` (unit in Hertz) and supply voltage,
+    /// `None` means `HCLK` is too high for the board at that voltage.
+    pub fn minimum_for_cpu_clock(
+        cpu_clock_frequency_in_hertz: u32,
+        supply_voltage: SupplyVoltage,
+    ) -> Option<Self> {
+        supply_voltage
+            .get_wait_state_max_cpu_clocks()
+            .iter()
+            .position(|&max_cpu_clock| cpu_clock_frequency_in_hertz <= max_cpu_clock)
+            .and_then(|wait_state| Self::try_from(wait_state as u32).ok())
+    }
+}
+
+/// The MCU supply voltage (VDD) range, the lower the voltage, the more wait states the
+/// flash needs for the same `HCLK`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupplyVoltage {
+    From1V8To2V1,
+    From2V1To2V4,
+    From2V4To2V7,
+    From2V7To3V6,
+}
+
+///
+impl SupplyVoltage {
+    /// The max `HCLK` for each wait state (index) in this voltage range
+    pub fn get_wait_state_max_cpu_clocks(&self) -> &'static [u32] {
+        match self {
+            Self::From1V8To2V1 => {
+                clock_source_selecting::FLASH_WAIT_STATE_MAX_CPU_CLOCKS_1V8_TO_2V1
+            }
+            Self::From2V1To2V4 => {
+                clock_source_selecting::FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V1_TO_2V4
+            }
+            Self::From2V4To2V7 => {
+                clock_source_selecting::FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V4_TO_2V7
+            }
+            Self::From2V7To3V6 => {
+                clock_source_selecting::FLASH_WAIT_STATE_MAX_CPU_CLOCKS_2V7_TO_3V6
+            }
+        }
+    }
 }
 
 ///
 pub struct FlashAccessControlRegister {}
 
 /// Alias
+#[allow(non_camel_case_types)]
 pub type FLASH_ACR = FlashAccessControlRegister;
 
 ///
 impl FlashAccessControlRegister {
+    /// Only the `LATENCY` field will be changed, prefetch and caches keep their state
+    pub fn set_flash_latency(registers: &impl RegisterAccess, flash_latency: FlashReadLatency) {
+        FlashAcrValue::modify(registers, |r| r.set_latency(flash_latency));
+    }
+
+    ///
+    pub fn enable_prefetch(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_prefetch_enabled(true));
+    }
+
+    ///
+    pub fn disable_prefetch(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_prefetch_enabled(false));
+    }
+
+    ///
+    pub fn is_prefetch_enabled(registers: &impl RegisterAccess) -> bool {
+        FlashAcrValue::read(registers).prefetch_enabled()
+    }
+
+    ///
+    pub fn enable_instruction_cache(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_instruction_cache_enabled(true));
+    }
+
+    ///
+    pub fn disable_instruction_cache(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_instruction_cache_enabled(false));
+    }
+
+    ///
+    pub fn is_instruction_cache_enabled(registers: &impl RegisterAccess) -> bool {
+        FlashAcrValue::read(registers).instruction_cache_enabled()
+    }
+
+    /// The instruction cache can only be reset while it's disabled, so it will be
+    /// disabled first, then `ICRST` is set and cleared, then it's enabled again if it
+    /// was enabled before.
+    pub fn reset_instruction_cache(registers: &impl RegisterAccess) {
+        let was_enabled = Self::is_instruction_cache_enabled(registers);
+
+        FlashAcrValue::modify(registers, |r| r.set_instruction_cache_enabled(false));
+        FlashAcrValue::modify(registers, |r| r.set_instruction_cache_reset(true));
+        FlashAcrValue::modify(registers, |r| r.set_instruction_cache_reset(false));
+
+        if was_enabled {
+            Self::enable_instruction_cache(registers);
+        }
+    }
+
     ///
-    pub fn set_flash_latency(wait_state: u32) {
-        let flash_acr_write_ptr = FLASH_ACR as *mut u32;
-
-        let flash_latency: FlashReadLatency = wait_state.into();
-        let flash_latency_bits = flash_latency.to_register_bits();
-
-        // #[cfg(feature = "enable-debug")]
-        // {
-        // hprintln!("wait_state: {:?}", wait_state);
-        // hprintln!("flash_latency: {:?}", flash_latency);
-        // hprintln!("flash_latency_bits: {:?}", flash_latency_bits);
-        // }
-
-        unsafe {
-            ptr::write_volatile(
-                flash_acr_write_ptr,
-                FLASH_ACR_INSTRUCTION_CACHE_ENABLE_BITS
-                    | FLASH_ACR_DATA_CACHE_ENABLE_BITS
-                    | FLASH_ACR_PREFETCH_ENABLE_BITS
-                    | flash_latency_bits << FLASH_ACR_LATENCY_START_BIT,
-            );
+    pub fn enable_data_cache(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_data_cache_enabled(true));
+    }
+
+    ///
+    pub fn disable_data_cache(registers: &impl RegisterAccess) {
+        FlashAcrValue::modify(registers, |r| r.set_data_cache_enabled(false));
+    }
+
+    ///
+    pub fn is_data_cache_enabled(registers: &impl RegisterAccess) -> bool {
+        FlashAcrValue::read(registers).data_cache_enabled()
+    }
+
+    /// The data cache can only be reset while it's disabled, so it will be disabled
+    /// first, then `DCRST` is set and cleared, then it's enabled again if it was
+    /// enabled before.
+    pub fn reset_data_cache(registers: &impl RegisterAccess) {
+        let was_enabled = Self::is_data_cache_enabled(registers);
+
+        FlashAcrValue::modify(registers, |r| r.set_data_cache_enabled(false));
+        FlashAcrValue::modify(registers, |r| r.set_data_cache_reset(true));
+        FlashAcrValue::modify(registers, |r| r.set_data_cache_reset(false));
+
+        if was_enabled {
+            Self::enable_data_cache(registers);
         }
     }
 
+    ///
+    pub fn get_flash_latency(registers: &impl RegisterAccess) -> FlashReadLatency {
+        FlashAcrValue::read(registers).latency()
+    }
+
     #[cfg(feature = "enable-debug")]
-    pub fn print_config() {
-        let flash_acr_read_ptr = FLASH_ACR as *const u32;
-        let flash_acr_register_value = unsafe { ptr::read_volatile(flash_acr_read_ptr) };
-
-        let flash_latency_bits = flash_acr_register_value & FLASH_ACR_LATENCY_BITS;
-        let flash_latency_value = match flash_latency_bits {
-            0b000 => FlashReadLatency::ZeroWaitState1CpuCycle,
-            0b001 => FlashReadLatency::OneWaitState2CpuCycles,
-            0b010 => FlashReadLatency::TwoWaitState3CpuCycles,
-            0b011 => FlashReadLatency::ThreeWaitState4CpuCycles,
-            0b100 => FlashReadLatency::FourWaitState5CpuCycles,
-            0b101 => FlashReadLatency::FiveWaitState6CpuCycles,
-            0b110 => FlashReadLatency::SixWaitState7CpuCycles,
-            0b111 => FlashReadLatency::SevenWaitState8CpuCycles,
-            _ => FlashReadLatency::WrongSetting,
-        };
-
-        let prefetch_bit = (flash_acr_register_value
-            & FLASH_ACR_PREFETCH_ENABLE_BITS >> FLASH_ACR_PREFETCH_ENABLE_START_BIT);
-        let prefetch_enabled = prefetch_bit == 1;
-
-        let instruction_cache_bit = (flash_acr_register_value
-            & FLASH_ACR_INSTRUCTION_CACHE_ENABLE_BITS)
-            >> FLASH_ACR_INSTRUCTION_CACHE_ENABLE_START_BIT;
-        let instruction_cache_enabled = instruction_cache_bit == 1;
-
-        let data_cache_bit = (flash_acr_register_value & FLASH_ACR_DATA_CACHE_ENABLE_BITS)
-            >> FLASH_ACR_DATA_CACHE_ENABLE_START_BIT;
-        let data_cache_enabled = data_cache_bit == 1;
-
-        let printing_header = "\n[ Flash access control register (FLASH_ACR) ]: \n";
+    pub fn print_config(registers: &impl RegisterAccess) {
         let _ = hprintln!(
-            "{}{}{}{}{}{}",
-            printing_header,
-            format_args!("FLASH_ACR value: {:#034b}", flash_acr_register_value),
-            format_args!(
-                "\nFalsh read latency: {:?},\t// bits: {:#05b}",
-                flash_latency_value, flash_latency_bits
-            ),
-            format_args!(
-                "\nPrefetch enabled: {:?},\t\t\t\t// bits: {:#02b}",
-                prefetch_enabled, prefetch_bit
-            ),
-            format_args!(
-                "\nInstruction cache enabled: {:?},\t\t// bits: {:#2b}",
-                instruction_cache_enabled, instruction_cache_bit
-            ),
-            format_args!(
-                "\nData cache enabled: {:?},\t\t\t// bits: {:#2b}",
-                data_cache_enabled, data_cache_bit
-            )
+            "\n[ Flash access control register (FLASH_ACR) ]: \n{:#?}",
+            FlashAcrValue::read(registers)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn set_flash_latency_keeps_prefetch_and_caches() {
+        let registers = RecordingRegisters::new();
+
+        FlashAccessControlRegister::set_flash_latency(
+            &registers,
+            FlashReadLatency::FiveWaitState6CpuCycles,
+        );
+        assert_eq!(registers.get_value(FLASH_ACR), 0b101);
+
+        FlashAccessControlRegister::enable_prefetch(&registers);
+        FlashAccessControlRegister::enable_data_cache(&registers);
+        FlashAccessControlRegister::set_flash_latency(
+            &registers,
+            FlashReadLatency::OneWaitState2CpuCycles,
+        );
+        assert_eq!(registers.get_value(FLASH_ACR), 1 << 10 | 1 << 8 | 0b001);
+        assert!(FlashAccessControlRegister::is_prefetch_enabled(&registers));
+        assert!(!FlashAccessControlRegister::is_instruction_cache_enabled(
+            &registers
+        ));
+        assert!(FlashAccessControlRegister::is_data_cache_enabled(
+            &registers
+        ));
+
+        FlashAccessControlRegister::disable_prefetch(&registers);
+        assert_eq!(registers.get_value(FLASH_ACR), 1 << 10 | 0b001);
+    }
+
+    #[test]
+    fn cache_reset_happens_while_the_cache_is_disabled() {
+        let registers = RecordingRegisters::new();
+        FlashAccessControlRegister::enable_instruction_cache(&registers);
+
+        FlashAccessControlRegister::reset_instruction_cache(&registers);
+        let writes: Vec<u32> = registers
+            .get_writes()
+            .iter()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(writes, vec![1 << 9, 0, 1 << 11, 0, 1 << 9]);
+
+        // Stays disabled if it was disabled
+        let registers = RecordingRegisters::new();
+        FlashAccessControlRegister::reset_data_cache(&registers);
+        assert_eq!(registers.get_value(FLASH_ACR), 0);
+        assert!(registers
+            .get_writes()
+            .iter()
+            .any(|(_, value)| *value == 1 << 12));
+    }
+
+    #[test]
+    fn minimum_latency_follows_the_supply_voltage() {
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(16_000_000, SupplyVoltage::From1V8To2V1),
+            Some(FlashReadLatency::ZeroWaitState1CpuCycle)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(30_000_000, SupplyVoltage::From2V7To3V6),
+            Some(FlashReadLatency::ZeroWaitState1CpuCycle)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(30_000_001, SupplyVoltage::From2V7To3V6),
+            Some(FlashReadLatency::OneWaitState2CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(48_000_000, SupplyVoltage::From2V4To2V7),
+            Some(FlashReadLatency::OneWaitState2CpuCycles)
+        );
+    }
+
+    #[cfg(feature = "use-stm32f407g-disc1")]
+    #[test]
+    fn stm32f407_wait_states() {
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(168_000_000, SupplyVoltage::From2V7To3V6),
+            Some(FlashReadLatency::FiveWaitState6CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(168_000_000, SupplyVoltage::From2V1To2V4),
+            Some(FlashReadLatency::SevenWaitState8CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(168_000_000, SupplyVoltage::From1V8To2V1),
+            None
+        );
+    }
+
+    #[cfg(feature = "use-weact-black-pill")]
+    #[test]
+    fn stm32f411_wait_states() {
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(100_000_000, SupplyVoltage::From2V7To3V6),
+            Some(FlashReadLatency::ThreeWaitState4CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(64_000_000, SupplyVoltage::From2V7To3V6),
+            Some(FlashReadLatency::OneWaitState2CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(100_000_000, SupplyVoltage::From1V8To2V1),
+            Some(FlashReadLatency::SixWaitState7CpuCycles)
+        );
+        assert_eq!(
+            FlashReadLatency::minimum_for_cpu_clock(101_000_000, SupplyVoltage::From2V7To3V6),
+            None
         );
     }
 }
@@ -0,0 +1,212 @@
+use crate::flash_access_control_register::{SupplyVoltage, FLASH_INTERFACE_REGISTER};
+use crate::flash_utils::FlashError;
+use crate::register_access::RegisterAccess;
+use crate::{impl_field_value, register};
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ Flash key, status and control registers -------------
+pub const FLASH_KEYR: u32 = FLASH_INTERFACE_REGISTER + 0x04; // page 98
+pub const FLASH_SR: u32 = FLASH_INTERFACE_REGISTER + 0x0C; // page 99
+pub const FLASH_CR: u32 = FLASH_INTERFACE_REGISTER + 0x10; // page 100
+
+// Write `KEY1` then `KEY2` to `FLASH_KEYR` to unlock `FLASH_CR`, any other value locks
+// `FLASH_CR` until the next reset, page 84
+pub const FLASH_KEY1: u32 = 0x4567_0123;
+pub const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+register! {
+    /// Flash status register (FLASH_SR), page 99
+    pub struct FlashSrValue: "FLASH_SR" @ FLASH_SR, reset = 0x0000_0000, {
+        /// End of operation, only set when `EOPIE` is enabled
+        end_of_operation, set_end_of_operation: EOP[0, 1] => bool;
+        /// Operation error, only set when `ERRIE` is enabled
+        operation_error, set_operation_error: OPERR[1, 1] => bool;
+        /// Write protection error
+        write_protection_error, set_write_protection_error: WRPERR[4, 1] => bool;
+        /// Programming alignment error
+        programming_alignment_error, set_programming_alignment_error: PGAERR[5, 1] => bool;
+        /// Programming parallelism error
+        programming_parallelism_error, set_programming_parallelism_error: PGPERR[6, 1] => bool;
+        /// Programming sequence error
+        programming_sequence_error, set_programming_sequence_error: PGSERR[7, 1] => bool;
+        /// A flash memory operation is in progress
+        busy, set_busy: BSY[16, 1] => bool;
+    }
+}
+
+/// All the flags which are cleared by writing `1`
+pub const FLASH_SR_CLEAR_FLAGS: u32 = FlashSrValue::EOP.mask()
+    | FlashSrValue::OPERR.mask()
+    | FlashSrValue::WRPERR.mask()
+    | FlashSrValue::PGAERR.mask()
+    | FlashSrValue::PGPERR.mask()
+    | FlashSrValue::PGSERR.mask();
+
+register! {
+    /// Flash control register (FLASH_CR), page 100
+    pub struct FlashCrValue: "FLASH_CR" @ FLASH_CR, reset = 0x8000_0000, {
+        /// Flash programming activated
+        programming, set_programming: PG[0, 1] => bool;
+        /// Sector erase activated
+        sector_erase, set_sector_erase: SER[1, 1] => bool;
+        /// Erase all user sectors
+        mass_erase, set_mass_erase: MER[2, 1] => bool;
+        /// The sector to erase
+        sector_number, set_sector_number: SNB[3, 5] => u32;
+        /// Program size, it has to match the supply voltage
+        program_size, set_program_size: PSIZE[8, 2] => FlashParallelism;
+        /// Start the erase operation
+        start, set_start: STRT[16, 1] => bool;
+        /// End of operation interrupt enable
+        end_of_operation_interrupt, set_end_of_operation_interrupt: EOPIE[24, 1] => bool;
+        /// Error interrupt enable
+        error_interrupt, set_error_interrupt: ERRIE[25, 1] => bool;
+        /// `FLASH_CR` is locked, only the unlock sequence can clear it
+        locked, set_locked: LOCK[31, 1] => bool;
+    }
+}
+
+impl_field_value!(FlashParallelism);
+
+/// How many bits are programmed at once, page 85
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashParallelism {
+    X8,
+    X16,
+    X32,
+    /// Needs the external `VPP` supply
+    X64,
+}
+
+///
+impl FlashParallelism {
+    /// The max parallelism for the supply voltage range without external `VPP`
+    pub fn for_supply_voltage(supply_voltage: SupplyVoltage) -> Self {
+        match supply_voltage {
+            SupplyVoltage::From1V8To2V1 => Self::X8,
+            SupplyVoltage::From2V1To2V4 | SupplyVoltage::From2V4To2V7 => Self::X16,
+            SupplyVoltage::From2V7To3V6 => Self::X32,
+        }
+    }
+
+    /// `PSIZE` is 2 bits, every value is a valid parallelism
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::X8,
+            0b01 => Self::X16,
+            0b10 => Self::X32,
+            _ => Self::X64,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::X8 => 0b00,
+            Self::X16 => 0b01,
+            Self::X32 => 0b10,
+            Self::X64 => 0b11,
+        }
+    }
+
+    /// How many bytes are programmed by each write
+    pub fn get_size_in_bytes(&self) -> u32 {
+        match self {
+            Self::X8 => 1,
+            Self::X16 => 2,
+            Self::X32 => 4,
+            Self::X64 => 8,
+        }
+    }
+}
+
+///
+pub struct FlashControlRegister {}
+
+///
+impl FlashControlRegister {
+    /// Write the key sequence to `FLASH_KEYR`, nothing happens if it's unlocked already
+    pub fn unlock(registers: &impl RegisterAccess) -> Result<(), FlashError> {
+        if !Self::is_locked(registers) {
+            return Ok(());
+        }
+
+        registers.write(FLASH_KEYR, FLASH_KEY1);
+        registers.write(FLASH_KEYR, FLASH_KEY2);
+
+        if Self::is_locked(registers) {
+            Err(FlashError::UnlockFailed)
+        } else {
+            Ok(())
+        }
+    }
+
+    ///
+    pub fn lock(registers: &impl RegisterAccess) {
+        FlashCrValue::modify(registers, |r| r.set_locked(true));
+    }
+
+    ///
+    pub fn is_locked(registers: &impl RegisterAccess) -> bool {
+        FlashCrValue::read(registers).locked()
+    }
+
+    /// Give up after polling `BSY` `max_wait_cycles` times
+    pub fn wait_while_busy(
+        registers: &impl RegisterAccess,
+        max_wait_cycles: u32,
+    ) -> Result<(), FlashError> {
+        for _ in 0..max_wait_cycles {
+            if !FlashSrValue::read(registers).busy() {
+                return Ok(());
+            }
+
+            registers.delay(1);
+        }
+
+        Err(FlashError::BusyTimeout)
+    }
+
+    /// Clear `EOP` and all the error flags left by the previous operation
+    pub fn clear_flags(registers: &impl RegisterAccess) {
+        // `rc_w1` bits, writing `0` keeps them untouched, so don't read-modify-write
+        FlashSrValue::from_bits(FLASH_SR_CLEAR_FLAGS).write(registers);
+    }
+
+    /// Turn the error flags into `FlashError` and clear them
+    pub fn check_errors(registers: &impl RegisterAccess) -> Result<(), FlashError> {
+        let status = FlashSrValue::read(registers);
+
+        let error = if status.write_protection_error() {
+            Some(FlashError::WriteProtectionError)
+        } else if status.programming_alignment_error() {
+            Some(FlashError::ProgrammingAlignmentError)
+        } else if status.programming_parallelism_error() {
+            Some(FlashError::ProgrammingParallelismError)
+        } else if status.programming_sequence_error() {
+            Some(FlashError::ProgrammingSequenceError)
+        } else if status.operation_error() {
+            Some(FlashError::OperationError)
+        } else {
+            None
+        };
+
+        match error {
+            Some(error) => {
+                Self::clear_flags(registers);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
+        let _ = hprintln!(
+            "\n[ Flash status register (FLASH_SR) ]: \n{:#?}\n\n[ Flash control register (FLASH_CR) ]: \n{:#?}",
+            FlashSrValue::read(registers),
+            FlashCrValue::read(registers)
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::flash_access_control_register::{SupplyVoltage, FLASH_INTERFACE_REGISTER};
+use crate::flash_utils::FlashError;
+use crate::register_access::RegisterAccess;
+use crate::{impl_field_value, register};
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ Flash option key and option control registers -------
+pub const FLASH_OPTKEYR: u32 = FLASH_INTERFACE_REGISTER + 0x08; // page 99
+pub const FLASH_OPTCR: u32 = FLASH_INTERFACE_REGISTER + 0x14; // page 102
+
+// Write `OPTKEY1` then `OPTKEY2` to `FLASH_OPTKEYR` to unlock `FLASH_OPTCR`, any other
+// value locks `FLASH_OPTCR` until the next reset, page 86
+pub const FLASH_OPTKEY1: u32 = 0x0819_2A3B;
+pub const FLASH_OPTKEY2: u32 = 0x4C5D_6E7F;
+
+/// Every sector has one `nWRP` bit (12 on F407, 8 on F411), `0` means write protected
+pub const MAX_WRITE_PROTECTION_SECTORS: u8 = 12;
+
+register! {
+    /// Flash option control register (FLASH_OPTCR), page 102. The values are loaded
+    /// from the option bytes after reset.
+    pub struct FlashOptcrValue: "FLASH_OPTCR" @ FLASH_OPTCR, reset = 0x0FFF_AAED, {
+        /// `FLASH_OPTCR` is locked, only the unlock sequence can clear it
+        option_locked, set_option_locked: OPTLOCK[0, 1] => bool;
+        /// Start programming the option bytes
+        option_start, set_option_start: OPTSTRT[1, 1] => bool;
+        /// Brownout reset threshold
+        bor_level, set_bor_level: BOR_LEV[2, 2] => BorLevel;
+        /// `1`: software watchdog, `0`: hardware watchdog (IWDG starts after reset)
+        software_watchdog, set_software_watchdog: WDG_SW[5, 1] => bool;
+        /// `1`: no reset when entering Stop mode
+        no_reset_on_stop, set_no_reset_on_stop: NRST_STOP[6, 1] => bool;
+        /// `1`: no reset when entering Standby mode
+        no_reset_on_standby, set_no_reset_on_standby: NRST_STDBY[7, 1] => bool;
+        /// Read protection
+        read_protection, set_read_protection: RDP[8, 8] => RdpLevel;
+        /// One bit per sector, `0` means write protected
+        not_write_protected_sectors, set_not_write_protected_sectors: NWRP[16, 12] => u32;
+    }
+}
+
+impl_field_value!(BorLevel);
+impl_field_value!(RdpLevel);
+
+/// Brownout reset threshold (`BOR_LEV`), page 88
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BorLevel {
+    /// 2.70V ~ 3.60V
+    Level3,
+    /// 2.40V ~ 2.70V
+    Level2,
+    /// 2.10V ~ 2.40V
+    Level1,
+    /// Only the power on/down reset (POR/PDR), 1.80V ~ 2.10V
+    Off,
+}
+
+///
+impl BorLevel {
+    /// The highest threshold below the supply voltage range
+    pub fn for_supply_voltage(supply_voltage: SupplyVoltage) -> Self {
+        match supply_voltage {
+            SupplyVoltage::From1V8To2V1 => Self::Off,
+            SupplyVoltage::From2V1To2V4 => Self::Level1,
+            SupplyVoltage::From2V4To2V7 => Self::Level2,
+            SupplyVoltage::From2V7To3V6 => Self::Level3,
+        }
+    }
+
+    /// `BOR_LEV` is 2 bits, every value is a valid level
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::Level3,
+            0b01 => Self::Level2,
+            0b10 => Self::Level1,
+            _ => Self::Off,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::Level3 => 0b00,
+            Self::Level2 => 0b01,
+            Self::Level1 => 0b10,
+            Self::Off => 0b11,
+        }
+    }
+}
+
+/// Read protection (`RDP`), page 89
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RdpLevel {
+    /// `0xAA`: no protection
+    Level0,
+    /// Any other value: no flash access from the debugger or the system memory boot.
+    /// Going back to `Level0` mass erases the flash.
+    Level1,
+    /// `0xCC`: the debug interface is disabled, the option bytes are frozen.
+    /// It's permanent, the chip can never go back to `Level1` or `Level0`!!!
+    Level2,
+}
+
+///
+impl RdpLevel {
+    /// Only `0xAA` and `0xCC` have their own levels
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0xFF {
+            0xAA => Self::Level0,
+            0xCC => Self::Level2,
+            _ => Self::Level1,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::Level0 => 0xAA,
+            Self::Level1 => 0x55,
+            Self::Level2 => 0xCC,
+        }
+    }
+}
+
+///
+impl FlashOptcrValue {
+    ///
+    pub fn is_sector_write_protected(&self, sector_number: u8) -> bool {
+        sector_number < MAX_WRITE_PROTECTION_SECTORS
+            && self.not_write_protected_sectors() & (1 << sector_number) == 0
+    }
+
+    /// Clear the sector `nWRP` bit to protect it, sectors out of range are ignored
+    pub fn set_sector_write_protected(self, sector_number: u8, protected: bool) -> Self {
+        if sector_number >= MAX_WRITE_PROTECTION_SECTORS {
+            return self;
+        }
+
+        let bits = self.not_write_protected_sectors();
+        self.set_not_write_protected_sectors(if protected {
+            bits & !(1 << sector_number)
+        } else {
+            bits | 1 << sector_number
+        })
+    }
+}
+
+///
+pub struct FlashOptionControlRegister {}
+
+///
+impl FlashOptionControlRegister {
+    /// Write the key sequence to `FLASH_OPTKEYR`, nothing happens if it's unlocked
+    /// already
+    pub fn unlock(registers: &impl RegisterAccess) -> Result<(), FlashError> {
+        if !Self::is_locked(registers) {
+            return Ok(());
+        }
+
+        registers.write(FLASH_OPTKEYR, FLASH_OPTKEY1);
+        registers.write(FLASH_OPTKEYR, FLASH_OPTKEY2);
+
+        if Self::is_locked(registers) {
+            Err(FlashError::UnlockFailed)
+        } else {
+            Ok(())
+        }
+    }
+
+    ///
+    pub fn lock(registers: &impl RegisterAccess) {
+        FlashOptcrValue::modify(registers, |r| r.set_option_locked(true));
+    }
+
+    ///
+    pub fn is_locked(registers: &impl RegisterAccess) -> bool {
+        FlashOptcrValue::read(registers).option_locked()
+    }
+
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
+        let value = FlashOptcrValue::read(registers);
+
+        let _ = hprintln!(
+            "\n[ Flash option control register (FLASH_OPTCR) ]: \n{:#?}",
+            value
+        );
+
+        let _ = hprintln!("Write protected sectors:");
+        for sector_number in 0..MAX_WRITE_PROTECTION_SECTORS {
+            if value.is_sector_write_protected(sector_number) {
+                let _ = hprintln!("    - sector {}", sector_number);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_option_bytes() {
+        let value = FlashOptcrValue::reset();
+        assert!(value.option_locked());
+        assert_eq!(value.bor_level(), BorLevel::Off);
+        assert_eq!(value.read_protection(), RdpLevel::Level0);
+        assert!(value.no_reset_on_stop() && value.no_reset_on_standby());
+        assert_eq!(value.not_write_protected_sectors(), 0xFFF);
+
+        // Any value but `0xAA` and `0xCC` is level 1
+        assert_eq!(RdpLevel::from_register_bits(0x00), RdpLevel::Level1);
+        assert_eq!(RdpLevel::from_register_bits(0xCC), RdpLevel::Level2);
+
+        let value = value
+            .set_sector_write_protected(11, true)
+            .set_sector_write_protected(12, true)
+            .set_bor_level(BorLevel::for_supply_voltage(SupplyVoltage::From2V7To3V6));
+        assert_eq!(value.bits(), 0x07FF_AAE1);
+        assert!(value.is_sector_write_protected(11));
+        assert!(!value.is_sector_write_protected(12));
+        assert!(!value
+            .set_sector_write_protected(11, false)
+            .is_sector_write_protected(11));
+    }
+}
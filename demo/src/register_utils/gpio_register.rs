@@ -0,0 +1,746 @@
+use crate::register_access::{Field, Mmio, Register, RegisterAccess};
+use crate::{impl_field_value, register};
+use core::convert::Infallible;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU16, Ordering};
+use embedded_hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin, ToggleableOutputPin};
+
+#[cfg(feature = "enable-debug")]
+use cortex_m_semihosting::hprintln;
+
+// ------ RCC AHB1 peripheral clock enable register -----------
+pub const RCC_AHB1ENR: u32 = 0x4002_3830; // page 242, 243
+
+// ------ GPIO registers --------------------------------------
+pub const GPIOA: u32 = 0x4002_0000; // page 65
+pub const GPIO_PORT_SIZE: u32 = 0x400; // GPIOB = 0x4002_0400, ..., GPIOI = 0x4002_2000
+
+pub const GPIO_MODER_OFFSET: u32 = 0x00; // page 281
+pub const GPIO_OTYPER_OFFSET: u32 = 0x04; // page 281
+pub const GPIO_OSPEEDR_OFFSET: u32 = 0x08; // page 282
+pub const GPIO_PUPDR_OFFSET: u32 = 0x0C; // page 282
+pub const GPIO_IDR_OFFSET: u32 = 0x10; // page 283
+pub const GPIO_ODR_OFFSET: u32 = 0x14; // page 283
+pub const GPIO_BSRR_OFFSET: u32 = 0x18; // page 284
+pub const GPIO_LCKR_OFFSET: u32 = 0x1C; // page 284
+pub const GPIO_AFRL_OFFSET: u32 = 0x20; // page 285
+pub const GPIO_AFRH_OFFSET: u32 = 0x24; // page 286
+
+/// `LCKR` lock key bit, page 284
+pub const GPIO_LCKR_LCKK: u32 = 1 << 16;
+
+register! {
+    /// RCC AHB1 peripheral clock enable register (RCC_AHB1ENR), only the GPIO port
+    /// bits, page 242
+    pub struct RccAhb1enrValue: "RCC_AHB1ENR" @ RCC_AHB1ENR, reset = 0x0010_0000, {
+        /// One bit per port, bit0 is `GPIOA` and bit8 is `GPIOI`
+        gpio_enabled_ports, set_gpio_enabled_ports: GPIOEN[0, 9] => u32;
+    }
+}
+
+impl_field_value!(GpioMode, GpioOutputType, GpioSpeed, GpioPull);
+
+/// `MODER` 2 bits per pin, page 281
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioMode {
+    Input,
+    Output,
+    Alternate,
+    Analog,
+}
+
+///
+impl GpioMode {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::Input,
+            0b01 => Self::Output,
+            0b10 => Self::Alternate,
+            _ => Self::Analog,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::Input => 0b00,
+            Self::Output => 0b01,
+            Self::Alternate => 0b10,
+            Self::Analog => 0b11,
+        }
+    }
+}
+
+/// `OTYPER` 1 bit per pin, page 281
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioOutputType {
+    PushPull,
+    OpenDrain,
+}
+
+///
+impl GpioOutputType {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b1 {
+            0 => Self::PushPull,
+            _ => Self::OpenDrain,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::PushPull => 0,
+            Self::OpenDrain => 1,
+        }
+    }
+}
+
+/// `OSPEEDR` 2 bits per pin, page 282. The max frequency depends on the supply voltage
+/// and the load, e.g. `VeryHigh` is 100Mhz at 2.7V ~ 3.6V with 30pF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioSpeed {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+///
+impl GpioSpeed {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::Low,
+            0b01 => Self::Medium,
+            0b10 => Self::High,
+            _ => Self::VeryHigh,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::Low => 0b00,
+            Self::Medium => 0b01,
+            Self::High => 0b10,
+            Self::VeryHigh => 0b11,
+        }
+    }
+}
+
+/// `PUPDR` 2 bits per pin, page 282
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpioPull {
+    None,
+    Up,
+    Down,
+}
+
+///
+impl GpioPull {
+    /// `0b11` is reserved, treat it as no pull
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b01 => Self::Up,
+            0b10 => Self::Down,
+            _ => Self::None,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::None => 0b00,
+            Self::Up => 0b01,
+            Self::Down => 0b10,
+        }
+    }
+}
+
+/// Which pin level means "on" for a LED or "pressed" for a button
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActiveLevel {
+    /// E.g. the Discovery user button, pressed connects the pin to VDD
+    High,
+    /// E.g. the Black Pill KEY button, pressed connects the pin to GND
+    Low,
+}
+
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum GpioError {
+    /// The `LCKR` lock key sequence didn't lock the port, page 284
+    LockFailed,
+}
+
+/// `'A'` is `0` and `'I'` is `8`, any other port fails to compile
+#[cfg(not(feature = "use-weact-black-pill"))]
+pub const fn get_port_index(port: char) -> u32 {
+    match port {
+        'A'..='I' => port as u32 - 'A' as u32,
+        _ => panic!("GPIO port must be 'A' ~ 'I'"),
+    }
+}
+
+/// The STM32F411 has no port F, G and I (RM0383 page 38), they fail to compile
+/// instead of bus faulting
+#[cfg(feature = "use-weact-black-pill")]
+pub const fn get_port_index(port: char) -> u32 {
+    match port {
+        'A'..='E' | 'H' => port as u32 - 'A' as u32,
+        _ => panic!("GPIO port must be 'A' ~ 'E' or 'H' on the STM32F411"),
+    }
+}
+
+// One bit per port, set by `GpioPort::take()`
+#[cfg(not(test))]
+static TAKEN_PORTS: AtomicU16 = AtomicU16::new(0);
+
+// Every test runs in its own thread, so every test starts with all the ports free
+#[cfg(test)]
+std::thread_local! {
+    static TAKEN_PORTS: AtomicU16 = AtomicU16::new(0);
+}
+
+/// `false` if the port is taken already
+fn try_take_port(index: u32) -> bool {
+    let bit = 1 << index;
+
+    #[cfg(not(test))]
+    let taken_ports = TAKEN_PORTS.fetch_or(bit, Ordering::AcqRel);
+    #[cfg(test)]
+    let taken_ports = TAKEN_PORTS.with(|ports| ports.fetch_or(bit, Ordering::AcqRel));
+
+    taken_ports & bit == 0
+}
+
+/// The port-wide registers of one GPIO port, `PORT` is `'A'` ~ `'I'`. It comes with
+/// the 16 pins from `take()`, the pins and the port can only be taken once.
+///
+/// `take()` turns on the port clock in `RCC_AHB1ENR`, the other ports (and DMA, CRC
+/// etc.) keep their bits.
+///
+/// ```
+/// let gpiod = GpioPort::<'D'>::take(Mmio).unwrap();
+/// let mut green_led = gpiod.p12.into_push_pull_output();
+/// green_led.set_high();
+/// gpiod.port.set_and_reset_pins(1 << 13, 0);
+/// ```
+pub struct GpioPort<const PORT: char, R: RegisterAccess = Mmio> {
+    registers: R,
+}
+
+///
+impl<const PORT: char, R: RegisterAccess> GpioPort<PORT, R> {
+    pub const INDEX: u32 = get_port_index(PORT);
+    pub const BASE_ADDRESS: u32 = GPIOA + Self::INDEX * GPIO_PORT_SIZE;
+
+    /// Enable the port clock, then hand out the port and its 16 pins. `None` if the
+    /// port is taken already, so every pin is handed out only once. A pin is untouched
+    /// until it's converted into a mode, e.g. `PA13`/`PA14` are the SWD pins
+    /// (alternate) after reset.
+    pub fn take(registers: R) -> Option<GpioPins<PORT, R>>
+    where
+        R: Clone,
+    {
+        if !try_take_port(Self::INDEX) {
+            return None;
+        }
+
+        Self::enable(&registers);
+
+        Some(GpioPins {
+            p0: Pin::new(registers.clone()),
+            p1: Pin::new(registers.clone()),
+            p2: Pin::new(registers.clone()),
+            p3: Pin::new(registers.clone()),
+            p4: Pin::new(registers.clone()),
+            p5: Pin::new(registers.clone()),
+            p6: Pin::new(registers.clone()),
+            p7: Pin::new(registers.clone()),
+            p8: Pin::new(registers.clone()),
+            p9: Pin::new(registers.clone()),
+            p10: Pin::new(registers.clone()),
+            p11: Pin::new(registers.clone()),
+            p12: Pin::new(registers.clone()),
+            p13: Pin::new(registers.clone()),
+            p14: Pin::new(registers.clone()),
+            p15: Pin::new(registers.clone()),
+            port: GpioPort { registers },
+        })
+    }
+
+    /// Enable the port clock, then the port registers can be accessed
+    fn enable(registers: &R) {
+        let enabled_ports = RccAhb1enrValue::read(registers).gpio_enabled_ports();
+        RccAhb1enrValue::modify(registers, |r| {
+            r.set_gpio_enabled_ports(enabled_ports | 1 << Self::INDEX)
+        });
+
+        // The peripheral clock is active 2 AHB cycles after the enable bit is set, the
+        // read back waits for that (the "delay after an RCC peripheral clock
+        // enabling" errata)
+        let _ = RccAhb1enrValue::read(registers);
+    }
+
+    /// `false` after reset, except the ports which `take()` or other code enabled
+    pub fn is_enabled(&self) -> bool {
+        RccAhb1enrValue::read(&self.registers).gpio_enabled_ports() & 1 << Self::INDEX != 0
+    }
+
+    /// All 16 pins from `IDR`
+    pub fn read_input(&self) -> u16 {
+        get_register(&self.registers, PORT, GPIO_IDR_OFFSET).read() as u16
+    }
+
+    /// All 16 pins from `ODR`
+    pub fn read_output(&self) -> u16 {
+        get_register(&self.registers, PORT, GPIO_ODR_OFFSET).read() as u16
+    }
+
+    /// Set and reset the pins in one atomic `BSRR` write, the pins which are not in
+    /// either mask stay untouched. Set wins if a pin is in both masks.
+    pub fn set_and_reset_pins(&self, set_pins: u16, reset_pins: u16) {
+        get_register(&self.registers, PORT, GPIO_BSRR_OFFSET)
+            .write((reset_pins as u32) << 16 | set_pins as u32);
+    }
+
+    /// Freeze `MODER`, `OTYPER`, `OSPEEDR`, `PUPDR` and `AFRL/AFRH` of the `pins` until
+    /// the next reset, page 284
+    pub fn lock(&self, pins: u16) -> Result<(), GpioError> {
+        let register = get_register(&self.registers, PORT, GPIO_LCKR_OFFSET);
+
+        // Write 1, write 0, write 1, read, then read `LCKK` again to confirm
+        register.write(GPIO_LCKR_LCKK | pins as u32);
+        register.write(pins as u32);
+        register.write(GPIO_LCKR_LCKK | pins as u32);
+        let _ = register.read();
+
+        if self.is_locked() {
+            Ok(())
+        } else {
+            Err(GpioError::LockFailed)
+        }
+    }
+
+    ///
+    pub fn is_locked(&self) -> bool {
+        get_register(&self.registers, PORT, GPIO_LCKR_OFFSET).is_set(GPIO_LCKR_LCKK)
+    }
+
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(&self) {
+        let _ = hprintln!(
+            "\n[ GPIO{} ]: \nMODER: {:#034b}\nOTYPER: {:#034b}\nOSPEEDR: {:#034b}\nPUPDR: {:#034b}\nIDR: {:#034b}\nODR: {:#034b}\nLCKR: {:#034b}\nAFRL: {:#034b}\nAFRH: {:#034b}",
+            PORT,
+            get_register(&self.registers, PORT, GPIO_MODER_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_OTYPER_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_OSPEEDR_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_PUPDR_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_IDR_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_ODR_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_LCKR_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_AFRL_OFFSET).read(),
+            get_register(&self.registers, PORT, GPIO_AFRH_OFFSET).read()
+        );
+    }
+}
+
+/// What `GpioPort::take()` hands out, move the pins out one by one, the port stays
+/// usable:
+///
+/// ```
+/// let gpioa = GpioPort::<'A'>::take(Mmio).unwrap();
+/// let tx = gpioa.p2.into_alternate::<7>();
+/// let key = gpioa.p0.into_input(GpioPull::Up);
+/// let input = gpioa.port.read_input();
+/// ```
+pub struct GpioPins<const PORT: char, R: RegisterAccess = Mmio> {
+    pub p0: Pin<PORT, 0, Unconfigured, R>,
+    pub p1: Pin<PORT, 1, Unconfigured, R>,
+    pub p2: Pin<PORT, 2, Unconfigured, R>,
+    pub p3: Pin<PORT, 3, Unconfigured, R>,
+    pub p4: Pin<PORT, 4, Unconfigured, R>,
+    pub p5: Pin<PORT, 5, Unconfigured, R>,
+    pub p6: Pin<PORT, 6, Unconfigured, R>,
+    pub p7: Pin<PORT, 7, Unconfigured, R>,
+    pub p8: Pin<PORT, 8, Unconfigured, R>,
+    pub p9: Pin<PORT, 9, Unconfigured, R>,
+    pub p10: Pin<PORT, 10, Unconfigured, R>,
+    pub p11: Pin<PORT, 11, Unconfigured, R>,
+    pub p12: Pin<PORT, 12, Unconfigured, R>,
+    pub p13: Pin<PORT, 13, Unconfigured, R>,
+    pub p14: Pin<PORT, 14, Unconfigured, R>,
+    pub p15: Pin<PORT, 15, Unconfigured, R>,
+    pub port: GpioPort<PORT, R>,
+}
+
+fn get_register<R: RegisterAccess>(registers: &R, port: char, offset: u32) -> Register<'_, R> {
+    Register::new(
+        registers,
+        GPIOA + get_port_index(port) * GPIO_PORT_SIZE + offset,
+    )
+}
+
+// ------ Pin modes (type-state) ------------------------------
+
+/// Not converted into any mode yet, the pin keeps what it is
+pub struct Unconfigured;
+
+///
+pub struct Input;
+
+/// Push-pull or open-drain
+pub struct Output;
+
+/// Alternate function `AF` (`0` ~ `15`), page 272
+pub struct Alternate<const AF: u8>;
+
+///
+impl<const AF: u8> Alternate<AF> {
+    /// The 4 bits value in `AFRL`/`AFRH`, any function above 15 fails to compile
+    const NUMBER: u32 = {
+        assert!(AF < 16, "GPIO alternate function must be 0 ~ 15");
+        AF as u32
+    };
+}
+
+/// ADC/DAC, the Schmitt trigger input is off
+pub struct Analog;
+
+/// Pin `N` (`0` ~ `15`) of `PORT` in `MODE`, only the methods which make sense in the
+/// mode are available, e.g. `set_high()` needs `Output`.
+pub struct Pin<const PORT: char, const N: u8, MODE, R: RegisterAccess = Mmio> {
+    registers: R,
+    _mode: PhantomData<MODE>,
+}
+
+///
+impl<const PORT: char, const N: u8, MODE, R: RegisterAccess> Pin<PORT, N, MODE, R> {
+    /// The 1 bit field, any pin number above 15 fails to compile
+    const BIT: Field = {
+        assert!(N < 16, "GPIO pin number must be 0 ~ 15");
+        Field::new(N, 1)
+    };
+    const TWO_BITS: Field = Field::new(N * 2, 2);
+
+    fn new(registers: R) -> Self {
+        let _ = Self::BIT;
+
+        Pin {
+            registers,
+            _mode: PhantomData,
+        }
+    }
+
+    fn into_mode<NewMode>(self, mode: GpioMode) -> Pin<PORT, N, NewMode, R> {
+        self.write_field(GPIO_MODER_OFFSET, Self::TWO_BITS, mode.to_register_bits());
+        Pin::new(self.registers)
+    }
+
+    fn get_register(&self, offset: u32) -> Register<'_, R> {
+        get_register(&self.registers, PORT, offset)
+    }
+
+    fn write_field(&self, offset: u32, field: Field, value: u32) {
+        self.get_register(offset).write_field(field, value);
+    }
+
+    ///
+    pub fn get_port(&self) -> char {
+        PORT
+    }
+
+    ///
+    pub fn get_number(&self) -> u8 {
+        N
+    }
+
+    /// What `MODER` says, it's the same as `MODE` unless the pin is `Unconfigured`
+    pub fn get_mode(&self) -> GpioMode {
+        GpioMode::from_register_bits(
+            self.get_register(GPIO_MODER_OFFSET)
+                .read_field(Self::TWO_BITS),
+        )
+    }
+
+    ///
+    pub fn set_pull(&self, pull: GpioPull) {
+        self.write_field(GPIO_PUPDR_OFFSET, Self::TWO_BITS, pull.to_register_bits());
+    }
+
+    ///
+    pub fn into_input(self, pull: GpioPull) -> Pin<PORT, N, Input, R> {
+        self.set_pull(pull);
+        self.into_mode(GpioMode::Input)
+    }
+
+    /// The output type is set before `MODER`, so an open-drain line never gets driven
+    /// high by accident
+    pub fn into_push_pull_output(self) -> Pin<PORT, N, Output, R> {
+        self.write_field(
+            GPIO_OTYPER_OFFSET,
+            Self::BIT,
+            GpioOutputType::PushPull.to_register_bits(),
+        );
+        self.into_mode(GpioMode::Output)
+    }
+
+    ///
+    pub fn into_open_drain_output(self) -> Pin<PORT, N, Output, R> {
+        self.write_field(
+            GPIO_OTYPER_OFFSET,
+            Self::BIT,
+            GpioOutputType::OpenDrain.to_register_bits(),
+        );
+        self.into_mode(GpioMode::Output)
+    }
+
+    /// `AFRL` for pin 0 ~ 7 and `AFRH` for pin 8 ~ 15, the function is selected before
+    /// `MODER` switches the pin over
+    pub fn into_alternate<const AF: u8>(self) -> Pin<PORT, N, Alternate<AF>, R> {
+        let (offset, field) = if N < 8 {
+            (GPIO_AFRL_OFFSET, Field::new(N * 4, 4))
+        } else {
+            (GPIO_AFRH_OFFSET, Field::new((N - 8) * 4, 4))
+        };
+        self.write_field(offset, field, Alternate::<AF>::NUMBER);
+
+        self.into_mode(GpioMode::Alternate)
+    }
+
+    /// The pull-up/down is turned off, it's not allowed in analog mode
+    pub fn into_analog(self) -> Pin<PORT, N, Analog, R> {
+        self.set_pull(GpioPull::None);
+        self.into_mode(GpioMode::Analog)
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> Pin<PORT, N, Input, R> {
+    /// From `IDR`
+    pub fn is_high(&self) -> bool {
+        self.get_register(GPIO_IDR_OFFSET).read_field(Self::BIT) != 0
+    }
+
+    ///
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> Pin<PORT, N, Output, R> {
+    /// `BSRR` write, no read-modify-write on `ODR`, so it's safe from the interrupts
+    pub fn set_high(&mut self) {
+        self.get_register(GPIO_BSRR_OFFSET).write(1 << N);
+    }
+
+    ///
+    pub fn set_low(&mut self) {
+        self.get_register(GPIO_BSRR_OFFSET).write(1 << (N + 16));
+    }
+
+    ///
+    pub fn toggle(&mut self) {
+        if self.is_set_high() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+
+    /// From `ODR`, what the pin is driven to
+    pub fn is_set_high(&self) -> bool {
+        self.get_register(GPIO_ODR_OFFSET).read_field(Self::BIT) != 0
+    }
+
+    ///
+    pub fn set_speed(&self, speed: GpioSpeed) {
+        self.write_field(
+            GPIO_OSPEEDR_OFFSET,
+            Self::TWO_BITS,
+            speed.to_register_bits(),
+        );
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, const AF: u8, R: RegisterAccess>
+    Pin<PORT, N, Alternate<AF>, R>
+{
+    ///
+    pub fn set_speed(&self, speed: GpioSpeed) {
+        self.write_field(
+            GPIO_OSPEEDR_OFFSET,
+            Self::TWO_BITS,
+            speed.to_register_bits(),
+        );
+    }
+
+    /// E.g. I2C needs open-drain
+    pub fn set_output_type(&self, output_type: GpioOutputType) {
+        self.write_field(
+            GPIO_OTYPER_OFFSET,
+            Self::BIT,
+            output_type.to_register_bits(),
+        );
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> OutputPin for Pin<PORT, N, Output, R> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Pin::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Pin::set_low(self);
+        Ok(())
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> StatefulOutputPin
+    for Pin<PORT, N, Output, R>
+{
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_high(self))
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(!Pin::is_set_high(self))
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> ToggleableOutputPin
+    for Pin<PORT, N, Output, R>
+{
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        Pin::toggle(self);
+        Ok(())
+    }
+}
+
+///
+impl<const PORT: char, const N: u8, R: RegisterAccess> InputPin for Pin<PORT, N, Input, R> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_low(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+
+    const GPIOD_MODER: u32 = 0x4002_0C00;
+    const GPIOD_BSRR: u32 = GPIOD_MODER + GPIO_BSRR_OFFSET;
+
+    #[test]
+    fn enable_keeps_the_other_ports_and_peripherals() {
+        assert_eq!(GpioPort::<'A', Mmio>::BASE_ADDRESS, 0x4002_0000);
+        assert_eq!(GpioPort::<'D', Mmio>::BASE_ADDRESS, GPIOD_MODER);
+        assert_eq!(GpioPort::<'H', Mmio>::BASE_ADDRESS, 0x4002_1C00);
+
+        let registers = RecordingRegisters::new();
+        // GPIOA, CCMDATARAMEN and DMA1
+        registers.write(RCC_AHB1ENR, 1 << 0 | 1 << 20 | 1 << 21);
+
+        let gpiod = GpioPort::<'D', _>::take(&registers).unwrap();
+        assert!(gpiod.port.is_enabled());
+        let gpioc = GpioPort::<'C', _>::take(&registers).unwrap();
+        assert!(gpioc.port.is_enabled());
+        assert_eq!(
+            registers.get_value(RCC_AHB1ENR),
+            1 << 0 | 1 << 2 | 1 << 3 | 1 << 20 | 1 << 21
+        );
+    }
+
+    #[test]
+    fn port_can_only_be_taken_once() {
+        let registers = RecordingRegisters::new();
+        assert!(GpioPort::<'D', _>::take(&registers).is_some());
+        assert!(GpioPort::<'D', _>::take(&registers).is_none());
+        assert!(GpioPort::<'E', _>::take(&registers).is_some());
+    }
+
+    #[test]
+    fn pin_modes_only_change_their_own_bits() {
+        let registers = RecordingRegisters::new();
+        registers.write(GPIOD_MODER, 0x0000_00FF);
+        let pins = GpioPort::<'D', _>::take(&registers).unwrap();
+        pins.port.set_and_reset_pins(1 << 13, 1 << 15);
+        assert_eq!(registers.get_value(GPIOD_BSRR), 1 << 31 | 1 << 13);
+
+        let mut green_led = pins.p12.into_push_pull_output();
+        let mut red_led = pins.p14.into_open_drain_output();
+        red_led.set_speed(GpioSpeed::VeryHigh);
+        assert_eq!(registers.get_value(GPIOD_MODER), 0x1100_00FF);
+        assert_eq!(
+            registers.get_value(GPIOD_MODER + GPIO_OTYPER_OFFSET),
+            1 << 14
+        );
+        assert_eq!(
+            registers.get_value(GPIOD_MODER + GPIO_OSPEEDR_OFFSET),
+            0b11 << 28
+        );
+        assert_eq!(green_led.get_mode(), GpioMode::Output);
+
+        green_led.set_high();
+        assert_eq!(registers.get_value(GPIOD_BSRR), 1 << 12);
+        red_led.set_low();
+        assert_eq!(registers.get_value(GPIOD_BSRR), 1 << 30);
+
+        // USART2 TX on PD5 is AF7 in AFRL, CAN1 RX on PD0 is AF9, SPI2 MISO on PD14
+        // would be AF5 in AFRH
+        let tx = pins.p5.into_alternate::<7>();
+        tx.set_output_type(GpioOutputType::PushPull);
+        pins.p0.into_alternate::<9>();
+        pins.p9.into_alternate::<5>();
+        assert_eq!(
+            registers.get_value(GPIOD_MODER + GPIO_AFRL_OFFSET),
+            7 << 20 | 9
+        );
+        assert_eq!(registers.get_value(GPIOD_MODER + GPIO_AFRH_OFFSET), 5 << 4);
+        assert_eq!(tx.get_mode(), GpioMode::Alternate);
+
+        let button = pins.p1.into_input(GpioPull::Down);
+        assert_eq!(
+            registers.get_value(GPIOD_MODER + GPIO_PUPDR_OFFSET),
+            0b10 << 2
+        );
+        assert!(button.is_low());
+        registers.write(GPIOD_MODER + GPIO_IDR_OFFSET, 1 << 1);
+        assert!(button.is_high());
+
+        button.into_analog();
+        assert_eq!(registers.get_value(GPIOD_MODER + GPIO_PUPDR_OFFSET), 0);
+    }
+
+    #[test]
+    fn lock_key_sequence() {
+        let registers = RecordingRegisters::new();
+        let gpioa = GpioPort::<'A', _>::take(&registers).unwrap().port;
+        let lckr = GPIOA + GPIO_LCKR_OFFSET;
+
+        assert!(!gpioa.is_locked());
+        assert_eq!(gpioa.lock(1 << 13 | 1 << 14), Ok(()));
+        assert!(gpioa.is_locked());
+        let writes: Vec<u32> = registers
+            .get_writes()
+            .iter()
+            .filter(|(address, _)| *address == lckr)
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(writes, vec![0x0001_6000, 0x0000_6000, 0x0001_6000]);
+    }
+}
@@ -1,33 +1,51 @@
-use crate::rcc_clock_settings::{clock_source_selecting, RCC_CR};
-use core::ptr;
+use crate::clock_utils::ClockConfigError;
+use crate::rcc_clock_settings::RCC_CR;
+use crate::register_access::RegisterAccess;
+use crate::{impl_field_value, register};
+use core::convert::TryFrom;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-// ------ RCC clock configuration register (RCC_CFGR) ---------
 pub const RCC_CGFCR: u32 = RCC_CR + 0x08; // page 228
 
-// bit0 ~ bit1
-pub const RCC_CFGR_SYS_CLOCK_SWITCH_START_BIT: u8 = 0;
-pub const RCC_CFGR_SYS_CLOCK_SWITCH_BITS: u32 = 0b11;
-
-// bit2 ~ bit3
-pub const RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_START_BIT: u8 = 2;
-pub const RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS: u32 = 0b11 << 2;
-
-// bit4 ~ bit7
-pub const RCC_CFGR_AHB_PRESCALER_START_BIT: u8 = 4;
-pub const RCC_CFGR_AHB_PRESCALER_BITS: u32 = 0b1111 << 4;
+register! {
+    /// RCC clock configuration register (RCC_CFGR), page 228
+    pub struct RccCfgrValue: "RCC_CFGR" @ RCC_CGFCR, reset = 0x0000_0000, {
+        /// System clock switch
+        clock_switch, set_clock_switch: SW[0, 2] => RccSystemClockSwtich;
+        /// System clock switch status, read-only
+        clock_switch_status, set_clock_switch_status: SWS[2, 2] => RccSystemClockSwtichStatus;
+        /// AHB prescaler
+        ahb_prescaler, set_ahb_prescaler: HPRE[4, 4] => RccAhbPrescaler;
+        /// APB low speed prescaler (APB1)
+        apb1_prescaler, set_apb1_prescaler: PPRE1[10, 3] => RccApbPrescaler;
+        /// APB high speed prescaler (APB2)
+        apb2_prescaler, set_apb2_prescaler: PPRE2[13, 3] => RccApbPrescaler;
+        /// HSE division factor for RTC clock
+        rtc_prescaler, set_rtc_prescaler: RTCPRE[16, 5] => u32;
+        /// Microcontroller clock output 1
+        mco1, set_mco1: MCO1[21, 2] => u32;
+        /// I2S clock selection
+        i2s_source, set_i2s_source: I2SSRC[23, 1] => bool;
+        /// MCO1 prescaler
+        mco1_prescaler, set_mco1_prescaler: MCO1PRE[24, 3] => u32;
+        /// MCO2 prescaler
+        mco2_prescaler, set_mco2_prescaler: MCO2PRE[27, 3] => u32;
+        /// Microcontroller clock output 2
+        mco2, set_mco2: MCO2[30, 2] => u32;
+    }
+}
 
-// bit10 ~ bit12
-pub const RCC_CFGR_APB1_LOW_SPEED_PRESCALER_START_BIT: u8 = 10;
-pub const RCC_CFGR_APB1_LOW_SPEED_PRESCALER_BITS: u32 = 0b111 << 10;
+impl_field_value!(
+    RccSystemClockSwtich,
+    RccSystemClockSwtichStatus,
+    RccAhbPrescaler,
+    RccApbPrescaler
+);
 
-// bit13 ~ bit15
-pub const RCC_CFGR_APB1_HIGH_SPEED_PRESCALER_START_BIT: u8 = 13;
-pub const RCC_CFGR_APB1_HIGH_SPEED_PRESCALER_BITS: u32 = 0b111 << 13;
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RccSystemClockSwtich {
     NotAllowed,
     HsiSelectedAsSytemClock,
@@ -36,6 +54,15 @@ pub enum RccSystemClockSwtich {
 }
 
 impl RccSystemClockSwtich {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits {
+            0b00 => RccSystemClockSwtich::HsiSelectedAsSytemClock,
+            0b01 => RccSystemClockSwtich::HseSelectedAsSytemClock,
+            0b10 => RccSystemClockSwtich::PllSelectedAsSytemClock,
+            _ => RccSystemClockSwtich::NotAllowed,
+        }
+    }
+
     pub fn to_register_bits(&self) -> u32 {
         match self {
             RccSystemClockSwtich::NotAllowed => 0b11,
@@ -47,7 +74,7 @@ impl RccSystemClockSwtich {
 }
 
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RccSystemClockSwtichStatus {
     NotApplicable,
     HsiUsedAsSytemClock,
@@ -55,7 +82,27 @@ pub enum RccSystemClockSwtichStatus {
     PllUsedAsSytemClock,
 }
 
-#[derive(Debug)]
+impl RccSystemClockSwtichStatus {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits {
+            0b00 => RccSystemClockSwtichStatus::HsiUsedAsSytemClock,
+            0b01 => RccSystemClockSwtichStatus::HseUsedAsSytemClock,
+            0b10 => RccSystemClockSwtichStatus::PllUsedAsSytemClock,
+            _ => RccSystemClockSwtichStatus::NotApplicable,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            RccSystemClockSwtichStatus::HsiUsedAsSytemClock => 0b00,
+            RccSystemClockSwtichStatus::HseUsedAsSytemClock => 0b01,
+            RccSystemClockSwtichStatus::PllUsedAsSytemClock => 0b10,
+            RccSystemClockSwtichStatus::NotApplicable => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RccAhbPrescaler {
     SystemClockNotDivided,
     SystemClockDividedBy2,
@@ -86,24 +133,41 @@ impl From<RccAhbPrescaler> for u32 {
 }
 
 /// From `u32` to `RccAhbPrescaler`
-impl From<u32> for RccAhbPrescaler {
-    fn from(value: u32) -> Self {
+impl TryFrom<u32> for RccAhbPrescaler {
+    type Error = ClockConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
-            2 => RccAhbPrescaler::SystemClockDividedBy2,
-            4 => RccAhbPrescaler::SystemClockDividedBy4,
-            8 => RccAhbPrescaler::SystemClockDividedBy8,
-            16 => RccAhbPrescaler::SystemClockDividedBy16,
-            64 => RccAhbPrescaler::SystemClockDividedBy64,
-            128 => RccAhbPrescaler::SystemClockDividedBy128,
-            256 => RccAhbPrescaler::SystemClockDividedBy256,
-            512 => RccAhbPrescaler::SystemClockDividedBy512,
-            _ => RccAhbPrescaler::SystemClockNotDivided,
+            1 => Ok(RccAhbPrescaler::SystemClockNotDivided),
+            2 => Ok(RccAhbPrescaler::SystemClockDividedBy2),
+            4 => Ok(RccAhbPrescaler::SystemClockDividedBy4),
+            8 => Ok(RccAhbPrescaler::SystemClockDividedBy8),
+            16 => Ok(RccAhbPrescaler::SystemClockDividedBy16),
+            64 => Ok(RccAhbPrescaler::SystemClockDividedBy64),
+            128 => Ok(RccAhbPrescaler::SystemClockDividedBy128),
+            256 => Ok(RccAhbPrescaler::SystemClockDividedBy256),
+            512 => Ok(RccAhbPrescaler::SystemClockDividedBy512),
+            _ => Err(ClockConfigError::WrongAhbPrescaler(value)),
         }
     }
 }
 
 impl RccAhbPrescaler {
-    fn to_register_bits(&self) -> u32 {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits {
+            0b1000 => RccAhbPrescaler::SystemClockDividedBy2,
+            0b1001 => RccAhbPrescaler::SystemClockDividedBy4,
+            0b1010 => RccAhbPrescaler::SystemClockDividedBy8,
+            0b1011 => RccAhbPrescaler::SystemClockDividedBy16,
+            0b1100 => RccAhbPrescaler::SystemClockDividedBy64,
+            0b1101 => RccAhbPrescaler::SystemClockDividedBy128,
+            0b1110 => RccAhbPrescaler::SystemClockDividedBy256,
+            0b1111 => RccAhbPrescaler::SystemClockDividedBy512,
+            _ => RccAhbPrescaler::SystemClockNotDivided,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
         match self {
             RccAhbPrescaler::SystemClockNotDivided => 0b0000,
             RccAhbPrescaler::SystemClockDividedBy2 => 0b1000,
@@ -118,7 +182,7 @@ impl RccAhbPrescaler {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RccApbPrescaler {
     AhbClockNotDivided,
     AhbClockDividedBy2,
@@ -141,20 +205,33 @@ impl From<RccApbPrescaler> for u32 {
 }
 
 /// From `u32` to `RccApbPrescaler`
-impl From<u32> for RccApbPrescaler {
-    fn from(value: u32) -> Self {
+impl TryFrom<u32> for RccApbPrescaler {
+    type Error = ClockConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
-            2 => RccApbPrescaler::AhbClockDividedBy2,
-            4 => RccApbPrescaler::AhbClockDividedBy4,
-            8 => RccApbPrescaler::AhbClockDividedBy8,
-            16 => RccApbPrescaler::AhbClockDividedBy16,
-            _ => RccApbPrescaler::AhbClockNotDivided,
+            1 => Ok(RccApbPrescaler::AhbClockNotDivided),
+            2 => Ok(RccApbPrescaler::AhbClockDividedBy2),
+            4 => Ok(RccApbPrescaler::AhbClockDividedBy4),
+            8 => Ok(RccApbPrescaler::AhbClockDividedBy8),
+            16 => Ok(RccApbPrescaler::AhbClockDividedBy16),
+            _ => Err(ClockConfigError::WrongApbPrescaler(value)),
         }
     }
 }
 
 impl RccApbPrescaler {
-    fn to_register_bits(&self) -> u32 {
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits {
+            0b100 => RccApbPrescaler::AhbClockDividedBy2,
+            0b101 => RccApbPrescaler::AhbClockDividedBy4,
+            0b110 => RccApbPrescaler::AhbClockDividedBy8,
+            0b111 => RccApbPrescaler::AhbClockDividedBy16,
+            _ => RccApbPrescaler::AhbClockNotDivided,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
         match self {
             RccApbPrescaler::AhbClockNotDivided => 0b000,
             RccApbPrescaler::AhbClockDividedBy2 => 0b100,
@@ -173,85 +250,38 @@ pub type RccCfgr = RccClockConfigurationRegister;
 
 ///
 impl RccClockConfigurationRegister {
-    ///
-    pub fn set_bus_prescaler(use_hse: bool) {
-        let rcc_cfgr_write_ptr = RCC_CGFCR as *mut u32;
-
-        let ahb_prescaler: RccAhbPrescaler = if use_hse {
-            clock_source_selecting::AHB_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::AHB_PRESCALER_FOR_HSI
-        }
-        .into();
-        let ahb_prescaler_bits = ahb_prescaler.to_register_bits();
-
-        let apb1_prescaler: RccApbPrescaler = if use_hse {
-            clock_source_selecting::APB1_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::APB1_PRESCALER_FOR_HSI
-        }
-        .into();
-        let apb1_prescaler_bits = apb1_prescaler.to_register_bits();
-
-        let apb2_prescaler: RccApbPrescaler = if use_hse {
-            clock_source_selecting::APB2_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::APB2_PRESCALER_FOR_HSI
-        }
-        .into();
-        let apb2_prescaler_bits = apb2_prescaler.to_register_bits();
-
-        // #[cfg(feature = "enable-debug")]
-        // {
-        // let _ = hprintln!("ahb_prescaler: {:?}", ahb_prescaler);
-        // let _ = hprintln!("ahb_prescaler_bits: {}", ahb_prescaler_bits);
-        // let _ = hprintln!("ahb_prescaler_bits: {:#06b}", ahb_prescaler_bits);
-        // let _ = hprintln!("apb1_prescaler: {:?}", apb1_prescaler);
-        // let _ = hprintln!("apb1_prescaler_bits: {}", apb1_prescaler_bits);
-        // let _ = hprintln!("apb1_prescaler_bits: {:#04b}", apb1_prescaler_bits);
-        // let _ = hprintln!("apb2_prescaler: {:?}", apb2_prescaler);
-        // let _ = hprintln!("apb2_prescaler_bits: {}", apb2_prescaler_bits);
-        // let _ = hprintln!("apb2_prescaler_bits: {:#04b}", apb2_prescaler_bits);
-        // }
-
-        unsafe {
-            ptr::write_volatile(
-                rcc_cfgr_write_ptr,
-                (ahb_prescaler_bits << RCC_CFGR_AHB_PRESCALER_START_BIT)
-                    | (apb1_prescaler_bits << RCC_CFGR_APB1_LOW_SPEED_PRESCALER_START_BIT)
-                    | (apb2_prescaler_bits << RCC_CFGR_APB1_HIGH_SPEED_PRESCALER_START_BIT),
-            );
-        }
+    /// Only the `HPRE`, `PPRE1` and `PPRE2` fields will be changed
+    pub fn set_bus_prescaler(
+        registers: &impl RegisterAccess,
+        ahb_prescaler: RccAhbPrescaler,
+        apb1_prescaler: RccApbPrescaler,
+        apb2_prescaler: RccApbPrescaler,
+    ) {
+        RccCfgrValue::modify(registers, |r| {
+            r.set_ahb_prescaler(ahb_prescaler)
+                .set_apb1_prescaler(apb1_prescaler)
+                .set_apb2_prescaler(apb2_prescaler)
+        });
 
         // Wait for the new prescalers to kick in
         // "The clocks are divided with the new prescaler factor from 1 to 16 AHB cycles after write"
-        cortex_m::asm::delay(16);
+        registers.delay(16);
     }
 
     ///
-    pub fn switch_clock_source_and_wait_for_stable(clock_source: RccSystemClockSwtich) {
+    pub fn switch_clock_source_and_wait_for_stable(
+        registers: &impl RegisterAccess,
+        clock_source: RccSystemClockSwtich,
+    ) {
         let clock_source_bits = clock_source.to_register_bits();
-        // #[cfg(feature = "enable-debug")]
-        // {
-        // let _ = hprintln!("clock_source: {:?}", clock_source);
-        // let _ = hprintln!("clock_source_bits: {:#04b}", clock_source_bits);
-        // }
-
-        let rcc_cfgr_write_ptr = RCC_CGFCR as *mut u32;
-        let rcc_cfgr_read_ptr = RCC_CGFCR as *const u32;
-        unsafe {
-            ptr::write_volatile(
-                rcc_cfgr_write_ptr,
-                clock_source_bits << RCC_CFGR_SYS_CLOCK_SWITCH_START_BIT,
-            );
-        }
+
+        RccCfgrValue::modify(registers, |r| r.set_clock_switch(clock_source));
 
         let mut still_not_stable = true;
         while still_not_stable {
-            let temp_register_value = unsafe { ptr::read_volatile(rcc_cfgr_read_ptr) };
-            let clock_switch_status_bits = (temp_register_value
-                & RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS)
-                >> RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_START_BIT;
+            let clock_switch_status_bits = RccCfgrValue::read(registers)
+                .clock_switch_status()
+                .to_register_bits();
             still_not_stable = clock_switch_status_bits != clock_source_bits;
 
             #[cfg(feature = "enable-debug")]
@@ -266,100 +296,152 @@ impl RccClockConfigurationRegister {
         }
     }
 
-    #[cfg(feature = "enable-debug")]
-    pub fn print_config() {
-        let rcc_sys_cfg_ptr = RCC_CGFCR as *const u32;
-        let rcc_sys_cfg_register_value = unsafe { ptr::read_volatile(rcc_sys_cfg_ptr) };
+    /// Same with `switch_clock_source_and_wait_for_stable()`, but give up after polling
+    /// `max_wait_cycles` times.
+    pub fn try_switch_clock_source(
+        registers: &impl RegisterAccess,
+        clock_source: RccSystemClockSwtich,
+        max_wait_cycles: u32,
+    ) -> Result<(), ClockConfigError> {
+        let clock_source_bits = clock_source.to_register_bits();
 
-        let clock_switch_bits = rcc_sys_cfg_register_value & RCC_CFGR_SYS_CLOCK_SWITCH_BITS;
+        RccCfgrValue::modify(registers, |r| r.set_clock_switch(clock_source));
 
-        let clock_switch_value = match clock_switch_bits {
-            0b00 => RccSystemClockSwtich::HsiSelectedAsSytemClock,
-            0b01 => RccSystemClockSwtich::HseSelectedAsSytemClock,
-            0b10 => RccSystemClockSwtich::PllSelectedAsSytemClock,
-            0b11 => RccSystemClockSwtich::NotAllowed,
-            _ => RccSystemClockSwtich::NotAllowed,
-        };
+        for _ in 0..max_wait_cycles {
+            let clock_switch_status_bits = RccCfgrValue::read(registers)
+                .clock_switch_status()
+                .to_register_bits();
 
-        let clock_switch_status_bits = (rcc_sys_cfg_register_value
-            & RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS)
-            >> RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_START_BIT;
+            if clock_switch_status_bits == clock_source_bits {
+                return Ok(());
+            }
 
-        let clock_switch_status_value = match clock_switch_status_bits {
-            0b00 => RccSystemClockSwtichStatus::HsiUsedAsSytemClock,
-            0b01 => RccSystemClockSwtichStatus::HseUsedAsSytemClock,
-            0b10 => RccSystemClockSwtichStatus::PllUsedAsSytemClock,
-            0b11 => RccSystemClockSwtichStatus::NotApplicable,
-            _ => RccSystemClockSwtichStatus::NotApplicable,
-        };
+            registers.delay(1);
+        }
 
-        let ahb_prescaler_bits = (rcc_sys_cfg_register_value & RCC_CFGR_AHB_PRESCALER_BITS)
-            >> RCC_CFGR_AHB_PRESCALER_START_BIT;
+        #[cfg(feature = "enable-debug")]
+        let _ = hprintln!(
+            "Clock switch to {:?} is not stable after {} cycles",
+            clock_source,
+            max_wait_cycles
+        );
 
-        let ahb_prescaler_value: u32 = match ahb_prescaler_bits {
-            0b1000 => RccAhbPrescaler::SystemClockDividedBy2,
-            0b1001 => RccAhbPrescaler::SystemClockDividedBy4,
-            0b1010 => RccAhbPrescaler::SystemClockDividedBy8,
-            0b1011 => RccAhbPrescaler::SystemClockDividedBy16,
-            0b1100 => RccAhbPrescaler::SystemClockDividedBy64,
-            0b1101 => RccAhbPrescaler::SystemClockDividedBy128,
-            0b1110 => RccAhbPrescaler::SystemClockDividedBy256,
-            0b1111 => RccAhbPrescaler::SystemClockDividedBy512,
-            _ => RccAhbPrescaler::SystemClockNotDivided,
-        }
-        .into();
+        Err(ClockConfigError::ClockSwitchTimeout)
+    }
 
-        let apb1_low_speed_prescaler_bits = (rcc_sys_cfg_register_value
-            & RCC_CFGR_APB1_LOW_SPEED_PRESCALER_BITS)
-            >> RCC_CFGR_APB1_LOW_SPEED_PRESCALER_START_BIT;
+    /// The clock source which is used as system clock right now
+    pub fn get_clock_switch_status(registers: &impl RegisterAccess) -> RccSystemClockSwtichStatus {
+        RccCfgrValue::read(registers).clock_switch_status()
+    }
 
-        let apb1_low_speed_prescaler_value: u32 = match apb1_low_speed_prescaler_bits {
-            0b100 => RccApbPrescaler::AhbClockDividedBy2,
-            0b101 => RccApbPrescaler::AhbClockDividedBy4,
-            0b110 => RccApbPrescaler::AhbClockDividedBy8,
-            0b111 => RccApbPrescaler::AhbClockDividedBy16,
-            _ => RccApbPrescaler::AhbClockNotDivided,
-        }
-        .into();
+    ///
+    pub fn get_ahb_prescaler(registers: &impl RegisterAccess) -> RccAhbPrescaler {
+        RccCfgrValue::read(registers).ahb_prescaler()
+    }
 
-        let apb2_high_speed_prescaler_bits = (rcc_sys_cfg_register_value
-            & RCC_CFGR_APB1_HIGH_SPEED_PRESCALER_BITS)
-            >> RCC_CFGR_APB1_HIGH_SPEED_PRESCALER_START_BIT;
+    ///
+    pub fn get_apb1_prescaler(registers: &impl RegisterAccess) -> RccApbPrescaler {
+        RccCfgrValue::read(registers).apb1_prescaler()
+    }
 
-        let apb2_high_speed_prescaler_value: u32 = match apb2_high_speed_prescaler_bits {
-            0b100 => RccApbPrescaler::AhbClockDividedBy2,
-            0b101 => RccApbPrescaler::AhbClockDividedBy4,
-            0b110 => RccApbPrescaler::AhbClockDividedBy8,
-            0b111 => RccApbPrescaler::AhbClockDividedBy16,
-            _ => RccApbPrescaler::AhbClockNotDivided,
-        }
-        .into();
+    ///
+    pub fn get_apb2_prescaler(registers: &impl RegisterAccess) -> RccApbPrescaler {
+        RccCfgrValue::read(registers).apb2_prescaler()
+    }
 
-        let printing_header = "\n[ RCC clock configuration register (RCC_CFGR) ]: \n";
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
         let _ = hprintln!(
-            "{}{}{}{}{}{}{}",
-            printing_header,
-            format_args!("value: {:034b}", rcc_sys_cfg_register_value),
-            format_args!(
-                "\nSystem clock selected: {:?}\t // bits: {:#04b}",
-                clock_switch_value, clock_switch_bits
-            ),
-            format_args!(
-                "\nSystem clock status: {:?}\t // bits: {:#04b}",
-                clock_switch_status_value, clock_switch_status_bits
-            ),
-            format_args!(
-                "\nAHB prescaler: {:?}\t\t\t\t // bits: {:#06b}",
-                ahb_prescaler_value, ahb_prescaler_bits
-            ),
-            format_args!(
-                "\nAPB low speed prescaler (APB1): {:?}\t\t // bits: {:#05b}",
-                apb1_low_speed_prescaler_value, apb1_low_speed_prescaler_bits
+            "\n[ RCC clock configuration register (RCC_CFGR) ]: \n{:#?}",
+            RccCfgrValue::read(registers)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rcc_clock_control_register::{RccClockControlRegister, RccCrValue};
+    use crate::simulated_registers::SimulatedRegisters;
+
+    #[test]
+    fn switch_to_hse_after_it_is_ready() {
+        let registers = SimulatedRegisters::new();
+        RccClockControlRegister::try_enable_hse_as_clock_source(&registers, 1000).unwrap();
+
+        assert_eq!(
+            RccClockConfigurationRegister::try_switch_clock_source(
+                &registers,
+                RccSystemClockSwtich::HseSelectedAsSytemClock,
+                100
             ),
-            format_args!(
-                "\nAPB high speed prescaler (APB2): {:?}\t\t // bits: {:#05b}",
-                apb2_high_speed_prescaler_value, apb2_high_speed_prescaler_bits
+            Ok(())
+        );
+        assert_eq!(
+            RccClockConfigurationRegister::get_clock_switch_status(&registers),
+            RccSystemClockSwtichStatus::HseUsedAsSytemClock
+        );
+    }
+
+    #[test]
+    fn bus_prescaler_and_clock_switch_keep_each_other() {
+        let registers = SimulatedRegisters::new();
+        RccClockControlRegister::try_enable_hse_as_clock_source(&registers, 1000).unwrap();
+        RccClockConfigurationRegister::try_switch_clock_source(
+            &registers,
+            RccSystemClockSwtich::HseSelectedAsSytemClock,
+            100,
+        )
+        .unwrap();
+
+        RccClockConfigurationRegister::set_bus_prescaler(
+            &registers,
+            RccAhbPrescaler::SystemClockDividedBy2,
+            RccApbPrescaler::AhbClockDividedBy4,
+            RccApbPrescaler::AhbClockDividedBy2,
+        );
+        assert_eq!(
+            RccClockConfigurationRegister::get_clock_switch_status(&registers),
+            RccSystemClockSwtichStatus::HseUsedAsSytemClock
+        );
+        assert_eq!(
+            RccCfgrValue::read(&registers).clock_switch(),
+            RccSystemClockSwtich::HseSelectedAsSytemClock
+        );
+        assert_eq!(
+            u32::from(RccClockConfigurationRegister::get_ahb_prescaler(&registers)),
+            2
+        );
+        assert_eq!(
+            u32::from(RccClockConfigurationRegister::get_apb1_prescaler(
+                &registers
+            )),
+            4
+        );
+        assert_eq!(
+            u32::from(RccClockConfigurationRegister::get_apb2_prescaler(
+                &registers
+            )),
+            2
+        );
+    }
+
+    #[test]
+    fn switch_to_a_clock_which_is_off_times_out() {
+        let registers = SimulatedRegisters::new();
+        RccCrValue::from_bits(0).set_hsi_on(true).write(&registers);
+
+        assert_eq!(
+            RccClockConfigurationRegister::try_switch_clock_source(
+                &registers,
+                RccSystemClockSwtich::PllSelectedAsSytemClock,
+                100
             ),
+            Err(ClockConfigError::ClockSwitchTimeout)
+        );
+        assert_eq!(
+            RccClockConfigurationRegister::get_clock_switch_status(&registers),
+            RccSystemClockSwtichStatus::HsiUsedAsSytemClock
         );
     }
 }
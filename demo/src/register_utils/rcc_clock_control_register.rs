@@ -1,21 +1,40 @@
+use crate::clock_utils::ClockConfigError;
 use crate::rcc_clock_settings::RCC_CR;
-use core::ptr;
-use cortex_m::asm::nop;
+use crate::register;
+use crate::register_access::RegisterAccess;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-// ------ RCC clock control register (RCC_CR), page 224 -------
-pub const RCC_CR_HSI_IS_ON: u32 = 1u32;
-pub const RCC_CR_HSI_IS_STABLE: u32 = 1 << 1;
-pub const RCC_CR_HSE_IS_ON: u32 = 1 << 16;
-pub const RCC_CR_HSE_IS_STABLE: u32 = 1 << 17;
-pub const RCC_CR_HSE_BYPASS: u32 = 1 << 18;
-pub const RCC_CR_CLOCK_SECURITY_IS_ON: u32 = 1 << 19;
-pub const RCC_CR_MAIN_PLL_IS_ON: u32 = 1 << 24;
-pub const RCC_CR_MAIN_PLL_IS_READY: u32 = 1 << 25;
-pub const RCC_CR_PLLI2S_IS_ON: u32 = 1 << 26;
-pub const RCC_CR_PLLI2S_IS_READY: u32 = 1 << 27;
+register! {
+    /// RCC clock control register (RCC_CR), page 224
+    pub struct RccCrValue: "RCC_CR" @ RCC_CR, reset = 0x0000_0083, {
+        /// Internal high-speed clock enable
+        hsi_on, set_hsi_on: HSION[0, 1] => bool;
+        /// Internal high-speed clock ready flag
+        hsi_ready, set_hsi_ready: HSIRDY[1, 1] => bool;
+        /// Internal high-speed clock trimming
+        hsi_trim, set_hsi_trim: HSITRIM[3, 5] => u32;
+        /// Internal high-speed clock calibration, read-only
+        hsi_calibration, set_hsi_calibration: HSICAL[8, 8] => u32;
+        /// HSE clock enable
+        hse_on, set_hse_on: HSEON[16, 1] => bool;
+        /// HSE clock ready flag
+        hse_ready, set_hse_ready: HSERDY[17, 1] => bool;
+        /// HSE oscillator bypassed with an external clock
+        hse_bypass, set_hse_bypass: HSEBYP[18, 1] => bool;
+        /// Clock security system enable
+        clock_security_on, set_clock_security_on: CSSON[19, 1] => bool;
+        /// Main PLL (PLL) enable
+        pll_on, set_pll_on: PLLON[24, 1] => bool;
+        /// Main PLL (PLL) clock ready flag
+        pll_ready, set_pll_ready: PLLRDY[25, 1] => bool;
+        /// PLLI2S enable
+        plli2s_on, set_plli2s_on: PLLI2SON[26, 1] => bool;
+        /// PLLI2S clock ready flag
+        plli2s_ready, set_plli2s_ready: PLLI2SRDY[27, 1] => bool;
+    }
+}
 
 ///
 pub struct RccClockControlRegister {}
@@ -26,88 +45,173 @@ pub type RccCr = RccClockControlRegister;
 ///
 impl RccClockControlRegister {
     ///
-    pub fn reset() {
-        unsafe {
-            ptr::write_volatile(RCC_CR as *mut u32, 0x0000_0083);
-        }
+    pub fn reset(registers: &impl RegisterAccess) {
+        RccCrValue::reset().write(registers);
     }
 
     /// Make sure call somewhere call `reset()` before calling this function!!!
-    pub fn enable_hse_as_clock_source_and_wait_for_it_stable() {
-        let rcc_cr_write_ptr = RCC_CR as *mut u32;
-        let rcc_cr_read_ptr = RCC_CR as *const u32;
-        unsafe {
-            ptr::write_volatile(rcc_cr_write_ptr, RCC_CR_HSE_IS_ON);
+    pub fn enable_hse_as_clock_source_and_wait_for_it_stable(registers: &impl RegisterAccess) {
+        RccCrValue::modify(registers, |r| r.set_hse_on(true));
 
-            while (ptr::read_volatile(rcc_cr_read_ptr) & RCC_CR_HSE_IS_ON) != RCC_CR_HSE_IS_ON {
-                #[cfg(feature = "enable-debug")]
-                let _ = hprintln!("Waiting for HSE to become stable>>>>>");
+        while !RccCrValue::read(registers).hse_ready() {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("Waiting for HSE to become stable>>>>>");
 
-                nop();
-            }
+            registers.delay(1);
         }
     }
 
     /// Make sure call somewhere call `reset()` before calling this function!!!
-    pub fn enable_pll_and_wait_for_it_stable() {
-        let rcc_cr_write_ptr = RCC_CR as *mut u32;
-        let rcc_cr_read_ptr = RCC_CR as *const u32;
-        unsafe {
-            ptr::write_volatile(rcc_cr_write_ptr, RCC_CR_MAIN_PLL_IS_ON);
+    pub fn enable_pll_and_wait_for_it_stable(registers: &impl RegisterAccess) {
+        RccCrValue::modify(registers, |r| r.set_pll_on(true));
+
+        while !RccCrValue::read(registers).pll_ready() {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("Waiting for Main PLL to become stable>>>>>");
+
+            registers.delay(1);
+        }
+    }
+
+    /// Same with `enable_hse_as_clock_source_and_wait_for_it_stable()`, but give up
+    /// after polling `max_wait_cycles` times, e.g. the crystal is missing or dead.
+    pub fn try_enable_hse_as_clock_source(
+        registers: &impl RegisterAccess,
+        max_wait_cycles: u32,
+    ) -> Result<(), ClockConfigError> {
+        RccCrValue::modify(registers, |r| r.set_hse_on(true));
+
+        if Self::wait_for(registers, RccCrValue::hse_ready, max_wait_cycles) {
+            Ok(())
+        } else {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("HSE is not stable after {} cycles", max_wait_cycles);
+
+            Err(ClockConfigError::HseStartupTimeout)
+        }
+    }
+
+    /// Same with `enable_pll_and_wait_for_it_stable()`, but give up after polling
+    /// `max_wait_cycles` times.
+    pub fn try_enable_pll(
+        registers: &impl RegisterAccess,
+        max_wait_cycles: u32,
+    ) -> Result<(), ClockConfigError> {
+        RccCrValue::modify(registers, |r| r.set_pll_on(true));
 
-            while (ptr::read_volatile(rcc_cr_read_ptr) & RCC_CR_MAIN_PLL_IS_READY) != RCC_CR_MAIN_PLL_IS_READY {
-                #[cfg(feature = "enable-debug")]
-                let _ = hprintln!("Waiting for Main PLL to become stable>>>>>");
+        if Self::wait_for(registers, RccCrValue::pll_ready, max_wait_cycles) {
+            Ok(())
+        } else {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("Main PLL is not locked after {} cycles", max_wait_cycles);
+
+            Err(ClockConfigError::PllLockTimeout)
+        }
+    }
+
+    /// Clear `PLLON` and wait for `PLLRDY` to go low, `RCC_PLLCFGR` can only be
+    /// written after that. The PLL must not be used as system clock.
+    pub fn try_disable_pll(
+        registers: &impl RegisterAccess,
+        max_wait_cycles: u32,
+    ) -> Result<(), ClockConfigError> {
+        if RccCrValue::read(registers).pll_on() {
+            RccCrValue::modify(registers, |r| r.set_pll_on(false));
+        }
+
+        if Self::wait_for(registers, |r| !r.pll_ready(), max_wait_cycles) {
+            Ok(())
+        } else {
+            #[cfg(feature = "enable-debug")]
+            let _ = hprintln!("Main PLL is not stopped after {} cycles", max_wait_cycles);
+
+            Err(ClockConfigError::PllStopTimeout)
+        }
+    }
 
-                nop();
+    /// Return `false` if the `is_ready` flag is still not set after polling
+    /// `max_wait_cycles` times
+    fn wait_for(
+        registers: &impl RegisterAccess,
+        is_ready: fn(&RccCrValue) -> bool,
+        max_wait_cycles: u32,
+    ) -> bool {
+        for _ in 0..max_wait_cycles {
+            if is_ready(&RccCrValue::read(registers)) {
+                return true;
             }
+
+            registers.delay(1);
         }
+
+        false
     }
 
     #[cfg(feature = "enable-debug")]
-    pub fn print_config() {
-        let rcc_register_ptr = RCC_CR as *const u32;
-        let rcc_register_value = unsafe { ptr::read_volatile(rcc_register_ptr) };
-
-        let hsi_is_on = (rcc_register_value & RCC_CR_HSI_IS_ON) == RCC_CR_HSI_IS_ON;
-        let hsi_is_stable = (rcc_register_value & RCC_CR_HSI_IS_STABLE) == RCC_CR_HSI_IS_STABLE;
-        let hse_is_on = (rcc_register_value & RCC_CR_HSE_IS_ON) == RCC_CR_HSE_IS_ON;
-        let hse_is_stable = (rcc_register_value & RCC_CR_HSE_IS_STABLE) == RCC_CR_HSE_IS_STABLE;
-        let hse_bypass = (rcc_register_value & RCC_CR_HSE_BYPASS) == RCC_CR_HSE_BYPASS;
-        let clock_security_is_on =
-            (rcc_register_value & RCC_CR_CLOCK_SECURITY_IS_ON) == RCC_CR_CLOCK_SECURITY_IS_ON;
-        let main_pll_is_on = (rcc_register_value & RCC_CR_MAIN_PLL_IS_ON) == RCC_CR_MAIN_PLL_IS_ON;
-        let main_pll_is_ready =
-            (rcc_register_value & RCC_CR_MAIN_PLL_IS_READY) == RCC_CR_MAIN_PLL_IS_READY;
-        let pll_i2s_is_on = (rcc_register_value & RCC_CR_PLLI2S_IS_ON) == RCC_CR_PLLI2S_IS_ON;
-        let pll_i2s_is_ready =
-            (rcc_register_value & RCC_CR_PLLI2S_IS_READY) == RCC_CR_PLLI2S_IS_READY;
-
-        let rcc_register_printing_header = "\n[ RCC clock control register (RCC_CR) ]: \n";
-
+    pub fn print_config(registers: &impl RegisterAccess) {
         let _ = hprintln!(
-            "{}{}{}{}{}{}{}{}{}{}{}{}",
-            rcc_register_printing_header,
-            format_args!("value: {:034b}", rcc_register_value),
-            format_args!("\nHigh speed internal (HSI) clock enable: {}", &hsi_is_on),
-            format_args!(
-                "\nHigh speed internal (HSI) clock stable: {}",
-                &hsi_is_stable
-            ),
-            format_args!("\nHigh speed external (HSE) clock enable: {}", &hse_is_on),
-            format_args!(
-                "\nHigh speed external (HSE) clock stable: {}",
-                &hse_is_stable
-            ),
-            format_args!(
-                "\nHSE oscillator bypassed with an external clock: {}",
-                &hse_bypass
-            ),
-            format_args!("\nClock security system enable: {}", &clock_security_is_on),
-            format_args!("\nMain PLL (PLL) enable: {}", &main_pll_is_on),
-            format_args!("\nMain PLL (PLL) is ready: {}", &main_pll_is_ready),
-            format_args!("\nPLLI2S is enable: {}", &pll_i2s_is_on),
-            format_args!("\nPLLI2S is ready: {}", &pll_i2s_is_ready)
+            "\n[ RCC clock control register (RCC_CR) ]: \n{:#?}",
+            RccCrValue::read(registers)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rcc_pll_config_register::RCC_PLLCFGR;
+    use crate::simulated_registers::SimulatedRegisters;
+
+    #[test]
+    fn hse_waits_for_the_ready_flag() {
+        let registers = SimulatedRegisters::new().hse_startup_cycles(500);
+        assert_eq!(
+            RccClockControlRegister::try_enable_hse_as_clock_source(&registers, 1000),
+            Ok(())
+        );
+        assert!(registers.get_cycles() >= 500);
+    }
+
+    #[test]
+    fn slow_hse_times_out() {
+        let registers = SimulatedRegisters::new().hse_startup_cycles(500);
+        assert_eq!(
+            RccClockControlRegister::try_enable_hse_as_clock_source(&registers, 100),
+            Err(ClockConfigError::HseStartupTimeout)
+        );
+    }
+
+    #[test]
+    fn pll_is_stopped_before_changing_its_config() {
+        let registers = SimulatedRegisters::new();
+        registers.write(RCC_PLLCFGR, (336 << 6) | 16);
+        assert_eq!(
+            RccClockControlRegister::try_enable_pll(&registers, 1000),
+            Ok(())
+        );
+
+        assert_eq!(
+            RccClockControlRegister::try_disable_pll(&registers, 1000),
+            Ok(())
+        );
+        let value = RccCrValue::read(&registers);
+        assert!(!value.pll_on());
+        assert!(!value.pll_ready());
+
+        // The new factors are taken now
+        registers.write(RCC_PLLCFGR, (192 << 6) | 16);
+        assert_eq!(registers.read(RCC_PLLCFGR), (192 << 6) | 16);
+    }
+
+    #[test]
+    fn pll_never_locks_with_invalid_config() {
+        let registers = SimulatedRegisters::new();
+
+        // PLLN = 20 is out of range
+        registers.write(RCC_PLLCFGR, (20 << 6) | 16);
+        assert_eq!(
+            RccClockControlRegister::try_enable_pll(&registers, 1000),
+            Err(ClockConfigError::PllLockTimeout)
         );
     }
 }
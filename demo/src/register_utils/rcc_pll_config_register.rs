@@ -1,33 +1,34 @@
-use crate::rcc_clock_settings::{clock_source_selecting, RCC_CR};
-use core::ptr;
+use crate::clock_utils::{PLL_M_MAX, PLL_M_MIN, PLL_N_MAX, PLL_N_MIN, PLL_Q_MAX, PLL_Q_MIN};
+use crate::rcc_clock_settings::RCC_CR;
+use crate::register_access::RegisterAccess;
+use crate::{impl_field_value, register};
+use core::convert::TryFrom;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
 
-// ------ RCC PLL configuration register (RCC_PLLCFGR) ---------
 pub const RCC_PLLCFGR: u32 = RCC_CR + 0x04; // page 226
 
-// bit0 ~ bit5
-pub const RCC_PLLCFGR_PLL_M_START_BIT: u8 = 0;
-pub const RCC_PLLCFGR_PLL_M_BITS: u32 = 0b111111;
-//
-// bit6 ~ bit14
-pub const RCC_PLLCFGR_PLL_N_START_BIT: u8 = 6;
-pub const RCC_PLLCFGR_PLL_N_BITS: u32 = 0b111111111 << 6;
-
-// bit16 ~ bit17
-pub const RCC_PLLCFGR_PLL_P_START_BIT: u8 = 16;
-pub const RCC_PLLCFGR_PLL_P_BITS: u32 = 0b11 << 16;
-
-// bit24 ~ bit27
-pub const RCC_PLLCFGR_PLL_Q_START_BIT: u8 = 24;
-pub const RCC_PLLCFGR_PLL_Q_BITS: u32 = 0b1111 << 24;
+register! {
+    /// RCC PLL configuration register (RCC_PLLCFGR), page 226
+    pub struct RccPllCfgrValue: "RCC_PLLCFGR" @ RCC_PLLCFGR, reset = 0x2400_3010, {
+        /// Division factor for the main PLL input clock
+        pll_m_bits, set_pll_m_bits: PLLM[0, 6] => u32;
+        /// Main PLL multiplication factor for VCO
+        pll_n_bits, set_pll_n_bits: PLLN[6, 9] => u32;
+        /// Main PLL division factor for main system clock
+        pll_p, set_pll_p: PLLP[16, 2] => PllP;
+        /// Main PLL entry clock source, `true` for HSE and `false` for HSI
+        pll_source_is_hse, set_pll_source_is_hse: PLLSRC[22, 1] => bool;
+        /// Main PLL division factor for USB OTG FS, SDIO and random number generator
+        pll_q_bits, set_pll_q_bits: PLLQ[24, 4] => u32;
+    }
+}
 
-pub const RCC_PLLCFGR_PLL_SRC_IS_HSE_START_BIT: u32 = 22;
-pub const RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS: u32 = 1 << 22;
+impl_field_value!(PllP);
 
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RccPllConfigurationError {
     WrongPllMConfiguration(u16),
     WrongPllNConfiguration(u16),
@@ -35,6 +36,150 @@ pub enum RccPllConfigurationError {
     WrongPllQConfiguration(u16),
 }
 
+/// Division factor for the main PLL input clock, `2 ≤ PLLM ≤ 63`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PllM(u32);
+
+impl PllM {
+    pub fn new(pll_m: u32) -> Result<Self, RccPllConfigurationError> {
+        if pll_m < PLL_M_MIN || pll_m > PLL_M_MAX {
+            return Err(RccPllConfigurationError::WrongPllMConfiguration(
+                pll_m as u16,
+            ));
+        }
+
+        Ok(PllM(pll_m))
+    }
+
+    /// `PLLM` bits is the factor itself
+    pub fn from_register_bits(bits: u32) -> Result<Self, RccPllConfigurationError> {
+        Self::new(bits)
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Main PLL multiplication factor for VCO, `50 ≤ PLLN ≤ 432`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PllN(u32);
+
+impl PllN {
+    pub fn new(pll_n: u32) -> Result<Self, RccPllConfigurationError> {
+        if pll_n < PLL_N_MIN || pll_n > PLL_N_MAX {
+            return Err(RccPllConfigurationError::WrongPllNConfiguration(
+                pll_n as u16,
+            ));
+        }
+
+        Ok(PllN(pll_n))
+    }
+
+    /// `PLLN` bits is the factor itself
+    pub fn from_register_bits(bits: u32) -> Result<Self, RccPllConfigurationError> {
+        Self::new(bits)
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Main PLL division factor for main system clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PllP {
+    Div2,
+    Div4,
+    Div6,
+    Div8,
+}
+
+/// From `u32` (the divider, not the register bits) to `PllP`
+impl TryFrom<u32> for PllP {
+    type Error = RccPllConfigurationError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            2 => Ok(PllP::Div2),
+            4 => Ok(PllP::Div4),
+            6 => Ok(PllP::Div6),
+            8 => Ok(PllP::Div8),
+            _ => Err(RccPllConfigurationError::WrongPllPConfiguration(
+                value as u16,
+            )),
+        }
+    }
+}
+
+impl PllP {
+    /// `PLLP` is 2 bits, every value is a valid divider
+    pub fn from_register_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::Div2,
+            0b01 => Self::Div4,
+            0b10 => Self::Div6,
+            _ => Self::Div8,
+        }
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        match self {
+            Self::Div2 => 0b00,
+            Self::Div4 => 0b01,
+            Self::Div6 => 0b10,
+            Self::Div8 => 0b11,
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        match self {
+            Self::Div2 => 2,
+            Self::Div4 => 4,
+            Self::Div6 => 6,
+            Self::Div8 => 8,
+        }
+    }
+}
+
+/// Main PLL division factor for USB OTG FS, SDIO and random number generator,
+/// `2 ≤ PLLQ ≤ 15`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PllQ(u32);
+
+impl PllQ {
+    pub fn new(pll_q: u32) -> Result<Self, RccPllConfigurationError> {
+        if pll_q < PLL_Q_MIN || pll_q > PLL_Q_MAX {
+            return Err(RccPllConfigurationError::WrongPllQConfiguration(
+                pll_q as u16,
+            ));
+        }
+
+        Ok(PllQ(pll_q))
+    }
+
+    /// `PLLQ` bits is the factor itself
+    pub fn from_register_bits(bits: u32) -> Result<Self, RccPllConfigurationError> {
+        Self::new(bits)
+    }
+
+    pub fn to_register_bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
 ///
 pub struct RccPllConfigurationRegister {}
 
@@ -43,193 +188,153 @@ pub type RccPllCfgr = RccPllConfigurationRegister;
 
 ///
 impl RccPllConfigurationRegister {
+    /// Make sure all the PLL factors are in the allowed range
+    pub fn check_pll_mnpq(
+        pll_m: u32,
+        pll_n: u32,
+        pll_p: u32,
+        pll_q: u32,
+    ) -> Result<(PllM, PllN, PllP, PllQ), RccPllConfigurationError> {
+        Ok((
+            PllM::new(pll_m)?,
+            PllN::new(pll_n)?,
+            PllP::try_from(pll_p)?,
+            PllQ::new(pll_q)?,
+        ))
+    }
+
+    /// Only the `PLLM`, `PLLN`, `PLLP`, `PLLQ` and `PLLSRC` fields will be changed, the
+    /// reserved bits keep their reset value.
+    pub fn set_pll_mnpq(
+        registers: &impl RegisterAccess,
+        pll_m: PllM,
+        pll_n: PllN,
+        pll_p: PllP,
+        pll_q: PllQ,
+        use_hse: bool,
+    ) {
+        RccPllCfgrValue::modify(registers, |r| {
+            r.set_pll_m_bits(pll_m.to_register_bits())
+                .set_pll_n_bits(pll_n.to_register_bits())
+                .set_pll_p(pll_p)
+                .set_pll_q_bits(pll_q.to_register_bits())
+                .set_pll_source_is_hse(use_hse)
+        });
+    }
+
     ///
-    pub fn set_pll_mnpq(use_hse: bool) {
-        let rcc_pllcfgr_write_ptr = RCC_PLLCFGR as *mut u32;
-
-        let pll_m = if use_hse {
-            clock_source_selecting::PLL_M_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::PLL_M_PRESCALER_FOR_HSI
-        };
-
-        let pll_n = if use_hse {
-            clock_source_selecting::PLL_N_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::PLL_N_PRESCALER_FOR_HSI
-        };
-
-        let pll_p = if use_hse {
-            clock_source_selecting::PLL_P_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::PLL_P_PRESCALER_FOR_HSI
-        };
-
-        let pll_q = if use_hse {
-            clock_source_selecting::PLL_Q_PRESCALER_FOR_HSE
-        } else {
-            clock_source_selecting::PLL_Q_PRESCALER_FOR_HSI
-        };
-
-        let mut pll_set_bits = (pll_m << RCC_PLLCFGR_PLL_M_START_BIT)
-            | (pll_n << RCC_PLLCFGR_PLL_N_START_BIT)
-            | (pll_p << RCC_PLLCFGR_PLL_P_START_BIT)
-            | (pll_q << RCC_PLLCFGR_PLL_Q_START_BIT);
-
-        if use_hse {
-            pll_set_bits |= RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS;
-        }
+    pub fn get_pll_m_value(
+        registers: &impl RegisterAccess,
+    ) -> Result<u32, RccPllConfigurationError> {
+        PllM::from_register_bits(RccPllCfgrValue::read(registers).pll_m_bits())
+            .map(|pll_m| pll_m.value())
+    }
 
-        // #[cfg(feature = "enable-debug")]
-        // {
-        // let _ = hprintln!("pll_m: {}", pll_m);
-        // let _ = hprintln!("pll_n: {}", pll_n);
-        // let _ = hprintln!("pll_p: {}", pll_p);
-        // let _ = hprintln!("pll_q: {}", pll_q);
-        // }
+    ///
+    pub fn get_pll_n_value(
+        registers: &impl RegisterAccess,
+    ) -> Result<u32, RccPllConfigurationError> {
+        PllN::from_register_bits(RccPllCfgrValue::read(registers).pll_n_bits())
+            .map(|pll_n| pll_n.value())
+    }
 
-        unsafe {
-            ptr::write_volatile(rcc_pllcfgr_write_ptr, pll_set_bits);
-        }
+    /// `PLL_P` bits `0b00/0b01/0b10/0b11` stand for the divider `2/4/6/8`
+    pub fn get_pll_p_value(
+        registers: &impl RegisterAccess,
+    ) -> Result<u32, RccPllConfigurationError> {
+        Ok(RccPllCfgrValue::read(registers).pll_p().value())
     }
 
-    // pub fn get_pll_m_value() -> Result<u32, RccPllConfigurationError> {
-    // let read_ptr = RCC_PLLCFGR as *const u32;
-    // let temp_value = unsafe { ptr::read_volatile(read_ptr) & RCC_PLLCFGR_PLL_M_BITS };
-    //
-    // if temp_value >= 2 && temp_value <= 63 {
-    // Ok(temp_value)
-    // } else {
-    // Err(RccPllConfigurationError::WrongPllMConfiguration(
-    // temp_value as u16,
-    // ))
-    // }
-    // }
-    //
-    // pub fn get_pll_n_value() -> Result<u32, RccPllConfigurationError> {
-    // let read_ptr = RCC_PLLCFGR as *const u32;
-    // let temp_value = unsafe {
-    // (ptr::read_volatile(read_ptr) & RCC_PLLCFGR_PLL_N_BITS) >> RCC_PLLCFGR_PLL_N_START_BIT
-    // };
-    // if temp_value >= 50 && temp_value <= 432 {
-    // Ok(temp_value)
-    // } else {
-    // Err(RccPllConfigurationError::WrongPllNConfiguration(
-    // temp_value as u16,
-    // ))
-    // }
-    // }
-    //
-    // pub fn get_pll_p_value() -> Result<u32, RccPllConfigurationError> {
-    // let read_ptr = RCC_PLLCFGR as *const u32;
-    // let temp_value = unsafe {
-    // (ptr::read_volatile(read_ptr) & RCC_PLLCFGR_PLL_P_BITS) >> RCC_PLLCFGR_PLL_P_START_BIT
-    // };
-    // if temp_value == 2 || temp_value == 4 || temp_value == 6 || temp_value == 8 {
-    // Ok(temp_value)
-    // } else {
-    // Err(RccPllConfigurationError::WrongPllPConfiguration(
-    // temp_value as u16,
-    // ))
-    // }
-    // }
-    //
-    // pub fn get_pll_q_value() -> Result<u32, RccPllConfigurationError> {
-    // let read_ptr = RCC_PLLCFGR as *const u32;
-    // let temp_value = unsafe {
-    // (ptr::read_volatile(read_ptr) & RCC_PLLCFGR_PLL_Q_BITS) >> RCC_PLLCFGR_PLL_Q_START_BIT
-    // };
-    // if temp_value >= 2 && temp_value <= 15 {
-    // Ok(temp_value)
-    // } else {
-    // Err(RccPllConfigurationError::WrongPllQConfiguration(
-    // temp_value as u16,
-    // ))
-    // }
-    // }
-    //
-    // pub fn is_hse_as_pll_src() -> bool {
-    // let read_ptr = RCC_PLLCFGR as *const u32;
-    // unsafe {
-    // ptr::read_volatile(read_ptr) & RCC_PLLCFGR_PLL_SRC_IS_HSE == RCC_PLLCFGR_PLL_SRC_IS_HSE
-    // }
-    // }
+    ///
+    pub fn get_pll_q_value(
+        registers: &impl RegisterAccess,
+    ) -> Result<u32, RccPllConfigurationError> {
+        PllQ::from_register_bits(RccPllCfgrValue::read(registers).pll_q_bits())
+            .map(|pll_q| pll_q.value())
+    }
+
+    ///
+    pub fn is_hse_as_pll_src(registers: &impl RegisterAccess) -> bool {
+        RccPllCfgrValue::read(registers).pll_source_is_hse()
+    }
 
     #[cfg(feature = "enable-debug")]
-    pub fn print_config() {
-        let read_ptr = RCC_PLLCFGR as *const u32;
-        let cfg_register_value = unsafe { ptr::read_volatile(read_ptr) };
-
-        let temp_m_value = cfg_register_value & RCC_PLLCFGR_PLL_M_BITS;
-        let pll_m_value = if temp_m_value >= 2 && temp_m_value <= 63 {
-            Ok(temp_m_value)
-        } else {
-            Err(RccPllConfigurationError::WrongPllMConfiguration(
-                temp_m_value as u16,
-            ))
-        };
-
-        let temp_n_value =
-            (cfg_register_value & RCC_PLLCFGR_PLL_N_BITS) >> RCC_PLLCFGR_PLL_N_START_BIT;
-        let pll_n_value = if temp_n_value >= 50 && temp_n_value <= 432 {
-            Ok(temp_n_value)
-        } else {
-            Err(RccPllConfigurationError::WrongPllNConfiguration(
-                temp_n_value as u16,
-            ))
-        };
-
-        let temp_p_value =
-            (cfg_register_value & RCC_PLLCFGR_PLL_P_BITS) >> RCC_PLLCFGR_PLL_P_START_BIT;
-        let pll_p_value =
-            if temp_p_value == 2 || temp_p_value == 4 || temp_p_value == 6 || temp_p_value == 8 {
-                Ok(temp_p_value)
-            } else {
-                Err(RccPllConfigurationError::WrongPllPConfiguration(
-                    temp_p_value as u16,
-                ))
-            };
-
-        let temp_q_value =
-            (cfg_register_value & RCC_PLLCFGR_PLL_Q_BITS) >> RCC_PLLCFGR_PLL_Q_START_BIT;
-        let pll_q_value = if temp_q_value >= 2 && temp_q_value <= 15 {
-            Ok(temp_q_value)
-        } else {
-            Err(RccPllConfigurationError::WrongPllQConfiguration(
-                temp_q_value as u16,
+    pub fn print_config(registers: &impl RegisterAccess) {
+        let _ = hprintln!(
+            "\n[ RCC PLL configuration register (RCC_PLLCFGR) ]: \n{:#?}",
+            RccPllCfgrValue::read(registers)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn pll_p_register_bits_round_trip() {
+        for (divider, bits) in [(2, 0b00), (4, 0b01), (6, 0b10), (8, 0b11)].iter() {
+            let pll_p = PllP::try_from(*divider).unwrap();
+            assert_eq!(pll_p.to_register_bits(), *bits);
+            assert_eq!(PllP::from_register_bits(*bits), pll_p);
+            assert_eq!(pll_p.value(), *divider);
+        }
+
+        assert_eq!(
+            PllP::try_from(3),
+            Err(RccPllConfigurationError::WrongPllPConfiguration(3))
+        );
+    }
+
+    #[test]
+    fn factors_are_range_checked() {
+        assert!(PllM::new(1).is_err());
+        assert!(PllM::new(64).is_err());
+        assert!(PllN::new(49).is_err());
+        assert!(PllN::new(433).is_err());
+        assert!(PllQ::new(16).is_err());
+        assert_eq!(
+            RccPllConfigurationRegister::check_pll_mnpq(16, 336, 2, 7),
+            Ok((
+                PllM::new(16).unwrap(),
+                PllN::new(336).unwrap(),
+                PllP::Div2,
+                PllQ::new(7).unwrap()
             ))
-        };
+        );
+    }
 
-        let pll_source = (cfg_register_value & RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS)
-            >> RCC_PLLCFGR_PLL_SRC_IS_HSE_START_BIT;
-        let pll_source_is_hse = pll_source == 1;
-        let pll_source_desc = if pll_source_is_hse { "HSE" } else { "HSI" };
+    #[test]
+    fn set_pll_mnpq_writes_the_pll_p_encoding() {
+        let registers = RecordingRegisters::new();
+        registers.write(RCC_PLLCFGR, RccPllCfgrValue::RESET_VALUE);
 
-        let printing_header = "\n[ RCC PLL configuration register (RCC_PLLCFGR) ]: \n";
-        let _ = hprintln!(
-            "{}{}{}{}{}{}{}",
-            printing_header,
-            format_args!("RCC_PLLCFGR value: {:#034b}", cfg_register_value),
-            format_args!(
-                "\nMain PLL M: {:?},\t// bits: {:#08b}",
-                pll_m_value, temp_m_value
-            ),
-            format_args!(
-                "\nMain PLL N: {:?},\t// bits: {:#011b}",
-                pll_n_value, temp_n_value
-            ),
-            format_args!(
-                "\nMain PLL P: {:?},\t// bits: {:#04b}",
-                pll_p_value, temp_p_value
-            ),
-            format_args!(
-                "\nMain PLL q: {:?},\t// bits: {:#06b}",
-                pll_q_value, temp_q_value
-            ),
-            format_args!(
-                "\nMain PLL source: {:?},\t// bits: {:#03b}",
-                pll_source_desc, pll_source
-            )
+        let (pll_m, pll_n, pll_p, pll_q) =
+            RccPllConfigurationRegister::check_pll_mnpq(8, 336, 2, 7).unwrap();
+        RccPllConfigurationRegister::set_pll_mnpq(&registers, pll_m, pll_n, pll_p, pll_q, true);
+        assert_eq!(registers.get_value(RCC_PLLCFGR) >> 16 & 0b11, 0b00);
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_p_value(&registers),
+            Ok(2)
+        );
+
+        RccPllConfigurationRegister::set_pll_mnpq(
+            &registers,
+            pll_m,
+            pll_n,
+            PllP::Div8,
+            pll_q,
+            true,
+        );
+        assert_eq!(registers.get_value(RCC_PLLCFGR) >> 16 & 0b11, 0b11);
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_p_value(&registers),
+            Ok(8)
+        );
+        assert_eq!(
+            RccPllConfigurationRegister::get_pll_m_value(&registers),
+            Ok(8)
         );
     }
 }
@@ -0,0 +1,508 @@
+use core::ptr;
+
+/// All the register modules read and write registers through this trait instead of
+/// calling `ptr::read_volatile` / `ptr::write_volatile` on the hard-coded addresses
+/// directly, that's why the same register code can run on the MCU (`Mmio`) and on
+/// the host (`RecordingRegisters`) by `cargo test`.
+pub trait RegisterAccess {
+    /// Read the 32bit register value at `address`
+    fn read(&self, address: u32) -> u32;
+
+    /// Write the 32bit `value` to the register at `address`
+    fn write(&self, address: u32, value: u32);
+
+    /// Write the 16bit `value` at `address`, e.g. programming the flash with the `x16`
+    /// parallelism. The default implementation does read-modify-write on the 32bit
+    /// word, which is only fine for the host side memory.
+    fn write_u16(&self, address: u32, value: u16) {
+        let word_address = address & !0b11;
+        let shift = (address & 0b10) * 8;
+        let word = self.read(word_address) & !(0xFFFF << shift);
+        self.write(word_address, word | (value as u32) << shift);
+    }
+
+    /// Write the 8bit `value` at `address`, e.g. programming the flash with the `x8`
+    /// parallelism. The default implementation does read-modify-write on the 32bit
+    /// word, which is only fine for the host side memory.
+    fn write_u8(&self, address: u32, value: u8) {
+        let word_address = address & !0b11;
+        let shift = (address & 0b11) * 8;
+        let word = self.read(word_address) & !(0xFF << shift);
+        self.write(word_address, word | (value as u32) << shift);
+    }
+
+    /// Busy wait for (at least) `cycles` CPU cycles
+    fn delay(&self, cycles: u32);
+}
+
+/// Memory-mapped IO, the real registers on the MCU
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio;
+
+///
+impl RegisterAccess for Mmio {
+    fn read(&self, address: u32) -> u32 {
+        unsafe { ptr::read_volatile(address as *const u32) }
+    }
+
+    fn write(&self, address: u32, value: u32) {
+        unsafe { ptr::write_volatile(address as *mut u32, value) }
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        unsafe { ptr::write_volatile(address as *mut u16, value) }
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        unsafe { ptr::write_volatile(address as *mut u8, value) }
+    }
+
+    fn delay(&self, cycles: u32) {
+        cortex_m::asm::delay(cycles);
+    }
+}
+
+/// So a driver can hold either `Mmio` or a reference to the host side registers
+impl<T: RegisterAccess + ?Sized> RegisterAccess for &T {
+    fn read(&self, address: u32) -> u32 {
+        (**self).read(address)
+    }
+
+    fn write(&self, address: u32, value: u32) {
+        (**self).write(address, value)
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        (**self).write_u16(address, value)
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        (**self).write_u8(address, value)
+    }
+
+    fn delay(&self, cycles: u32) {
+        (**self).delay(cycles)
+    }
+}
+
+/// A bit field inside a 32bit register, e.g. `RCC_CFGR` `HPRE` is `Field::new(4, 4)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    pub start_bit: u8,
+    pub width: u8,
+}
+
+///
+impl Field {
+    pub const fn new(start_bit: u8, width: u8) -> Self {
+        Field { start_bit, width }
+    }
+
+    /// The field bits in the register, e.g. `0b1111 << 4` for `HPRE`
+    pub const fn mask(&self) -> u32 {
+        (u32::MAX >> (32 - self.width as u32)) << self.start_bit
+    }
+
+    /// Extract the field value from the whole register value
+    pub fn get(&self, register_value: u32) -> u32 {
+        (register_value & self.mask()) >> self.start_bit
+    }
+
+    /// Replace the field value inside the whole register value, the bits outside
+    /// the field stay untouched. `field_value` is truncated to the field width.
+    pub fn set(&self, register_value: u32, field_value: u32) -> u32 {
+        (register_value & !self.mask()) | ((field_value << self.start_bit) & self.mask())
+    }
+}
+
+/// One register at `address`, all the helpers except `write()` are read-modify-write,
+/// so they only change the bits they're asked to change.
+pub struct Register<'a, R: RegisterAccess + ?Sized> {
+    registers: &'a R,
+    address: u32,
+}
+
+///
+impl<'a, R: RegisterAccess + ?Sized> Register<'a, R> {
+    pub fn new(registers: &'a R, address: u32) -> Self {
+        Register { registers, address }
+    }
+
+    ///
+    pub fn read(&self) -> u32 {
+        self.registers.read(self.address)
+    }
+
+    /// Overwrite the whole register, only use it when all the bits are owned
+    pub fn write(&self, value: u32) {
+        self.registers.write(self.address, value)
+    }
+
+    /// Read the register, pass the value to `f` and write back what `f` returns
+    pub fn modify<F: FnOnce(u32) -> u32>(&self, f: F) {
+        let value = self.read();
+        self.write(f(value));
+    }
+
+    ///
+    pub fn set_bits(&self, bits: u32) {
+        self.modify(|value| value | bits)
+    }
+
+    ///
+    pub fn clear_bits(&self, bits: u32) {
+        self.modify(|value| value & !bits)
+    }
+
+    /// `true` if all the `bits` are set
+    pub fn is_set(&self, bits: u32) -> bool {
+        self.read() & bits == bits
+    }
+
+    ///
+    pub fn read_field(&self, field: Field) -> u32 {
+        field.get(self.read())
+    }
+
+    ///
+    pub fn write_field(&self, field: Field, field_value: u32) {
+        self.modify(|value| field.set(value, field_value))
+    }
+}
+
+/// Convert a field value between its typed form and the raw field bits, every type
+/// used as a field in `register!` has to implement it.
+pub trait FieldValue: Sized {
+    /// `bits` is already shifted down to bit0 and only contains the field bits
+    fn from_field_bits(bits: u32) -> Self;
+
+    ///
+    fn to_field_bits(&self) -> u32;
+}
+
+///
+impl FieldValue for bool {
+    fn from_field_bits(bits: u32) -> Self {
+        bits != 0
+    }
+
+    fn to_field_bits(&self) -> u32 {
+        *self as u32
+    }
+}
+
+///
+impl FieldValue for u32 {
+    fn from_field_bits(bits: u32) -> Self {
+        bits
+    }
+
+    fn to_field_bits(&self) -> u32 {
+        *self
+    }
+}
+
+/// Implement `FieldValue` for enums which already got `from_register_bits()` and
+/// `to_register_bits()`
+#[macro_export]
+macro_rules! impl_field_value {
+    ($($enum_type:ty),* $(,)?) => {
+        $(
+            impl $crate::register_access::FieldValue for $enum_type {
+                fn from_field_bits(bits: u32) -> Self {
+                    <$enum_type>::from_register_bits(bits)
+                }
+
+                fn to_field_bits(&self) -> u32 {
+                    self.to_register_bits()
+                }
+            }
+        )*
+    };
+}
+
+/// Declare a register once: the address, the reset value and all the fields, then it
+/// generates a value type with:
+///
+/// - the `ADDRESS` and `RESET_VALUE` constants
+/// - a `Field` constant for every field, e.g. `RccCrValue::HSEON`
+/// - a typed getter and a chainable setter for every field
+/// - `read()`, `write()` and `modify()` which go through `RegisterAccess`
+/// - a field-by-field `Debug` dump
+///
+/// ```ignore
+/// register! {
+///     /// RCC clock control register (RCC_CR), page 224
+///     pub struct RccCrValue: "RCC_CR" @ RCC_CR, reset = 0x0000_0083, {
+///         /// Internal high-speed clock enable
+///         hsi_on, set_hsi_on: HSION[0, 1] => bool;
+///         hse_on, set_hse_on: HSEON[16, 1] => bool;
+///     }
+/// }
+///
+/// RccCrValue::modify(registers, |r| r.set_hse_on(true));
+/// let hse_is_ready = RccCrValue::read(registers).hse_ready();
+/// ```
+///
+/// Enum-valued fields need `FieldValue`, see `impl_field_value!`.
+#[macro_export]
+macro_rules! register {
+    (
+        $(#[$attribute:meta])*
+        $visibility:vis struct $name:ident: $register_name:literal @ $address:expr, reset = $reset_value:expr, {
+            $(
+                $(#[$field_attribute:meta])*
+                $getter:ident, $setter:ident: $field:ident[$start_bit:expr, $width:expr] => $field_type:ty;
+            )*
+        }
+    ) => {
+        $(#[$attribute])*
+        #[derive(Clone, Copy, PartialEq)]
+        $visibility struct $name(u32);
+
+        #[allow(dead_code)]
+        impl $name {
+            pub const ADDRESS: u32 = $address;
+            pub const RESET_VALUE: u32 = $reset_value;
+
+            $(
+                $(#[$field_attribute])*
+                pub const $field: $crate::register_access::Field =
+                    $crate::register_access::Field::new($start_bit, $width);
+            )*
+
+            /// The value after reset
+            pub const fn reset() -> Self {
+                $name($reset_value)
+            }
+
+            ///
+            pub const fn from_bits(bits: u32) -> Self {
+                $name(bits)
+            }
+
+            ///
+            pub const fn bits(&self) -> u32 {
+                self.0
+            }
+
+            ///
+            pub fn read(registers: &impl $crate::register_access::RegisterAccess) -> Self {
+                $name(registers.read(Self::ADDRESS))
+            }
+
+            /// Overwrite the whole register
+            pub fn write(&self, registers: &impl $crate::register_access::RegisterAccess) {
+                registers.write(Self::ADDRESS, self.0)
+            }
+
+            /// Read the register, change it by `f` and write it back, the fields which
+            /// `f` doesn't set stay untouched
+            pub fn modify<F: FnOnce(Self) -> Self>(
+                registers: &impl $crate::register_access::RegisterAccess,
+                f: F,
+            ) {
+                f(Self::read(registers)).write(registers)
+            }
+
+            $(
+                $(#[$field_attribute])*
+                pub fn $getter(&self) -> $field_type {
+                    <$field_type as $crate::register_access::FieldValue>::from_field_bits(
+                        Self::$field.get(self.0),
+                    )
+                }
+
+                $(#[$field_attribute])*
+                pub fn $setter(self, value: $field_type) -> Self {
+                    $name(Self::$field.set(
+                        self.0,
+                        $crate::register_access::FieldValue::to_field_bits(&value),
+                    ))
+                }
+            )*
+        }
+
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.debug_struct($register_name)
+                    .field("value", &format_args!("{:#034b}", self.0))
+                    $(.field(stringify!($field), &self.$getter()))*
+                    .finish()
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+pub use self::recording::RecordingRegisters;
+
+/// In-memory registers for the host tests, every read/write will be recorded
+#[cfg(test)]
+mod recording {
+    use super::RegisterAccess;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::vec::Vec;
+
+    ///
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RegisterOperation {
+        Read { address: u32, value: u32 },
+        Write { address: u32, value: u32 },
+    }
+
+    /// Registers which never changed by the "hardware", all start from `0`. Use
+    /// `set_forced_bits` to simulate the status bits which the hardware sets, e.g.
+    /// the ready flags.
+    #[derive(Debug, Default)]
+    pub struct RecordingRegisters {
+        values: RefCell<HashMap<u32, u32>>,
+        forced_bits: RefCell<HashMap<u32, u32>>,
+        operations: RefCell<Vec<RegisterOperation>>,
+    }
+
+    ///
+    impl RecordingRegisters {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// `bits` will always be set when reading the register at `address`
+        pub fn set_forced_bits(&self, address: u32, bits: u32) {
+            self.forced_bits.borrow_mut().insert(address, bits);
+        }
+
+        /// The value which was written last time (without the forced bits)
+        pub fn get_value(&self, address: u32) -> u32 {
+            *self.values.borrow().get(&address).unwrap_or(&0)
+        }
+
+        ///
+        pub fn get_operations(&self) -> Vec<RegisterOperation> {
+            self.operations.borrow().clone()
+        }
+
+        /// Only the write operations, in `(address, value)` pairs
+        pub fn get_writes(&self) -> Vec<(u32, u32)> {
+            self.operations
+                .borrow()
+                .iter()
+                .filter_map(|operation| match *operation {
+                    RegisterOperation::Write { address, value } => Some((address, value)),
+                    RegisterOperation::Read { .. } => None,
+                })
+                .collect()
+        }
+    }
+
+    ///
+    impl RegisterAccess for RecordingRegisters {
+        fn read(&self, address: u32) -> u32 {
+            let forced_bits = *self.forced_bits.borrow().get(&address).unwrap_or(&0);
+            let value = self.get_value(address) | forced_bits;
+
+            self.operations
+                .borrow_mut()
+                .push(RegisterOperation::Read { address, value });
+
+            value
+        }
+
+        fn write(&self, address: u32, value: u32) {
+            self.values.borrow_mut().insert(address, value);
+
+            self.operations
+                .borrow_mut()
+                .push(RegisterOperation::Write { address, value });
+        }
+
+        fn delay(&self, _cycles: u32) {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: u32 = 0x4002_3808;
+    const HPRE: Field = Field::new(4, 4);
+
+    #[test]
+    fn field_mask_get_and_set() {
+        assert_eq!(HPRE.mask(), 0b1111 << 4);
+        assert_eq!(Field::new(0, 32).mask(), u32::MAX);
+        assert_eq!(HPRE.get(0b1010_0101), 0b1010);
+        assert_eq!(HPRE.set(0xFFFF_FFFF, 0), 0xFFFF_FF0F);
+
+        // Too wide value is truncated instead of touching the other fields
+        assert_eq!(HPRE.set(0, 0b1_0001), 0b0001_0000);
+    }
+
+    #[test]
+    fn modify_only_changes_the_given_bits() {
+        let registers = RecordingRegisters::new();
+        let register = Register::new(&registers, ADDRESS);
+        register.write(0b1000_0001);
+
+        register.set_bits(1 << 16);
+        assert_eq!(registers.get_value(ADDRESS), 0b1000_0001 | 1 << 16);
+
+        register.clear_bits(1);
+        assert_eq!(registers.get_value(ADDRESS), 0b1000_0000 | 1 << 16);
+        assert!(register.is_set(1 << 16 | 1 << 7));
+        assert!(!register.is_set(1 << 16 | 1));
+
+        register.write_field(HPRE, 0b1001);
+        assert_eq!(registers.get_value(ADDRESS), 0b1001_0000 | 1 << 16);
+        assert_eq!(register.read_field(HPRE), 0b1001);
+
+        register.modify(|value| value ^ 1 << 16);
+        assert_eq!(registers.get_value(ADDRESS), 0b1001_0000);
+    }
+
+    #[test]
+    fn narrow_writes_only_change_their_own_bytes() {
+        let registers = RecordingRegisters::new();
+        registers.write(ADDRESS, 0x1122_3344);
+
+        registers.write_u8(ADDRESS + 1, 0xAA);
+        assert_eq!(registers.get_value(ADDRESS), 0x1122_AA44);
+
+        registers.write_u16(ADDRESS + 2, 0xBBCC);
+        assert_eq!(registers.get_value(ADDRESS), 0xBBCC_AA44);
+    }
+
+    crate::register! {
+        /// A made up register for testing the macro
+        struct TestRegister: "TEST" @ ADDRESS, reset = 0x0000_0083, {
+            enabled, set_enabled: EN[0, 1] => bool;
+            prescaler, set_prescaler: PRE[4, 4] => u32;
+        }
+    }
+
+    #[test]
+    fn register_macro_reads_and_writes_fields() {
+        assert_eq!(TestRegister::ADDRESS, ADDRESS);
+        assert_eq!(TestRegister::PRE.mask(), 0b1111 << 4);
+
+        let reset = TestRegister::reset();
+        assert_eq!(reset.bits(), 0x83);
+        assert!(reset.enabled());
+        assert_eq!(reset.prescaler(), 0b1000);
+
+        let registers = RecordingRegisters::new();
+        registers.write(ADDRESS, 0xFFFF_0000);
+        TestRegister::modify(&registers, |r| r.set_enabled(true).set_prescaler(0b1_0101));
+        assert_eq!(registers.get_value(ADDRESS), 0xFFFF_0051);
+
+        let value = TestRegister::read(&registers);
+        assert_eq!(value.prescaler(), 0b0101);
+        assert_eq!(value.set_enabled(false).bits(), 0xFFFF_0050);
+
+        let debug = format!("{:?}", value);
+        assert!(debug.starts_with("TEST {"));
+        assert!(debug.contains("EN: true"));
+        assert!(debug.contains("PRE: 5"));
+    }
+}
@@ -0,0 +1,521 @@
+use crate::flash_control_register::{
+    FlashCrValue, FlashParallelism, FlashSrValue, FLASH_CR, FLASH_KEY1, FLASH_KEY2, FLASH_KEYR,
+    FLASH_SR, FLASH_SR_CLEAR_FLAGS,
+};
+use crate::flash_option_control_register::{
+    FlashOptcrValue, RdpLevel, FLASH_OPTCR, FLASH_OPTKEY1, FLASH_OPTKEY2, FLASH_OPTKEYR,
+};
+use crate::flash_utils::{FlashLayout, FLASH_MEMORY_START};
+use crate::register_access::RegisterAccess;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::vec::Vec;
+
+/// Every register or memory access takes this many CPU cycles
+pub const ACCESS_CYCLES: u64 = 1;
+
+/// A small behavioral model of the flash interface and the main memory for the host
+/// tests:
+///
+/// - `FLASH_CR` starts locked, only `KEY1` then `KEY2` in `FLASH_KEYR` unlocks it, a
+///   wrong key locks it until "reset" (a new `SimulatedFlash`)
+/// - `STRT` with `SER` erases the sector to `0xFF`, `BSY` stays high for `erase_cycles`
+/// - a write to the main memory with `PG` set programs it, `BSY` stays high for
+///   `program_cycles`. Programming can only clear bits, like the real flash.
+/// - `PGSERR`, `PGPERR`, `PGAERR` and `WRPERR` are raised for a write without `PG`, a
+///   write which doesn't match `PSIZE`, an unaligned write and a write or erase on a
+///   write protected sector
+/// - any access while `BSY` is high stalls until the operation is done
+/// - `power_cut_after_operations(n)` tears the `n + 1`th program or erase operation
+///   (only the first half of the bytes are changed), then `BSY` stays high and every
+///   write is ignored, `reboot()` then gives a new chip with the same main memory
+///   content
+/// - `FLASH_OPTCR` has its own `OPTKEY1`/`OPTKEY2` unlock sequence, `OPTSTRT` programs
+///   the option bytes (kept by `reboot()`) unless `RDP` level 2 is active, `BSY`
+///   stays high for `erase_cycles`. The `nWRP` bits protect the sectors.
+///
+/// All the other registers (e.g. `FLASH_ACR`) are plain memory.
+#[derive(Debug)]
+pub struct SimulatedFlash {
+    layout: FlashLayout,
+    erase_cycles: u64,
+    program_cycles: u64,
+    power_cut_after_operations: Option<u64>,
+    state: RefCell<SimulatedFlashState>,
+}
+
+#[derive(Debug)]
+struct SimulatedFlashState {
+    now: u64,
+    busy_until: u64,
+    key_step: u8,
+    key_error: bool,
+    flash_cr: u32,
+    flash_sr: u32,
+    option_key_step: u8,
+    option_key_error: bool,
+    flash_optcr: u32,
+    option_bytes: u32,
+    operations: u64,
+    powered_off: bool,
+    main_memory: Vec<u8>,
+    memory: HashMap<u32, u32>,
+}
+
+///
+impl SimulatedFlashState {
+    /// `FLASH_CR` and `FLASH_OPTCR` are locked, `FLASH_OPTCR` is loaded from the
+    /// option bytes
+    fn after_reset(main_memory: Vec<u8>, option_bytes: u32) -> Self {
+        SimulatedFlashState {
+            now: 0,
+            busy_until: 0,
+            key_step: 0,
+            key_error: false,
+            flash_cr: FlashCrValue::RESET_VALUE,
+            flash_sr: FlashSrValue::RESET_VALUE,
+            option_key_step: 0,
+            option_key_error: false,
+            flash_optcr: FlashOptcrValue::from_bits(option_bytes)
+                .set_option_locked(true)
+                .set_option_start(false)
+                .bits(),
+            option_bytes,
+            operations: 0,
+            powered_off: false,
+            main_memory,
+            memory: HashMap::new(),
+        }
+    }
+
+    fn is_sector_write_protected(&self, number: u8) -> bool {
+        FlashOptcrValue::from_bits(self.option_bytes).is_sector_write_protected(number)
+    }
+}
+
+///
+impl SimulatedFlash {
+    /// Fully erased main memory, 1000 cycles sector erase and 10 cycles programming
+    pub fn new(layout: FlashLayout) -> Self {
+        SimulatedFlash {
+            layout,
+            erase_cycles: 1000,
+            program_cycles: 10,
+            power_cut_after_operations: None,
+            state: RefCell::new(SimulatedFlashState::after_reset(
+                vec![0xFF; layout.get_total_size() as usize],
+                FlashOptcrValue::RESET_VALUE,
+            )),
+        }
+    }
+
+    ///
+    pub fn erase_cycles(mut self, cycles: u64) -> Self {
+        self.erase_cycles = cycles;
+        self
+    }
+
+    ///
+    pub fn program_cycles(mut self, cycles: u64) -> Self {
+        self.program_cycles = cycles;
+        self
+    }
+
+    ///
+    pub fn write_protect_sector(self, number: u8) -> Self {
+        {
+            let mut state = self.state.borrow_mut();
+            state.option_bytes = FlashOptcrValue::from_bits(state.option_bytes)
+                .set_sector_write_protected(number, true)
+                .bits();
+            state.flash_optcr = FlashOptcrValue::from_bits(state.option_bytes)
+                .set_option_locked(true)
+                .bits();
+        }
+        self
+    }
+
+    /// Lose power in the middle of the program or erase operation after `operations`
+    /// completed ones
+    pub fn power_cut_after_operations(mut self, operations: u64) -> Self {
+        self.power_cut_after_operations = Some(operations);
+        self
+    }
+
+    /// A new chip after reset with the same main memory content, protection and timing
+    pub fn reboot(&self) -> Self {
+        let state = self.state.borrow();
+
+        SimulatedFlash {
+            layout: self.layout,
+            erase_cycles: self.erase_cycles,
+            program_cycles: self.program_cycles,
+            power_cut_after_operations: None,
+            state: RefCell::new(SimulatedFlashState::after_reset(
+                state.main_memory.clone(),
+                state.option_bytes,
+            )),
+        }
+    }
+
+    ///
+    pub fn is_powered_off(&self) -> bool {
+        self.state.borrow().powered_off
+    }
+
+    /// How many program and erase operations have been started
+    pub fn get_operations(&self) -> u64 {
+        self.state.borrow().operations
+    }
+
+    /// How many CPU cycles have passed since reset
+    pub fn get_cycles(&self) -> u64 {
+        self.state.borrow().now
+    }
+
+    /// The main memory content, no access cycles and no flash rules
+    pub fn get_bytes(&self, address: u32, length: usize) -> Vec<u8> {
+        let offset = (address - FLASH_MEMORY_START) as usize;
+        self.state.borrow().main_memory[offset..offset + length].to_vec()
+    }
+
+    /// Overwrite the main memory content, no access cycles and no flash rules
+    pub fn set_bytes(&self, address: u32, bytes: &[u8]) {
+        let offset = (address - FLASH_MEMORY_START) as usize;
+        self.state.borrow_mut().main_memory[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn is_main_memory(&self, address: u32) -> bool {
+        address >= FLASH_MEMORY_START && address - FLASH_MEMORY_START < self.layout.get_total_size()
+    }
+
+    /// Every access takes `ACCESS_CYCLES`, and stalls while `BSY` is high
+    fn access(&self, state: &mut SimulatedFlashState) {
+        state.now = state.now.max(state.busy_until) + ACCESS_CYCLES;
+    }
+
+    fn is_write_protected(&self, state: &SimulatedFlashState, address: u32) -> bool {
+        self.layout
+            .get_sector_by_address(address)
+            .map_or(false, |sector| {
+                state.is_sector_write_protected(sector.number)
+            })
+    }
+
+    fn set_error(state: &mut SimulatedFlashState, error_flag: u32) {
+        state.flash_sr |= error_flag;
+    }
+
+    /// Count the operation, return `true` if the power is cut in the middle of it
+    fn is_torn(&self, state: &mut SimulatedFlashState) -> bool {
+        let is_torn = self.power_cut_after_operations == Some(state.operations);
+        state.operations += 1;
+        state.powered_off = is_torn;
+        is_torn
+    }
+
+    fn program(&self, address: u32, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        self.access(&mut state);
+
+        if state.powered_off {
+            return;
+        }
+
+        let control = FlashCrValue::from_bits(state.flash_cr);
+        if !control.programming() {
+            return Self::set_error(&mut state, FlashSrValue::PGSERR.mask());
+        }
+
+        // `x64` is written as 2 words
+        let expected_size = match control.program_size() {
+            FlashParallelism::X64 => 4,
+            parallelism => parallelism.get_size_in_bytes() as usize,
+        };
+        if bytes.len() != expected_size {
+            return Self::set_error(&mut state, FlashSrValue::PGPERR.mask());
+        }
+
+        if address as usize % bytes.len() != 0 {
+            return Self::set_error(&mut state, FlashSrValue::PGAERR.mask());
+        }
+
+        if self.is_write_protected(&state, address) {
+            return Self::set_error(&mut state, FlashSrValue::WRPERR.mask());
+        }
+
+        let length = if self.is_torn(&mut state) {
+            bytes.len() / 2
+        } else {
+            bytes.len()
+        };
+
+        let offset = (address - FLASH_MEMORY_START) as usize;
+        for (index, byte) in bytes[..length].iter().enumerate() {
+            state.main_memory[offset + index] &= byte;
+        }
+        state.busy_until = state.now + self.program_cycles;
+    }
+
+    fn write_flash_keyr(&self, state: &mut SimulatedFlashState, value: u32) {
+        if !FlashCrValue::from_bits(state.flash_cr).locked() || state.key_error {
+            return;
+        }
+
+        match (state.key_step, value) {
+            (0, FLASH_KEY1) => state.key_step = 1,
+            (1, FLASH_KEY2) => {
+                state.key_step = 0;
+                state.flash_cr &= !FlashCrValue::LOCK.mask();
+            }
+            _ => state.key_error = true,
+        }
+    }
+
+    fn write_flash_optkeyr(&self, state: &mut SimulatedFlashState, value: u32) {
+        if !FlashOptcrValue::from_bits(state.flash_optcr).option_locked() || state.option_key_error
+        {
+            return;
+        }
+
+        match (state.option_key_step, value) {
+            (0, FLASH_OPTKEY1) => state.option_key_step = 1,
+            (1, FLASH_OPTKEY2) => {
+                state.option_key_step = 0;
+                state.flash_optcr &= !FlashOptcrValue::OPTLOCK.mask();
+            }
+            _ => state.option_key_error = true,
+        }
+    }
+
+    fn write_flash_optcr(&self, state: &mut SimulatedFlashState, value: u32) {
+        // Locked until the unlock sequence
+        if FlashOptcrValue::from_bits(state.flash_optcr).option_locked() {
+            return;
+        }
+
+        let control = FlashOptcrValue::from_bits(value);
+        state.flash_optcr = control.set_option_start(false).bits();
+
+        if !control.option_start() {
+            return;
+        }
+
+        // Frozen by `RDP` level 2
+        if FlashOptcrValue::from_bits(state.option_bytes).read_protection() == RdpLevel::Level2 {
+            state.flash_optcr = state.option_bytes;
+            return;
+        }
+
+        state.option_bytes = control
+            .set_option_locked(false)
+            .set_option_start(false)
+            .bits();
+        state.busy_until = state.now + self.erase_cycles;
+    }
+
+    fn write_flash_cr(&self, state: &mut SimulatedFlashState, value: u32) {
+        // Locked until the unlock sequence
+        if FlashCrValue::from_bits(state.flash_cr).locked() {
+            return;
+        }
+
+        let control = FlashCrValue::from_bits(value);
+        state.flash_cr = control.set_start(false).bits();
+
+        if !control.start() {
+            return;
+        }
+
+        let sector_numbers: Vec<u8> = if control.mass_erase() {
+            (0..self.layout.get_sector_sizes().len() as u8).collect()
+        } else if control.sector_erase() {
+            vec![control.sector_number() as u8]
+        } else {
+            Vec::new()
+        };
+
+        let sectors: Option<Vec<_>> = sector_numbers
+            .iter()
+            .map(|&number| self.layout.get_sector(number))
+            .collect();
+        let sectors = match sectors {
+            Some(ref sectors) if !sectors.is_empty() => sectors,
+            _ => return Self::set_error(state, FlashSrValue::PGSERR.mask()),
+        };
+
+        if sectors
+            .iter()
+            .any(|sector| state.is_sector_write_protected(sector.number))
+        {
+            return Self::set_error(state, FlashSrValue::WRPERR.mask());
+        }
+
+        let is_torn = self.is_torn(state);
+
+        for sector in sectors.iter() {
+            let offset = (sector.start_address - FLASH_MEMORY_START) as usize;
+            let length = if is_torn {
+                sector.size as usize / 2
+            } else {
+                sector.size as usize
+            };
+
+            for byte in state.main_memory[offset..offset + length].iter_mut() {
+                *byte = 0xFF;
+            }
+        }
+        state.busy_until = state.now + self.erase_cycles * sectors.len() as u64;
+    }
+}
+
+///
+impl RegisterAccess for SimulatedFlash {
+    fn read(&self, address: u32) -> u32 {
+        let mut state = self.state.borrow_mut();
+        state.now += ACCESS_CYCLES;
+
+        if self.is_main_memory(address) {
+            let offset = (address - FLASH_MEMORY_START) as usize;
+            let bytes = &state.main_memory[offset..offset + 4];
+            return u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        match address {
+            FLASH_KEYR => 0,
+            FLASH_SR => {
+                let busy = state.powered_off || state.now < state.busy_until;
+                FlashSrValue::from_bits(state.flash_sr)
+                    .set_busy(busy)
+                    .bits()
+            }
+            FLASH_CR => state.flash_cr,
+            FLASH_OPTKEYR => 0,
+            FLASH_OPTCR => state.flash_optcr,
+            _ => *state.memory.get(&address).unwrap_or(&0),
+        }
+    }
+
+    fn write(&self, address: u32, value: u32) {
+        if self.is_main_memory(address) {
+            return self.program(address, &value.to_le_bytes());
+        }
+
+        let mut state = self.state.borrow_mut();
+        self.access(&mut state);
+
+        if state.powered_off {
+            return;
+        }
+
+        match address {
+            FLASH_KEYR => self.write_flash_keyr(&mut state, value),
+            FLASH_SR => state.flash_sr &= !(value & FLASH_SR_CLEAR_FLAGS),
+            FLASH_CR => self.write_flash_cr(&mut state, value),
+            FLASH_OPTKEYR => self.write_flash_optkeyr(&mut state, value),
+            FLASH_OPTCR => self.write_flash_optcr(&mut state, value),
+            _ => {
+                state.memory.insert(address, value);
+            }
+        }
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        if self.is_main_memory(address) {
+            return self.program(address, &value.to_le_bytes());
+        }
+
+        let word_address = address & !0b11;
+        let shift = (address & 0b10) * 8;
+        let word = self.read(word_address) & !(0xFFFF << shift);
+        self.write(word_address, word | (value as u32) << shift);
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        if self.is_main_memory(address) {
+            return self.program(address, &[value]);
+        }
+
+        let word_address = address & !0b11;
+        let shift = (address & 0b11) * 8;
+        let word = self.read(word_address) & !(0xFF << shift);
+        self.write(word_address, word | (value as u32) << shift);
+    }
+
+    fn delay(&self, cycles: u32) {
+        self.state.borrow_mut().now += cycles as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECTOR_5: u32 = 0x0802_0000;
+
+    #[test]
+    fn wrong_key_locks_until_reset() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        flash.write(FLASH_KEYR, FLASH_KEY2);
+        flash.write(FLASH_KEYR, FLASH_KEY1);
+        flash.write(FLASH_KEYR, FLASH_KEY2);
+        assert!(FlashCrValue::read(&flash).locked());
+
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+        flash.write(FLASH_KEYR, FLASH_KEY1);
+        flash.write(FLASH_KEYR, FLASH_KEY2);
+        assert!(!FlashCrValue::read(&flash).locked());
+    }
+
+    #[test]
+    fn programming_only_clears_bits() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407);
+
+        // `PG` is not set
+        flash.write(SECTOR_5, 0);
+        assert!(FlashSrValue::read(&flash).programming_sequence_error());
+        assert_eq!(flash.get_bytes(SECTOR_5, 4), vec![0xFF; 4]);
+
+        flash.write(FLASH_KEYR, FLASH_KEY1);
+        flash.write(FLASH_KEYR, FLASH_KEY2);
+        FlashCrValue::modify(&flash, |r| {
+            r.set_program_size(FlashParallelism::X32)
+                .set_programming(true)
+        });
+        flash.write(SECTOR_5, 0xFFFF_00F0);
+        flash.write(SECTOR_5, 0x0FFF_FF0F);
+        assert_eq!(flash.read(SECTOR_5), 0x0FFF_0000);
+
+        // Stalls while busy
+        let cycles = flash.get_cycles();
+        flash.write_u8(SECTOR_5 + 4, 0);
+        assert!(flash.get_cycles() >= cycles + 10);
+        assert!(FlashSrValue::read(&flash).programming_parallelism_error());
+    }
+
+    #[test]
+    fn power_cut_tears_the_operation_and_stops_the_writes() {
+        let flash = SimulatedFlash::new(FlashLayout::Stm32f407).power_cut_after_operations(1);
+        flash.write(FLASH_KEYR, FLASH_KEY1);
+        flash.write(FLASH_KEYR, FLASH_KEY2);
+        FlashCrValue::modify(&flash, |r| {
+            r.set_program_size(FlashParallelism::X32)
+                .set_programming(true)
+        });
+
+        flash.write(SECTOR_5, 0x1111_1111);
+        flash.write(SECTOR_5 + 4, 0x2222_2222);
+        flash.write(SECTOR_5 + 8, 0x3333_3333);
+        assert!(flash.is_powered_off());
+        assert!(FlashSrValue::read(&flash).busy());
+        assert_eq!(
+            flash.get_bytes(SECTOR_5, 12),
+            vec![0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+
+        let flash = flash.reboot();
+        assert!(!flash.is_powered_off());
+        assert!(FlashCrValue::read(&flash).locked());
+        assert_eq!(flash.read(SECTOR_5 + 4), 0xFFFF_2222);
+    }
+}
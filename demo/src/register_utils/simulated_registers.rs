@@ -0,0 +1,647 @@
+use crate::clock_utils::{
+    PLL_M_MAX, PLL_M_MIN, PLL_N_MAX, PLL_N_MIN, PLL_VCO_INPUT_MAX_FREQUENCY,
+    PLL_VCO_INPUT_MIN_FREQUENCY, PLL_VCO_OUTPUT_MAX_FREQUENCY, PLL_VCO_OUTPUT_MIN_FREQUENCY,
+};
+use crate::flash_access_control_register::{
+    FlashAcrValue, FlashReadLatency, SupplyVoltage, FLASH_ACR,
+};
+use crate::rcc_clock_config_register::{RccCfgrValue, RCC_CGFCR};
+use crate::rcc_clock_control_register::RccCrValue;
+use crate::rcc_clock_settings::{clock_source_selecting, RCC_CR};
+use crate::rcc_pll_config_register::{RccPllCfgrValue, RCC_PLLCFGR};
+use crate::register_access::RegisterAccess;
+use crate::system_tick_timer_register::{StkCtrlValue, STK_CTRL, STK_LOAD, STK_VAL};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Reset values, RM0090 page 224 and page 226
+const RCC_CR_RESET_VALUE: u32 = 0x0000_0081;
+const RCC_PLLCFGR_RESET_VALUE: u32 = RccPllCfgrValue::RESET_VALUE;
+
+const RCC_CR_HSI_IS_ON: u32 = RccCrValue::HSION.mask();
+const RCC_CR_HSI_IS_STABLE: u32 = RccCrValue::HSIRDY.mask();
+const RCC_CR_HSE_IS_ON: u32 = RccCrValue::HSEON.mask();
+const RCC_CR_HSE_IS_STABLE: u32 = RccCrValue::HSERDY.mask();
+const RCC_CR_MAIN_PLL_IS_ON: u32 = RccCrValue::PLLON.mask();
+const RCC_CR_MAIN_PLL_IS_READY: u32 = RccCrValue::PLLRDY.mask();
+const RCC_CR_PLLI2S_IS_READY: u32 = RccCrValue::PLLI2SRDY.mask();
+
+const RCC_CFGR_SYS_CLOCK_SWITCH_BITS: u32 = RccCfgrValue::SW.mask();
+const RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS: u32 = RccCfgrValue::SWS.mask();
+const RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_START_BIT: u8 = RccCfgrValue::SWS.start_bit;
+
+const RCC_PLLCFGR_PLL_M_BITS: u32 = RccPllCfgrValue::PLLM.mask();
+const RCC_PLLCFGR_PLL_N_BITS: u32 = RccPllCfgrValue::PLLN.mask();
+const RCC_PLLCFGR_PLL_N_START_BIT: u8 = RccPllCfgrValue::PLLN.start_bit;
+const RCC_PLLCFGR_PLL_P_BITS: u32 = RccPllCfgrValue::PLLP.mask();
+const RCC_PLLCFGR_PLL_P_START_BIT: u8 = RccPllCfgrValue::PLLP.start_bit;
+const RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS: u32 = RccPllCfgrValue::PLLSRC.mask();
+
+const STK_CTRL_ENABLE_BIT: u32 = StkCtrlValue::ENABLE.mask();
+const STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT: u32 = StkCtrlValue::TICKINT.mask();
+const STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT: u32 = StkCtrlValue::CLKSOURCE.mask();
+const STK_CTRL_COUNTDOWN_TO_ZERO_BIT: u32 = StkCtrlValue::COUNTFLAG.mask();
+
+// Read-only bits which only the "hardware" can change
+const RCC_CR_READY_BITS: u32 =
+    RCC_CR_HSI_IS_STABLE | RCC_CR_HSE_IS_STABLE | RCC_CR_MAIN_PLL_IS_READY | RCC_CR_PLLI2S_IS_READY;
+
+// `SW` / `SWS` values
+const CLOCK_SWITCH_HSI: u32 = 0b00;
+const CLOCK_SWITCH_HSE: u32 = 0b01;
+const CLOCK_SWITCH_PLL: u32 = 0b10;
+
+/// Every register access takes this many CPU cycles, so a polling loop which never
+/// calls `delay()` still moves the simulated time forward.
+pub const ACCESS_CYCLES: u64 = 1;
+
+/// A small behavioral model of RCC + FLASH + SysTick for the host tests.
+///
+/// Unlike `RecordingRegisters`, the simulated "hardware" reacts to the writes:
+///
+/// - `HSEON` raises `HSERDY` after `hse_startup_cycles`
+/// - `PLLON` raises `PLLRDY` after `pll_lock_cycles`, only if `RCC_PLLCFGR` is valid
+///   and the PLL input clock is ready, clearing it drops `PLLRDY` after
+///   `pll_stop_cycles`
+/// - `SW` in `RCC_CFGR` is reflected into `SWS` after `clock_switch_cycles`, only if
+///   the selected clock is ready
+/// - an oscillator which is used (directly or through the PLL) as system clock can't
+///   be turned off
+/// - SysTick counts down with the simulated CPU cycles
+/// - `HCLK` (after a clock switch or an `HPRE` write) which needs more wait states
+///   than `LATENCY` in `FLASH_ACR` holds is recorded, see
+///   `get_flash_latency_violations()`, the real chip reads garbage from the flash
+///
+/// The simulated time only moves forward by `delay()`, `advance()` and every
+/// register access (`ACCESS_CYCLES`). All the other registers (`FLASH_ACR` included)
+/// are plain memory. The writes are recorded like `RecordingRegisters` does, see
+/// `get_writes()`.
+/// `HCLK` (unit in Hertz) ran with a `LATENCY` below the minimum for it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashLatencyViolation {
+    pub cpu_clock: u32,
+    pub flash_latency: FlashReadLatency,
+}
+
+#[derive(Debug)]
+pub struct SimulatedRegisters {
+    hse_frequency: u32,
+    supply_voltage: SupplyVoltage,
+    hse_startup_cycles: Option<u64>,
+    pll_lock_cycles: Option<u64>,
+    pll_stop_cycles: u64,
+    clock_switch_cycles: u64,
+    state: RefCell<SimulatedState>,
+}
+
+#[derive(Debug, Default)]
+struct SimulatedState {
+    now: u64,
+    rcc_cr: u32,
+    hsi_on_since: Option<u64>,
+    hse_on_since: Option<u64>,
+    pll_on_since: Option<u64>,
+    pll_stopped_at: u64,
+    rcc_cfgr: u32,
+    clock_switch_written_at: u64,
+    clock_switch_status: u32,
+    rcc_pllcfgr: u32,
+    stk_ctrl: u32,
+    stk_load: u32,
+    stk_val: u32,
+    stk_prescaler_remainder: u64,
+    system_tick_exceptions: u32,
+    memory: HashMap<u32, u32>,
+    writes: Vec<(u32, u32)>,
+    flash_latency_violations: Vec<FlashLatencyViolation>,
+}
+
+///
+impl SimulatedRegisters {
+    /// A chip right after reset, with the board HSE which becomes ready after 100
+    /// cycles, a PLL which locks after 50 cycles and stops after 10 cycles, and a 2
+    /// cycles clock switch. The supply voltage is 2.7V ~ 3.6V.
+    pub fn new() -> Self {
+        SimulatedRegisters {
+            hse_frequency: clock_source_selecting::HSE_FREQUENCY,
+            supply_voltage: SupplyVoltage::From2V7To3V6,
+            hse_startup_cycles: Some(100),
+            pll_lock_cycles: Some(50),
+            pll_stop_cycles: 10,
+            clock_switch_cycles: 2,
+            state: RefCell::new(SimulatedState {
+                rcc_cr: RCC_CR_RESET_VALUE,
+                hsi_on_since: Some(0),
+                rcc_pllcfgr: RCC_PLLCFGR_RESET_VALUE,
+                clock_switch_status: CLOCK_SWITCH_HSI,
+                ..SimulatedState::default()
+            }),
+        }
+    }
+
+    ///
+    pub fn hse_frequency(mut self, hertz: u32) -> Self {
+        self.hse_frequency = hertz;
+        self
+    }
+
+    /// The minimum `LATENCY` for `HCLK` depends on it
+    pub fn supply_voltage(mut self, supply_voltage: SupplyVoltage) -> Self {
+        self.supply_voltage = supply_voltage;
+        self
+    }
+
+    ///
+    pub fn hse_startup_cycles(mut self, cycles: u64) -> Self {
+        self.hse_startup_cycles = Some(cycles);
+        self
+    }
+
+    /// The crystal is missing or dead, `HSERDY` never goes high
+    pub fn hse_never_ready(mut self) -> Self {
+        self.hse_startup_cycles = None;
+        self
+    }
+
+    ///
+    pub fn pll_lock_cycles(mut self, cycles: u64) -> Self {
+        self.pll_lock_cycles = Some(cycles);
+        self
+    }
+
+    /// `PLLRDY` never goes high, even with a valid `RCC_PLLCFGR`
+    pub fn pll_never_locks(mut self) -> Self {
+        self.pll_lock_cycles = None;
+        self
+    }
+
+    ///
+    pub fn pll_stop_cycles(mut self, cycles: u64) -> Self {
+        self.pll_stop_cycles = cycles;
+        self
+    }
+
+    ///
+    pub fn clock_switch_cycles(mut self, cycles: u64) -> Self {
+        self.clock_switch_cycles = cycles;
+        self
+    }
+
+    /// Let the simulated time move forward without any register access
+    pub fn advance(&self, cycles: u64) {
+        let mut state = self.state.borrow_mut();
+        self.advance_state(&mut state, cycles);
+    }
+
+    /// How many CPU cycles have passed since reset
+    pub fn get_cycles(&self) -> u64 {
+        self.state.borrow().now
+    }
+
+    /// The frequency of the clock which `SWS` reports as system clock
+    pub fn get_system_clock_frequency(&self) -> u32 {
+        self.get_system_clock_frequency_of(&self.state.borrow())
+    }
+
+    /// All the writes in `(address, value)` pairs, in order
+    pub fn get_writes(&self) -> Vec<(u32, u32)> {
+        self.state.borrow().writes.clone()
+    }
+
+    /// Every time `HCLK` ran faster than `LATENCY` allows, in order
+    pub fn get_flash_latency_violations(&self) -> Vec<FlashLatencyViolation> {
+        self.state.borrow().flash_latency_violations.clone()
+    }
+
+    /// How many SysTick exceptions became pending, reset to `0` after calling
+    pub fn take_system_tick_exceptions(&self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let exceptions = state.system_tick_exceptions;
+        state.system_tick_exceptions = 0;
+        exceptions
+    }
+
+    fn advance_state(&self, state: &mut SimulatedState, cycles: u64) {
+        state.now += cycles;
+
+        if state.stk_ctrl & STK_CTRL_ENABLE_BIT == STK_CTRL_ENABLE_BIT {
+            let ticks = if state.stk_ctrl & STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT != 0 {
+                cycles
+            } else {
+                let total_cycles = state.stk_prescaler_remainder + cycles;
+                state.stk_prescaler_remainder = total_cycles % 8;
+                total_cycles / 8
+            };
+
+            Self::count_down_system_tick(state, ticks);
+        }
+
+        self.settle_clock_switch(state);
+    }
+
+    /// The counter reloads from `STK_LOAD` on the tick after reaching `0`, every
+    /// time it reaches `0` sets `COUNTFLAG` (and pends the exception if `TICKINT`).
+    fn count_down_system_tick(state: &mut SimulatedState, mut ticks: u64) {
+        let reload = state.stk_load as u64;
+        if reload == 0 || ticks == 0 {
+            return;
+        }
+
+        if state.stk_val == 0 {
+            state.stk_val = state.stk_load;
+            ticks -= 1;
+        }
+
+        let value = state.stk_val as u64;
+        if ticks < value {
+            state.stk_val = (value - ticks) as u32;
+            return;
+        }
+
+        ticks -= value;
+        let counted_to_zero = 1 + ticks / (reload + 1);
+        let remainder = ticks % (reload + 1);
+        state.stk_val = if remainder == 0 {
+            0
+        } else {
+            (reload - (remainder - 1)) as u32
+        };
+
+        state.stk_ctrl |= STK_CTRL_COUNTDOWN_TO_ZERO_BIT;
+        if state.stk_ctrl & STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT != 0 {
+            state.system_tick_exceptions += counted_to_zero as u32;
+        }
+    }
+
+    fn hse_ready_at(&self, state: &SimulatedState) -> Option<u64> {
+        match (state.hse_on_since, self.hse_startup_cycles) {
+            (Some(since), Some(startup_cycles)) => Some(since + startup_cycles),
+            _ => None,
+        }
+    }
+
+    fn pll_input_frequency(&self, state: &SimulatedState) -> u32 {
+        if state.rcc_pllcfgr & RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS != 0 {
+            self.hse_frequency
+        } else {
+            clock_source_selecting::HSI_FREQUENCY
+        }
+    }
+
+    /// The PLL doesn't lock with the factors out of range
+    fn pll_config_is_valid(&self, state: &SimulatedState) -> bool {
+        let pll_m = state.rcc_pllcfgr & RCC_PLLCFGR_PLL_M_BITS;
+        let pll_n = (state.rcc_pllcfgr & RCC_PLLCFGR_PLL_N_BITS) >> RCC_PLLCFGR_PLL_N_START_BIT;
+        if pll_m < PLL_M_MIN || pll_m > PLL_M_MAX || pll_n < PLL_N_MIN || pll_n > PLL_N_MAX {
+            return false;
+        }
+
+        let vco_input = self.pll_input_frequency(state) / pll_m;
+        let vco_output = vco_input as u64 * pll_n as u64;
+
+        vco_input >= PLL_VCO_INPUT_MIN_FREQUENCY
+            && vco_input <= PLL_VCO_INPUT_MAX_FREQUENCY
+            && vco_output >= PLL_VCO_OUTPUT_MIN_FREQUENCY as u64
+            && vco_output <= PLL_VCO_OUTPUT_MAX_FREQUENCY as u64
+    }
+
+    fn pll_ready_at(&self, state: &SimulatedState) -> Option<u64> {
+        if !self.pll_config_is_valid(state) {
+            return None;
+        }
+
+        let input_ready_at = if state.rcc_pllcfgr & RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS != 0 {
+            self.hse_ready_at(state)?
+        } else {
+            state.hsi_on_since?
+        };
+
+        match (state.pll_on_since, self.pll_lock_cycles) {
+            (Some(since), Some(lock_cycles)) => Some(since.max(input_ready_at) + lock_cycles),
+            _ => None,
+        }
+    }
+
+    fn clock_ready_at(&self, state: &SimulatedState, clock_switch: u32) -> Option<u64> {
+        match clock_switch {
+            CLOCK_SWITCH_HSI => state.hsi_on_since,
+            CLOCK_SWITCH_HSE => self.hse_ready_at(state),
+            CLOCK_SWITCH_PLL => self.pll_ready_at(state),
+            _ => None,
+        }
+    }
+
+    fn is_ready(&self, state: &SimulatedState, ready_at: Option<u64>) -> bool {
+        ready_at.map_or(false, |ready_at| state.now >= ready_at)
+    }
+
+    fn get_pll_output_frequency(&self, state: &SimulatedState) -> u32 {
+        let pll_m = (state.rcc_pllcfgr & RCC_PLLCFGR_PLL_M_BITS) as u64;
+        let pll_n =
+            ((state.rcc_pllcfgr & RCC_PLLCFGR_PLL_N_BITS) >> RCC_PLLCFGR_PLL_N_START_BIT) as u64;
+        let pll_p_bits =
+            (state.rcc_pllcfgr & RCC_PLLCFGR_PLL_P_BITS) >> RCC_PLLCFGR_PLL_P_START_BIT;
+        let pll_p = ((pll_p_bits + 1) * 2) as u64;
+
+        (self.pll_input_frequency(state) as u64 * pll_n / (pll_m * pll_p)) as u32
+    }
+
+    fn get_system_clock_frequency_of(&self, state: &SimulatedState) -> u32 {
+        match state.clock_switch_status {
+            CLOCK_SWITCH_HSE => self.hse_frequency,
+            CLOCK_SWITCH_PLL => self.get_pll_output_frequency(state),
+            _ => clock_source_selecting::HSI_FREQUENCY,
+        }
+    }
+
+    /// Record a violation if `LATENCY` is below the minimum for the current `HCLK`
+    fn check_flash_latency(&self, state: &mut SimulatedState) {
+        let ahb_prescaler: u32 = RccCfgrValue::from_bits(state.rcc_cfgr)
+            .ahb_prescaler()
+            .into();
+        let cpu_clock = self.get_system_clock_frequency_of(state) / ahb_prescaler;
+        let flash_latency =
+            FlashAcrValue::from_bits(*state.memory.get(&FLASH_ACR).unwrap_or(&0)).latency();
+
+        let is_enough = FlashReadLatency::minimum_for_cpu_clock(cpu_clock, self.supply_voltage)
+            .map_or(false, |minimum| {
+                flash_latency.to_register_bits() >= minimum.to_register_bits()
+            });
+        if !is_enough {
+            state.flash_latency_violations.push(FlashLatencyViolation {
+                cpu_clock,
+                flash_latency,
+            });
+        }
+    }
+
+    /// `PLLON` is cleared, but `PLLRDY` is still high
+    fn pll_is_stopping(&self, state: &SimulatedState) -> bool {
+        state.pll_on_since.is_none() && state.now < state.pll_stopped_at
+    }
+
+    /// `SWS` follows `SW` once the selected clock is ready
+    fn settle_clock_switch(&self, state: &mut SimulatedState) {
+        let clock_switch = state.rcc_cfgr & RCC_CFGR_SYS_CLOCK_SWITCH_BITS;
+        if clock_switch == state.clock_switch_status {
+            return;
+        }
+
+        if let Some(ready_at) = self.clock_ready_at(state, clock_switch) {
+            let switched_at =
+                ready_at.max(state.clock_switch_written_at) + self.clock_switch_cycles;
+            if state.now >= switched_at {
+                state.clock_switch_status = clock_switch;
+                self.check_flash_latency(state);
+            }
+        }
+    }
+
+    /// The on bits which the hardware refuses to clear, because that clock is used
+    /// as system clock right now
+    fn locked_on_bits(&self, state: &SimulatedState) -> u32 {
+        match state.clock_switch_status {
+            CLOCK_SWITCH_HSI => RCC_CR_HSI_IS_ON,
+            CLOCK_SWITCH_HSE => RCC_CR_HSE_IS_ON,
+            CLOCK_SWITCH_PLL => {
+                if state.rcc_pllcfgr & RCC_PLLCFGR_PLL_SRC_IS_HSE_BITS != 0 {
+                    RCC_CR_MAIN_PLL_IS_ON | RCC_CR_HSE_IS_ON
+                } else {
+                    RCC_CR_MAIN_PLL_IS_ON | RCC_CR_HSI_IS_ON
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_rcc_cr(&self, state: &mut SimulatedState, value: u32) {
+        let value = (value & !RCC_CR_READY_BITS) | self.locked_on_bits(state);
+        let now = state.now;
+
+        if value & RCC_CR_MAIN_PLL_IS_ON == 0 && self.is_ready(state, self.pll_ready_at(state)) {
+            state.pll_stopped_at = now + self.pll_stop_cycles;
+        }
+
+        let update_since = |since: &mut Option<u64>, bit: u32| match (value & bit != 0, *since) {
+            (true, None) => *since = Some(now),
+            (false, _) => *since = None,
+            _ => {}
+        };
+        update_since(&mut state.hsi_on_since, RCC_CR_HSI_IS_ON);
+        update_since(&mut state.hse_on_since, RCC_CR_HSE_IS_ON);
+        update_since(&mut state.pll_on_since, RCC_CR_MAIN_PLL_IS_ON);
+
+        state.rcc_cr = value;
+    }
+
+    fn read_rcc_cr(&self, state: &SimulatedState) -> u32 {
+        let mut value = state.rcc_cr;
+
+        if self.is_ready(state, state.hsi_on_since) {
+            value |= RCC_CR_HSI_IS_STABLE;
+        }
+        if self.is_ready(state, self.hse_ready_at(state)) {
+            value |= RCC_CR_HSE_IS_STABLE;
+        }
+        if self.is_ready(state, self.pll_ready_at(state)) || self.pll_is_stopping(state) {
+            value |= RCC_CR_MAIN_PLL_IS_READY;
+        }
+
+        value
+    }
+}
+
+///
+impl RegisterAccess for SimulatedRegisters {
+    fn read(&self, address: u32) -> u32 {
+        let mut state = self.state.borrow_mut();
+        self.advance_state(&mut state, ACCESS_CYCLES);
+
+        match address {
+            RCC_CR => self.read_rcc_cr(&state),
+            RCC_CGFCR => {
+                (state.rcc_cfgr & !RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS)
+                    | (state.clock_switch_status << RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_START_BIT)
+            }
+            RCC_PLLCFGR => state.rcc_pllcfgr,
+            STK_CTRL => {
+                // `COUNTFLAG` is cleared by reading
+                let value = state.stk_ctrl;
+                state.stk_ctrl &= !STK_CTRL_COUNTDOWN_TO_ZERO_BIT;
+                value
+            }
+            STK_LOAD => state.stk_load,
+            STK_VAL => state.stk_val,
+            _ => *state.memory.get(&address).unwrap_or(&0),
+        }
+    }
+
+    fn write(&self, address: u32, value: u32) {
+        let mut state = self.state.borrow_mut();
+        self.advance_state(&mut state, ACCESS_CYCLES);
+        state.writes.push((address, value));
+
+        match address {
+            RCC_CR => self.write_rcc_cr(&mut state, value),
+            RCC_CGFCR => {
+                if (value ^ state.rcc_cfgr) & RCC_CFGR_SYS_CLOCK_SWITCH_BITS != 0 {
+                    state.clock_switch_written_at = state.now;
+                }
+                state.rcc_cfgr = value & !RCC_CFGR_SYS_CLOCK_SWITCH_STATUS_BITS;
+                self.check_flash_latency(&mut state);
+            }
+            RCC_PLLCFGR => {
+                // Ignored until the PLL is stopped
+                if state.pll_on_since.is_none() && !self.pll_is_stopping(&state) {
+                    state.rcc_pllcfgr = value;
+                }
+            }
+            STK_CTRL => {
+                state.stk_ctrl = (value & !STK_CTRL_COUNTDOWN_TO_ZERO_BIT)
+                    | (state.stk_ctrl & STK_CTRL_COUNTDOWN_TO_ZERO_BIT);
+            }
+            STK_LOAD => state.stk_load = value & 0x00FF_FFFF,
+            STK_VAL => {
+                // Any write clears the counter and `COUNTFLAG`
+                state.stk_val = 0;
+                state.stk_ctrl &= !STK_CTRL_COUNTDOWN_TO_ZERO_BIT;
+            }
+            FLASH_ACR => {
+                state.memory.insert(address, value);
+                self.check_flash_latency(&mut state);
+            }
+            _ => {
+                state.memory.insert(address, value);
+            }
+        }
+
+        self.settle_clock_switch(&mut state);
+    }
+
+    fn delay(&self, cycles: u32) {
+        self.advance(cycles as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hse_becomes_ready_after_startup_cycles() {
+        let registers = SimulatedRegisters::new().hse_startup_cycles(10);
+        assert_eq!(
+            registers.read(RCC_CR) & RCC_CR_HSI_IS_STABLE,
+            RCC_CR_HSI_IS_STABLE
+        );
+
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_HSE_IS_ON);
+        assert_eq!(registers.read(RCC_CR) & RCC_CR_HSE_IS_STABLE, 0);
+
+        registers.advance(10);
+        assert_eq!(
+            registers.read(RCC_CR) & RCC_CR_HSE_IS_STABLE,
+            RCC_CR_HSE_IS_STABLE
+        );
+    }
+
+    #[test]
+    fn hse_never_ready() {
+        let registers = SimulatedRegisters::new().hse_never_ready();
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_HSE_IS_ON);
+        registers.advance(1_000_000);
+        assert_eq!(registers.read(RCC_CR) & RCC_CR_HSE_IS_STABLE, 0);
+    }
+
+    #[test]
+    fn pll_only_locks_with_valid_config() {
+        let registers = SimulatedRegisters::new().pll_lock_cycles(5);
+
+        // M = 1 is not allowed
+        registers.write(RCC_PLLCFGR, (336 << 6) | 1);
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_MAIN_PLL_IS_ON);
+        registers.advance(100);
+        assert_eq!(registers.read(RCC_CR) & RCC_CR_MAIN_PLL_IS_READY, 0);
+
+        // Can't change `RCC_PLLCFGR` while the PLL is on
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON);
+        registers.write(RCC_PLLCFGR, (336 << 6) | 16);
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_MAIN_PLL_IS_ON);
+        registers.advance(5);
+        assert_eq!(
+            registers.read(RCC_CR) & RCC_CR_MAIN_PLL_IS_READY,
+            RCC_CR_MAIN_PLL_IS_READY
+        );
+
+        // Nor before `PLLRDY` goes low
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON);
+        registers.write(RCC_PLLCFGR, (192 << 6) | 16);
+        assert_eq!(registers.read(RCC_PLLCFGR), (336 << 6) | 16);
+        registers.advance(10);
+        assert_eq!(registers.read(RCC_CR) & RCC_CR_MAIN_PLL_IS_READY, 0);
+        registers.write(RCC_PLLCFGR, (192 << 6) | 16);
+        assert_eq!(registers.read(RCC_PLLCFGR), (192 << 6) | 16);
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_MAIN_PLL_IS_ON);
+        registers.advance(5);
+        assert_eq!(
+            registers.read(RCC_CR) & RCC_CR_MAIN_PLL_IS_READY,
+            RCC_CR_MAIN_PLL_IS_READY
+        );
+    }
+
+    #[test]
+    fn clock_switch_status_follows_ready_clock() {
+        let registers = SimulatedRegisters::new().hse_startup_cycles(20);
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON | RCC_CR_HSE_IS_ON);
+        registers.write(RCC_CGFCR, CLOCK_SWITCH_HSE);
+        assert_eq!(registers.read(RCC_CGFCR) >> 2 & 0b11, CLOCK_SWITCH_HSI);
+
+        registers.advance(30);
+        assert_eq!(registers.read(RCC_CGFCR) >> 2 & 0b11, CLOCK_SWITCH_HSE);
+        assert_eq!(
+            registers.get_system_clock_frequency(),
+            clock_source_selecting::HSE_FREQUENCY
+        );
+
+        // HSE is the system clock now, can't turn it off
+        registers.write(RCC_CR, RCC_CR_HSI_IS_ON);
+        assert_eq!(registers.read(RCC_CR) & RCC_CR_HSE_IS_ON, RCC_CR_HSE_IS_ON);
+    }
+
+    #[test]
+    fn system_tick_counts_down_and_reloads() {
+        let registers = SimulatedRegisters::new();
+        registers.write(STK_LOAD, 99);
+        registers.write(STK_VAL, 0);
+        registers.write(
+            STK_CTRL,
+            STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT
+                | STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT
+                | STK_CTRL_ENABLE_BIT,
+        );
+
+        // 1 cycle to reload, 100 cycles for each countdown to zero
+        registers.advance(1 + 250);
+        assert_eq!(registers.take_system_tick_exceptions(), 2);
+        assert_eq!(registers.take_system_tick_exceptions(), 0);
+
+        let control = registers.read(STK_CTRL);
+        assert_eq!(
+            control & STK_CTRL_COUNTDOWN_TO_ZERO_BIT,
+            STK_CTRL_COUNTDOWN_TO_ZERO_BIT
+        );
+        assert_eq!(registers.read(STK_CTRL) & STK_CTRL_COUNTDOWN_TO_ZERO_BIT, 0);
+    }
+
+    #[test]
+    fn system_tick_with_ahb_divided_by_8() {
+        let registers = SimulatedRegisters::new();
+        registers.write(STK_LOAD, 999);
+        registers.write(STK_VAL, 0);
+        registers.write(STK_CTRL, STK_CTRL_ENABLE_BIT);
+
+        // 1 tick to reload, then 10 ticks down, the read itself is less than 1 tick
+        registers.advance(8 * 11);
+        assert_eq!(registers.read(STK_VAL), 989);
+    }
+}
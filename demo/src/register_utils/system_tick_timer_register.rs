@@ -1,5 +1,5 @@
-use crate::rcc_clock_settings::{clock_source_selecting, RCC_CR};
-use core::ptr;
+use crate::register;
+use crate::register_access::RegisterAccess;
 
 #[cfg(feature = "enable-debug")]
 use cortex_m_semihosting::hprintln;
@@ -8,18 +8,95 @@ use cortex_m_semihosting::hprintln;
 pub const STK_CTRL: u32 = 0xE000E010; // page 246
 pub const STK_LOAD: u32 = 0xE000E014; // page 246
 pub const STK_VAL: u32 = 0xE000E018; // page 246
+pub const STK_CALIB: u32 = 0xE000E01C; // page 246
 
-pub const STK_CTRL_ENABLE_START_BIT: u8 = 0;
-pub const STK_CTRL_ENABLE_BIT: u32 = 1;
+/// `STK_LOAD` and `STK_VAL` only have 24 bits
+pub const MAX_RELOAD_VALUE: u32 = 0x00FF_FFFF;
 
-pub const STK_CTRL_EXCEPTION_REQUEST_ENABLE_START_BIT: u8 = 1;
-pub const STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT: u32 = 1 << 1;
+// ------ System Control Block (SCB) --------------------------
+pub const SCB_ICSR: u32 = 0xE000ED04; // page 225
 
-pub const STK_CTRL_USE_CPU_CLOCK_FREQUENCY_START_BIT: u8 = 2;
-pub const STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT: u32 = 1 << 2;
+register! {
+    /// SysTick control and status register (STK_CTRL), page 247
+    pub struct StkCtrlValue: "STK_CTRL" @ STK_CTRL, reset = 0x0000_0000, {
+        /// Counter enable
+        enabled, set_enabled: ENABLE[0, 1] => bool;
+        /// Counting down to zero asserts the SysTick exception request
+        exception_enabled, set_exception_enabled: TICKINT[1, 1] => bool;
+        /// Clock source, `true` for processor clock (AHB) and `false` for AHB/8
+        use_cpu_clock, set_use_cpu_clock: CLKSOURCE[2, 1] => bool;
+        /// Timer counted to 0 since last time this was read, clear on read
+        counted_to_zero, set_counted_to_zero: COUNTFLAG[16, 1] => bool;
+    }
+}
+
+register! {
+    /// SysTick reload value register (STK_LOAD), page 248
+    pub struct StkLoadValue: "STK_LOAD" @ STK_LOAD, reset = 0x0000_0000, {
+        /// The value to load into `STK_VAL` when the counter reaches 0
+        reload, set_reload: RELOAD[0, 24] => u32;
+    }
+}
+
+register! {
+    /// SysTick current value register (STK_VAL), page 249
+    pub struct StkValValue: "STK_VAL" @ STK_VAL, reset = 0x0000_0000, {
+        /// The current value of the SysTick counter
+        current, set_current: CURRENT[0, 24] => u32;
+    }
+}
+
+register! {
+    /// Interrupt control and state register (ICSR), only the SysTick bits, page 225
+    pub struct ScbIcsrValue: "ICSR" @ SCB_ICSR, reset = 0x0000_0000, {
+        /// Write `1` to clear the pending SysTick exception
+        clear_system_tick_pending, set_clear_system_tick_pending: PENDSTCLR[25, 1] => bool;
+        /// The SysTick exception is pending, write `1` to make it pending
+        system_tick_pending, set_system_tick_pending: PENDSTSET[26, 1] => bool;
+    }
+}
+
+register! {
+    /// SysTick calibration value register (STK_CALIB), page 249
+    pub struct StkCalibValue: "STK_CALIB" @ STK_CALIB, reset = 0x0000_0000, {
+        /// The reload value for 10ms, `0` means unknown
+        ten_milliseconds, set_ten_milliseconds: TENMS[0, 24] => u32;
+        /// `TENMS` is not exact
+        skew, set_skew: SKEW[30, 1] => bool;
+        /// No reference clock (AHB/8) is provided
+        no_reference, set_no_reference: NOREF[31, 1] => bool;
+    }
+}
+
+/// The clock which the SysTick counter counts on (`STK_CTRL` bit 2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemTickClockSource {
+    /// Processor clock (AHB)
+    Ahb,
+    /// External reference clock, AHB/8
+    AhbDividedBy8,
+}
+
+///
+impl SystemTickClockSource {
+    ///
+    pub fn get_frequency(&self, cpu_clock_frequency_in_hertz: u32) -> u32 {
+        match self {
+            Self::Ahb => cpu_clock_frequency_in_hertz,
+            Self::AhbDividedBy8 => cpu_clock_frequency_in_hertz / 8,
+        }
+    }
+}
 
-pub const STK_CTRL_COUNTDOWN_TO_ZERO_START_BIT: u8 = 16;
-pub const STK_CTRL_COUNTDOWN_TO_ZERO_BIT: u32 = 1 << 16;
+/// Why the tick frequency can't be used
+#[derive(Debug, Clone, PartialEq)]
+pub enum SystemTickError {
+    /// The reload value (`ticks - 1`) doesn't fit into the 24 bits `STK_LOAD`, use a
+    /// higher tick frequency or `AhbDividedBy8`
+    ReloadValueTooLarge(u32),
+    /// Less than 2 clock ticks per SysTick tick, `0` is not a valid reload value
+    TickFrequencyTooHigh(u32),
+}
 
 pub struct SystemTickTimer {}
 
@@ -37,11 +114,11 @@ impl SystemTickTimer {
     ///
     /// 4. Write a value to `STL_VAL` register will clear it's current value to `0` and reset
     ///    `STK_CTRL` register `bit16` to `0`. It works like a reset countdown trigger.
-    pub fn enable(cpu_clock_frequency_in_hertz: u32, enable_exception: bool) {
-        let stk_ctrl_write_ptr = STK_CTRL as *mut u32;
-        let stk_reload_write_ptr = STK_LOAD as *mut u32;
-        let stk_val_write_ptr = STK_VAL as *mut u32;
-
+    pub fn enable(
+        registers: &impl RegisterAccess,
+        cpu_clock_frequency_in_hertz: u32,
+        enable_exception: bool,
+    ) -> Result<(), SystemTickError> {
         // `1mhz` means got 1_000_000 system ticks per second or 1_000 system ticks per milliseconds.
         //
         // so `cpu_clock_frequency_in_hertz` means got `cpu_clock_frequency_in_hertz / 1000`
@@ -54,91 +131,250 @@ impl SystemTickTimer {
         // but can't we do like this, as the `stk_val` register only got 24 bit to save the current
         // countdown value which means will overflow. that's why we only can save `1ms` ticks
         // value and run `n` ms in a loop to reach the goal: wait for n milliseconds
-
         //
-        // and page 248 tells us that we need to do ` (cpu_clock_frequency_in_hertz / 1000) - 1`
-        let reload_countdown = (cpu_clock_frequency_in_hertz / 1000) - 1;
-        let set_bits = if enable_exception {
-            STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT
-                | STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT
-                | STK_CTRL_ENABLE_BIT
-        } else {
-            STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT | STK_CTRL_ENABLE_BIT
+        // 1ms on the processor clock always fits into 24 bits (168_000 ticks at 168Mhz), it
+        // only fails below 2kHz (e.g. a 0Hz `cpu_clock_frequency_in_hertz`)
+        Self::start(
+            registers,
+            cpu_clock_frequency_in_hertz,
+            SystemTickClockSource::Ahb,
+            1000,
+            enable_exception,
+        )
+    }
+
+    /// Tick `tick_frequency_in_hertz` times per second (e.g. 100Hz, 1kHz, 10kHz) on
+    /// `clock_source`, the reload value is checked before anything is written.
+    pub fn start(
+        registers: &impl RegisterAccess,
+        cpu_clock_frequency_in_hertz: u32,
+        clock_source: SystemTickClockSource,
+        tick_frequency_in_hertz: u32,
+        enable_exception: bool,
+    ) -> Result<(), SystemTickError> {
+        let reload = Self::calculate_reload_value(
+            clock_source.get_frequency(cpu_clock_frequency_in_hertz),
+            tick_frequency_in_hertz,
+        )?;
+
+        Self::disable(registers);
+        Self::set_reload_value(registers, reload);
+        Self::reset(registers);
+
+        StkCtrlValue::modify(registers, |r| {
+            r.set_use_cpu_clock(clock_source == SystemTickClockSource::Ahb)
+                .set_enabled(true)
+                .set_exception_enabled(enable_exception)
+        });
+
+        Ok(())
+    }
+
+    /// Page 248 tells us that the reload value is `ticks - 1`
+    pub fn calculate_reload_value(
+        clock_source_frequency_in_hertz: u32,
+        tick_frequency_in_hertz: u32,
+    ) -> Result<u32, SystemTickError> {
+        let ticks = match clock_source_frequency_in_hertz.checked_div(tick_frequency_in_hertz) {
+            Some(ticks) if ticks >= 2 => ticks,
+            _ => {
+                return Err(SystemTickError::TickFrequencyTooHigh(
+                    tick_frequency_in_hertz,
+                ))
+            }
         };
-        unsafe {
-            ptr::write_volatile(stk_reload_write_ptr, reload_countdown);
-            ptr::write_volatile(stk_val_write_ptr, 0x00000000);
-            ptr::write_volatile(stk_ctrl_write_ptr as *mut u32, set_bits);
+
+        if ticks - 1 > MAX_RELOAD_VALUE {
+            return Err(SystemTickError::ReloadValueTooLarge(ticks - 1));
         }
+
+        Ok(ticks - 1)
+    }
+
+    /// Stop counting, the exception setting and the counter value are kept
+    pub fn disable(registers: &impl RegisterAccess) {
+        StkCtrlValue::modify(registers, |r| r.set_enabled(false));
     }
 
     ///
-    pub fn get_current_countdown_value() -> u32 {
-        let stk_val_read_ptr = STK_VAL as *const u32;
-        unsafe { ptr::read_volatile(stk_val_read_ptr) }
+    pub fn is_enabled(registers: &impl RegisterAccess) -> bool {
+        StkCtrlValue::read(registers).enabled()
     }
 
-    #[cfg(feature = "enable-debug")]
-    pub fn print_config() {
-        let stk_ctrl_read_ptr = STK_CTRL as *const u32;
-        let stk_ctrl_register_value = unsafe { ptr::read_volatile(stk_ctrl_read_ptr) };
-
-        let enable_bit =
-            (stk_ctrl_register_value & STK_CTRL_ENABLE_BIT) >> STK_CTRL_ENABLE_START_BIT;
-        let control_register_enabled = enable_bit == 1;
-
-        let exception_request_enabled_bit = (stk_ctrl_register_value
-            & STK_CTRL_EXCEPTION_REQUEST_ENABLE_BIT)
-            >> STK_CTRL_EXCEPTION_REQUEST_ENABLE_START_BIT;
-        let exception_request_enabled = exception_request_enabled_bit == 1;
-
-        let use_cpu_clock_frequency_bit = (stk_ctrl_register_value
-            & STK_CTRL_USE_CPU_CLOCK_FREQUENCY_BIT)
-            >> STK_CTRL_USE_CPU_CLOCK_FREQUENCY_START_BIT;
-        let use_cpu_clock_frequency = use_cpu_clock_frequency_bit == 1;
-        let clock_source_desc = if use_cpu_clock_frequency {
-            "Processor clock (AHB)"
+    /// The counter reached 0 since the last time, `COUNTFLAG` is cleared by this read
+    pub fn is_counted_to_zero(registers: &impl RegisterAccess) -> bool {
+        StkCtrlValue::read(registers).counted_to_zero()
+    }
+
+    /// Writing any value to `STK_VAL` clears the counter and `COUNTFLAG`, the counter
+    /// restarts from the reload value
+    pub fn reset(registers: &impl RegisterAccess) {
+        StkValValue::reset().write(registers);
+    }
+
+    /// Takes effect the next time the counter reaches 0 or after `reset()`. Only 24
+    /// bits are kept.
+    pub fn set_reload_value(registers: &impl RegisterAccess, reload: u32) {
+        StkLoadValue::reset()
+            .set_reload(reload & MAX_RELOAD_VALUE)
+            .write(registers);
+    }
+
+    ///
+    pub fn get_clock_source(registers: &impl RegisterAccess) -> SystemTickClockSource {
+        if StkCtrlValue::read(registers).use_cpu_clock() {
+            SystemTickClockSource::Ahb
         } else {
-            "AHB/8"
-        };
+            SystemTickClockSource::AhbDividedBy8
+        }
+    }
+
+    /// How many times the counter reaches 0 per second with the current clock source
+    /// and reload value
+    pub fn get_tick_frequency(
+        registers: &impl RegisterAccess,
+        cpu_clock_frequency_in_hertz: u32,
+    ) -> u32 {
+        Self::get_clock_source(registers).get_frequency(cpu_clock_frequency_in_hertz)
+            / (Self::get_reload_value(registers) + 1)
+    }
+
+    /// The `TENMS` reload value from `STK_CALIB`, `None` if the chip doesn't provide an
+    /// exact one. STM32F4 gives `18_750`, which is 1ms (not 10ms) on AHB/8 only when
+    /// HCLK is 150Mhz, page 249. So it's only a reference for checking the clock setup.
+    pub fn get_calibration_value(registers: &impl RegisterAccess) -> Option<u32> {
+        let calibration = StkCalibValue::read(registers);
+
+        if calibration.no_reference() || calibration.skew() || calibration.ten_milliseconds() == 0 {
+            None
+        } else {
+            Some(calibration.ten_milliseconds())
+        }
+    }
 
-        let countdown_to_zero_bit = (stk_ctrl_register_value & STK_CTRL_COUNTDOWN_TO_ZERO_BIT)
-            >> STK_CTRL_COUNTDOWN_TO_ZERO_START_BIT;
-        let countdown_to_zero = countdown_to_zero_bit == 1;
+    ///
+    pub fn get_current_countdown_value(registers: &impl RegisterAccess) -> u32 {
+        StkValValue::read(registers).current()
+    }
 
-        let stk_load_read_ptr = STK_LOAD as *const u32;
-        let stk_load_register_value = unsafe { ptr::read_volatile(stk_load_read_ptr) };
+    /// The countdown restarts from this value after reaching 0
+    pub fn get_reload_value(registers: &impl RegisterAccess) -> u32 {
+        StkLoadValue::read(registers).reload()
+    }
 
-        let reload_value = stk_load_register_value;
+    /// The counter reached 0 but the SysTick exception handler hasn't run yet, e.g.
+    /// the interrupts are disabled
+    pub fn is_exception_pending(registers: &impl RegisterAccess) -> bool {
+        ScbIcsrValue::read(registers).system_tick_pending()
+    }
 
-        let printing_header = "\n[ System Tick Timer Control Register (STK_CTRL) ]: \n";
-        let printing_header_2 = "\n\n[ System Tick Timer Reload Register (STK_LOAD) ]: \n";
+    #[cfg(feature = "enable-debug")]
+    pub fn print_config(registers: &impl RegisterAccess) {
         let _ = hprintln!(
-            "{}{}{}{}{}{}{}{}{}",
-            printing_header,
-            format_args!("value: {:034b}", stk_ctrl_register_value),
-            format_args!(
-                "\nCounter enabled: {:?}\t\t\t\t\t\t\t // bits: {:#03b}",
-                control_register_enabled, enable_bit
-            ),
-            format_args!(
-                "\nCounting down to zero to asserts the SysTick exception request: {:?}\t // bits: {:#03b}",
-                exception_request_enabled, exception_request_enabled_bit
-            ),
-            format_args!(
-                "\nClock source: {:?}\t\t\t\t\t // bits: {:#03b}",
-                clock_source_desc, use_cpu_clock_frequency_bit
-            ),
-            format_args!(
-                "\nTimer counted to 0: {:?}\t\t\t\t\t\t // bits: {:#03b}",
-                countdown_to_zero, countdown_to_zero_bit
-            ),
-            printing_header_2,
-            format_args!("value: {:034b}", stk_load_register_value),
-            format_args!(
-                "\nReload value: {:?}\t // bits: {:#026b}",
-                reload_value, reload_value
-            ),
+            "\n[ System Tick Timer Control Register (STK_CTRL) ]: \n{:#?}\n\n[ System Tick Timer Reload Register (STK_LOAD) ]: \n{:#?}\n\n[ System Tick Timer Calibration Register (STK_CALIB) ]: \n{:#?}",
+            StkCtrlValue::read(registers),
+            StkLoadValue::read(registers),
+            StkCalibValue::read(registers)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::register_access::RecordingRegisters;
+
+    #[test]
+    fn reload_value_for_tick_frequency() {
+        let hclk = 168_000_000;
+        let ahb = SystemTickClockSource::Ahb.get_frequency(hclk);
+        let ahb_8 = SystemTickClockSource::AhbDividedBy8.get_frequency(hclk);
+
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, 1000),
+            Ok(167_999)
         );
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, 10_000),
+            Ok(16_799)
+        );
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb_8, 100),
+            Ok(209_999)
+        );
+
+        // 100Hz needs 1_680_000 ticks on AHB, still fits into 24 bits
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, 100),
+            Ok(1_679_999)
+        );
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, 1),
+            Err(SystemTickError::ReloadValueTooLarge(167_999_999))
+        );
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, hclk),
+            Err(SystemTickError::TickFrequencyTooHigh(hclk))
+        );
+        assert_eq!(
+            SystemTickTimer::calculate_reload_value(ahb, 0),
+            Err(SystemTickError::TickFrequencyTooHigh(0))
+        );
+    }
+
+    #[test]
+    fn start_disable_and_reset() {
+        let registers = RecordingRegisters::new();
+
+        assert_eq!(
+            SystemTickTimer::start(&registers, 84_000_000, SystemTickClockSource::Ahb, 1, true),
+            Err(SystemTickError::ReloadValueTooLarge(83_999_999))
+        );
+        assert!(registers.get_writes().is_empty());
+
+        SystemTickTimer::start(
+            &registers,
+            84_000_000,
+            SystemTickClockSource::AhbDividedBy8,
+            10,
+            true,
+        )
+        .unwrap();
+        assert_eq!(registers.get_value(STK_LOAD), 1_049_999);
+        assert!(SystemTickTimer::is_enabled(&registers));
+        assert_eq!(
+            SystemTickTimer::get_clock_source(&registers),
+            SystemTickClockSource::AhbDividedBy8
+        );
+        assert_eq!(
+            SystemTickTimer::get_tick_frequency(&registers, 84_000_000),
+            10
+        );
+
+        SystemTickTimer::disable(&registers);
+        let control = StkCtrlValue::read(&registers);
+        assert!(!control.enabled() && control.exception_enabled());
+
+        registers.write(STK_VAL, 1234);
+        SystemTickTimer::reset(&registers);
+        assert_eq!(SystemTickTimer::get_current_countdown_value(&registers), 0);
+
+        registers.set_forced_bits(STK_CTRL, StkCtrlValue::COUNTFLAG.mask());
+        assert!(SystemTickTimer::is_counted_to_zero(&registers));
+    }
+
+    #[test]
+    fn calibration_value() {
+        let registers = RecordingRegisters::new();
+        assert_eq!(SystemTickTimer::get_calibration_value(&registers), None);
+
+        registers.write(STK_CALIB, 18_750);
+        assert_eq!(
+            SystemTickTimer::get_calibration_value(&registers),
+            Some(18_750)
+        );
+
+        registers.write(STK_CALIB, StkCalibValue::SKEW.mask() | 18_750);
+        assert_eq!(SystemTickTimer::get_calibration_value(&registers), None);
     }
 }
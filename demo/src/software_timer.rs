@@ -0,0 +1,398 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+
+/// What to do when a timer expires.
+///
+/// The callback runs in the SysTick exception handler, keep it short. A flag is set to
+/// `true` for the thread mode to poll, and it's the thread mode's job to clear it.
+#[derive(Clone, Copy)]
+pub enum TimerAction {
+    Callback(fn()),
+    Flag(&'static AtomicBool),
+}
+
+///
+impl TimerAction {
+    ///
+    pub fn fire(&self) {
+        match self {
+            Self::Callback(callback) => callback(),
+            Self::Flag(flag) => flag.store(true, Ordering::Release),
+        }
+    }
+}
+
+/// Identifies a started timer, it never matches a later timer in the same slot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerHandle {
+    index: usize,
+    generation: u32,
+}
+
+/// Why a timer can't be started
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimerError {
+    /// All `CAPACITY` slots are in use
+    Full,
+    /// A periodic timer needs at least 1ms period
+    ZeroPeriod,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    generation: u32,
+    deadline: u64,
+    /// `0` for a one-shot timer
+    period: u64,
+    action: TimerAction,
+    missed_deadlines: u32,
+}
+
+/// A fixed-capacity software timer service in milliseconds, no heap.
+///
+/// `advance()` is called with the uptime on every SysTick, the timers which are due
+/// fire once. If the ticks were late (e.g. the interrupts were disabled for a while),
+/// every deadline which has passed before the timer could fire is counted as missed,
+/// a periodic timer skips to its next deadline after `now` instead of firing the
+/// missed ones in a burst.
+///
+/// The earliest deadline is cached, so the ticks without any due timer only take a
+/// compare.
+pub struct SoftwareTimers<const CAPACITY: usize> {
+    timers: [Option<Timer>; CAPACITY],
+    now: u64,
+    next_deadline: u64,
+    next_generation: u32,
+    total_missed_deadlines: u32,
+}
+
+///
+impl<const CAPACITY: usize> SoftwareTimers<CAPACITY> {
+    ///
+    pub const fn new() -> Self {
+        SoftwareTimers {
+            timers: [None; CAPACITY],
+            now: 0,
+            next_deadline: u64::MAX,
+            next_generation: 0,
+            total_missed_deadlines: 0,
+        }
+    }
+
+    /// Fire `action` once after `delay` milliseconds
+    pub fn start_one_shot(
+        &mut self,
+        delay: u32,
+        action: TimerAction,
+    ) -> Result<TimerHandle, TimerError> {
+        self.start(delay as u64, 0, action)
+    }
+
+    /// Fire `action` every `period` milliseconds, the first time is after one period
+    pub fn start_periodic(
+        &mut self,
+        period: u32,
+        action: TimerAction,
+    ) -> Result<TimerHandle, TimerError> {
+        if period == 0 {
+            return Err(TimerError::ZeroPeriod);
+        }
+
+        self.start(period as u64, period as u64, action)
+    }
+
+    /// `false` if the timer has fired (one-shot) or has been cancelled already
+    pub fn cancel(&mut self, handle: TimerHandle) -> bool {
+        if !self.is_active(handle) {
+            return false;
+        }
+
+        self.timers[handle.index] = None;
+        self.update_next_deadline();
+        true
+    }
+
+    ///
+    pub fn is_active(&self, handle: TimerHandle) -> bool {
+        self.get_timer(handle).is_some()
+    }
+
+    /// How many deadlines of this timer have been missed, `None` if it's not active
+    pub fn get_missed_deadlines(&self, handle: TimerHandle) -> Option<u32> {
+        self.get_timer(handle).map(|timer| timer.missed_deadlines)
+    }
+
+    /// The missed deadlines of all the timers, including the finished ones
+    pub fn get_total_missed_deadlines(&self) -> u32 {
+        self.total_missed_deadlines
+    }
+
+    /// The uptime of the last `advance()`
+    pub fn get_now(&self) -> u64 {
+        self.now
+    }
+
+    /// Move the time to `now` (milliseconds), and return the actions of the timers
+    /// which are due, the caller fires them.
+    pub fn advance(&mut self, now: u64) -> [Option<TimerAction>; CAPACITY] {
+        let mut due_actions = [None; CAPACITY];
+        self.now = now;
+
+        if now < self.next_deadline {
+            return due_actions;
+        }
+
+        for (slot, due_action) in self.timers.iter_mut().zip(due_actions.iter_mut()) {
+            let timer = match slot {
+                Some(timer) if timer.deadline <= now => timer,
+                _ => continue,
+            };
+
+            *due_action = Some(timer.action);
+
+            let missed_deadlines = if timer.period == 0 {
+                (now > timer.deadline) as u64
+            } else {
+                // All the deadlines before `now`, the one at `now` is on time
+                let missed_deadlines = (now - timer.deadline + timer.period - 1) / timer.period;
+                timer.deadline += ((now - timer.deadline) / timer.period + 1) * timer.period;
+                missed_deadlines
+            } as u32;
+
+            timer.missed_deadlines = timer.missed_deadlines.saturating_add(missed_deadlines);
+            self.total_missed_deadlines =
+                self.total_missed_deadlines.saturating_add(missed_deadlines);
+
+            if timer.period == 0 {
+                *slot = None;
+            }
+        }
+
+        self.update_next_deadline();
+        due_actions
+    }
+
+    fn start(
+        &mut self,
+        delay: u64,
+        period: u64,
+        action: TimerAction,
+    ) -> Result<TimerHandle, TimerError> {
+        let index = self
+            .timers
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(TimerError::Full)?;
+
+        let generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1);
+
+        let deadline = self.now + delay;
+        self.timers[index] = Some(Timer {
+            generation,
+            deadline,
+            period,
+            action,
+            missed_deadlines: 0,
+        });
+        self.next_deadline = self.next_deadline.min(deadline);
+
+        Ok(TimerHandle { index, generation })
+    }
+
+    fn get_timer(&self, handle: TimerHandle) -> Option<&Timer> {
+        self.timers
+            .get(handle.index)?
+            .as_ref()
+            .filter(|timer| timer.generation == handle.generation)
+    }
+
+    fn update_next_deadline(&mut self) {
+        self.next_deadline = self
+            .timers
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+            .unwrap_or(u64::MAX);
+    }
+}
+
+/// `SoftwareTimers` in a `static`, shared by the SysTick exception handler and the
+/// thread mode. Every access runs in a critical section, the due actions are fired
+/// after leaving it.
+///
+/// ```
+/// static TIMERS: SharedSoftwareTimers<4> = SharedSoftwareTimers::new();
+///
+/// monotonic::set_tick_handler(|now| TIMERS.advance(now));
+/// TIMERS.start_periodic(500, TimerAction::Callback(toggle_led))?;
+/// ```
+pub struct SharedSoftwareTimers<const CAPACITY: usize> {
+    timers: Mutex<RefCell<SoftwareTimers<CAPACITY>>>,
+}
+
+///
+impl<const CAPACITY: usize> SharedSoftwareTimers<CAPACITY> {
+    ///
+    pub const fn new() -> Self {
+        SharedSoftwareTimers {
+            timers: Mutex::new(RefCell::new(SoftwareTimers::new())),
+        }
+    }
+
+    /// Call it from the SysTick exception handler
+    pub fn advance(&self, now: u64) {
+        let due_actions = interrupt::free(|cs| self.timers.borrow(cs).borrow_mut().advance(now));
+
+        for action in due_actions.iter().flatten() {
+            action.fire();
+        }
+    }
+
+    ///
+    pub fn start_one_shot(
+        &self,
+        delay: u32,
+        action: TimerAction,
+    ) -> Result<TimerHandle, TimerError> {
+        interrupt::free(|cs| {
+            self.timers
+                .borrow(cs)
+                .borrow_mut()
+                .start_one_shot(delay, action)
+        })
+    }
+
+    ///
+    pub fn start_periodic(
+        &self,
+        period: u32,
+        action: TimerAction,
+    ) -> Result<TimerHandle, TimerError> {
+        interrupt::free(|cs| {
+            self.timers
+                .borrow(cs)
+                .borrow_mut()
+                .start_periodic(period, action)
+        })
+    }
+
+    ///
+    pub fn cancel(&self, handle: TimerHandle) -> bool {
+        interrupt::free(|cs| self.timers.borrow(cs).borrow_mut().cancel(handle))
+    }
+
+    ///
+    pub fn get_missed_deadlines(&self, handle: TimerHandle) -> Option<u32> {
+        interrupt::free(|cs| self.timers.borrow(cs).borrow().get_missed_deadlines(handle))
+    }
+
+    ///
+    pub fn get_total_missed_deadlines(&self) -> u32 {
+        interrupt::free(|cs| self.timers.borrow(cs).borrow().get_total_missed_deadlines())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicU32;
+
+    fn fire_all<const CAPACITY: usize>(due_actions: [Option<TimerAction>; CAPACITY]) -> usize {
+        due_actions
+            .iter()
+            .flatten()
+            .map(|action| action.fire())
+            .count()
+    }
+
+    #[test]
+    fn periodic_and_one_shot_timers() {
+        static BLINKS: AtomicU32 = AtomicU32::new(0);
+        static UART_TIMEOUT: AtomicBool = AtomicBool::new(false);
+
+        let mut timers = SoftwareTimers::<4>::new();
+        let blink = timers
+            .start_periodic(
+                500,
+                TimerAction::Callback(|| {
+                    BLINKS.fetch_add(1, Ordering::Relaxed);
+                }),
+            )
+            .unwrap();
+        let timeout = timers
+            .start_one_shot(20, TimerAction::Flag(&UART_TIMEOUT))
+            .unwrap();
+
+        for now in 1..=1500 {
+            fire_all(timers.advance(now));
+
+            if now == 19 {
+                assert!(!UART_TIMEOUT.load(Ordering::Acquire));
+            }
+        }
+
+        assert_eq!(BLINKS.load(Ordering::Relaxed), 3);
+        assert!(UART_TIMEOUT.load(Ordering::Acquire));
+        assert!(timers.is_active(blink));
+        assert!(!timers.is_active(timeout));
+        assert_eq!(timers.get_total_missed_deadlines(), 0);
+
+        assert!(timers.cancel(blink));
+        assert!(!timers.cancel(blink));
+        assert_eq!(fire_all(timers.advance(2000)), 0);
+    }
+
+    #[test]
+    fn late_ticks_are_reported_as_missed_deadlines() {
+        let mut timers = SoftwareTimers::<2>::new();
+        let periodic = timers
+            .start_periodic(10, TimerAction::Callback(|| {}))
+            .unwrap();
+        timers
+            .start_one_shot(15, TimerAction::Callback(|| {}))
+            .unwrap();
+
+        // On time
+        assert_eq!(fire_all(timers.advance(10)), 1);
+        assert_eq!(timers.get_missed_deadlines(periodic), Some(0));
+
+        // The ticks stopped until 45: deadline 20, 30 and 40 are missed, it fires once
+        // and the next deadline is 50
+        assert_eq!(fire_all(timers.advance(45)), 2);
+        assert_eq!(timers.get_missed_deadlines(periodic), Some(3));
+        assert_eq!(fire_all(timers.advance(49)), 0);
+        assert_eq!(fire_all(timers.advance(50)), 1);
+
+        // 3 from the periodic one, 1 from the one-shot one
+        assert_eq!(timers.get_total_missed_deadlines(), 4);
+    }
+
+    #[test]
+    fn capacity_and_stale_handles() {
+        let mut timers = SoftwareTimers::<1>::new();
+        assert_eq!(
+            timers.start_periodic(0, TimerAction::Callback(|| {})),
+            Err(TimerError::ZeroPeriod)
+        );
+
+        let first = timers
+            .start_one_shot(1, TimerAction::Callback(|| {}))
+            .unwrap();
+        assert_eq!(
+            timers.start_one_shot(1, TimerAction::Callback(|| {})),
+            Err(TimerError::Full)
+        );
+
+        fire_all(timers.advance(1));
+        let second = timers
+            .start_one_shot(1, TimerAction::Callback(|| {}))
+            .unwrap();
+
+        // Same slot, but the old handle can't cancel the new timer
+        assert!(!timers.cancel(first));
+        assert!(timers.is_active(second));
+    }
+}